@@ -0,0 +1,146 @@
+//! Times a simple arithmetic function, run with `cargo bench` for the tree walker and with
+//! `cargo bench --features compiled` for the compiled closure backend.
+
+use freight_vm::{
+    execution_engine::ExecutionEngine,
+    expression::Expression,
+    function::{ArgCount, FunctionRef, FunctionWriter},
+    operators::BinaryOperator,
+    type_registry::RuntimeTypeId,
+    value::Value,
+    TypeSystem,
+};
+use std::{convert::Infallible, hint::black_box, time::Instant};
+
+#[derive(Debug, Clone)]
+struct BenchTypeSystem;
+
+impl TypeSystem for BenchTypeSystem {
+    type Value = BenchValue;
+    type UnaryOp = Add;
+    type BinaryOp = Add;
+    type TernaryOp = Infallible;
+    type Init = ();
+    type TypeId = BenchTypeId;
+    type GlobalContext = ();
+    type RuntimeError = Infallible;
+    type ErrorConverter = ();
+    type RegionAlloc = ();
+    type CallFallback = ();
+}
+
+#[derive(Debug, PartialEq)]
+struct BenchTypeId;
+
+impl RuntimeTypeId for BenchTypeId {}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+enum BenchValue {
+    Number(i64),
+    Function(FunctionRef<BenchTypeSystem>),
+    #[default]
+    Null,
+}
+
+impl From<FunctionRef<BenchTypeSystem>> for BenchValue {
+    fn from(value: FunctionRef<BenchTypeSystem>) -> Self {
+        BenchValue::Function(value)
+    }
+}
+
+impl Value for BenchValue {
+    type TS = BenchTypeSystem;
+
+    fn uninitialized_reference() -> Self {
+        BenchValue::Null
+    }
+
+    fn get_type(&self) -> &BenchTypeId {
+        &BenchTypeId
+    }
+
+    fn deep_clone(&self) -> Self {
+        self.clone()
+    }
+
+    fn dupe_ref(&self) -> Self {
+        self.clone()
+    }
+
+    fn into_ref(self) -> Self {
+        self
+    }
+
+    fn cast_to_function(&self) -> Option<&FunctionRef<BenchTypeSystem>> {
+        match self {
+            BenchValue::Function(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    fn assign(&mut self, value: BenchValue) {
+        *self = value;
+    }
+
+    fn is_truthy(&self) -> bool {
+        !matches!(self, BenchValue::Null | BenchValue::Number(0))
+    }
+
+    #[cfg(feature = "variadic_functions")]
+    fn gen_list(_: Vec<Self>) -> Self {
+        BenchValue::Null
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Add;
+
+impl BinaryOperator<BenchValue> for Add {
+    fn apply_2(&self, a: &BenchValue, b: &BenchValue) -> BenchValue {
+        match (a, b) {
+            (BenchValue::Number(a), BenchValue::Number(b)) => BenchValue::Number(a + b),
+            _ => BenchValue::Null,
+        }
+    }
+}
+
+impl freight_vm::operators::UnaryOperator<BenchValue> for Add {
+    fn apply_1(&self, val: &BenchValue) -> BenchValue {
+        self.apply_2(val, &BenchValue::Number(1))
+    }
+}
+
+fn main() {
+    let mut engine = ExecutionEngine::<BenchTypeSystem>::new_default();
+    let mut sum = FunctionWriter::new(ArgCount::Fixed(1));
+    let x = sum.create_variable();
+    let add = |l, r| Expression::BinaryOpEval(Add, [l, r].into());
+    for _ in 0..100 {
+        sum.evaluate_expression(Expression::AssignStack(
+            x,
+            add(
+                add(Expression::stack(x), Expression::stack(0)),
+                Expression::RawValue(BenchValue::Number(1)),
+            )
+            .into(),
+        ));
+    }
+    sum.evaluate_expression(Expression::stack(x));
+    let sum = engine.register_function(sum, 0);
+
+    let iterations = 100_000;
+    let start = Instant::now();
+    for i in 0..iterations {
+        black_box(engine.call(&sum, [BenchValue::Number(i)]).unwrap());
+    }
+    let elapsed = start.elapsed();
+    let backend = if cfg!(feature = "compiled") {
+        "compiled"
+    } else {
+        "tree walker"
+    };
+    println!(
+        "{backend}: {iterations} calls in {elapsed:?} ({:?} per call)",
+        elapsed / iterations as u32
+    );
+}
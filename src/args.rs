@@ -0,0 +1,86 @@
+//! Typed access to the arguments of native functions
+
+use crate::{
+    error::FreightError, execution_engine::Stack, function::FunctionRef, value::Value, TypeSystem,
+};
+
+/// A Rust type which arguments can be converted into with [Args::get]
+pub trait FromValue<V: Value>: Sized {
+    /// What the argument should have been, for error messages
+    const EXPECTED: &'static str;
+
+    /// Convert a value, or return `None` if it isn't of the expected type
+    fn from_value(value: &V) -> Option<Self>;
+}
+
+impl<V: Value> FromValue<V> for FunctionRef<V::TS> {
+    const EXPECTED: &'static str = "a function";
+
+    fn from_value(value: &V) -> Option<Self> {
+        value.cast_to_function().cloned()
+    }
+}
+
+/// A view over the stack frame passed to a native function, whose accessors return descriptive
+/// errors instead of panicking
+pub struct Args<'a, TS: TypeSystem> {
+    values: Stack<'a, TS::Value>,
+}
+
+impl<'a, TS: TypeSystem> Args<'a, TS> {
+    pub fn new(values: Stack<'a, TS::Value>) -> Self {
+        Args { values }
+    }
+
+    /// The size of the frame. Optional arguments which weren't passed hold the default value.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Fail with [FreightError::IncorrectArgumentCount] unless the frame holds at least `n`
+    /// arguments
+    pub fn len_at_least(&self, n: usize) -> Result<(), FreightError> {
+        if self.values.len() < n {
+            return Err(FreightError::IncorrectArgumentCount {
+                expected_min: n,
+                expected_max: None,
+                actual: self.values.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The argument at an index
+    pub fn value(&self, index: usize) -> Result<&TS::Value, FreightError> {
+        self.len_at_least(index + 1)?;
+        Ok(&self.values[index])
+    }
+
+    /// Move the argument at an index out of the frame, leaving the default value in its place
+    pub fn take(&mut self, index: usize) -> Result<TS::Value, FreightError> {
+        self.len_at_least(index + 1)?;
+        Ok(std::mem::take(&mut self.values[index]))
+    }
+
+    /// Convert the argument at an index, failing with [FreightError::InvalidArgument] if it isn't
+    /// of the expected type
+    pub fn get<T: FromValue<TS::Value>>(&self, index: usize) -> Result<T, FreightError> {
+        let value = self.value(index)?;
+        T::from_value(value).ok_or_else(|| FreightError::InvalidArgument {
+            index,
+            expected: T::EXPECTED,
+            found: value.summarize(1, 40),
+        })
+    }
+
+    /// The arguments from an index onwards, which is empty if the frame is smaller. With
+    /// variadic functions, the last of these holds the list of extra arguments.
+    pub fn rest(&mut self, start: usize) -> &mut [TS::Value] {
+        let start = start.min(self.values.len());
+        &mut self.values[start..]
+    }
+}
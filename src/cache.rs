@@ -0,0 +1,196 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::FreightError, execution_engine::ExecutionEngine, expression::NativeFunction,
+    value::Value, TypeSystem,
+};
+
+#[derive(Debug, Clone)]
+struct CacheEntry<V> {
+    id: u64,
+    key: V,
+    value: V,
+    inserted: Instant,
+}
+
+/// A bounded cache of values managed by the engine, which scripts use through the natives
+/// created by [ValueCache::get_native] and [ValueCache::put_native]. Keys are hashed with
+/// [Value::hash_key].
+///
+/// Once the cache is full, the entry which was inserted first is evicted. Entries older than the
+/// time to live are treated as missing.
+#[derive(Debug, Clone)]
+pub struct ValueCache<TS: TypeSystem> {
+    entries: HashMap<u64, Vec<CacheEntry<TS::Value>>>,
+    /// The hash and id of each entry, in insertion order. May contain entries which were removed.
+    order: VecDeque<(u64, u64)>,
+    next_id: u64,
+    len: usize,
+    pub max_entries: usize,
+    pub ttl: Option<Duration>,
+}
+
+impl<TS: TypeSystem> Default for ValueCache<TS> {
+    fn default() -> Self {
+        ValueCache {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            next_id: 0,
+            len: 0,
+            max_entries: 1024,
+            ttl: None,
+        }
+    }
+}
+
+fn hash_key<V: Value>(key: &V) -> Result<u64, FreightError> {
+    key.hash_key().ok_or_else(|| FreightError::Unhashable {
+        value: key.summarize(1, 40),
+    })
+}
+
+impl<TS: TypeSystem> ValueCache<TS> {
+    /// Get the value stored for a key, if there is one and it hasn't expired
+    pub fn get(&mut self, key: &TS::Value) -> Result<Option<TS::Value>, FreightError> {
+        let hash = hash_key(key)?;
+        let value = self.lookup(hash, key);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_cache_request(value.is_some());
+        Ok(value)
+    }
+
+    fn lookup(&mut self, hash: u64, key: &TS::Value) -> Option<TS::Value> {
+        let bucket = self.entries.get_mut(&hash)?;
+        let index = bucket.iter().position(|entry| entry.key == *key)?;
+        if self
+            .ttl
+            .is_some_and(|ttl| bucket[index].inserted.elapsed() >= ttl)
+        {
+            bucket.swap_remove(index);
+            self.len -= 1;
+            return None;
+        }
+        Some(bucket[index].value.clone())
+    }
+
+    /// Every stored key and value, including expired entries which haven't been removed yet
+    pub fn entries(&self) -> impl Iterator<Item = (&TS::Value, &TS::Value)> {
+        self.entries
+            .values()
+            .flatten()
+            .map(|entry| (&entry.key, &entry.value))
+    }
+
+    /// Store a value for a key, replacing any value already stored for it
+    pub fn put(&mut self, key: TS::Value, value: TS::Value) -> Result<(), FreightError> {
+        let hash = hash_key(&key)?;
+        let bucket = self.entries.entry(hash).or_default();
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.key == key) {
+            entry.value = value;
+            entry.inserted = Instant::now();
+            return Ok(());
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        bucket.push(CacheEntry {
+            id,
+            key,
+            value,
+            inserted: Instant::now(),
+        });
+        self.order.push_back((hash, id));
+        self.len += 1;
+        while self.len > self.max_entries {
+            self.evict_oldest();
+        }
+        if self.order.len() > 2 * self.max_entries.max(self.len) {
+            self.compact();
+        }
+        Ok(())
+    }
+
+    /// Remove the value stored for a key, returning it
+    pub fn remove(&mut self, key: &TS::Value) -> Result<Option<TS::Value>, FreightError> {
+        let hash = hash_key(key)?;
+        let Some(bucket) = self.entries.get_mut(&hash) else {
+            return Ok(None);
+        };
+        let Some(index) = bucket.iter().position(|entry| entry.key == *key) else {
+            return Ok(None);
+        };
+        self.len -= 1;
+        Ok(Some(bucket.swap_remove(index).value))
+    }
+
+    /// A copy whose keys and values are made independent of this cache's by `copy`
+    pub(crate) fn fork<E>(
+        &self,
+        copy: &mut impl FnMut(&TS::Value) -> Result<TS::Value, E>,
+    ) -> Result<ValueCache<TS>, E> {
+        let mut cache = self.clone();
+        for entry in cache.entries.values_mut().flatten() {
+            entry.key = copy(&entry.key)?;
+            entry.value = copy(&entry.value)?;
+        }
+        Ok(cache)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.len = 0;
+    }
+
+    /// The number of entries, including expired entries which haven't been removed yet
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn evict_oldest(&mut self) {
+        while let Some((hash, id)) = self.order.pop_front() {
+            let Some(bucket) = self.entries.get_mut(&hash) else {
+                continue;
+            };
+            if let Some(index) = bucket.iter().position(|entry| entry.id == id) {
+                bucket.swap_remove(index);
+                self.len -= 1;
+                return;
+            }
+        }
+    }
+
+    /// Drop the ids of removed entries from the insertion order
+    fn compact(&mut self) {
+        let entries = &self.entries;
+        self.order.retain(|(hash, id)| {
+            entries
+                .get(hash)
+                .is_some_and(|bucket| bucket.iter().any(|entry| entry.id == *id))
+        });
+        self.entries.retain(|_, bucket| !bucket.is_empty());
+    }
+
+    /// A native taking a key, which returns the value stored in the engine's cache for it or the
+    /// default value if there is none
+    pub fn get_native() -> NativeFunction<TS> {
+        NativeFunction::named("cache.get", |engine: &mut ExecutionEngine<TS>, args| {
+            Ok(engine.cache.get(&args[0])?.unwrap_or_default())
+        })
+    }
+
+    /// A native taking a key and a value, which stores the value in the engine's cache
+    pub fn put_native() -> NativeFunction<TS> {
+        NativeFunction::named("cache.put", |engine: &mut ExecutionEngine<TS>, args| {
+            let value = std::mem::take(&mut args[1]);
+            engine.cache.put(std::mem::take(&mut args[0]), value)?;
+            Ok(Default::default())
+        })
+    }
+}
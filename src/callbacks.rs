@@ -0,0 +1,122 @@
+use std::{cell::UnsafeCell, collections::HashMap, rc::Rc};
+
+use crate::{
+    error::FreightError,
+    function::{FunctionRef, FunctionType},
+    slice_pool::RcSlicePool,
+    TypeSystem,
+};
+
+/// Identifies a handler registered with a [CallbackRegistry]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HandlerId(pub usize);
+
+#[derive(Debug, Clone)]
+struct Handler<TS: TypeSystem> {
+    id: HandlerId,
+    priority: i32,
+    func: FunctionRef<TS>,
+}
+
+/// Script functions handling named events, dispatched with
+/// [crate::execution_engine::ExecutionEngine::dispatch].
+///
+/// Handlers run in order of descending priority, and in registration order within a priority.
+/// A handler consumes the event by returning a value for which [CallbackRegistry::consumed] is
+/// true, and no handlers after it are run.
+#[derive(Debug, Clone)]
+pub struct CallbackRegistry<TS: TypeSystem> {
+    events: HashMap<Rc<str>, Vec<Handler<TS>>>,
+    next_id: usize,
+    /// Whether a handler's return value consumes the event. By default events are never consumed.
+    pub consumed: fn(&TS::Value) -> bool,
+}
+
+impl<TS: TypeSystem> Default for CallbackRegistry<TS> {
+    fn default() -> Self {
+        CallbackRegistry {
+            events: HashMap::new(),
+            next_id: 0,
+            consumed: |_| false,
+        }
+    }
+}
+
+impl<TS: TypeSystem> CallbackRegistry<TS> {
+    /// Handle an event with a function, which is called with the event's arguments
+    pub fn register(&mut self, event: &str, priority: i32, func: FunctionRef<TS>) -> HandlerId {
+        let id = HandlerId(self.next_id);
+        self.next_id += 1;
+        let handlers = self.events.entry(event.into()).or_default();
+        let index = handlers.partition_point(|handler| handler.priority >= priority);
+        handlers.insert(index, Handler { id, priority, func });
+        id
+    }
+
+    /// Remove a handler, returning `false` if it was not registered
+    pub fn unregister(&mut self, id: HandlerId) -> bool {
+        for handlers in self.events.values_mut() {
+            if let Some(index) = handlers.iter().position(|handler| handler.id == id) {
+                handlers.remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Remove every handler. Ids aren't reused, so ids of removed handlers stay unregistered.
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    /// The handlers of an event, in dispatch order
+    pub fn handlers(&self, event: &str) -> impl Iterator<Item = (HandlerId, &FunctionRef<TS>)> {
+        self.events
+            .get(event)
+            .into_iter()
+            .flatten()
+            .map(|handler| (handler.id, &handler.func))
+    }
+
+    /// Every registered handler along with its event, in no particular order
+    pub fn all_handlers(&self) -> impl Iterator<Item = (&Rc<str>, HandlerId, &FunctionRef<TS>)> {
+        self.events.iter().flat_map(|(event, handlers)| {
+            handlers
+                .iter()
+                .map(move |handler| (event, handler.id, &handler.func))
+        })
+    }
+
+    /// A copy whose handlers' captured values are made independent of this registry's by
+    /// `copy`, with their environments taken from `pool`
+    pub(crate) fn fork<E>(
+        &self,
+        copy: &mut impl FnMut(&TS::Value) -> Result<TS::Value, E>,
+        pool: &Rc<UnsafeCell<RcSlicePool<TS::Value>>>,
+    ) -> Result<CallbackRegistry<TS>, E> {
+        let mut registry = self.clone();
+        for handler in registry.events.values_mut().flatten() {
+            if let FunctionType::CapturingRef(captures) = &handler.func.function_type {
+                let captures = captures
+                    .iter()
+                    .map(&mut *copy)
+                    .collect::<Result<Vec<_>, _>>()?;
+                handler.func.function_type =
+                    FunctionType::CapturingRef(RcSlicePool::from_pool(pool.clone(), captures));
+            }
+        }
+        Ok(registry)
+    }
+}
+
+/// The outcome of dispatching an event
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DispatchReport {
+    /// The number of handlers which were run
+    pub handled: usize,
+    /// The handler which consumed the event, if any
+    pub consumed_by: Option<HandlerId>,
+    /// The errors raised by handlers. A failing handler doesn't stop the dispatch unless
+    /// execution was cancelled.
+    pub errors: Vec<(HandlerId, FreightError)>,
+}
@@ -0,0 +1,110 @@
+//! Compiles the most common kinds of expressions into nested closures when a function is registered
+
+use std::fmt::Debug;
+
+use crate::{
+    error::FreightError,
+    execution_engine::ExecutionEngine,
+    expression::{Expression, VariableType},
+    operators::{BinaryOperator, TernaryOperator, UnaryOperator},
+    value::Value,
+    TypeSystem,
+};
+
+type CompiledFn<TS> = dyn Fn(
+    &mut ExecutionEngine<TS>,
+    &mut [<TS as TypeSystem>::Value],
+    &[<TS as TypeSystem>::Value],
+) -> Result<<TS as TypeSystem>::Value, FreightError>;
+
+/// An expression compiled into a closure
+pub struct CompiledExpression<TS: TypeSystem>(Box<CompiledFn<TS>>);
+
+impl<TS: TypeSystem> Debug for CompiledExpression<TS> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CompiledExpression").finish()
+    }
+}
+
+impl<TS: TypeSystem> CompiledExpression<TS> {
+    #[inline]
+    pub(crate) fn evaluate(
+        &self,
+        engine: &mut ExecutionEngine<TS>,
+        stack: &mut [TS::Value],
+        captured: &[TS::Value],
+    ) -> Result<TS::Value, FreightError> {
+        (self.0)(engine, stack, captured)
+    }
+
+    /// Compile an expression, which must not be moved, mutated or dropped while the result is alive
+    pub(crate) unsafe fn compile(expr: &Expression<TS>) -> CompiledExpression<TS> {
+        let compiled: Box<CompiledFn<TS>> = match expr {
+            Expression::RawValue(value) => {
+                let value = value.clone();
+                Box::new(move |_, _, _| Ok(value.clone()))
+            }
+            Expression::Variable(VariableType::Stack(addr)) => {
+                let addr = *addr;
+                Box::new(move |_, stack, _| Ok(stack[addr].dupe_ref()))
+            }
+            Expression::Variable(VariableType::Captured(addr)) => {
+                let addr = *addr;
+                Box::new(move |_, _, captured| Ok(captured[addr].dupe_ref()))
+            }
+            Expression::Variable(VariableType::Global(addr)) => {
+                let addr = *addr;
+                Box::new(move |engine, _, _| Ok(engine.use_global(addr).dupe_ref()))
+            }
+            Expression::BinaryOpEval(op, operands) => {
+                let op = op.clone();
+                let [l, r] = &**operands;
+                let (l, r) = (Self::compile(l), Self::compile(r));
+                Box::new(move |engine, stack, captured| {
+                    let l = l.evaluate(engine, stack, captured)?;
+                    if let Some(result) = op.short_circuit(&l) {
+                        return Ok(result);
+                    }
+                    let r = r.evaluate(engine, stack, captured)?;
+                    Ok(op.apply_2(&l, &r))
+                })
+            }
+            Expression::UnaryOpEval(op, operand) => {
+                let op = op.clone();
+                let operand = Self::compile(operand);
+                Box::new(move |engine, stack, captured| {
+                    Ok(op.apply_1(&operand.evaluate(engine, stack, captured)?))
+                })
+            }
+            Expression::TernaryOpEval(op, operands) => {
+                let op = op.clone();
+                let [a, b, c] = &**operands;
+                let (a, b, c) = (Self::compile(a), Self::compile(b), Self::compile(c));
+                Box::new(move |engine, stack, captured| {
+                    let a = a.evaluate(engine, stack, captured)?;
+                    let b = b.evaluate(engine, stack, captured)?;
+                    let c = c.evaluate(engine, stack, captured)?;
+                    Ok(op.apply_3(&a, &b, &c))
+                })
+            }
+            Expression::AssignStack(addr, value)
+                if !matches!(**value, Expression::Initialize(..)) =>
+            {
+                let addr = *addr;
+                let value = Self::compile(value);
+                Box::new(move |engine, stack, captured| {
+                    let value = value.evaluate(engine, stack, captured)?;
+                    stack[addr].assign(value);
+                    Ok(Default::default())
+                })
+            }
+            _ => {
+                let expr = expr as *const Expression<TS>;
+                Box::new(move |engine, stack, captured| {
+                    engine.evaluate_internal(&*expr, stack, captured)
+                })
+            }
+        };
+        CompiledExpression(compiled)
+    }
+}
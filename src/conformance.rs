@@ -0,0 +1,314 @@
+//! Checks which panic if the engine doesn't follow the evaluation semantics Freight guarantees
+
+use crate::{
+    error::FreightError,
+    execution_engine::{ArgumentEvaluation, ExecutionEngine, Verbosity},
+    expression::{Expression, VariableType},
+    function::{ArgCount, FunctionRef, FunctionWriter},
+    value::{Value, ValueFactory},
+};
+
+/// The minimal set of values and operators needed to run the conformance checks
+pub trait ConformanceTypeSystem: ValueFactory {
+    /// The global context the checks' engines are created with
+    fn global_context() -> Self::GlobalContext;
+    /// Read an integer value, returning `None` if the value is not an integer
+    fn as_int(value: &Self::Value) -> Option<i64>;
+    /// A binary operator which adds two integers
+    fn add() -> Self::BinaryOp;
+    /// The number of elements in a list created by [crate::value::Value::gen_list]
+    #[cfg(feature = "variadic_functions")]
+    fn list_len(value: &Self::Value) -> Option<usize>;
+}
+
+/// Generate a `#[test]` for each conformance check, run against the given type system
+#[macro_export]
+macro_rules! conformance_tests {
+    ($ts:ty) => {
+        #[test]
+        fn conformance_capture_timing() {
+            $crate::conformance::capture_timing::<$ts>();
+        }
+
+        #[test]
+        fn conformance_argument_evaluation_order() {
+            $crate::conformance::argument_evaluation_order::<$ts>();
+        }
+
+        #[test]
+        fn conformance_operator_evaluation_order() {
+            $crate::conformance::operator_evaluation_order::<$ts>();
+        }
+
+        #[test]
+        fn conformance_return_target_routing() {
+            $crate::conformance::return_target_routing::<$ts>();
+        }
+
+        #[test]
+        fn conformance_return_from_argument() {
+            $crate::conformance::return_from_argument::<$ts>();
+        }
+
+        #[test]
+        fn conformance_return_across_calls() {
+            $crate::conformance::return_across_calls::<$ts>();
+        }
+
+        #[test]
+        fn conformance_error_propagation() {
+            $crate::conformance::error_propagation::<$ts>();
+        }
+
+        #[test]
+        fn conformance_variadic_packing() {
+            $crate::conformance::variadic_packing::<$ts>();
+        }
+    };
+}
+
+/// Run every conformance check
+pub fn run_all<TS: ConformanceTypeSystem>() {
+    capture_timing::<TS>();
+    argument_evaluation_order::<TS>();
+    operator_evaluation_order::<TS>();
+    return_target_routing::<TS>();
+    return_from_argument::<TS>();
+    return_across_calls::<TS>();
+    error_propagation::<TS>();
+    variadic_packing::<TS>();
+}
+
+fn int<TS: ConformanceTypeSystem>(value: i64) -> Expression<TS> {
+    Expression::RawValue(TS::int(value))
+}
+
+fn expect_int<TS: ConformanceTypeSystem>(result: Result<TS::Value, FreightError>, expected: i64) {
+    let value = result.unwrap_or_else(|e| panic!("Expected {expected}, got error: {e}"));
+    assert_eq!(
+        TS::as_int(&value),
+        Some(expected),
+        "Got {}",
+        summarize(&value)
+    );
+}
+
+fn summarize<V: Value>(value: &V) -> String {
+    let verbosity = Verbosity::default();
+    value.summarize(verbosity.depth, verbosity.max_len)
+}
+
+/// An engine with a global counter starting at 0 and a function which increments the counter
+/// and returns its new value
+fn counter_engine<TS: ConformanceTypeSystem>() -> (ExecutionEngine<TS>, usize, FunctionRef<TS>) {
+    let mut engine = ExecutionEngine::new(TS::global_context());
+    let counter = engine.create_global();
+    engine
+        .evaluate(&Expression::AssignGlobal(counter, int(0).into()))
+        .unwrap();
+    let mut tick = FunctionWriter::new(ArgCount::Fixed(0));
+    tick.evaluate_expression(Expression::AssignGlobal(
+        counter,
+        Expression::BinaryOpEval(TS::add(), [Expression::global(counter), int(1)].into()).into(),
+    ));
+    tick.evaluate_expression(Expression::global(counter));
+    let tick = engine.register_function(tick, 0);
+    (engine, counter, tick)
+}
+
+/// A function returning its first argument
+fn first<TS: ConformanceTypeSystem>(engine: &mut ExecutionEngine<TS>) -> FunctionRef<TS> {
+    let mut first = FunctionWriter::new(ArgCount::Fixed(2));
+    first.evaluate_expression(Expression::stack(0));
+    engine.register_function(first, 0)
+}
+
+/// Captured values are read when the closure is created, and only see later assignments if
+/// [Value::dupe_ref] shares a reference
+pub fn capture_timing<TS: ConformanceTypeSystem>() {
+    let mut slot = TS::Value::uninitialized_reference();
+    slot.assign(TS::int(1));
+    let captured = slot.dupe_ref();
+    slot.assign(TS::int(2));
+    let expected = TS::as_int(&captured).unwrap_or(1);
+
+    let mut engine = ExecutionEngine::<TS>::new(TS::global_context());
+    let mut closure = FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![]);
+    closure.evaluate_expression(Expression::captured(0));
+    closure.set_captures(vec![VariableType::Stack(0)]);
+    let closure = engine.register_function(closure, 0);
+
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    let x = main.create_variable();
+    let f = main.create_variable();
+    main.evaluate_expression(Expression::AssignStack(x, int(1).into()));
+    main.evaluate_expression(Expression::AssignStack(
+        f,
+        Expression::FunctionCapture(closure).into(),
+    ));
+    main.evaluate_expression(Expression::AssignStack(x, int(2).into()));
+    main.evaluate_expression(Expression::DynamicFunctionCall(
+        Expression::stack(f).into(),
+        vec![],
+    ));
+    let main = engine.register_function(main, 0);
+    expect_int::<TS>(engine.call(&main, []), expected);
+}
+
+/// Call arguments are evaluated left to right, in every [ArgumentEvaluation] mode
+pub fn argument_evaluation_order<TS: ConformanceTypeSystem>() {
+    argument_evaluation_order_in::<TS>(ArgumentEvaluation::Interleaved);
+    argument_evaluation_order_in::<TS>(ArgumentEvaluation::Eager);
+}
+
+fn argument_evaluation_order_in<TS: ConformanceTypeSystem>(mode: ArgumentEvaluation) {
+    let (mut engine, counter, tick) = counter_engine::<TS>();
+    engine.argument_evaluation = mode;
+    let first = first(&mut engine);
+    let expr = Expression::StaticFunctionCall(
+        first,
+        vec![
+            Expression::StaticFunctionCall(tick.clone(), vec![]),
+            Expression::StaticFunctionCall(tick, vec![]),
+        ],
+    );
+    expect_int::<TS>(engine.evaluate(&expr), 1);
+    expect_int::<TS>(engine.evaluate(&Expression::global(counter)), 2);
+}
+
+/// The left operand of a binary operator is evaluated before the right operand
+pub fn operator_evaluation_order<TS: ConformanceTypeSystem>() {
+    let (mut engine, counter, tick) = counter_engine::<TS>();
+    let expr = Expression::BinaryOpEval(
+        TS::add(),
+        [
+            Expression::StaticFunctionCall(tick, vec![]),
+            Expression::global(counter),
+        ]
+        .into(),
+    );
+    expect_int::<TS>(engine.evaluate(&expr), 2);
+}
+
+/// A return is caught by the innermost enclosing target with a matching id, either a
+/// [Expression::ReturnTarget] or the function it was registered with
+pub fn return_target_routing<TS: ConformanceTypeSystem>() {
+    let mut engine = ExecutionEngine::<TS>::new(TS::global_context());
+    let function_target = engine.create_return_target();
+    let block_target = engine.create_return_target();
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.evaluate_expression(Expression::BinaryOpEval(
+        TS::add(),
+        [
+            Expression::ReturnTarget(
+                block_target,
+                Expression::Return(block_target, int(10).into()).into(),
+            ),
+            int(1),
+        ]
+        .into(),
+    ));
+    let main = engine.register_function(main, function_target);
+    expect_int::<TS>(engine.call(&main, []), 11);
+
+    let mut early = FunctionWriter::new(ArgCount::Fixed(0));
+    early.evaluate_expression(Expression::Return(function_target, int(5).into()));
+    early.evaluate_expression(int(6));
+    let early = engine.register_function(early, function_target);
+    expect_int::<TS>(engine.call(&early, []), 5);
+}
+
+/// A return inside an argument abandons the call: later arguments are not evaluated and the
+/// callee is not run
+pub fn return_from_argument<TS: ConformanceTypeSystem>() {
+    let (mut engine, counter, tick) = counter_engine::<TS>();
+    let target = engine.create_return_target();
+    let mut callee = FunctionWriter::new(ArgCount::Fixed(2));
+    callee.evaluate_expression(Expression::StaticFunctionCall(tick.clone(), vec![]));
+    let callee = engine.register_function(callee, 0);
+    let expr = Expression::ReturnTarget(
+        target,
+        Expression::StaticFunctionCall(
+            callee,
+            vec![
+                Expression::Return(target, int(7).into()),
+                Expression::StaticFunctionCall(tick, vec![]),
+            ],
+        )
+        .into(),
+    );
+    expect_int::<TS>(engine.evaluate(&expr), 7);
+    expect_int::<TS>(engine.evaluate(&Expression::global(counter)), 0);
+}
+
+/// A return crosses closure calls and [Expression::Catch] to reach the call of the function
+/// whose target it names, and reaches the host if that function isn't being called
+pub fn return_across_calls<TS: ConformanceTypeSystem>() {
+    let mut engine = ExecutionEngine::<TS>::new(TS::global_context());
+    let outer_target = engine.create_return_target();
+    let closure_target = engine.create_return_target();
+    let mut closure =
+        FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![VariableType::Stack(0)]);
+    closure.evaluate_expression(Expression::Catch(
+        Expression::Return(outer_target, Expression::captured(0).into()).into(),
+    ));
+    let closure = engine.register_function(closure, closure_target);
+
+    let mut outer = FunctionWriter::new(ArgCount::Fixed(0));
+    let x = outer.create_variable();
+    let f = outer.create_variable();
+    outer.evaluate_expression(Expression::AssignStack(x, int(3).into()));
+    outer.evaluate_expression(Expression::AssignStack(
+        f,
+        Expression::FunctionCapture(closure).into(),
+    ));
+    outer.evaluate_expression(Expression::DynamicFunctionCall(
+        Expression::stack(f).into(),
+        vec![],
+    ));
+    outer.evaluate_expression(int(4));
+    let outer = engine.register_function(outer, outer_target);
+    expect_int::<TS>(engine.call(&outer, []), 3);
+
+    assert_eq!(
+        engine.evaluate(&Expression::Return(outer_target, int(1).into())),
+        Err(FreightError::Return {
+            target: outer_target
+        })
+    );
+}
+
+/// An error raised while evaluating an argument aborts the call before any later arguments are
+/// evaluated
+pub fn error_propagation<TS: ConformanceTypeSystem>() {
+    let (mut engine, counter, tick) = counter_engine::<TS>();
+    let first = first(&mut engine);
+    let expr = Expression::StaticFunctionCall(
+        first,
+        vec![
+            Expression::DynamicFunctionCall(int(1).into(), vec![]),
+            Expression::StaticFunctionCall(tick, vec![]),
+        ],
+    );
+    assert_eq!(
+        engine.evaluate(&expr),
+        Err(FreightError::InvalidInvocationTarget)
+    );
+    expect_int::<TS>(engine.evaluate(&Expression::global(counter)), 0);
+}
+
+/// Arguments past the maximum of a variadic function are packed into a list in the slot after
+/// the last fixed argument. Does nothing unless the `variadic_functions` feature is enabled.
+pub fn variadic_packing<TS: ConformanceTypeSystem>() {
+    #[cfg(feature = "variadic_functions")]
+    {
+        let mut engine = ExecutionEngine::<TS>::new(TS::global_context());
+        let mut rest = FunctionWriter::new(ArgCount::new_variadic(1..=1));
+        rest.evaluate_expression(Expression::stack(1));
+        let rest = engine.register_function(rest, 0);
+        let value = engine
+            .call(&rest, [TS::int(1), TS::int(2), TS::int(3)])
+            .unwrap();
+        assert_eq!(TS::list_len(&value), Some(2), "Got {}", summarize(&value));
+    }
+}
@@ -0,0 +1,104 @@
+//! Higher-order natives for frontends which encode control flow as calls taking functions,
+//! rather than compiling to [crate::expression::Expression::Conditional] and
+//! [crate::expression::Expression::While]. The loops run inside the natives, so they don't grow
+//! the stack however many times they repeat, and they check for cancellation like the loop
+//! expressions do. Conditions are tested with [Value::is_truthy].
+//!
+//! For example, `if x { a } else { b }` can be compiled to a call of [ControlFlow::if_then_else]
+//! with two closures:
+//!
+//! ```ignore
+//! let if_then_else = FunctionRef::new_native(id, ControlFlow::if_then_else(), ArgCount::Fixed(3));
+//! writer.evaluate_expression(Expression::StaticFunctionCall(
+//!     if_then_else,
+//!     vec![
+//!         Expression::stack(x),
+//!         Expression::FunctionCapture(then_branch),
+//!         Expression::FunctionCapture(else_branch),
+//!     ],
+//! ));
+//! ```
+
+use crate::{
+    args::{Args, FromValue},
+    error::FreightError,
+    execution_engine::ExecutionEngine,
+    expression::NativeFunction,
+    function::FunctionRef,
+    value::{Value, ValueFactory},
+    TypeSystem,
+};
+
+/// Constructors for the control flow natives
+pub struct ControlFlow;
+
+impl ControlFlow {
+    /// A native taking a condition and two functions taking no arguments. Calls the first
+    /// function if the condition is truthy and the second otherwise, returning its result.
+    pub fn if_then_else<TS: TypeSystem>() -> NativeFunction<TS> {
+        NativeFunction::named(
+            "control_flow.if_then_else",
+            |engine: &mut ExecutionEngine<TS>, args| {
+                let args = Args::<TS>::new(args);
+                let branch = match args.value(0)?.is_truthy() {
+                    true => 1,
+                    false => 2,
+                };
+                let branch: FunctionRef<TS> = args.get(branch)?;
+                call(engine, &branch, [])
+            },
+        )
+    }
+
+    /// A native taking two functions taking no arguments. Calls the body for as long as calling
+    /// the condition returns a truthy value, returning the default value.
+    pub fn while_do<TS: TypeSystem>() -> NativeFunction<TS> {
+        NativeFunction::named(
+            "control_flow.while_do",
+            |engine: &mut ExecutionEngine<TS>, args| {
+                let (condition, body): (FunctionRef<TS>, FunctionRef<TS>) = {
+                    let args = Args::<TS>::new(args);
+                    (args.get(0)?, args.get(1)?)
+                };
+                while call(engine, &condition, [])?.is_truthy() {
+                    call(engine, &body, [])?;
+                    engine.check_loop_cancellation(args)?;
+                }
+                Ok(Default::default())
+            },
+        )
+    }
+
+    /// A native taking a start and an end int and a function taking one argument. Calls the
+    /// function with each int from the start up to but not including the end, returning the
+    /// default value.
+    pub fn for_range<TS: ValueFactory>() -> NativeFunction<TS>
+    where
+        i64: FromValue<TS::Value>,
+    {
+        NativeFunction::named(
+            "control_flow.for_range",
+            |engine: &mut ExecutionEngine<TS>, args| {
+                let (start, end, body): (i64, i64, FunctionRef<TS>) = {
+                    let args = Args::<TS>::new(args);
+                    (args.get(0)?, args.get(1)?, args.get(2)?)
+                };
+                for i in start..end {
+                    call(engine, &body, [TS::int(i)])?;
+                    engine.check_loop_cancellation(args)?;
+                }
+                Ok(Default::default())
+            },
+        )
+    }
+}
+
+/// Call a function from within a native, keeping the error trace of the call being made
+fn call<TS: TypeSystem, const N: usize>(
+    engine: &mut ExecutionEngine<TS>,
+    func: &FunctionRef<TS>,
+    args: [TS::Value; N],
+) -> Result<TS::Value, FreightError> {
+    let mut args = args.into_iter();
+    engine.call_internal(func, |_| Ok(args.next().unwrap()), N)
+}
@@ -0,0 +1,617 @@
+//! Functions which suspend themselves with [Expression::Yield] and are resumed by the host
+
+use std::{collections::HashSet, fmt::Debug, future::Future, pin::Pin};
+
+use crate::{
+    error::FreightError,
+    execution_engine::ExecutionEngine,
+    expression::Expression,
+    function::{FunctionRef, FunctionType},
+    slice_pool::{IntoExactSizeIterator, PooledRcSlice},
+    value::Value,
+    TypeSystem,
+};
+
+/// The output of an [AsyncNativeFunction], awaited by the host
+pub type NativeFuture<V> = Pin<Box<dyn Future<Output = Result<V, FreightError>>>>;
+
+type AsyncNativeInner<TS> = fn(
+    &mut ExecutionEngine<TS>,
+    Vec<<TS as TypeSystem>::Value>,
+) -> NativeFuture<<TS as TypeSystem>::Value>;
+
+/// A native returning a future of its result, which doesn't borrow the engine, called with
+/// [Expression::AwaitNative]
+#[derive(Clone)]
+pub struct AsyncNativeFunction<TS: TypeSystem>(pub(crate) AsyncNativeInner<TS>);
+
+impl<TS: TypeSystem> AsyncNativeFunction<TS> {
+    pub fn new(value: AsyncNativeInner<TS>) -> Self {
+        Self(value)
+    }
+}
+
+impl<TS: TypeSystem> Debug for AsyncNativeFunction<TS> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AsyncNativeFunction")
+    }
+}
+
+/// Why [Coroutine::resume] returned
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoroutineState<V> {
+    /// The coroutine suspended itself with this value, and can be resumed again
+    Yielded(V),
+    /// The coroutine finished with this value
+    Returned(V),
+}
+
+/// A step on the way from a coroutine's body to the [Expression::Yield] it is suspended at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum Position {
+    /// A top-level expression of the function
+    Statement(usize),
+    /// The body of a loop, with the number of iterations of a [Expression::Repeat] left after
+    /// this one
+    Loop(usize),
+    /// The branch of a [Expression::Conditional] that was taken
+    Branch(bool),
+    /// The value of an [Expression::AssignStack]
+    Assign,
+    Yield,
+}
+
+enum Flow<V> {
+    Done(V),
+    Yielded(V),
+}
+
+/// A call to a function which can suspend itself at a top-level yield, or one nested in loops,
+/// conditionals and stack assignments
+pub struct Coroutine<TS: TypeSystem> {
+    func: FunctionRef<TS>,
+    /// The generation of the function when the coroutine started, see
+    /// [ExecutionEngine::replace_function]
+    generation: u32,
+    frame: Vec<TS::Value>,
+    captured: Option<PooledRcSlice<TS::Value>>,
+    /// Where the coroutine is suspended, outermost first. Empty before it has run.
+    suspended: Vec<Position>,
+    finished: bool,
+    /// The scopes of the calls the coroutine was started in, see
+    /// [ExecutionEngine::call_with_scope]
+    scopes: Vec<TS::Value>,
+    /// The future of the [Expression::AwaitNative] the coroutine is suspended at
+    pending: Option<NativeFuture<TS::Value>>,
+}
+
+/// The state of a suspended [Coroutine] and the globals, random number generator and clock of
+/// the engine running it
+#[cfg(feature = "serde")]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "TS: crate::serialization::SerializableTypeSystem")]
+pub struct Checkpoint<TS: TypeSystem> {
+    /// The coroutine's function
+    func: FunctionRef<TS>,
+    frame: Vec<TS::Value>,
+    captured: Option<Vec<TS::Value>>,
+    suspended: Vec<Position>,
+    finished: bool,
+    #[serde(default)]
+    scopes: Vec<TS::Value>,
+    globals: Vec<TS::Value>,
+    rng: crate::services::Rng,
+    clock: crate::services::Clock,
+}
+
+/// Several suspended coroutines saved together with the globals, random number generator, clock
+/// and remaining fuel of the engine running them
+#[cfg(feature = "serde")]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "TS: crate::serialization::SerializableTypeSystem")]
+pub struct Snapshot<TS: TypeSystem> {
+    coroutines: Vec<SavedCoroutine<TS>>,
+    globals: Vec<TS::Value>,
+    rng: crate::services::Rng,
+    clock: crate::services::Clock,
+    fuel: Option<u64>,
+}
+
+/// The state of a suspended [Coroutine], without the engine running it
+#[cfg(feature = "serde")]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "TS: crate::serialization::SerializableTypeSystem")]
+struct SavedCoroutine<TS: TypeSystem> {
+    /// The coroutine's function
+    func: FunctionRef<TS>,
+    frame: Vec<TS::Value>,
+    captured: Option<Vec<TS::Value>>,
+    suspended: Vec<Position>,
+    finished: bool,
+    #[serde(default)]
+    scopes: Vec<TS::Value>,
+}
+
+/// The state of a single resumption
+struct Run<'a, TS: TypeSystem> {
+    frame: &'a mut [TS::Value],
+    captured: &'a [TS::Value],
+    suspended: &'a [Position],
+    /// The expressions of the function which are or contain a yield, found when it was built
+    yielding: &'a HashSet<*const Expression<TS>>,
+    /// Whether execution is still finding its way back to the yield it was suspended at
+    resuming: bool,
+    resume_value: Option<TS::Value>,
+    /// The path to the yield being suspended at, innermost first
+    path: Vec<Position>,
+    /// The future of the [Expression::AwaitNative] being suspended at
+    pending: Option<NativeFuture<TS::Value>>,
+}
+
+impl<TS: TypeSystem> ExecutionEngine<TS> {
+    /// Bind the arguments of a call to a script function without running it, in the scopes this
+    /// engine is in now
+    pub fn start_coroutine(
+        &mut self,
+        func: &FunctionRef<TS>,
+        args: impl IntoExactSizeIterator<Item = TS::Value>,
+    ) -> Result<Coroutine<TS>, FreightError> {
+        let captured = match &func.function_type {
+            FunctionType::Static => None,
+            FunctionType::CapturingRef(captured) => Some(captured.clone()),
+            FunctionType::Native(_) | FunctionType::CapturingDef(_) => {
+                return Err(FreightError::InvalidInvocationTarget)
+            }
+        };
+        let args = args.into_exact_size_iter();
+        let arg_count = args.len();
+        if !func.arg_count.valid_arg_count(arg_count) {
+            return Err(FreightError::IncorrectArgumentCount {
+                expected_min: func.arg_count.min(),
+                expected_max: func.arg_count.max(),
+                actual: arg_count,
+            });
+        }
+        let mut frame: Vec<TS::Value> = (0..func.stack_size)
+            .map(|i| {
+                if func.layout.is_alloc(i) {
+                    Value::uninitialized_reference()
+                } else {
+                    Default::default()
+                }
+            })
+            .collect();
+        let fixed = func.arg_count.max_capped();
+        let mut args = args;
+        for (i, arg) in args.by_ref().take(fixed).enumerate() {
+            frame[i] = if func.layout.is_alloc(i) {
+                arg.into_ref()
+            } else {
+                arg
+            };
+        }
+        #[cfg(feature = "variadic_functions")]
+        if let crate::function::ArgCount::Variadic { .. } = func.arg_count {
+            frame[fixed] = Value::gen_list(args.collect());
+        }
+        Ok(Coroutine {
+            generation: self.function_generation(func.location),
+            func: func.clone(),
+            frame,
+            captured,
+            suspended: vec![],
+            finished: false,
+            scopes: self.scopes.clone(),
+            pending: None,
+        })
+    }
+
+    /// Continue from a [Checkpoint] taken on an engine with the same program loaded
+    #[cfg(feature = "serde")]
+    pub fn restore_coroutine(&mut self, checkpoint: Checkpoint<TS>) -> Coroutine<TS> {
+        self.restore_globals(checkpoint.globals);
+        self.rng = checkpoint.rng;
+        self.clock = checkpoint.clock;
+        self.load_coroutine(SavedCoroutine {
+            func: checkpoint.func,
+            frame: checkpoint.frame,
+            captured: checkpoint.captured,
+            suspended: checkpoint.suspended,
+            finished: checkpoint.finished,
+            scopes: checkpoint.scopes,
+        })
+    }
+
+    /// Save coroutines started on this engine with the globals of its active namespace and its fuel
+    #[cfg(feature = "serde")]
+    pub fn snapshot<'a>(
+        &self,
+        coroutines: impl IntoIterator<Item = &'a Coroutine<TS>>,
+    ) -> Snapshot<TS>
+    where
+        TS: 'a,
+    {
+        Snapshot {
+            coroutines: coroutines.into_iter().map(Coroutine::save).collect(),
+            globals: self.globals.clone(),
+            rng: self.rng,
+            clock: self.clock.clone(),
+            fuel: self.fuel,
+        }
+    }
+
+    /// Continue from a [Snapshot], returning the coroutines in the order they were saved
+    #[cfg(feature = "serde")]
+    pub fn restore_snapshot(&mut self, snapshot: Snapshot<TS>) -> Vec<Coroutine<TS>> {
+        self.restore_globals(snapshot.globals);
+        self.rng = snapshot.rng;
+        self.clock = snapshot.clock;
+        self.fuel = snapshot.fuel;
+        snapshot
+            .coroutines
+            .into_iter()
+            .map(|saved| self.load_coroutine(saved))
+            .collect()
+    }
+
+    #[cfg(feature = "serde")]
+    fn restore_globals(&mut self, globals: Vec<TS::Value>) {
+        for (addr, value) in globals.into_iter().enumerate() {
+            if addr == self.globals.len() {
+                self.create_global();
+            }
+            self.globals[addr] = value;
+            if let Some(journal) = &mut self.fork_journal {
+                journal.overwrite_global(self.namespace, addr);
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn load_coroutine(&self, saved: SavedCoroutine<TS>) -> Coroutine<TS> {
+        let mut func = saved.func;
+        let captured = saved.captured.map(|captured| {
+            let captured =
+                crate::slice_pool::RcSlicePool::from_pool(self.rc_pool.clone(), captured);
+            func.function_type = FunctionType::CapturingRef(captured.clone());
+            captured
+        });
+        Coroutine {
+            generation: self.function_generation(func.location),
+            func,
+            frame: saved.frame,
+            captured,
+            suspended: saved.suspended,
+            finished: saved.finished,
+            scopes: saved.scopes,
+            pending: None,
+        }
+    }
+
+    fn function_generation(&self, location: usize) -> u32 {
+        self.functions()
+            .get(location)
+            .map_or(0, |function| function.generation)
+    }
+
+    /// Call a script function from an async host, awaiting the futures of the natives it calls
+    /// with [Expression::AwaitNative], see [Coroutine::run_async]
+    pub async fn call_async<F, Fut>(
+        &mut self,
+        func: &FunctionRef<TS>,
+        args: impl IntoExactSizeIterator<Item = TS::Value>,
+        on_yield: F,
+    ) -> Result<TS::Value, FreightError>
+    where
+        F: FnMut(TS::Value) -> Fut,
+        Fut: Future<Output = Result<TS::Value, FreightError>>,
+    {
+        self.start_coroutine(func, args)?
+            .run_async(self, on_yield)
+            .await
+    }
+}
+
+impl<TS: TypeSystem> Coroutine<TS> {
+    /// Whether the coroutine has returned or failed, after which it can't be resumed
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Save the state of this coroutine and the globals of the engine's active namespace
+    #[cfg(feature = "serde")]
+    pub fn checkpoint(&self, engine: &ExecutionEngine<TS>) -> Checkpoint<TS> {
+        let SavedCoroutine {
+            func,
+            frame,
+            captured,
+            suspended,
+            finished,
+            scopes,
+        } = self.save();
+        Checkpoint {
+            func,
+            frame,
+            captured,
+            suspended,
+            finished,
+            scopes,
+            globals: engine.globals.clone(),
+            rng: engine.rng,
+            clock: engine.clock.clone(),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn save(&self) -> SavedCoroutine<TS> {
+        let mut func = self.func.clone();
+        func.function_type = FunctionType::Static;
+        SavedCoroutine {
+            func,
+            frame: self.frame.clone(),
+            captured: self.captured.as_ref().map(|captured| captured.to_vec()),
+            suspended: self.suspended.clone(),
+            finished: self.finished,
+            scopes: self.scopes.clone(),
+        }
+    }
+
+    /// Call `visit` with each value the suspended coroutine holds, which are roots alongside
+    /// [ExecutionEngine::trace_roots]
+    pub fn trace_roots(&self, mut visit: impl FnMut(&TS::Value)) {
+        self.frame.iter().for_each(&mut visit);
+        if let Some(captured) = &self.captured {
+            captured.iter().for_each(&mut visit);
+        }
+        self.scopes.iter().for_each(visit);
+    }
+
+    /// Run the coroutine to completion, resuming it with the output of the native's future or of
+    /// `on_yield` each time it suspends
+    pub async fn run_async<F, Fut>(
+        mut self,
+        engine: &mut ExecutionEngine<TS>,
+        mut on_yield: F,
+    ) -> Result<TS::Value, FreightError>
+    where
+        F: FnMut(TS::Value) -> Fut,
+        Fut: Future<Output = Result<TS::Value, FreightError>>,
+    {
+        let mut value = Default::default();
+        loop {
+            match self.resume(engine, value)? {
+                CoroutineState::Yielded(request) => {
+                    value = match self.pending.take() {
+                        Some(future) => future.await?,
+                        None => on_yield(request).await?,
+                    }
+                }
+                CoroutineState::Returned(value) => return Ok(value),
+            }
+        }
+    }
+
+    /// The future of the [Expression::AwaitNative] the coroutine is suspended at
+    pub fn take_future(&mut self) -> Option<NativeFuture<TS::Value>> {
+        self.pending.take()
+    }
+
+    /// Run the coroutine until it yields or returns, with the result of the yield it is
+    /// suspended at
+    pub fn resume(
+        &mut self,
+        engine: &mut ExecutionEngine<TS>,
+        value: TS::Value,
+    ) -> Result<CoroutineState<TS::Value>, FreightError> {
+        if self.finished {
+            return Err(FreightError::CoroutineFinished);
+        }
+        self.pending = None;
+        let function = engine.get_function(self.func.location);
+        if function.generation != self.generation {
+            if !self.suspended.is_empty() {
+                self.finished = true;
+                return Err(FreightError::FunctionReplaced {
+                    function: self.func.location,
+                });
+            }
+            self.generation = function.generation;
+        }
+        if let Err(error) = engine.check_entry(&self.func) {
+            self.finished = true;
+            return Err(error);
+        }
+        let _frame_guard = engine.frame_guard(&self.func);
+        let suspended = std::mem::take(&mut self.suspended);
+        let start = match suspended.first() {
+            Some(Position::Statement(i)) => *i,
+            _ => 0,
+        };
+        let captured = self
+            .captured
+            .as_deref()
+            .map_or(&[][..], |captured| &captured[..]);
+        let mut yielded = None;
+        let mut pending = None;
+        // The coroutine runs in the scopes it was started in, and the resumer's are put back after
+        std::mem::swap(&mut engine.scopes, &mut self.scopes);
+        let result = engine.run_frame(&self.func, &mut self.frame, |engine, frame| {
+            let mut run = Run {
+                frame,
+                captured,
+                suspended: &suspended,
+                yielding: &function.yielding,
+                resuming: !suspended.is_empty(),
+                resume_value: Some(value),
+                path: vec![],
+                pending: None,
+            };
+            let mut result = Default::default();
+            for (i, expr) in function.expressions.iter().enumerate().skip(start) {
+                if engine.debugger.is_some()
+                    && (i == start || function.statements[i] != function.statements[i - 1])
+                {
+                    engine.debug_statement(function.statements[i]);
+                }
+                match run.exec(engine, expr, 1) {
+                    Ok(Flow::Done(value)) => result = value,
+                    Ok(Flow::Yielded(value)) => {
+                        run.path.push(Position::Statement(i));
+                        run.path.reverse();
+                        yielded = Some(run.path);
+                        pending = run.pending;
+                        return Ok(value);
+                    }
+                    Err(error) => {
+                        engine.failed_expression = function.statements[i];
+                        return Err(error);
+                    }
+                }
+            }
+            Ok(result)
+        });
+        std::mem::swap(&mut engine.scopes, &mut self.scopes);
+        if let Some(path) = yielded {
+            self.suspended = path;
+            self.pending = pending;
+            return result.map(CoroutineState::Yielded);
+        }
+        self.finished = true;
+        match result {
+            Ok(value) => Ok(CoroutineState::Returned(value)),
+            Err(FreightError::Return { target }) if target == function.return_target => Ok(
+                CoroutineState::Returned(std::mem::take(&mut engine.return_value)),
+            ),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl<TS: TypeSystem> Run<'_, TS> {
+    fn evaluate(
+        &mut self,
+        engine: &mut ExecutionEngine<TS>,
+        expr: &Expression<TS>,
+    ) -> Result<TS::Value, FreightError> {
+        engine.evaluate_internal(expr, self.frame, self.captured)
+    }
+
+    /// The position at a depth of the path being resumed, if execution hasn't reached the yield
+    /// it was suspended at yet
+    fn resuming(&self, level: usize) -> Option<Position> {
+        if self.resuming {
+            self.suspended.get(level).copied()
+        } else {
+            None
+        }
+    }
+
+    fn exec(
+        &mut self,
+        engine: &mut ExecutionEngine<TS>,
+        expr: &Expression<TS>,
+        level: usize,
+    ) -> Result<Flow<TS::Value>, FreightError> {
+        if !self.yielding.contains(&(expr as *const _)) {
+            return self.evaluate(engine, expr).map(Flow::Done);
+        }
+        let resuming = self.resuming(level);
+        // Expressions stepped into here are entered like evaluated ones, but only once, rather
+        // than again on the way back to the yield being resumed
+        if resuming.is_none() {
+            engine.enter_expression(expr, self.frame, self.captured)?;
+        }
+        let (flow, position) = match expr {
+            Expression::Yield(value) => {
+                if resuming.is_some() {
+                    self.resuming = false;
+                    let value = self.resume_value.take().unwrap_or_default();
+                    return Ok(Flow::Done(value));
+                }
+                let value = self.evaluate(engine, value)?;
+                (Flow::Yielded(value), Position::Yield)
+            }
+            Expression::AwaitNative(native, args) => {
+                if resuming.is_some() {
+                    self.resuming = false;
+                    let value = self.resume_value.take().unwrap_or_default();
+                    return Ok(Flow::Done(value));
+                }
+                let args = args
+                    .iter()
+                    .map(|arg| self.evaluate(engine, arg))
+                    .collect::<Result<_, _>>()?;
+                self.pending = Some((native.0)(engine, args));
+                (Flow::Yielded(Default::default()), Position::Yield)
+            }
+            Expression::Probe(probe, inner) => {
+                if resuming.is_none() {
+                    engine.hit_probe(*probe);
+                }
+                return self.exec(engine, inner, level);
+            }
+            Expression::Spanned(span, inner) => {
+                let flow = self.exec(engine, inner, level);
+                if let Err(error) = &flow {
+                    engine.record_error_span(*span, error);
+                }
+                return flow;
+            }
+            Expression::AssignStack(addr, value) => match self.exec(engine, value, level + 1)? {
+                Flow::Done(value) => {
+                    self.frame[*addr].assign(value);
+                    return Ok(Flow::Done(Default::default()));
+                }
+                flow => (flow, Position::Assign),
+            },
+            Expression::While { condition, body } => {
+                let mut resumed = resuming.is_some();
+                loop {
+                    if !resumed && !self.evaluate(engine, condition)?.is_truthy() {
+                        return Ok(Flow::Done(Default::default()));
+                    }
+                    resumed = false;
+                    if let flow @ Flow::Yielded(_) = self.exec(engine, body, level + 1)? {
+                        break (flow, Position::Loop(0));
+                    }
+                    engine.check_loop_cancellation(self.frame)?;
+                }
+            }
+            Expression::Repeat(times, body) => {
+                let mut remaining = match resuming {
+                    Some(Position::Loop(remaining)) => remaining + 1,
+                    _ => *times,
+                };
+                loop {
+                    if remaining == 0 {
+                        return Ok(Flow::Done(Default::default()));
+                    }
+                    remaining -= 1;
+                    if let flow @ Flow::Yielded(_) = self.exec(engine, body, level + 1)? {
+                        break (flow, Position::Loop(remaining));
+                    }
+                    engine.check_loop_cancellation(self.frame)?;
+                }
+            }
+            Expression::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let taken = match resuming {
+                    Some(Position::Branch(taken)) => taken,
+                    _ => self.evaluate(engine, condition)?.is_truthy(),
+                };
+                let branch = if taken { then } else { otherwise };
+                match self.exec(engine, branch, level + 1)? {
+                    flow @ Flow::Yielded(_) => (flow, Position::Branch(taken)),
+                    flow => return Ok(flow),
+                }
+            }
+            _ => return self.evaluate(engine, expr).map(Flow::Done),
+        };
+        self.path.push(position);
+        Ok(flow)
+    }
+}
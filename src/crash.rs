@@ -0,0 +1,110 @@
+//! Script context for panics inside the engine. While [ExecutionEngine::crash_reports] is set,
+//! each call the engine makes records its frame if a panic unwinds through it, so a host which
+//! catches the panic can take a [CrashReport] describing where the script was. Install
+//! [install_panic_hook] to include the panic message.
+
+use std::{cell::RefCell, panic, rc::Rc, sync::Once};
+
+use crate::{
+    execution_engine::ExecutionEngine,
+    expression::SpanId,
+    function::{FunctionMetadata, FunctionRef},
+    TypeSystem,
+};
+
+/// A call the engine was making when it panicked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashFrame {
+    /// The address of the function, or the id of a native
+    pub function: usize,
+    pub metadata: Option<Rc<FunctionMetadata>>,
+    /// The innermost [crate::expression::Expression::Spanned] the call was evaluating
+    pub span: Option<SpanId>,
+}
+
+/// Where scripts were when the engine panicked, see [take_crash_report]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CrashReport {
+    /// The panic message and location, if [install_panic_hook] was called
+    pub message: Option<String>,
+    /// The calls being made, innermost first
+    pub frames: Vec<CrashFrame>,
+}
+
+thread_local! {
+    static REPORT: RefCell<Option<CrashReport>> = const { RefCell::new(None) };
+    /// Spans unwound through since the last frame was recorded, innermost first
+    static SPANS: RefCell<Vec<SpanId>> = const { RefCell::new(vec![]) };
+}
+
+/// Take the report of the last panic which unwound through an engine on this thread
+pub fn take_crash_report() -> Option<CrashReport> {
+    SPANS.with(|spans| spans.borrow_mut().clear());
+    REPORT.with(|report| report.borrow_mut().take())
+}
+
+/// Install a panic hook which starts a crash report with the panic message, then runs the
+/// previously installed hook. Installing it more than once has no effect.
+pub fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            REPORT.with(|report| {
+                *report.borrow_mut() = Some(CrashReport {
+                    message: Some(info.to_string()),
+                    frames: vec![],
+                });
+            });
+            SPANS.with(|spans| spans.borrow_mut().clear());
+            previous(info);
+        }));
+    });
+}
+
+/// Records a call's frame when dropped by a panic
+pub(crate) struct FrameGuard {
+    pub(crate) function: usize,
+    pub(crate) metadata: Option<Rc<FunctionMetadata>>,
+}
+
+impl Drop for FrameGuard {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            return;
+        }
+        let span = SPANS.with(|spans| spans.borrow_mut().drain(..).next());
+        REPORT.with(|report| {
+            report
+                .borrow_mut()
+                .get_or_insert_with(Default::default)
+                .frames
+                .push(CrashFrame {
+                    function: self.function,
+                    metadata: self.metadata.take(),
+                    span,
+                });
+        });
+    }
+}
+
+/// Records a span when dropped by a panic, for the frame around it
+pub(crate) struct SpanGuard(pub(crate) SpanId);
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            SPANS.with(|spans| spans.borrow_mut().push(self.0));
+        }
+    }
+}
+
+impl<TS: TypeSystem> ExecutionEngine<TS> {
+    #[inline]
+    pub(crate) fn frame_guard(&self, func: &FunctionRef<TS>) -> Option<FrameGuard> {
+        self.crash_reports.then(|| FrameGuard {
+            function: func.location,
+            metadata: func.metadata.clone(),
+        })
+    }
+}
@@ -0,0 +1,186 @@
+//! Stepping through execution one expression at a time, for building debuggers
+
+use std::collections::HashSet;
+
+use crate::{
+    error::FreightError, execution_engine::ExecutionEngine, expression::Expression,
+    function::FunctionRef, slice_pool::IntoExactSizeIterator, TypeSystem,
+};
+
+/// The state of execution when a [DebugSession] stops before evaluating an expression
+#[derive(Debug)]
+pub struct Step<'a, TS: TypeSystem> {
+    pub expression: &'a Expression<TS>,
+    /// The address of the script function being executed, or `None` for expressions evaluated
+    /// directly by the host
+    pub function: Option<usize>,
+    /// The index of the function's top-level expression being evaluated
+    pub statement: Option<usize>,
+    /// Whether execution stopped because it reached a breakpoint
+    pub breakpoint: bool,
+    /// The number of script functions being executed
+    pub depth: usize,
+    /// The stack frame of the function being executed
+    pub frame: &'a [TS::Value],
+    /// The values captured by the function being executed
+    pub captured: &'a [TS::Value],
+    pub globals: &'a [TS::Value],
+}
+
+/// What a [DebugSession] does after stopping at a [Step]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepAction {
+    /// Stop before the next expression
+    Step,
+    /// Only stop at the next breakpoint
+    Run,
+    /// Stop executing, failing with [FreightError::Cancelled]
+    Abort,
+}
+
+/// Called by a [DebugSession] before each expression is evaluated
+pub type StepHandler<TS> = Box<dyn FnMut(&Step<'_, TS>) -> StepAction>;
+
+pub(crate) struct Debugger<TS: TypeSystem> {
+    handler: StepHandler<TS>,
+    /// The addresses of the script functions being executed and the index of the top-level
+    /// expression each one is evaluating, innermost last
+    functions: Vec<(usize, Option<usize>)>,
+    breakpoints: HashSet<(usize, usize)>,
+    stepping: bool,
+    /// Whether the next expression is a top-level expression with a breakpoint
+    at_breakpoint: bool,
+}
+
+impl<TS: TypeSystem> ExecutionEngine<TS> {
+    pub(crate) fn debug_enter(&mut self, func: &FunctionRef<TS>) {
+        if let Some(debugger) = &mut self.debugger {
+            debugger.functions.push((func.location, None));
+        }
+    }
+
+    pub(crate) fn debug_exit(&mut self) {
+        if let Some(debugger) = &mut self.debugger {
+            debugger.functions.pop();
+        }
+    }
+
+    /// Called before a function evaluates its top-level expression with the given index
+    pub(crate) fn debug_statement(&mut self, statement: usize) {
+        if let Some(debugger) = &mut self.debugger {
+            if let Some((function, current)) = debugger.functions.last_mut() {
+                *current = Some(statement);
+                debugger.at_breakpoint = debugger.breakpoints.contains(&(*function, statement));
+            }
+        }
+    }
+
+    pub(crate) fn debug_step(
+        &mut self,
+        expression: &Expression<TS>,
+        frame: &[TS::Value],
+        captured: &[TS::Value],
+    ) -> Result<(), FreightError> {
+        // The handler can't reach the debugger, since it only sees the engine's state through
+        // the step
+        let Some(mut debugger) = self.debugger.take() else {
+            return Ok(());
+        };
+        let breakpoint = std::mem::take(&mut debugger.at_breakpoint);
+        if !debugger.stepping && !breakpoint {
+            self.debugger = Some(debugger);
+            return Ok(());
+        }
+        let current = debugger.functions.last().copied();
+        let step = Step {
+            expression,
+            function: current.map(|(function, _)| function),
+            statement: current.and_then(|(_, statement)| statement),
+            breakpoint,
+            depth: debugger.functions.len(),
+            frame,
+            captured,
+            globals: &self.globals,
+        };
+        let action = (debugger.handler)(&step);
+        debugger.stepping = action == StepAction::Step;
+        self.debugger = Some(debugger);
+        match action {
+            StepAction::Step | StepAction::Run => Ok(()),
+            StepAction::Abort => Err(FreightError::Cancelled),
+        }
+    }
+}
+
+/// Runs code on an engine while stopping before expressions to hand control to a [StepHandler].
+/// The session starts out stopping before every expression, and the handler decides whether to
+/// keep stepping or run until the next breakpoint. The engine stops calling the handler once the
+/// session is dropped.
+///
+/// Natives are not stepped into, but script functions they call are. Stepping disables the
+/// compiled backend for as long as the session exists.
+pub struct DebugSession<'a, TS: TypeSystem> {
+    engine: &'a mut ExecutionEngine<TS>,
+}
+
+impl<'a, TS: TypeSystem> DebugSession<'a, TS> {
+    pub fn new(engine: &'a mut ExecutionEngine<TS>, handler: StepHandler<TS>) -> Self {
+        engine.debugger = Some(Box::new(Debugger {
+            handler,
+            functions: vec![],
+            breakpoints: HashSet::new(),
+            stepping: true,
+            at_breakpoint: false,
+        }));
+        DebugSession { engine }
+    }
+
+    fn debugger(&mut self) -> &mut Debugger<TS> {
+        self.engine
+            .debugger
+            .as_mut()
+            .expect("The debugger is only removed when the session is dropped")
+    }
+
+    /// Stop before a function evaluates the top-level expression with the given index
+    pub fn set_breakpoint(&mut self, function: &FunctionRef<TS>, statement: usize) {
+        let location = function.location;
+        self.debugger().breakpoints.insert((location, statement));
+    }
+
+    /// Remove a breakpoint, returning `false` if it was not set
+    pub fn clear_breakpoint(&mut self, function: &FunctionRef<TS>, statement: usize) -> bool {
+        let location = function.location;
+        self.debugger().breakpoints.remove(&(location, statement))
+    }
+
+    /// Whether the handler is called before every expression, rather than only at breakpoints
+    pub fn set_stepping(&mut self, stepping: bool) {
+        self.debugger().stepping = stepping;
+    }
+
+    /// Call a function, stepping through every expression it evaluates
+    pub fn call(
+        &mut self,
+        func: &FunctionRef<TS>,
+        args: impl IntoExactSizeIterator<Item = TS::Value>,
+    ) -> Result<TS::Value, FreightError> {
+        self.engine.call(func, args)
+    }
+
+    /// Evaluate an expression, stepping through it
+    pub fn evaluate(&mut self, expr: &Expression<TS>) -> Result<TS::Value, FreightError> {
+        self.engine.evaluate(expr)
+    }
+
+    /// Access the engine between calls
+    pub fn engine(&mut self) -> &mut ExecutionEngine<TS> {
+        self.engine
+    }
+}
+
+impl<TS: TypeSystem> Drop for DebugSession<'_, TS> {
+    fn drop(&mut self) {
+        self.engine.debugger = None;
+    }
+}
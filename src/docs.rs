@@ -0,0 +1,102 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    function::{FunctionRef, FunctionType},
+    symbols::{Symbol, SymbolTable},
+    TypeSystem,
+};
+
+/// Documentation attached to a function or global, for REPLs and editors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Documentation {
+    pub doc: Rc<str>,
+    /// A human readable signature, in the syntax of the frontend
+    pub signature: Option<Rc<str>>,
+}
+
+impl Documentation {
+    pub fn new(doc: impl Into<Rc<str>>) -> Documentation {
+        Documentation {
+            doc: doc.into(),
+            signature: None,
+        }
+    }
+
+    pub fn with_signature(mut self, signature: impl Into<Rc<str>>) -> Documentation {
+        self.signature = Some(signature.into());
+        self
+    }
+}
+
+/// What a [Documentation] is attached to. Natives are identified by the id they were created with
+/// in [FunctionRef::new_native], since they don't have an address in the function table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DocTarget {
+    Function(usize),
+    Native(usize),
+    Global(usize),
+}
+
+impl DocTarget {
+    pub fn function<TS: TypeSystem>(func: &FunctionRef<TS>) -> DocTarget {
+        match func.function_type {
+            FunctionType::Native(_) => DocTarget::Native(func.location),
+            _ => DocTarget::Function(func.location),
+        }
+    }
+
+    pub fn symbol<TS: TypeSystem>(symbol: &Symbol<TS>) -> DocTarget {
+        match symbol {
+            Symbol::Function(func) => DocTarget::function(func),
+            Symbol::Global(addr) => DocTarget::Global(*addr),
+        }
+    }
+}
+
+/// Documentation for the functions and globals of a program. Empty unless a frontend attaches
+/// documentation, so programs without it don't pay for it.
+#[derive(Debug, Clone, Default)]
+pub struct DocTable {
+    docs: HashMap<DocTarget, Documentation>,
+}
+
+impl DocTable {
+    /// Attach documentation, replacing any previously attached to the same target
+    pub fn insert(&mut self, target: DocTarget, doc: Documentation) {
+        self.docs.insert(target, doc);
+    }
+
+    pub fn remove(&mut self, target: DocTarget) -> Option<Documentation> {
+        self.docs.remove(&target)
+    }
+
+    pub fn get(&self, target: DocTarget) -> Option<&Documentation> {
+        self.docs.get(&target)
+    }
+
+    pub fn function<TS: TypeSystem>(&self, func: &FunctionRef<TS>) -> Option<&Documentation> {
+        self.get(DocTarget::function(func))
+    }
+
+    pub fn global(&self, addr: usize) -> Option<&Documentation> {
+        self.get(DocTarget::Global(addr))
+    }
+
+    /// Find the documentation of whatever a name is currently bound to
+    pub fn lookup<TS: TypeSystem>(
+        &self,
+        symbols: &SymbolTable<TS>,
+        name: &str,
+    ) -> Option<&Documentation> {
+        let symbol = symbols.resolve(symbols.lookup(name)?)?;
+        self.get(DocTarget::symbol(symbol))
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+}
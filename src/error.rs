@@ -1,6 +1,14 @@
-use std::{error::Error, fmt::Display};
+use std::{
+    any::Any,
+    error::Error,
+    fmt::{Debug, Display},
+    rc::Rc,
+};
 
-use crate::{execution_engine::ExecutionEngine, TypeSystem};
+use crate::{
+    args::Args, execution_engine::ExecutionEngine, expression::NativeFunction, value::Value,
+    TypeSystem,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum FreightError {
@@ -13,6 +21,149 @@ pub enum FreightError {
     Return {
         target: usize,
     },
+    Escape {
+        label: usize,
+    },
+    InvalidInitialization {
+        reason: String,
+    },
+    MethodNotFound {
+        method: String,
+    },
+    FieldNotFound {
+        index: usize,
+    },
+    UnresolvedSymbol {
+        name: String,
+    },
+    Cancelled,
+    OutOfFuel,
+    OutOfMemory,
+    /// A value was nested more deeply than [crate::value::NestingGuard] allows
+    NestingTooDeep {
+        max_depth: usize,
+    },
+    /// A value contains itself, so it can't be copied or written out in full
+    CyclicValue,
+    /// A transferred value refers to a function which the receiving engine doesn't have, see
+    /// [crate::transfer]
+    IncompatibleFunction {
+        location: usize,
+    },
+    /// A function can't be replaced by a body which lays out its stack frame differently, see
+    /// [crate::execution_engine::ExecutionEngine::replace_function]
+    IncompatibleReplacement {
+        location: usize,
+    },
+    /// A coroutine was resumed after the function it is suspended in was replaced
+    FunctionReplaced {
+        function: usize,
+    },
+    /// An engine and its fork both added functions, globals or namespaces, which would need the
+    /// same addresses, see [crate::execution_engine::ExecutionEngine::merge]
+    ForkConflict {
+        table: &'static str,
+    },
+    RateLimited {
+        native: usize,
+    },
+    /// A function calls a native which wasn't given a name with
+    /// [crate::expression::NativeFunction::named], so it can't be hashed the same way in every
+    /// process, see [crate::program::fingerprint]
+    UnnamedNative {
+        function: usize,
+    },
+    UnsupportedLiteral {
+        kind: String,
+    },
+    Unhashable {
+        value: String,
+    },
+    ModuleUnloaded {
+        module: usize,
+    },
+    /// A script tried to invoke or resolve a function marked with
+    /// [crate::function::FunctionRef::host_only]
+    HostOnlyFunction {
+        function: usize,
+    },
+    /// An argument of a native wasn't of the type it expected, see [crate::args::Args::get]
+    InvalidArgument {
+        index: usize,
+        expected: &'static str,
+        found: String,
+    },
+    /// A named argument didn't match a parameter of the function being called or was bound more
+    /// than once, or a required parameter wasn't bound, see
+    /// [crate::expression::Expression::NamedFunctionCall]
+    ArgumentName {
+        function: usize,
+        name: String,
+    },
+    /// An [crate::expression::Expression::Yield] was evaluated outside of a position a
+    /// coroutine can be suspended at
+    InvalidYield,
+    CoroutineFinished,
+    /// A language-specific error, see [FreightError::runtime]
+    Runtime {
+        error: RuntimeError,
+    },
+}
+
+impl FreightError {
+    /// Raise an error of the type system's [TypeSystem::RuntimeError] type, which propagates like
+    /// any other error
+    pub fn runtime<TS: TypeSystem>(error: TS::RuntimeError) -> FreightError {
+        FreightError::Runtime {
+            error: RuntimeError(Rc::new(error)),
+        }
+    }
+
+    /// The language-specific error this is, if it was raised with [FreightError::runtime]
+    pub fn as_runtime<TS: TypeSystem>(&self) -> Option<&TS::RuntimeError> {
+        match self {
+            FreightError::Runtime { error } => error.0.as_any().downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// A [TypeSystem::RuntimeError] with its type erased, so [FreightError] doesn't depend on the
+/// type system. Read it back with [FreightError::as_runtime].
+#[derive(Clone)]
+pub struct RuntimeError(Rc<dyn AnyRuntimeError>);
+
+trait AnyRuntimeError: Debug + Display {
+    fn as_any(&self) -> &dyn Any;
+    fn dyn_eq(&self, other: &dyn AnyRuntimeError) -> bool;
+}
+
+impl<E: Debug + Display + PartialEq + 'static> AnyRuntimeError for E {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn dyn_eq(&self, other: &dyn AnyRuntimeError) -> bool {
+        other.as_any().downcast_ref::<E>() == Some(self)
+    }
+}
+
+impl Debug for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl PartialEq for RuntimeError {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.dyn_eq(&*other.0)
+    }
 }
 
 impl Display for FreightError {
@@ -37,6 +188,78 @@ impl Display for FreightError {
             Self::Return { target } => {
                 write!(f, "Could not return to target {target}")
             }
+            Self::Escape { label } => {
+                write!(f, "Could not escape to label {label}")
+            }
+            Self::InvalidInitialization { reason } => {
+                write!(f, "Invalid initialization: {reason}")
+            }
+            Self::MethodNotFound { method } => {
+                write!(f, "No method named {method}")
+            }
+            Self::FieldNotFound { index } => {
+                write!(f, "No field at index {index}")
+            }
+            Self::UnresolvedSymbol { name } => {
+                write!(f, "{name} is not defined")
+            }
+            Self::Cancelled => f.write_str("Execution was cancelled"),
+            Self::OutOfFuel => f.write_str("Ran out of fuel"),
+            Self::OutOfMemory => f.write_str("Ran out of memory"),
+            Self::NestingTooDeep { max_depth } => {
+                write!(f, "Value is nested more than {max_depth} levels deep")
+            }
+            Self::CyclicValue => f.write_str("Value contains itself"),
+            Self::IncompatibleFunction { location } => {
+                write!(f, "Function {location} differs between the engines")
+            }
+            Self::IncompatibleReplacement { location } => {
+                write!(
+                    f,
+                    "Function {location} can't be replaced by a body with a different frame"
+                )
+            }
+            Self::FunctionReplaced { function } => {
+                write!(
+                    f,
+                    "Function {function} was replaced while a coroutine was suspended in it"
+                )
+            }
+            Self::ForkConflict { table } => {
+                write!(f, "The engine and its fork both added {table}")
+            }
+            Self::UnnamedNative { function } => {
+                write!(f, "Function {function} calls a native without a name")
+            }
+            Self::RateLimited { native } => {
+                write!(f, "Native function {native} exceeded its rate limit")
+            }
+            Self::UnsupportedLiteral { kind } => {
+                write!(f, "The type system has no {kind} values")
+            }
+            Self::Unhashable { value } => {
+                write!(f, "{value} can't be used as a key")
+            }
+            Self::ModuleUnloaded { module } => {
+                write!(f, "Module {module} has been unloaded")
+            }
+            Self::HostOnlyFunction { function } => {
+                write!(f, "Function {function} can only be called by the host")
+            }
+            Self::InvalidArgument {
+                index,
+                expected,
+                found,
+            } => write!(f, "Argument {index} should be {expected}, found {found}"),
+            Self::ArgumentName { function, name } => {
+                write!(
+                    f,
+                    "Argument {name} can't be bound in a call to function {function}"
+                )
+            }
+            Self::InvalidYield => f.write_str("Can't yield here"),
+            Self::CoroutineFinished => f.write_str("The coroutine has already finished"),
+            Self::Runtime { error } => write!(f, "{error}"),
         }
     }
 }
@@ -65,3 +288,43 @@ impl<TS: TypeSystem> OrReturn<TS> for Result<TS::Value, FreightError> {
         }
     }
 }
+
+/// Converts between errors raised during execution and script values, so that languages built on
+/// Freight can expose errors to scripts in a consistent way
+pub trait ErrorConverter<TS: TypeSystem>: Debug {
+    /// Convert an error into a value that can be inspected by a script
+    fn error_to_value(error: FreightError, engine: &mut ExecutionEngine<TS>) -> TS::Value;
+
+    /// Convert a value back into an error, if it represents one, so it can be raised again by
+    /// [rethrow]
+    fn value_to_error(value: &TS::Value) -> Option<FreightError>;
+}
+
+impl<TS: TypeSystem> ErrorConverter<TS> for () {
+    fn error_to_value(_: FreightError, _: &mut ExecutionEngine<TS>) -> TS::Value {
+        TS::Value::default()
+    }
+
+    fn value_to_error(_: &TS::Value) -> Option<FreightError> {
+        None
+    }
+}
+
+/// A native taking a value made from an error by [crate::expression::Expression::Catch], which
+/// raises the error again after converting it back with [ErrorConverter::value_to_error]. A value
+/// which doesn't represent an error is rejected with [FreightError::InvalidArgument].
+pub fn rethrow<TS: TypeSystem>() -> NativeFunction<TS> {
+    NativeFunction::named("error.rethrow", |_, args| {
+        let args = Args::<TS>::new(args);
+        let value = args.value(0)?;
+        Err(
+            TS::ErrorConverter::value_to_error(value).unwrap_or_else(|| {
+                FreightError::InvalidArgument {
+                    index: 0,
+                    expected: "error",
+                    found: value.summarize(1, 40),
+                }
+            }),
+        )
+    })
+}
@@ -1,45 +1,366 @@
-use self::stack::StackPool;
-#[cfg(feature = "variadic_functions")]
-use crate::function::ArgCount;
+use self::{fork::ForkJournal, stack::StackPool};
+#[cfg(feature = "profiling")]
+use crate::profiler::{HotPathReport, Profiler};
+#[cfg(feature = "metrics")]
+use crate::telemetry;
 use crate::{
-    error::FreightError,
-    expression::{Expression, VariableType},
-    function::{FunctionRef, FunctionType, FunctionWriter},
-    operators::{BinaryOperator, Initializer, UnaryOperator},
-    slice_pool::{IntoExactSizeIterator, RcSlicePool},
-    value::Value,
+    cache::ValueCache,
+    callbacks::{CallbackRegistry, DispatchReport, HandlerId},
+    crash::SpanGuard,
+    debugger::Debugger,
+    docs::{DocTable, DocTarget, Documentation},
+    error::{ErrorConverter, FreightError},
+    expression::{CallSiteCache, Expression, NativeFunction, SpanId, VariableType},
+    fallback::{CallFallback, CallRedirect, MAX_CALL_REDIRECTS},
+    fuel::FuelSchedule,
+    function::{
+        ArgCount, FunctionFeatures, FunctionMetadata, FunctionRef, FunctionType, FunctionWriter,
+    },
+    hooks::{ExpressionHooks, UnwindFrame, UnwindHook},
+    instrument::{Instrumentation, ProbeMode},
+    lint::{lint_function, Diagnostic},
+    module::{ModuleId, ModuleTable},
+    namespace::{Namespace, NamespaceId},
+    operators::{BinaryOperator, Initializer, TernaryOperator, UnaryOperator},
+    program::{function_hashes, validate_references, InvalidReference, ProgramDiff},
+    rate_limit::{RateLimit, RateLimits},
+    region::RegionAlloc,
+    rewrite::{rewrite, ConstantFolder, Rewriter, SwitchRewriter},
+    services::{Clock, Rng},
+    slice_pool::{IntoExactSizeIterator, PooledRcSlice, PooledVec, RcSlicePool, VecPool},
+    symbols::{Symbol, SymbolId, SymbolTable},
+    type_registry::TypeRegistry,
+    value::{Literal, NestingGuard, Value, ValueFactory},
+    watchdog::{CancellationSnapshot, CancellationToken, IN_LOOP},
     TypeSystem,
 };
 use crate::{error::OrReturn, function::Function};
-use std::cell::UnsafeCell;
+use std::cell::{RefCell, UnsafeCell};
 use std::rc::Rc;
 
+mod builder;
+mod fork;
 pub mod stack;
 
+pub use builder::EngineBuilder;
+
 pub type Stack<'a, T> = &'a mut [T];
 
+/// The address given to native functions invoked by [Expression::NativeFunctionCall]
+pub const ANONYMOUS_NATIVE: usize = usize::MAX;
+
+/// Limits on how much of a value is written in diagnostic output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verbosity {
+    /// How many levels of nested values to describe
+    pub depth: usize,
+    /// Roughly how many characters to write per value
+    pub max_len: usize,
+}
+
+impl Default for Verbosity {
+    fn default() -> Self {
+        Verbosity {
+            depth: 3,
+            max_len: 200,
+        }
+    }
+}
+
+/// When call arguments are evaluated relative to the callee's stack frame being written.
+///
+/// In every mode the argument count is validated before any argument is evaluated, and arguments
+/// are evaluated exactly once, from left to right, before the callee's body starts.
+/// If an argument raises an error, no later arguments are evaluated and the callee is not run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArgumentEvaluation {
+    /// Reserve the callee's stack frame first and write each argument into it as soon as it is
+    /// evaluated. Avoids a copy, but argument expressions that call into the engine run while the
+    /// callee's frame is partially written.
+    #[default]
+    Interleaved,
+    /// Evaluate every argument into a temporary buffer before the callee's stack frame is
+    /// reserved or written
+    Eager,
+}
+
+/// A call validated by [ExecutionEngine::prepare_call], which borrows the engine for as long as
+/// it is being invoked
+pub struct PreparedCall<'a, TS: TypeSystem> {
+    engine: &'a mut ExecutionEngine<TS>,
+    func: FunctionRef<TS>,
+    arg_count: usize,
+}
+
+impl<TS: TypeSystem> PreparedCall<'_, TS> {
+    /// Call the function. The arguments have already been evaluated, so they are written
+    /// directly into the callee's stack frame regardless of [ArgumentEvaluation].
+    #[inline]
+    pub fn invoke(
+        &mut self,
+        args: impl IntoExactSizeIterator<Item = TS::Value>,
+    ) -> Result<TS::Value, FreightError> {
+        let mut iter = args.into_exact_size_iter();
+        if iter.len() != self.arg_count {
+            return Err(FreightError::IncorrectArgumentCount {
+                expected_min: self.arg_count,
+                expected_max: Some(self.arg_count),
+                actual: iter.len(),
+            });
+        }
+        self.engine.error_trace.clear();
+        self.engine.error_span = None;
+        let result =
+            self.engine
+                .enter_function(&self.func, |_| Ok(iter.next().unwrap()), self.arg_count);
+        #[cfg(feature = "metrics")]
+        if let Err(error) = &result {
+            telemetry::record_error(error);
+        }
+        result
+    }
+
+    /// Access the engine between invocations
+    pub fn engine(&mut self) -> &mut ExecutionEngine<TS> {
+        self.engine
+    }
+}
+
+/// Calls into an engine whose global context is borrowed separately, see
+/// [ExecutionEngine::with_context_and_caller]
+pub struct Caller<'a, TS: TypeSystem> {
+    engine: &'a mut ExecutionEngine<TS>,
+}
+
+impl<TS: TypeSystem> Caller<'_, TS> {
+    /// Lend the context back to the engine for the duration of a call
+    fn lend<R>(
+        &mut self,
+        context: &mut TS::GlobalContext,
+        f: impl FnOnce(&mut ExecutionEngine<TS>) -> R,
+    ) -> R {
+        std::mem::swap(context, &mut self.engine.context);
+        let result = f(self.engine);
+        std::mem::swap(context, &mut self.engine.context);
+        result
+    }
+
+    /// Call a function with the context, which the engine and the natives it calls can use as
+    /// usual until the call returns
+    pub fn call(
+        &mut self,
+        context: &mut TS::GlobalContext,
+        func: &FunctionRef<TS>,
+        args: impl IntoExactSizeIterator<Item = TS::Value>,
+    ) -> Result<TS::Value, FreightError> {
+        self.lend(context, |engine| engine.call(func, args))
+    }
+
+    /// Evaluate an expression with the context, see [Caller::call]
+    pub fn evaluate(
+        &mut self,
+        context: &mut TS::GlobalContext,
+        expr: &Expression<TS>,
+    ) -> Result<TS::Value, FreightError> {
+        self.lend(context, |engine| engine.evaluate(expr))
+    }
+}
+
+/// The elements of a script-produced sequence, see [ExecutionEngine::iterate]
+pub struct ScriptIterator<'a, TS: TypeSystem> {
+    engine: &'a mut ExecutionEngine<TS>,
+    next: Option<Result<FunctionRef<TS>, FreightError>>,
+}
+
+impl<TS: TypeSystem> Iterator for ScriptIterator<'_, TS> {
+    type Item = Result<TS::Value, FreightError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = match self.next.take()? {
+            Ok(next) => next,
+            Err(error) => return Some(Err(error)),
+        };
+        match self.engine.call(&next, []) {
+            Ok(value) if value.is_iteration_end() => None,
+            Ok(value) => {
+                self.next = Some(Ok(next));
+                Some(Ok(value))
+            }
+            Err(error) => Some(Err(error)),
+        }
+    }
+}
+
+impl<TS: TypeSystem> std::iter::FusedIterator for ScriptIterator<'_, TS> {}
+
+/// Something owned by an engine which references a function, see
+/// [ExecutionEngine::references_to]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReferenceHolder {
+    Global {
+        namespace: NamespaceId,
+        addr: usize,
+    },
+    /// An entry of the engine's [ValueCache] whose key or value is the function, identified by a
+    /// summary of its key
+    CacheEntry {
+        key: String,
+    },
+    Callback {
+        event: Rc<str>,
+        handler: HandlerId,
+    },
+}
+
 pub struct ExecutionEngine<TS: TypeSystem> {
     pub(crate) num_globals: usize,
     pub(crate) globals: Vec<TS::Value>,
-    pub(crate) functions: UnsafeCell<Vec<Function<TS>>>,
+    /// Reference counted so functions being executed stay in place when natives or hooks
+    /// register more functions, and so forks can share the bodies registered before them.
+    pub(crate) functions: UnsafeCell<Vec<Rc<Function<TS>>>>,
+    /// Bodies replaced by [ExecutionEngine::replace_function], kept alive since calls may still
+    /// be running them. Shared with forks of this engine. Never shrinks, so it holds one body per
+    /// replacement until the engine and all of its forks are dropped.
+    pub(crate) retired_functions: Rc<RefCell<Vec<Rc<Function<TS>>>>>,
     pub(crate) next_return_target: usize,
+    pub(crate) next_escape_label: usize,
     pub(crate) return_value: TS::Value,
     pub stack: Rc<UnsafeCell<StackPool<TS::Value>>>,
     pub rc_pool: Rc<UnsafeCell<RcSlicePool<TS::Value>>>,
+    pub(crate) scratch_pool: Rc<UnsafeCell<VecPool<TS::Value>>>,
     pub context: TS::GlobalContext,
+    pub verbosity: Verbosity,
+    pub argument_evaluation: ArgumentEvaluation,
+    pub types: TypeRegistry<TS>,
+    pub symbols: SymbolTable<TS>,
+    pub hooks: ExpressionHooks<TS>,
+    pub on_unwind_frame: Option<UnwindHook<TS>>,
+    /// How many hooks are running, see [ExecutionEngine::hook_depth]
+    pub(crate) hook_depth: usize,
+    /// The index of the top-level expression which raised the error being propagated, set by
+    /// [Function::call] for [UnwindFrame::expression]
+    pub(crate) failed_expression: usize,
+    /// The frames popped by the most recent error, see [ExecutionEngine::error_trace]
+    pub(crate) error_trace: Vec<UnwindFrame>,
+    /// The innermost span of the most recent error, see [ExecutionEngine::error_span]
+    pub(crate) error_span: Option<SpanId>,
+    /// The scopes of the calls made with [ExecutionEngine::call_with_scope], outermost first
+    pub(crate) scopes: Vec<TS::Value>,
+    pub cancellation: CancellationToken,
+    /// Where execution stopped the last time it was cancelled
+    pub cancelled_at: Option<CancellationSnapshot>,
+    /// Called by [ExecutionEngine::clear_runtime_state] to clear the global context
+    pub on_clear: Vec<fn(&mut TS::GlobalContext)>,
+    pub fuel_schedule: FuelSchedule,
+    pub docs: DocTable,
+    pub rate_limits: RateLimits,
+    /// Run over every function registered with this engine or its forks, which share them so
+    /// functions merged back from a fork were prepared the same way
+    #[allow(clippy::type_complexity)]
+    pub rewriters: Rc<RefCell<Vec<Box<dyn Rewriter<TS>>>>>,
+    /// Whether [FunctionWriter::hoist_global_reads] is applied to functions when they are
+    /// registered
+    pub hoist_global_reads: bool,
+    /// Where probes are inserted into functions when they are registered, and what they
+    /// recorded, see [ExecutionEngine::start_instrumentation]. Never replaced once set, since
+    /// registered functions refer to its probes by id.
+    pub(crate) instrumentation: Option<Instrumentation>,
+    /// Whether functions are checked with [lint_function] when they are registered, collecting
+    /// the results in [ExecutionEngine::diagnostics]
+    pub lint: bool,
+    pub(crate) diagnostics: Vec<Diagnostic>,
+    /// Whether calls record their frames for a [crate::crash::CrashReport] when the engine
+    /// panics
+    pub crash_reports: bool,
+    /// How deeply values may nest when the engine copies them with [Value::try_deep_clone], see
+    /// [ExecutionEngine::nesting_guard]
+    pub max_value_depth: usize,
+    /// The source of randomness for scripts, see [crate::services]
+    pub rng: Rng,
+    /// The source of time for scripts, see [crate::services]
+    pub clock: Clock,
+    pub callbacks: CallbackRegistry<TS>,
+    /// The cache used by [ValueCache::get_native] and [ValueCache::put_native]
+    pub cache: ValueCache<TS>,
+    pub modules: ModuleTable,
+    /// The namespace whose globals and callbacks are in use
+    pub(crate) namespace: NamespaceId,
+    /// The globals and callbacks of every inactive namespace. The active namespace's slot is
+    /// empty, since they are stored in the engine itself.
+    pub(crate) namespaces: Vec<Option<Namespace<TS>>>,
+    pub(crate) fuel: Option<u64>,
+    pub(crate) memory_limit: Option<usize>,
+    /// The pool sizes the engine was built with, used when its pools are replaced
+    pub(crate) builder: EngineBuilder<TS>,
+    /// Set while a [crate::debugger::DebugSession] is active
+    pub(crate) debugger: Option<Box<Debugger<TS>>>,
+    /// Set in engines created by [ExecutionEngine::fork]
+    pub(crate) fork_journal: Option<ForkJournal>,
+    #[cfg(feature = "profiling")]
+    pub(crate) profiler: Option<Profiler>,
 }
 
 impl<TS: TypeSystem> ExecutionEngine<TS> {
     pub fn new(context: TS::GlobalContext) -> Self {
+        Self::with_builder(context, Default::default())
+    }
+
+    /// Configure an engine's pools and optimizations before creating it
+    pub fn builder() -> EngineBuilder<TS> {
+        Default::default()
+    }
+
+    pub(crate) fn with_builder(context: TS::GlobalContext, builder: EngineBuilder<TS>) -> Self {
         Self {
             num_globals: 0,
             globals: vec![],
             functions: vec![].into(),
+            retired_functions: Default::default(),
             next_return_target: 0,
+            next_escape_label: 0,
             return_value: Default::default(),
-            stack: Default::default(),
+            stack: builder.stack(),
             context,
-            rc_pool: Default::default(),
+            rc_pool: builder.pool(),
+            scratch_pool: builder.pool(),
+            verbosity: Default::default(),
+            argument_evaluation: Default::default(),
+            types: Default::default(),
+            symbols: Default::default(),
+            hooks: Default::default(),
+            on_unwind_frame: None,
+            hook_depth: 0,
+            failed_expression: 0,
+            error_trace: vec![],
+            error_span: None,
+            scopes: vec![],
+            cancellation: Default::default(),
+            cancelled_at: None,
+            on_clear: vec![],
+            fuel_schedule: Default::default(),
+            docs: Default::default(),
+            rate_limits: Default::default(),
+            rewriters: Default::default(),
+            hoist_global_reads: false,
+            instrumentation: None,
+            lint: false,
+            crash_reports: false,
+            max_value_depth: 256,
+            diagnostics: vec![],
+            rng: Rng::default(),
+            clock: Clock::default(),
+            callbacks: Default::default(),
+            cache: Default::default(),
+            modules: Default::default(),
+            namespace: NamespaceId::ROOT,
+            namespaces: vec![None],
+            fuel: None,
+            memory_limit: None,
+            builder,
+            debugger: None,
+            fork_journal: None,
+            #[cfg(feature = "profiling")]
+            profiler: None,
         }
     }
 
@@ -50,37 +371,484 @@ impl<TS: TypeSystem> ExecutionEngine<TS> {
         Self::new(Default::default())
     }
 
+    /// A guard limiting values to [ExecutionEngine::max_value_depth], for natives and
+    /// serializers which walk values recursively
+    pub fn nesting_guard(&self) -> NestingGuard {
+        NestingGuard::new(self.max_value_depth)
+    }
+
+    /// Get an empty buffer with room for at least `capacity` values, for native functions that
+    /// need temporary storage. The buffer is cleared and returned to the engine's pool when it is
+    /// dropped, and may safely outlive any borrow of the engine.
+    pub fn scratch(&self, capacity: usize) -> PooledVec<TS::Value> {
+        VecPool::request(self.scratch_pool.clone(), capacity)
+    }
+
+    /// Describe a value for diagnostic output, according to this engine's [Verbosity]
+    pub fn summarize(&self, value: &TS::Value) -> String {
+        value.summarize(self.verbosity.depth, self.verbosity.max_len)
+    }
+
     #[inline]
     pub fn get_function<'a>(&self, id: usize) -> &'a Function<TS> {
-        unsafe { &(*self.functions.get())[id] }
+        unsafe { &(&*self.functions.get())[id] }
+    }
+
+    /// All functions registered in this engine, indexed by their address
+    pub fn functions(&self) -> &[Rc<Function<TS>>] {
+        unsafe { &*self.functions.get() }
+    }
+
+    /// The metadata of the function registered at an address, for describing frames such as
+    /// those in [ExecutionEngine::error_trace]
+    pub fn function_metadata(&self, addr: usize) -> Option<&FunctionMetadata> {
+        self.functions().get(addr)?.metadata()
+    }
+
+    /// What the function registered at an address uses, see [FunctionFeatures]
+    pub fn function_features(&self, addr: usize) -> Option<FunctionFeatures> {
+        Some(self.functions().get(addr)?.features())
+    }
+
+    /// Compare this engine's functions against the functions of an older version of the program
+    pub fn diff_functions(&self, old_hashes: &[u64]) -> ProgramDiff {
+        ProgramDiff::from_hashes(old_hashes, &function_hashes(self.functions()))
+    }
+
+    /// Find the documentation of the function or global a name is bound to, see [DocTable].
+    /// Functions marked with [FunctionRef::host_only] have none.
+    pub fn help(&self, name: &str) -> Option<&Documentation> {
+        let symbol = self.symbols.resolve(self.symbols.lookup(name)?)?;
+        if matches!(symbol, Symbol::Function(func) if func.host_only) {
+            return None;
+        }
+        self.docs.get(DocTarget::symbol(symbol))
+    }
+
+    /// Whether a name is bound to something scripts can use, as checked by
+    /// [Expression::IsDefined]. Names which have never been interned aren't defined.
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.symbols
+            .lookup(name)
+            .is_some_and(|id| self.symbol_defined(id))
+    }
+
+    #[inline]
+    pub(crate) fn symbol_defined(&self, id: SymbolId) -> bool {
+        match self.symbols.resolve(id) {
+            Some(Symbol::Function(func)) => {
+                !func.host_only && func.module.is_none_or(|tag| self.modules.is_live(tag))
+            }
+            Some(Symbol::Global(addr)) => *addr < self.globals.len(),
+            None => false,
+        }
+    }
+
+    /// A stable hash of the program registered with this engine, see [crate::program::fingerprint]
+    pub fn fingerprint(&self) -> Result<u64, FreightError> {
+        crate::program::fingerprint(self)
+    }
+
+    /// Check the function references embedded in every registered function, see
+    /// [validate_references]. Call this once all functions have been registered.
+    pub fn validate(&self) -> Result<(), Vec<InvalidReference>> {
+        let invalid = validate_references(self);
+        if invalid.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid)
+        }
     }
 
     pub fn register_function(
         &mut self,
-        func: FunctionWriter<TS>,
+        mut func: FunctionWriter<TS>,
         return_target: usize,
     ) -> FunctionRef<TS> {
-        unsafe {
-            let functions = &mut *self.functions.get();
-            let func_ref = func.to_ref(functions.len());
-            let func = func.build(return_target);
-            functions.push(func);
-            func_ref
+        let location = self.functions().len();
+        self.prepare_function(&mut func, location, self.hoist_global_reads);
+        let func_ref = func.to_ref(location);
+        unsafe { &mut *self.functions.get() }.push(Rc::new(func.build(return_target)));
+        func_ref
+    }
+
+    /// Swap the body of a registered function for a new one, so references to it which are
+    /// already stored or captured in closures run the new code from their next call. Calls
+    /// already running the old body finish it. Resuming a coroutine suspended in the old body
+    /// fails with [FreightError::FunctionReplaced], while coroutines which haven't started yet
+    /// run the new body.
+    ///
+    /// The old body is kept in memory until this engine and all of its forks are dropped, since
+    /// calls may still be running it, so every replacement grows the engine by the size of the
+    /// replaced body. Hosts which reload often should load the program into a fresh engine from
+    /// time to time instead.
+    ///
+    /// Existing references decide how the function's stack frame is laid out, so the new body
+    /// must take the same arguments, use the same stack slots and capture as many values, or the
+    /// replacement fails with [FreightError::IncompatibleReplacement]. Returns a reference
+    /// carrying the new body's parameter names and metadata.
+    pub fn replace_function(
+        &mut self,
+        func: &FunctionRef<TS>,
+        mut body: FunctionWriter<TS>,
+        return_target: usize,
+    ) -> Result<FunctionRef<TS>, FreightError> {
+        let location = func.location;
+        let capture_count = |function_type: &FunctionType<TS>| match function_type {
+            FunctionType::Static => Some(0),
+            FunctionType::CapturingDef(captures) => Some(captures.len()),
+            FunctionType::CapturingRef(captures) => Some(captures.len()),
+            FunctionType::Native(_) => None,
+        };
+        if location >= self.functions().len() || capture_count(&func.function_type).is_none() {
+            return Err(FreightError::IncompatibleReplacement { location });
+        }
+        self.prepare_function(&mut body, location, self.hoist_global_reads);
+        let mut replacement = body.to_ref(location);
+        if capture_count(&func.function_type) != capture_count(&replacement.function_type)
+            || replacement.arg_count != func.arg_count
+            || replacement.stack_size != func.stack_size
+            || replacement.layout != func.layout
+        {
+            return Err(FreightError::IncompatibleReplacement { location });
+        }
+        self.install_body(location, Rc::new(body.build(return_target)));
+        if let Some(journal) = &mut self.fork_journal {
+            if !journal.replaced.contains(&location) {
+                journal.replaced.push(location);
+            }
+        }
+        replacement.function_type = func.function_type.clone();
+        replacement.module = func.module;
+        replacement.host_only = func.host_only;
+        Ok(replacement)
+    }
+
+    /// Put a new body in place of a registered function's, retiring the old one
+    pub(crate) fn install_body(&mut self, location: usize, mut body: Rc<Function<TS>>) {
+        let functions = unsafe { &mut *self.functions.get() };
+        if let Some(body) = Rc::get_mut(&mut body) {
+            body.generation = functions[location].generation.wrapping_add(1);
+        }
+        let old = std::mem::replace(&mut functions[location], body);
+        self.retired_functions.borrow_mut().push(old);
+    }
+
+    /// Apply the engine's rewriters, lints and instrumentation to a function about to be
+    /// registered at an address, hoisting its global reads if `hoist` is set
+    pub(crate) fn prepare_function(
+        &mut self,
+        func: &mut FunctionWriter<TS>,
+        location: usize,
+        hoist: bool,
+    ) {
+        if self.builder.optimize {
+            let mut optimizers: [Box<dyn Rewriter<TS>>; 2] =
+                [Box::new(ConstantFolder), Box::new(SwitchRewriter)];
+            for expr in &mut func.expressions {
+                rewrite(expr, &mut optimizers);
+            }
+        }
+        let mut rewriters = self.rewriters.borrow_mut();
+        if !rewriters.is_empty() {
+            for expr in &mut func.expressions {
+                rewrite(expr, &mut rewriters);
+            }
+        }
+        drop(rewriters);
+        if hoist {
+            func.hoist_global_reads();
+        }
+        if self.lint {
+            let diagnostics = lint_function(func, location);
+            self.diagnostics.extend(diagnostics);
+        }
+        if self.builder.optimize {
+            func.drop_discarded();
+        }
+        if let Some(instrumentation) = &mut self.instrumentation {
+            instrumentation.instrument(&mut func.expressions, location);
         }
     }
 
+    /// Start inserting probes into functions registered from now on, returning the
+    /// [Instrumentation] to select what is probed. If instrumentation was already started, its
+    /// probes and counts are kept and only the mode changes.
+    pub fn start_instrumentation(&mut self, mode: ProbeMode) -> &mut Instrumentation {
+        let instrumentation = self
+            .instrumentation
+            .get_or_insert_with(|| Instrumentation::new(mode));
+        instrumentation.mode = mode;
+        instrumentation
+    }
+
+    /// The probes inserted since [ExecutionEngine::start_instrumentation] and what they recorded
+    pub fn instrumentation(&self) -> Option<&Instrumentation> {
+        self.instrumentation.as_ref()
+    }
+
+    /// Select what is probed in functions registered from now on, or reset the counts
+    pub fn instrumentation_mut(&mut self) -> Option<&mut Instrumentation> {
+        self.instrumentation.as_mut()
+    }
+
+    #[inline]
+    pub(crate) fn hit_probe(&mut self, probe: usize) {
+        if let Some(instrumentation) = &mut self.instrumentation {
+            instrumentation.hit(probe);
+        }
+    }
+
+    /// The lints found in functions registered while [ExecutionEngine::lint] was set
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Remove and return the lints found so far, see [ExecutionEngine::diagnostics]
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
     pub fn create_return_target(&mut self) -> usize {
         self.next_return_target += 1;
         self.next_return_target - 1
     }
 
+    /// Create a label for [Expression::Escape] and [Expression::EscapeTo]
+    pub fn create_escape_label(&mut self) -> usize {
+        self.next_escape_label += 1;
+        self.next_escape_label - 1
+    }
+
+    /// Register a function belonging to a module, which can only be invoked while the module is
+    /// loaded, see [ModuleTable]
+    pub fn register_module_function(
+        &mut self,
+        module: ModuleId,
+        func: FunctionWriter<TS>,
+        return_target: usize,
+    ) -> FunctionRef<TS> {
+        let tag = self.modules.tag(module);
+        self.register_function(func, return_target).with_module(tag)
+    }
+
+    /// The globals of every namespace which hold a function of a module that has since been
+    /// unloaded. Only globals holding the function directly are found, not functions nested
+    /// inside other values.
+    pub fn dangling_globals(&self) -> Vec<(NamespaceId, usize)> {
+        let is_dangling = |value: &TS::Value| {
+            value
+                .cast_to_function()
+                .and_then(|func| func.module)
+                .is_some_and(|tag| !self.modules.is_live(tag))
+        };
+        self.all_globals()
+            .filter(|(_, _, value)| is_dangling(value))
+            .map(|(namespace, addr, _)| (namespace, addr))
+            .collect()
+    }
+
+    /// Find everything owned by the engine which still references a function: globals of every
+    /// namespace, entries of the [ValueCache] and handlers in the [CallbackRegistry]. Closures
+    /// of the function count as references to it. Like [ExecutionEngine::dangling_globals], only
+    /// values holding the function directly are found, not functions nested inside other values.
+    pub fn references_to(&self, func: &FunctionRef<TS>) -> Vec<ReferenceHolder> {
+        let is_func = |value: &TS::Value| value.cast_to_function() == Some(func);
+        let globals = self
+            .all_globals()
+            .filter(|(_, _, value)| is_func(value))
+            .map(|(namespace, addr, _)| ReferenceHolder::Global { namespace, addr });
+        let cache = self
+            .cache
+            .entries()
+            .filter(|(key, value)| is_func(key) || is_func(value))
+            .map(|(key, _)| ReferenceHolder::CacheEntry {
+                key: self.summarize(key),
+            });
+        let callbacks = self
+            .callbacks
+            .all_handlers()
+            .filter(|(_, _, handler)| *handler == func)
+            .map(|(event, handler, _)| ReferenceHolder::Callback {
+                event: event.clone(),
+                handler,
+            });
+        globals.chain(cache).chain(callbacks).collect()
+    }
+
+    /// The globals of the current namespace followed by those of every other namespace
+    fn all_globals(&self) -> impl Iterator<Item = (NamespaceId, usize, &TS::Value)> {
+        let namespaces = self
+            .namespaces
+            .iter()
+            .enumerate()
+            .filter_map(|(i, namespace)| Some((NamespaceId(i), &namespace.as_ref()?.globals)));
+        std::iter::once((self.namespace, &self.globals))
+            .chain(namespaces)
+            .flat_map(|(namespace, globals)| {
+                globals
+                    .iter()
+                    .enumerate()
+                    .map(move |(addr, value)| (namespace, addr, value))
+            })
+    }
+
+    /// Create a reference to a native function whose calls are limited by [RateLimits]
+    pub fn register_limited_native(
+        &mut self,
+        id: usize,
+        func: NativeFunction<TS>,
+        arg_count: ArgCount,
+        limit: RateLimit,
+    ) -> FunctionRef<TS> {
+        self.rate_limits.insert(id, limit);
+        FunctionRef::new_native(id, func, arg_count)
+    }
+
+    /// A global of the active namespace about to be read or written, which a fork copies from
+    /// its parent the first time it uses it
+    #[inline]
+    pub(crate) fn use_global(&mut self, addr: usize) -> &mut TS::Value {
+        if let Some(journal) = &mut self.fork_journal {
+            journal.use_global(self.namespace, &mut self.globals, addr);
+        }
+        &mut self.globals[addr]
+    }
+
     pub fn create_global(&mut self) -> usize {
         self.globals.push(Value::uninitialized_reference());
+        self.num_globals += 1;
         self.globals.len() - 1
     }
 
+    /// Reset the globals of every namespace to uninitialized references
     pub fn reset_globals(&mut self) {
         self.globals = vec![Value::uninitialized_reference(); self.num_globals];
+        for namespace in self.namespaces.iter_mut().flatten() {
+            namespace.reset_globals();
+        }
+        if let Some(journal) = &mut self.fork_journal {
+            journal.overwrite_all();
+        }
+    }
+
+    /// Create a namespace with no globals and no callbacks. Namespaces share everything else,
+    /// including functions, so they are much cheaper than separate engines. Globals created and
+    /// accessed while a namespace is active, including by calls made with
+    /// [ExecutionEngine::call_in], belong to that namespace.
+    pub fn create_namespace(&mut self) -> NamespaceId {
+        self.namespaces.push(Some(Namespace::default()));
+        NamespaceId(self.namespaces.len() - 1)
+    }
+
+    /// The namespace whose globals and callbacks are in use
+    pub fn current_namespace(&self) -> NamespaceId {
+        self.namespace
+    }
+
+    /// Make a namespace active, returning the namespace which was active before
+    pub fn enter_namespace(&mut self, id: NamespaceId) -> NamespaceId {
+        if id == self.namespace {
+            return id;
+        }
+        let incoming = self.namespaces[id.0]
+            .take()
+            .expect("Namespace was not created by this engine");
+        let outgoing = Namespace {
+            num_globals: std::mem::replace(&mut self.num_globals, incoming.num_globals),
+            globals: std::mem::replace(&mut self.globals, incoming.globals),
+            callbacks: std::mem::replace(&mut self.callbacks, incoming.callbacks),
+        };
+        self.namespaces[self.namespace.0] = Some(outgoing);
+        std::mem::replace(&mut self.namespace, id)
+    }
+
+    /// Run a closure with a namespace active, restoring the previously active namespace afterwards
+    pub fn in_namespace<R>(&mut self, id: NamespaceId, f: impl FnOnce(&mut Self) -> R) -> R {
+        let previous = self.enter_namespace(id);
+        let result = f(self);
+        self.enter_namespace(previous);
+        result
+    }
+
+    /// Call a function with a namespace active
+    pub fn call_in(
+        &mut self,
+        namespace: NamespaceId,
+        func: &FunctionRef<TS>,
+        args: impl IntoExactSizeIterator<Item = TS::Value>,
+    ) -> Result<TS::Value, FreightError> {
+        self.in_namespace(namespace, |engine| engine.call(func, args))
+    }
+
+    /// Discard all state created by running scripts, while keeping registered functions.
+    ///
+    /// Every global is reset to an uninitialized reference (so existing global addresses stay
+    /// valid), the callbacks of every namespace are unregistered, pooled allocations are released,
+    /// and cancellation and profiling state are cleared. Registered functions, the symbol table,
+    /// the type registry and expression hooks are kept.
+    /// Each function in [ExecutionEngine::on_clear] is then called so the global context can
+    /// clear its own state.
+    ///
+    /// Must not be called while a call is running on this engine.
+    pub fn clear_runtime_state(&mut self) {
+        self.reset_globals();
+        self.callbacks.clear();
+        for namespace in self.namespaces.iter_mut().flatten() {
+            namespace.callbacks.clear();
+        }
+        self.return_value = Default::default();
+        self.rc_pool = self.builder.pool();
+        self.scratch_pool = self.builder.pool();
+        self.cancellation.reset();
+        self.cancelled_at = None;
+        self.error_trace.clear();
+        self.error_span = None;
+        self.scopes.clear();
+        self.rate_limits.reset();
+        self.cache.clear();
+        #[cfg(feature = "profiling")]
+        if let Some(profiler) = &mut self.profiler {
+            profiler.clear();
+        }
+        for clear in self.on_clear.clone() {
+            clear(&mut self.context);
+        }
+    }
+
+    /// How many hooks are running, counting both [ExpressionHooks] and [ExecutionEngine::on_unwind_frame].
+    ///
+    /// Hooks may call back into the engine: calling functions, evaluating expressions and
+    /// registering functions are all supported, and hooks run for anything those calls
+    /// evaluate. A hook can check this to avoid recursing into itself, such as an assignment
+    /// hook which calls a script that assigns.
+    pub fn hook_depth(&self) -> usize {
+        self.hook_depth
+    }
+
+    /// The stack frames popped by the error most recently returned to the host, innermost
+    /// first. Cleared whenever the host calls into the engine, and when a script catches an
+    /// error with [Expression::Catch].
+    pub fn error_trace(&self) -> &[UnwindFrame] {
+        &self.error_trace
+    }
+
+    /// The span of the innermost [Expression::Spanned] which the error most recently returned to
+    /// the host passed through, so front-ends can map the failure back to the source. Cleared
+    /// like [ExecutionEngine::error_trace].
+    pub fn error_span(&self) -> Option<SpanId> {
+        self.error_span
+    }
+
+    pub(crate) fn record_error_span(&mut self, span: SpanId, error: &FreightError) {
+        if self.error_span.is_none()
+            && !matches!(
+                error,
+                FreightError::Return { .. } | FreightError::Escape { .. }
+            )
+        {
+            self.error_span = Some(span);
+        }
     }
 
     #[inline]
@@ -91,16 +859,179 @@ impl<TS: TypeSystem> ExecutionEngine<TS> {
     ) -> Result<TS::Value, FreightError> {
         let mut iter = args.into_exact_size_iter();
         let arg_count = iter.len();
-        self.call_internal(func, |_| Ok(iter.next().unwrap()), arg_count)
+        self.error_trace.clear();
+        self.error_span = None;
+        let result = self.call_internal(func, |_| Ok(iter.next().unwrap()), arg_count);
+        #[cfg(feature = "metrics")]
+        if let Err(error) = &result {
+            telemetry::record_error(error);
+        }
+        result
+    }
+
+    /// Call a function with a scope value which natives can read with [ExecutionEngine::scope]
+    /// for the duration of the call, such as data about the request being served. Calls with
+    /// scopes nest, and the scope is removed when the call returns or fails. Coroutines started
+    /// during the call keep its scopes, see [ExecutionEngine::start_coroutine].
+    pub fn call_with_scope(
+        &mut self,
+        func: &FunctionRef<TS>,
+        args: impl IntoExactSizeIterator<Item = TS::Value>,
+        scope: TS::Value,
+    ) -> Result<TS::Value, FreightError> {
+        let depth = self.scopes.len();
+        self.scopes.push(scope);
+        let result = self.call(func, args);
+        self.scopes.truncate(depth);
+        result
+    }
+
+    /// The scope of the innermost call made with [ExecutionEngine::call_with_scope] which is
+    /// still running
+    pub fn scope(&self) -> Option<&TS::Value> {
+        self.scopes.last()
+    }
+
+    /// The scopes of the running calls made with [ExecutionEngine::call_with_scope], outermost
+    /// first
+    pub fn scopes(&self) -> &[TS::Value] {
+        &self.scopes
+    }
+
+    /// Call a function and store its result in `dest`, a convenience wrapper around
+    /// [ExecutionEngine::call] for hosts which keep results in existing slots. The previous value
+    /// of `dest` is dropped once the call has succeeded, and is left untouched if it fails.
+    #[inline]
+    pub fn call_into(
+        &mut self,
+        func: &FunctionRef<TS>,
+        args: impl IntoExactSizeIterator<Item = TS::Value>,
+        dest: &mut TS::Value,
+    ) -> Result<(), FreightError> {
+        *dest = self.call(func, args)?;
+        Ok(())
+    }
+
+    /// Call a function, converting any error it raises into a value using the type system's
+    /// [ErrorConverter]. Returns can't cross this boundary: an [Expression::Return] escaping the
+    /// call is converted like any other error.
+    pub fn call_checked(
+        &mut self,
+        func: &FunctionRef<TS>,
+        args: impl IntoExactSizeIterator<Item = TS::Value>,
+    ) -> TS::Value {
+        match self.call(func, args) {
+            Ok(value) => value,
+            Err(error) => TS::ErrorConverter::error_to_value(error, self),
+        }
+    }
+
+    /// Call a function with arguments created by the type system's [ValueFactory]
+    pub fn call_with_literals(
+        &mut self,
+        func: &FunctionRef<TS>,
+        args: &[Literal],
+    ) -> Result<TS::Value, FreightError>
+    where
+        TS: ValueFactory,
+    {
+        let args = args
+            .iter()
+            .map(TS::literal)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|literal| FreightError::UnsupportedLiteral {
+                kind: literal.kind().to_string(),
+            })?;
+        self.call(func, args)
+    }
+
+    /// Call the handlers of an event registered in [ExecutionEngine::callbacks] with the given
+    /// arguments, until one of them consumes it. Handlers registered or removed while the event
+    /// is being dispatched only take effect for later events.
+    pub fn dispatch(&mut self, event: &str, args: &[TS::Value]) -> DispatchReport {
+        let handlers: Vec<_> = self
+            .callbacks
+            .handlers(event)
+            .map(|(id, func)| (id, func.clone()))
+            .collect();
+        let mut report = DispatchReport::default();
+        for (id, func) in handlers {
+            report.handled += 1;
+            match self.call(&func, args.iter().cloned()) {
+                Ok(value) if (self.callbacks.consumed)(&value) => {
+                    report.consumed_by = Some(id);
+                    break;
+                }
+                Ok(_) => (),
+                Err(FreightError::Cancelled) => {
+                    report.errors.push((id, FreightError::Cancelled));
+                    break;
+                }
+                Err(error) => report.errors.push((id, error)),
+            }
+        }
+        report
+    }
+
+    /// Consume a sequence produced by a script. The value must be a function taking no arguments
+    /// which returns the next element each time it is called, and a value for which
+    /// [Value::is_iteration_end] is true once the sequence is exhausted. Iteration stops after
+    /// the end of the sequence or the first error.
+    pub fn iterate(&mut self, value: &TS::Value) -> ScriptIterator<'_, TS> {
+        let next = value
+            .cast_to_function()
+            .cloned()
+            .ok_or(FreightError::InvalidInvocationTarget);
+        ScriptIterator {
+            engine: self,
+            next: Some(next),
+        }
+    }
+
+    /// Borrow the global context and the rest of the engine independently, so natives can keep
+    /// using the context while calling back into the engine. The context is taken out of the
+    /// engine for the duration of `f`, and the engine only has it back while it is lent to a
+    /// call through the [Caller].
+    pub fn with_context_and_caller<R>(
+        &mut self,
+        f: impl FnOnce(&mut TS::GlobalContext, &mut Caller<TS>) -> R,
+    ) -> R
+    where
+        TS::GlobalContext: Default,
+    {
+        let mut context = std::mem::take(&mut self.context);
+        let result = f(&mut context, &mut Caller { engine: self });
+        self.context = context;
+        result
+    }
+
+    /// Validate a call to a function with a fixed number of arguments once, so it can be invoked
+    /// repeatedly without redoing the checks [ExecutionEngine::call] does on every call
+    pub fn prepare_call(
+        &mut self,
+        func: &FunctionRef<TS>,
+        arg_count: usize,
+    ) -> Result<PreparedCall<'_, TS>, FreightError> {
+        if !func.arg_count.valid_arg_count(arg_count) {
+            return Err(FreightError::IncorrectArgumentCount {
+                expected_min: func.arg_count.min(),
+                expected_max: func.arg_count.max(),
+                actual: arg_count,
+            });
+        }
+        Ok(PreparedCall {
+            engine: self,
+            func: func.clone(),
+            arg_count,
+        })
     }
 
     pub(crate) fn call_internal(
         &mut self,
         func: &FunctionRef<TS>,
-        mut args: impl FnMut(&mut ExecutionEngine<TS>) -> Result<TS::Value, FreightError>,
+        args: impl FnMut(&mut ExecutionEngine<TS>) -> Result<TS::Value, FreightError>,
         arg_count: usize,
     ) -> Result<TS::Value, FreightError> {
-        let mut stack = StackPool::request(self.stack.clone(), func.stack_size);
         if !func.arg_count.valid_arg_count(arg_count) {
             return Err(FreightError::IncorrectArgumentCount {
                 expected_min: func.arg_count.min(),
@@ -108,6 +1039,41 @@ impl<TS: TypeSystem> ExecutionEngine<TS> {
                 actual: arg_count,
             });
         }
+        self.call_valid(func, args, arg_count)
+    }
+
+    /// Call a function which is known to take `arg_count` arguments
+    fn call_valid(
+        &mut self,
+        func: &FunctionRef<TS>,
+        mut args: impl FnMut(&mut ExecutionEngine<TS>) -> Result<TS::Value, FreightError>,
+        arg_count: usize,
+    ) -> Result<TS::Value, FreightError> {
+        if self.argument_evaluation == ArgumentEvaluation::Eager && arg_count > 0 {
+            self.check_memory(arg_count)?;
+            let mut evaluated = StackPool::request(self.stack.clone(), arg_count);
+            for arg in evaluated.iter_mut() {
+                *arg = args(self)?;
+            }
+            let mut evaluated = evaluated.iter_mut();
+            return self.enter_function(
+                func,
+                |_| Ok(std::mem::take(evaluated.next().unwrap())),
+                arg_count,
+            );
+        }
+        self.enter_function(func, args, arg_count)
+    }
+
+    fn enter_function(
+        &mut self,
+        func: &FunctionRef<TS>,
+        mut args: impl FnMut(&mut ExecutionEngine<TS>) -> Result<TS::Value, FreightError>,
+        arg_count: usize,
+    ) -> Result<TS::Value, FreightError> {
+        self.check_entry(func)?;
+        let _frame_guard = self.frame_guard(func);
+        let mut stack = StackPool::request(self.stack.clone(), func.stack_size);
         let mut arg_num = 0;
         let max = func.arg_count.max_capped().min(arg_count);
         while arg_num < max {
@@ -137,22 +1103,235 @@ impl<TS: TypeSystem> ExecutionEngine<TS> {
             stack[func.arg_count.max_capped()] = Value::gen_list(vargs);
         }
 
-        if let FunctionType::Native(func) = &func.function_type {
-            return func(self, &mut stack);
+        self.run_frame(func, &mut stack, |engine, stack| {
+            match &func.function_type {
+                FunctionType::Native(native) => native(engine, stack),
+                FunctionType::CapturingRef(captures) => engine
+                    .get_function(func.location)
+                    .call(engine, stack, captures),
+                FunctionType::Static => engine.get_function(func.location).call(engine, stack, &[]),
+                FunctionType::CapturingDef(_) => Err(FreightError::InvalidInvocationTarget),
+            }
+        })
+    }
+
+    /// Fail if a function can't be entered, since its module was unloaded or its frame would
+    /// exceed the memory limit
+    pub(crate) fn check_entry(&self, func: &FunctionRef<TS>) -> Result<(), FreightError> {
+        if let Some(tag) = func.module {
+            if !self.modules.is_live(tag) {
+                return Err(FreightError::ModuleUnloaded {
+                    module: tag.module.0,
+                });
+            }
+        }
+        self.check_memory(func.stack_size)
+    }
+
+    /// Run the body of a call whose frame is set up: stop if execution was cancelled, charge a
+    /// native's rate limit and fuel, track the call in the debugger and region allocator, and
+    /// record the frame in the error trace if it fails
+    pub(crate) fn run_frame(
+        &mut self,
+        func: &FunctionRef<TS>,
+        stack: &mut [TS::Value],
+        body: impl FnOnce(&mut Self, &mut [TS::Value]) -> Result<TS::Value, FreightError>,
+    ) -> Result<TS::Value, FreightError> {
+        if self.cancellation.is_cancelled() {
+            self.cancelled_at = Some(CancellationSnapshot {
+                function: func.location,
+                frame: stack.iter().map(|v| self.summarize(v)).collect(),
+            });
+            return Err(FreightError::Cancelled);
+        }
+
+        if let FunctionType::Native(native) = &func.function_type {
+            if !self.rate_limits.is_empty() && !self.rate_limits.acquire(func.location) {
+                return Err(FreightError::RateLimited {
+                    native: func.location,
+                });
+            }
+            if self.fuel.is_some() {
+                self.consume_fuel(self.fuel_schedule.native_cost(native))?;
+            }
         }
-        let function = self.get_function(func.location);
 
-        match &func.function_type {
-            FunctionType::CapturingRef(captures) => function.call(self, &mut stack, captures),
-            FunctionType::Static => function.call(self, &mut stack, &[]),
-            FunctionType::CapturingDef(_) => Err(FreightError::InvalidInvocationTarget),
-            FunctionType::Native(_) => unreachable!("Native function already handled"),
+        #[cfg(feature = "metrics")]
+        telemetry::record_call(matches!(func.function_type, FunctionType::Native(_)));
+        let region = TS::RegionAlloc::enter_call(self);
+        let native = matches!(func.function_type, FunctionType::Native(_));
+        if !native {
+            self.debug_enter(func);
+        }
+        let mut result = body(self, stack);
+        if !native {
+            self.debug_exit();
+        }
+        match &result {
+            Err(FreightError::Return { .. } | FreightError::Escape { .. }) => {
+                // The value being returned is carried by the engine, and outlives this call
+                let mut returned = std::mem::take(&mut self.return_value);
+                TS::RegionAlloc::exit_call(self, region, Some(&mut returned));
+                self.return_value = returned;
+                return result;
+            }
+            Err(error) => {
+                let frame = UnwindFrame {
+                    function: func.location,
+                    native,
+                    expression: (!native).then_some(self.failed_expression),
+                };
+                self.error_trace.push(frame);
+                if let Some(hook) = self.on_unwind_frame {
+                    // Calls made by the hook mustn't clear the trace of the error unwinding
+                    let trace = std::mem::take(&mut self.error_trace);
+                    let span = self.error_span.take();
+                    self.hook_depth += 1;
+                    hook(self, &frame, error);
+                    self.hook_depth -= 1;
+                    self.error_trace = trace;
+                    self.error_span = span;
+                }
+            }
+            Ok(_) => (),
+        }
+        TS::RegionAlloc::exit_call(self, region, result.as_mut().ok());
+        result
+    }
+
+    /// Start counting expression evaluations, discarding any previously recorded counts
+    #[cfg(feature = "profiling")]
+    pub fn start_profiling(&mut self) {
+        self.profiler = Some(Profiler::default());
+    }
+
+    /// Stop counting expression evaluations, returning the recorded counts
+    #[cfg(feature = "profiling")]
+    pub fn stop_profiling(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    /// Report the `limit` hottest expression subtrees evaluated since profiling was started
+    #[cfg(feature = "profiling")]
+    pub fn hot_path_report(&self, limit: usize) -> HotPathReport {
+        match &self.profiler {
+            Some(profiler) => HotPathReport::new(profiler, self, limit),
+            None => HotPathReport::default(),
+        }
+    }
+
+    /// Whether anything needs to observe every evaluated expression
+    #[cfg(feature = "compiled")]
+    #[inline]
+    pub(crate) fn is_instrumented(&self) -> bool {
+        #[cfg(feature = "profiling")]
+        if self.profiler.is_some() {
+            return true;
+        }
+        self.fuel.is_some() || !self.hooks.is_empty() || self.debugger.is_some()
+    }
+
+    /// Limit how much more work this engine may do, as measured by its [FuelSchedule], or remove
+    /// the limit with `None`. Once the fuel runs out, evaluation fails with
+    /// [FreightError::OutOfFuel].
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// How much fuel is left, or `None` if there is no limit
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Limit how many value slots this engine may hold in its stack, captured environments and
+    /// globals, or remove the limit with `None`. Calls and captures which would exceed it fail
+    /// with [FreightError::OutOfMemory]. Globals created by the host are counted, but never
+    /// refused.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
+    /// The memory limit in value slots, or `None` if there is no limit
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// How many value slots this engine holds in its stack, captured environments and globals
+    pub fn memory_used(&self) -> usize {
+        unsafe {
+            self.globals.len()
+                + (*self.stack.get()).used()
+                + (*self.rc_pool.get()).live()
+                + (*self.scratch_pool.get()).live()
+        }
+    }
+
+    /// Fail if holding `slots` more value slots would exceed the memory limit
+    #[inline]
+    fn check_memory(&self, slots: usize) -> Result<(), FreightError> {
+        match self.memory_limit {
+            Some(limit) if self.memory_used() + slots > limit => Err(FreightError::OutOfMemory),
+            _ => Ok(()),
         }
     }
 
+    #[inline]
+    fn consume_fuel(&mut self, cost: u64) -> Result<(), FreightError> {
+        let Some(fuel) = &mut self.fuel else {
+            return Ok(());
+        };
+        if *fuel < cost {
+            *fuel = 0;
+            return Err(FreightError::OutOfFuel);
+        }
+        *fuel -= cost;
+        #[cfg(feature = "metrics")]
+        telemetry::record_fuel(cost);
+        Ok(())
+    }
+
     #[inline]
     pub fn evaluate(&mut self, expr: &Expression<TS>) -> Result<TS::Value, FreightError> {
-        self.evaluate_internal(expr, &mut [], &[])
+        self.error_trace.clear();
+        self.error_span = None;
+        let result = self.evaluate_internal(expr, &mut [], &[]);
+        #[cfg(feature = "metrics")]
+        if let Err(error) = &result {
+            telemetry::record_error(error);
+        }
+        result
+    }
+
+    /// Profile an expression about to be evaluated, run its hooks, charge its fuel and stop at it
+    /// in the debugger. Paths which evaluate an expression without [Self::evaluate_internal] must
+    /// call this first.
+    #[inline]
+    pub(crate) fn enter_expression(
+        &mut self,
+        expr: &Expression<TS>,
+        stack: &mut [TS::Value],
+        captured: &[TS::Value],
+    ) -> Result<(), FreightError> {
+        #[cfg(feature = "profiling")]
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(expr);
+        }
+        let kind = expr.kind();
+        if self.hooks.is_active(kind) {
+            for hook in self.hooks.get(kind).to_vec() {
+                self.hook_depth += 1;
+                let result = hook(self, expr);
+                self.hook_depth -= 1;
+                result?;
+            }
+        }
+        if self.fuel.is_some() {
+            self.consume_fuel(self.fuel_schedule.cost(kind))?;
+        }
+        if self.debugger.is_some() {
+            self.debug_step(expr, stack, captured)?;
+        }
+        Ok(())
     }
 
     pub(crate) fn evaluate_internal(
@@ -161,16 +1340,20 @@ impl<TS: TypeSystem> ExecutionEngine<TS> {
         stack: &mut [TS::Value],
         captured: &[TS::Value],
     ) -> Result<TS::Value, FreightError> {
+        self.enter_expression(expr, stack, captured)?;
         let result = match expr {
             Expression::RawValue(v) => v.clone(),
             Expression::Variable(var) => match var {
                 VariableType::Captured(addr) => captured[*addr].dupe_ref(),
                 VariableType::Stack(addr) => stack[*addr].dupe_ref(),
-                VariableType::Global(addr) => self.globals[*addr].dupe_ref(),
+                VariableType::Global(addr) => self.use_global(*addr).dupe_ref(),
             },
             Expression::BinaryOpEval(op, operands) => {
                 let [l, r] = &**operands;
                 let l = self.evaluate_internal(l, stack, captured)?;
+                if let Some(result) = op.short_circuit(&l) {
+                    return Ok(result);
+                }
                 let r = self.evaluate_internal(r, stack, captured)?;
                 op.apply_2(&l, &r)
             }
@@ -178,6 +1361,13 @@ impl<TS: TypeSystem> ExecutionEngine<TS> {
                 let v = self.evaluate_internal(v, stack, captured)?;
                 op.apply_1(&v)
             }
+            Expression::TernaryOpEval(op, operands) => {
+                let [a, b, c] = &**operands;
+                let a = self.evaluate_internal(a, stack, captured)?;
+                let b = self.evaluate_internal(b, stack, captured)?;
+                let c = self.evaluate_internal(c, stack, captured)?;
+                op.apply_3(&a, &b, &c)
+            }
             Expression::StaticFunctionCall(func, args) => {
                 let mut args = args.iter();
                 let arg_count = args.len();
@@ -188,15 +1378,75 @@ impl<TS: TypeSystem> ExecutionEngine<TS> {
                 )?
             }
             Expression::DynamicFunctionCall(func, args) => {
+                let func: TS::Value = self.evaluate_internal(func, stack, captured)?;
+                self.call_dynamic(func, args, stack, captured, None)?
+            }
+            Expression::CachedDynamicCall(func, args, cache) => {
+                let func: TS::Value = self.evaluate_internal(func, stack, captured)?;
+                self.call_dynamic(func, args, stack, captured, Some(cache))?
+            }
+            Expression::MethodCall(receiver, name, args) => {
+                let receiver = self.evaluate_internal(receiver, stack, captured)?;
+                let Some(method) = self.types.method(receiver.get_type(), name).cloned() else {
+                    return Err(FreightError::MethodNotFound {
+                        method: name.to_string(),
+                    });
+                };
+                let mut receiver = Some(receiver);
+                let mut iter = args.iter();
+                self.call_internal(
+                    &method,
+                    |e| match receiver.take() {
+                        Some(receiver) => Ok(receiver),
+                        None => e.evaluate_internal(iter.next().unwrap(), stack, captured),
+                    },
+                    args.len() + 1,
+                )?
+            }
+            Expression::NamedFunctionCall { func, args, named } => {
                 let func: TS::Value = self.evaluate_internal(func, stack, captured)?;
                 let Some(func): Option<&FunctionRef<TS>> = func.cast_to_function() else {
                     return Err(FreightError::InvalidInvocationTarget);
                 };
-                let mut iter = args.iter();
-                let arg_count = iter.len();
+                if func.host_only {
+                    return Err(FreightError::HostOnlyFunction {
+                        function: func.location,
+                    });
+                }
+                let mut bound = Vec::with_capacity(args.len() + named.len());
+                for arg in args {
+                    bound.push(Some(self.evaluate_internal(arg, stack, captured)?));
+                }
+                for (name, arg) in named {
+                    let value = self.evaluate_internal(arg, stack, captured)?;
+                    let unbound = || FreightError::ArgumentName {
+                        function: func.location,
+                        name: name.to_string(),
+                    };
+                    let index = func.param_index(name).ok_or_else(unbound)?;
+                    if index >= bound.len() {
+                        bound.resize(index + 1, None);
+                    }
+                    if bound[index].replace(value).is_some() {
+                        return Err(unbound());
+                    }
+                }
+                let required = func.arg_count.min();
+                if bound.len() < required {
+                    bound.resize(required, None);
+                }
+                if let Some(index) = bound[..required].iter().position(Option::is_none) {
+                    let name = func.param_names().and_then(|names| names.get(index));
+                    return Err(FreightError::ArgumentName {
+                        function: func.location,
+                        name: name.map_or_else(|| index.to_string(), |name| name.to_string()),
+                    });
+                }
+                let arg_count = bound.len();
+                let mut bound = bound.into_iter();
                 self.call_internal(
                     func,
-                    |e| e.evaluate_internal(iter.next().unwrap(), stack, captured),
+                    |_| Ok(bound.next().flatten().unwrap_or_default()),
                     arg_count,
                 )?
             }
@@ -204,35 +1454,63 @@ impl<TS: TypeSystem> ExecutionEngine<TS> {
                 let FunctionType::CapturingDef(capture) = &func.function_type else {
                 return Err(FreightError::InvalidInvocationTarget);
             };
+                self.check_memory(capture.len())?;
                 let mut func = func.clone();
-                let captures_iter = capture.iter().map(|var| match var {
-                    VariableType::Captured(addr) => captured[*addr].dupe_ref(),
-                    VariableType::Stack(addr) => stack[*addr].dupe_ref(),
-                    VariableType::Global(addr) => self.globals[*addr].dupe_ref(),
-                });
-
-                func.function_type = FunctionType::CapturingRef(RcSlicePool::from_pool(
-                    self.rc_pool.clone(),
-                    captures_iter,
-                ));
+                func.function_type =
+                    FunctionType::CapturingRef(self.capture_environment(capture, stack, captured));
                 func.into()
             }
+            Expression::SharedCapture { captures, closures } => {
+                self.check_memory(captures.len())?;
+                let environment = self.capture_environment(captures, stack, captured);
+                for (func, addr) in closures {
+                    if !matches!(func.function_type, FunctionType::CapturingDef(_)) {
+                        return Err(FreightError::InvalidInvocationTarget);
+                    }
+                    let mut func = func.clone();
+                    func.function_type = FunctionType::CapturingRef(environment.clone());
+                    stack[*addr].assign(func.into());
+                }
+                Default::default()
+            }
             Expression::AssignStack(addr, expr) => {
+                if let Expression::Initialize(init, args) = &**expr {
+                    self.enter_expression(expr, stack, captured)?;
+                    let collected = self.evaluate_initializer_args(init, args, stack, captured)?;
+                    init.initialize_into(collected, &mut stack[*addr], self)?;
+                    return Ok(Default::default());
+                }
                 let val = self.evaluate_internal(expr, stack, captured)?;
                 stack[*addr].assign(val);
                 Default::default()
             }
             Expression::NativeFunctionCall(func, args) => {
-                let mut collected = StackPool::request(self.stack.clone(), args.len());
-                for (i, arg) in args.iter().enumerate() {
-                    collected[i] = self.evaluate_internal(arg, stack, captured)?.clone();
-                }
-
-                func(self, &mut collected)?
+                let func = FunctionRef::new_native(
+                    ANONYMOUS_NATIVE,
+                    func.clone(),
+                    ArgCount::Fixed(args.len()),
+                );
+                let mut args = args.iter();
+                let arg_count = args.len();
+                self.call_internal(
+                    &func,
+                    |e| e.evaluate_internal(args.next().unwrap(), stack, captured),
+                    arg_count,
+                )?
             }
             Expression::AssignGlobal(addr, expr) => {
+                if let Expression::Initialize(init, args) = &**expr {
+                    self.enter_expression(expr, stack, captured)?;
+                    let collected = self.evaluate_initializer_args(init, args, stack, captured)?;
+                    // The value is built into a copy of the global, so the initializer can still
+                    // borrow the engine and read the global through it
+                    let mut target = self.use_global(*addr).clone();
+                    init.initialize_into(collected, &mut target, self)?;
+                    *self.use_global(*addr) = target;
+                    return Ok(Default::default());
+                }
                 let val = self.evaluate_internal(expr, stack, captured)?;
-                self.globals[*addr].assign(val);
+                self.use_global(*addr).assign(val);
                 Default::default()
             }
             Expression::AssignDynamic(args) => {
@@ -242,12 +1520,115 @@ impl<TS: TypeSystem> ExecutionEngine<TS> {
                 target.assign(value);
                 Default::default()
             }
-            Expression::Initialize(init, args) => {
-                let mut collected = Vec::with_capacity(args.len());
-                for arg in args {
-                    collected.push(self.evaluate_internal(arg, stack, captured)?);
+            Expression::ResolveSymbol(id) => match self.symbols.resolve(*id) {
+                Some(Symbol::Function(func)) if func.host_only => {
+                    return Err(FreightError::HostOnlyFunction {
+                        function: func.location,
+                    })
+                }
+                Some(Symbol::Function(func)) => func.clone().into(),
+                Some(Symbol::Global(addr)) => self.use_global(*addr).dupe_ref(),
+                None => {
+                    return Err(FreightError::UnresolvedSymbol {
+                        name: self.symbols.name(*id).to_string(),
+                    })
                 }
-                init.initialize(collected, self)
+            },
+            Expression::IsDefined {
+                symbol,
+                defined,
+                undefined,
+            } => {
+                let branch = match self.symbol_defined(*symbol) {
+                    true => defined,
+                    false => undefined,
+                };
+                self.evaluate_internal(branch, stack, captured)?
+            }
+            Expression::Switch {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                let scrutinee = self.evaluate_internal(scrutinee, stack, captured)?;
+                let arm = scrutinee
+                    .as_discriminant()
+                    .and_then(|i| arms.get(i))
+                    .unwrap_or(default);
+                self.evaluate_internal(arm, stack, captured)?
+            }
+            Expression::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let branch = if self
+                    .evaluate_internal(condition, stack, captured)?
+                    .is_truthy()
+                {
+                    then
+                } else {
+                    otherwise
+                };
+                self.evaluate_internal(branch, stack, captured)?
+            }
+            Expression::While { condition, body } => {
+                while self
+                    .evaluate_internal(condition, stack, captured)?
+                    .is_truthy()
+                {
+                    self.evaluate_internal(body, stack, captured)?;
+                    self.check_loop_cancellation(stack)?;
+                }
+                Default::default()
+            }
+            Expression::Repeat(times, body) => {
+                for _ in 0..*times {
+                    self.evaluate_internal(body, stack, captured)?;
+                    self.check_loop_cancellation(stack)?;
+                }
+                Default::default()
+            }
+            Expression::Yield(_) | Expression::AwaitNative(..) => {
+                return Err(FreightError::InvalidYield)
+            }
+            Expression::Probe(probe, expr) => {
+                self.hit_probe(*probe);
+                return self.evaluate_internal(expr, stack, captured);
+            }
+            Expression::Spanned(span, expr) => {
+                let _span_guard = self.crash_reports.then_some(SpanGuard(*span));
+                let result = self.evaluate_internal(expr, stack, captured);
+                if let Err(error) = &result {
+                    self.record_error_span(*span, error);
+                }
+                return result;
+            }
+            Expression::FreshCells(slots) => {
+                let mut guard = self.nesting_guard();
+                for slot in slots {
+                    stack[*slot] = stack[*slot].try_fresh_ref(&mut guard)?;
+                }
+                Default::default()
+            }
+            Expression::FieldGet(expr, index) => {
+                let value = self.evaluate_internal(expr, stack, captured)?;
+                value
+                    .get_field_by_index(*index)
+                    .ok_or(FreightError::FieldNotFound { index: *index })?
+            }
+            Expression::FieldSet(args, index) => {
+                let [target, value] = &**args;
+                let mut target = self.evaluate_internal(target, stack, captured)?.dupe_ref();
+                let value = self.evaluate_internal(value, stack, captured)?;
+                if !target.set_field_by_index(*index, value) {
+                    return Err(FreightError::FieldNotFound { index: *index });
+                }
+                Default::default()
+            }
+            Expression::Initialize(init, args) => {
+                let collected = self.evaluate_initializer_args(init, args, stack, captured)?;
+                init.initialize(collected, self)?
             }
             Expression::ReturnTarget(target, expr) => self
                 .evaluate_internal(&**expr, stack, captured)
@@ -256,7 +1637,187 @@ impl<TS: TypeSystem> ExecutionEngine<TS> {
                 self.return_value = self.evaluate_internal(&**expr, stack, captured)?;
                 return Err(FreightError::Return { target: *target });
             }
+            Expression::Escape(label, expr) => {
+                match self.evaluate_internal(&**expr, stack, captured) {
+                    Err(FreightError::Escape { label: escaped }) if escaped == *label => {
+                        std::mem::take(&mut self.return_value)
+                    }
+                    result => result?,
+                }
+            }
+            Expression::EscapeTo(label, expr) => {
+                self.return_value = self.evaluate_internal(&**expr, stack, captured)?;
+                return Err(FreightError::Escape { label: *label });
+            }
+            Expression::Catch(expr) => match self.evaluate_internal(expr, stack, captured) {
+                Err(error @ (FreightError::Return { .. } | FreightError::Escape { .. })) => {
+                    return Err(error)
+                }
+                Err(error) => {
+                    self.error_trace.clear();
+                    self.error_span = None;
+                    TS::ErrorConverter::error_to_value(error, self)
+                }
+                Ok(value) => value,
+            },
         };
         Ok(result)
     }
+
+    /// Why a dynamic call to a value with a number of arguments can't be made as written
+    /// Call the target of a dynamic call. If the call site has a cache which holds the target's
+    /// address and generation, the target was already checked there and is called directly.
+    fn call_dynamic(
+        &mut self,
+        target: TS::Value,
+        args: &[Expression<TS>],
+        stack: &mut [TS::Value],
+        captured: &[TS::Value],
+        cache: Option<&CallSiteCache>,
+    ) -> Result<TS::Value, FreightError> {
+        let site = cache.and_then(|_| target.cast_to_function().and_then(|f| self.call_site(f)));
+        let cached = site.is_some() && cache.is_some_and(|cache| cache.0.get() == site);
+        if !cached {
+            if let Some(error) = Self::dynamic_call_error(&target, args.len()) {
+                if !TS::CallFallback::handles(&target, args.len()) {
+                    return Err(error);
+                }
+                let args = args
+                    .iter()
+                    .map(|arg| self.evaluate_internal(arg, stack, captured))
+                    .collect::<Result<_, _>>()?;
+                return self.call_fallback(target, args, error);
+            }
+        }
+        let Some(func): Option<&FunctionRef<TS>> = target.cast_to_function() else {
+            return Err(FreightError::InvalidInvocationTarget);
+        };
+        if func.host_only {
+            return Err(FreightError::HostOnlyFunction {
+                function: func.location,
+            });
+        }
+        if let (false, Some(cache)) = (cached, cache) {
+            cache.0.set(site);
+        }
+        let mut iter = args.iter();
+        let arg_count = iter.len();
+        self.call_valid(
+            func,
+            |e| e.evaluate_internal(iter.next().unwrap(), stack, captured),
+            arg_count,
+        )
+    }
+
+    /// The address and generation of a script function, which identify what a
+    /// [CallSiteCache] last called
+    fn call_site(&self, func: &FunctionRef<TS>) -> Option<(usize, u32)> {
+        match func.function_type {
+            FunctionType::Static | FunctionType::CapturingRef(_) => {
+                let function = self.functions().get(func.location)?;
+                Some((func.location, function.generation))
+            }
+            _ => None,
+        }
+    }
+
+    fn dynamic_call_error(target: &TS::Value, arg_count: usize) -> Option<FreightError> {
+        let Some(func) = target.cast_to_function() else {
+            return Some(FreightError::InvalidInvocationTarget);
+        };
+        if func.arg_count.valid_arg_count(arg_count) {
+            return None;
+        }
+        Some(FreightError::IncorrectArgumentCount {
+            expected_min: func.arg_count.min(),
+            expected_max: func.arg_count.max(),
+            actual: arg_count,
+        })
+    }
+
+    /// Resolve a dynamic call through [TypeSystem::CallFallback], following its redirects until
+    /// one can be called or [MAX_CALL_REDIRECTS] is reached. Fails with the last target's error
+    /// if the fallback doesn't handle it.
+    fn call_fallback(
+        &mut self,
+        mut target: TS::Value,
+        mut args: Vec<TS::Value>,
+        mut error: FreightError,
+    ) -> Result<TS::Value, FreightError> {
+        for _ in 0..MAX_CALL_REDIRECTS {
+            (target, args) = match TS::CallFallback::fallback(self, target, args)? {
+                CallRedirect::Value(value) => return Ok(value),
+                CallRedirect::Call(target, args) => (target, args),
+            };
+            match Self::dynamic_call_error(&target, args.len()) {
+                None => return self.call_value(&target, args),
+                Some(next) if TS::CallFallback::handles(&target, args.len()) => error = next,
+                Some(next) => return Err(next),
+            }
+        }
+        Err(error)
+    }
+
+    /// Call a value known to be a function taking that many arguments
+    fn call_value(
+        &mut self,
+        target: &TS::Value,
+        args: Vec<TS::Value>,
+    ) -> Result<TS::Value, FreightError> {
+        let func = target.cast_to_function().unwrap();
+        if func.host_only {
+            return Err(FreightError::HostOnlyFunction {
+                function: func.location,
+            });
+        }
+        let arg_count = args.len();
+        let mut iter = args.into_iter();
+        self.call_internal(func, |_| Ok(iter.next().unwrap()), arg_count)
+    }
+
+    fn capture_environment(
+        &mut self,
+        capture: &[VariableType],
+        stack: &mut [TS::Value],
+        captured: &[TS::Value],
+    ) -> PooledRcSlice<TS::Value> {
+        let pool = self.rc_pool.clone();
+        let captures_iter = capture.iter().map(|var| match var {
+            VariableType::Captured(addr) => captured[*addr].dupe_ref(),
+            VariableType::Stack(addr) => stack[*addr].dupe_ref(),
+            VariableType::Global(addr) => self.use_global(*addr).dupe_ref(),
+        });
+        RcSlicePool::from_pool(pool, captures_iter)
+    }
+
+    /// Loops don't necessarily enter any functions, so they check for cancellation themselves
+    #[inline]
+    pub(crate) fn check_loop_cancellation(
+        &mut self,
+        stack: &[TS::Value],
+    ) -> Result<(), FreightError> {
+        if self.cancellation.is_cancelled() {
+            self.cancelled_at = Some(CancellationSnapshot {
+                function: IN_LOOP,
+                frame: stack.iter().map(|v| self.summarize(v)).collect(),
+            });
+            return Err(FreightError::Cancelled);
+        }
+        Ok(())
+    }
+
+    fn evaluate_initializer_args(
+        &mut self,
+        init: &TS::Init,
+        args: &[Expression<TS>],
+        stack: &mut [TS::Value],
+        captured: &[TS::Value],
+    ) -> Result<Vec<TS::Value>, FreightError> {
+        init.kind().check_len(args.len())?;
+        let mut collected = Vec::with_capacity(args.len());
+        for arg in args {
+            collected.push(self.evaluate_internal(arg, stack, captured)?);
+        }
+        Ok(collected)
+    }
 }
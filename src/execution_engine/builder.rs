@@ -0,0 +1,87 @@
+use std::{cell::UnsafeCell, marker::PhantomData, rc::Rc};
+
+use super::{stack::StackPool, ExecutionEngine};
+use crate::{
+    slice_pool::{Poolable, SlicePool},
+    TypeSystem,
+};
+
+/// Sets the sizes of an engine's pools and whether it optimizes functions before it is created,
+/// see [ExecutionEngine::builder]. The defaults suit most programs, but tiny embedded targets may
+/// want smaller pools and large programs bigger ones.
+#[derive(Debug, Clone)]
+pub struct EngineBuilder<TS: TypeSystem> {
+    stack_capacity: usize,
+    pool_buckets: usize,
+    max_cache_per: usize,
+    pub(crate) optimize: bool,
+    type_system: PhantomData<TS>,
+}
+
+impl<TS: TypeSystem> Default for EngineBuilder<TS> {
+    fn default() -> Self {
+        EngineBuilder {
+            stack_capacity: 10000,
+            pool_buckets: 100,
+            max_cache_per: 1000,
+            optimize: true,
+            type_system: PhantomData,
+        }
+    }
+}
+
+impl<TS: TypeSystem> EngineBuilder<TS> {
+    /// How many value slots the stack starts with, and grows by when a call needs more
+    pub fn stack_capacity(mut self, slots: usize) -> Self {
+        self.stack_capacity = slots;
+        self
+    }
+
+    /// How many sizes of collections are kept for reuse by the captured environment and scratch
+    /// pools. Collections of these sizes or bigger are freed instead.
+    pub fn pool_buckets(mut self, sizes: usize) -> Self {
+        self.pool_buckets = sizes;
+        self
+    }
+
+    /// How many collections of each size the pools keep for reuse
+    pub fn max_cached_per_size(mut self, count: usize) -> Self {
+        self.max_cache_per = count;
+        self
+    }
+
+    /// Whether functions are optimized when they are registered, folding constants with
+    /// [crate::rewrite::ConstantFolder], turning chains of comparisons into switches with
+    /// [crate::rewrite::SwitchRewriter] and dropping top-level expressions other than the last
+    /// which are [crate::expression::Expression::is_pure], since their values are discarded.
+    /// Enabled by default; disabling it keeps functions as they were written, such as for
+    /// debugging a front-end.
+    pub fn optimize(mut self, enabled: bool) -> Self {
+        self.optimize = enabled;
+        self
+    }
+
+    pub fn build(self, context: TS::GlobalContext) -> ExecutionEngine<TS> {
+        ExecutionEngine::with_builder(context, self)
+    }
+
+    pub fn build_default(self) -> ExecutionEngine<TS>
+    where
+        TS::GlobalContext: Default,
+    {
+        self.build(Default::default())
+    }
+
+    pub(crate) fn stack(&self) -> Rc<UnsafeCell<StackPool<TS::Value>>> {
+        Rc::new(UnsafeCell::new(StackPool::with_capacity(
+            self.stack_capacity,
+        )))
+    }
+
+    pub(crate) fn pool<C: Poolable<TS::Value>>(&self) -> Rc<UnsafeCell<SlicePool<TS::Value, C>>> {
+        Rc::new(UnsafeCell::new(SlicePool::new(
+            self.pool_buckets,
+            self.max_cache_per,
+        )))
+    }
+}
@@ -0,0 +1,312 @@
+use std::{convert::Infallible, rc::Rc};
+
+use super::ExecutionEngine;
+use crate::{
+    error::FreightError,
+    function::Function,
+    namespace::{Namespace, NamespaceId},
+    value::Value,
+    TypeSystem,
+};
+
+/// What a fork has changed since it was created, which [ExecutionEngine::merge] replays onto the
+/// engine it was forked from
+#[derive(Debug)]
+pub(crate) struct ForkJournal {
+    /// Whether globals are copied the first time the fork uses them, rather than when it was
+    /// created
+    lazy: bool,
+    /// Which globals the fork has used, for each namespace which existed when it was created
+    used: Vec<Vec<bool>>,
+    /// How many functions the parent had when the fork was created
+    functions: usize,
+    /// The functions the fork has replaced
+    pub(crate) replaced: Vec<usize>,
+    /// The parent's fuel when the fork was created
+    fuel: Option<u64>,
+}
+
+impl ForkJournal {
+    /// Record that the fork is about to use a global, copying it first if it still shares the
+    /// global with its parent
+    #[inline]
+    pub(crate) fn use_global<V: Value>(
+        &mut self,
+        namespace: NamespaceId,
+        globals: &mut [V],
+        addr: usize,
+    ) {
+        let used = self
+            .used
+            .get_mut(namespace.0)
+            .and_then(|used| used.get_mut(addr));
+        if let Some(used @ false) = used {
+            if self.lazy {
+                globals[addr] = globals[addr].fresh_ref();
+            }
+            *used = true;
+        }
+    }
+
+    /// Record that a global was overwritten without being read
+    #[cfg(feature = "serde")]
+    pub(crate) fn overwrite_global(&mut self, namespace: NamespaceId, addr: usize) {
+        if let Some(used) = self
+            .used
+            .get_mut(namespace.0)
+            .and_then(|used| used.get_mut(addr))
+        {
+            *used = true;
+        }
+    }
+
+    /// Record that every global of every namespace was overwritten
+    pub(crate) fn overwrite_all(&mut self) {
+        self.used.iter_mut().for_each(|used| used.fill(true));
+    }
+}
+
+impl<TS: TypeSystem> ExecutionEngine<TS> {
+    /// Create a child engine for speculative execution, whose changes can be discarded by
+    /// dropping it or applied to this engine with [ExecutionEngine::merge].
+    ///
+    /// The child starts out sharing this engine's globals, and copies each one with
+    /// [Value::fresh_ref] the first time it reads or writes it, so forking costs little however
+    /// many globals the engine has. It shares the bodies of this engine's functions and its
+    /// rewriters, but functions it registers or replaces only change its own function table.
+    /// Scopes, callbacks and cached values are copied up front, and it gets its own copy of
+    /// everything else. The child's profiling and instrumentation start disabled, so functions
+    /// it registers carry no probes and its evaluations of this engine's probes aren't counted.
+    /// The cancellation token is shared, so cancelling this engine cancels the child.
+    pub fn fork(&self) -> ExecutionEngine<TS>
+    where
+        TS::GlobalContext: Clone,
+    {
+        self.fork_with(true, |value| Ok::<_, Infallible>(value.fresh_ref()))
+            .unwrap_or_else(|never| match never {})
+    }
+
+    /// Create a child engine like [ExecutionEngine::fork], copying every global up front with
+    /// [Value::try_fresh_ref] so a cyclic or too deeply nested global fails here instead of
+    /// overflowing the stack
+    pub fn try_fork(&self) -> Result<ExecutionEngine<TS>, FreightError>
+    where
+        TS::GlobalContext: Clone,
+    {
+        let mut guard = self.nesting_guard();
+        self.fork_with(false, |value| value.try_fresh_ref(&mut guard))
+    }
+
+    fn fork_with<E>(
+        &self,
+        lazy: bool,
+        mut copy: impl FnMut(&TS::Value) -> Result<TS::Value, E>,
+    ) -> Result<ExecutionEngine<TS>, E>
+    where
+        TS::GlobalContext: Clone,
+    {
+        let rc_pool = self.builder.pool();
+        let globals = if lazy {
+            self.globals.clone()
+        } else {
+            self.globals
+                .iter()
+                .map(&mut copy)
+                .collect::<Result<_, _>>()?
+        };
+        let scopes = self
+            .scopes
+            .iter()
+            .map(&mut copy)
+            .collect::<Result<_, _>>()?;
+        let namespaces = self
+            .namespaces
+            .iter()
+            .map(|namespace| {
+                namespace
+                    .as_ref()
+                    .map(|ns| ns.fork(lazy, &mut copy, &rc_pool))
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let used = namespaces
+            .iter()
+            .map(|namespace| {
+                let count = namespace
+                    .as_ref()
+                    .map_or(self.globals.len(), |ns| ns.globals.len());
+                vec![false; count]
+            })
+            .collect();
+        Ok(ExecutionEngine {
+            num_globals: self.num_globals,
+            globals,
+            functions: self.functions().to_vec().into(),
+            retired_functions: self.retired_functions.clone(),
+            next_return_target: self.next_return_target,
+            next_escape_label: self.next_escape_label,
+            return_value: Default::default(),
+            stack: self.builder.stack(),
+            callbacks: self.callbacks.fork(&mut copy, &rc_pool)?,
+            cache: self.cache.fork(&mut copy)?,
+            rc_pool,
+            scratch_pool: self.builder.pool(),
+            context: self.context.clone(),
+            verbosity: self.verbosity,
+            argument_evaluation: self.argument_evaluation,
+            types: self.types.clone(),
+            symbols: self.symbols.clone(),
+            hooks: self.hooks.clone(),
+            on_unwind_frame: self.on_unwind_frame,
+            hook_depth: 0,
+            failed_expression: 0,
+            error_trace: vec![],
+            error_span: None,
+            scopes,
+            cancellation: self.cancellation.clone(),
+            cancelled_at: None,
+            on_clear: self.on_clear.clone(),
+            fuel_schedule: self.fuel_schedule.clone(),
+            docs: self.docs.clone(),
+            rate_limits: self.rate_limits.clone(),
+            rewriters: self.rewriters.clone(),
+            hoist_global_reads: self.hoist_global_reads,
+            instrumentation: None,
+            lint: self.lint,
+            crash_reports: self.crash_reports,
+            max_value_depth: self.max_value_depth,
+            diagnostics: vec![],
+            rng: self.rng,
+            clock: self.clock.clone(),
+            modules: self.modules.clone(),
+            namespace: self.namespace,
+            namespaces,
+            fuel: self.fuel,
+            memory_limit: self.memory_limit,
+            builder: self.builder.clone(),
+            debugger: None,
+            fork_journal: Some(ForkJournal {
+                lazy,
+                used,
+                functions: self.functions().len(),
+                replaced: vec![],
+                fuel: self.fuel,
+            }),
+            #[cfg(feature = "profiling")]
+            profiler: None,
+        })
+    }
+
+    /// Apply the changes made by a fork of this engine.
+    ///
+    /// The globals the fork used are copied back along with any it created, and the functions it
+    /// registered or replaced are installed, so globals and functions this engine changed since
+    /// forking are kept unless the fork used them too. The fork's global context, runtime types,
+    /// symbols, documentation, callbacks, cache, modules and rate limit counts replace this
+    /// engine's, the fuel the fork used is subtracted from this engine's, and the fork's active
+    /// namespace becomes active.
+    ///
+    /// Fails without changing this engine with [FreightError::ForkConflict] if both engines added
+    /// functions, globals to the same namespace, or namespaces since forking. Panics if `fork`
+    /// was not created by [ExecutionEngine::fork] or [ExecutionEngine::try_fork] on this engine.
+    pub fn merge(&mut self, mut fork: ExecutionEngine<TS>) -> Result<(), FreightError> {
+        let journal = fork
+            .fork_journal
+            .take()
+            .filter(|_| Rc::ptr_eq(&self.retired_functions, &fork.retired_functions))
+            .expect("Can only merge forks of this engine");
+        let conflict = |table| Err(FreightError::ForkConflict { table });
+        if fork.functions().len() > journal.functions && self.functions().len() > journal.functions
+        {
+            return conflict("functions");
+        }
+        if fork.namespaces.len() > journal.used.len() && self.namespaces.len() > journal.used.len()
+        {
+            return conflict("namespaces");
+        }
+        for (id, used) in journal.used.iter().enumerate() {
+            let id = NamespaceId(id);
+            if fork.namespace_size(id) > used.len() && self.namespace_size(id) > used.len() {
+                return conflict("globals");
+            }
+        }
+
+        let mut functions: Vec<Option<Rc<Function<TS>>>> = std::mem::take(fork.functions.get_mut())
+            .into_iter()
+            .map(Some)
+            .collect();
+        for location in journal.replaced {
+            if location >= journal.functions {
+                continue;
+            }
+            let body = functions[location].take().unwrap();
+            self.install_body(location, body);
+        }
+        unsafe { &mut *self.functions.get() }
+            .extend(functions.drain(journal.functions..).flatten());
+
+        self.park_namespace();
+        fork.park_namespace();
+        for (id, incoming) in fork.namespaces.iter_mut().enumerate() {
+            let incoming = incoming.take().unwrap();
+            let Some(used) = journal.used.get(id) else {
+                self.namespaces.push(Some(incoming));
+                continue;
+            };
+            let namespace = self.namespaces[id].as_mut().unwrap();
+            let mut globals = incoming.globals.into_iter();
+            for (addr, value) in globals.by_ref().take(used.len()).enumerate() {
+                if used[addr] {
+                    namespace.globals[addr] = value;
+                }
+            }
+            namespace.globals.extend(globals);
+            namespace.num_globals = namespace.globals.len();
+            namespace.callbacks = incoming.callbacks;
+        }
+        self.unpark_namespace(fork.namespace);
+
+        self.next_return_target = self.next_return_target.max(fork.next_return_target);
+        self.next_escape_label = self.next_escape_label.max(fork.next_escape_label);
+        self.context = fork.context;
+        self.types = fork.types;
+        self.symbols = fork.symbols;
+        self.docs = fork.docs;
+        self.rate_limits = fork.rate_limits;
+        self.cache = fork.cache;
+        self.modules = fork.modules;
+        self.fuel = match (self.fuel, journal.fuel, fork.fuel) {
+            (Some(fuel), Some(start), Some(left)) => {
+                Some(fuel.saturating_sub(start.saturating_sub(left)))
+            }
+            _ => fork.fuel,
+        };
+        Ok(())
+    }
+
+    /// The number of globals in a namespace
+    fn namespace_size(&self, id: NamespaceId) -> usize {
+        match &self.namespaces[id.0] {
+            Some(namespace) => namespace.globals.len(),
+            None => self.globals.len(),
+        }
+    }
+
+    /// Move the active namespace's globals and callbacks into its slot, leaving every namespace
+    /// in [ExecutionEngine::namespaces] until [ExecutionEngine::unpark_namespace]
+    fn park_namespace(&mut self) {
+        self.namespaces[self.namespace.0] = Some(Namespace {
+            num_globals: std::mem::take(&mut self.num_globals),
+            globals: std::mem::take(&mut self.globals),
+            callbacks: std::mem::take(&mut self.callbacks),
+        });
+    }
+
+    fn unpark_namespace(&mut self, id: NamespaceId) {
+        let namespace = self.namespaces[id.0].take().unwrap();
+        self.num_globals = namespace.num_globals;
+        self.globals = namespace.globals;
+        self.callbacks = namespace.callbacks;
+        self.namespace = id;
+    }
+}
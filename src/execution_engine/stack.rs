@@ -4,9 +4,19 @@ use std::{
     rc::Rc,
 };
 
+/// A stack of frames, allocated in chunks so the stack can grow without moving frames which are
+/// already in use
 pub struct StackPool<T: Default> {
-    stack: Vec<T>,
+    /// The chunk frames are taken from, followed by any chunks it grew into before
+    chunks: Vec<Box<[T]>>,
+    current: usize,
+    /// The offset of the next frame in the current chunk
     base: usize,
+    /// The base of each chunk before the current one, from when it was left
+    bases: Vec<usize>,
+    used: usize,
+    /// The number of slots in each chunk the stack grows by, unless a frame needs more
+    chunk_size: usize,
 }
 
 pub struct StackSlice<'a, T: Default> {
@@ -30,38 +40,76 @@ impl<'a, T: Default> DerefMut for StackSlice<'a, T> {
 
 impl<'a, T: Default> Drop for StackSlice<'a, T> {
     fn drop(&mut self) {
-        let mut pool = unsafe { &mut *self.stack.get() };
-        pool.base -= self.slice.len();
+        let pool = unsafe { &mut *self.stack.get() };
+        pool.pop(self.slice.len());
     }
 }
 
 impl<T: Default> StackPool<T> {
+    /// Create a stack which starts with room for `capacity` slots, and grows by as many
     pub fn with_capacity(capacity: usize) -> StackPool<T> {
-        let mut stack = Vec::with_capacity(capacity);
-        for _ in 0..capacity {
-            stack.push(Default::default());
+        StackPool {
+            chunks: vec![new_chunk(capacity)],
+            current: 0,
+            base: 0,
+            bases: vec![],
+            used: 0,
+            chunk_size: capacity,
         }
-        StackPool { stack, base: 0 }
     }
 
+    /// Take a frame of `capacity` slots from the top of the stack, growing the stack if it
+    /// doesn't fit. Frames must be released in the reverse order they were requested.
     pub fn request<'a>(cell: Rc<UnsafeCell<Self>>, capacity: usize) -> StackSlice<'a, T> {
         let this = unsafe { &mut *cell.get() };
-        if this.base + capacity >= this.stack.len() {
-            panic!("Stack overflow {} / {}", this.base, this.stack.len());
+        if this.base + capacity > this.chunks[this.current].len() {
+            this.grow(capacity);
         }
 
         unsafe {
-            let ptr = this.stack.as_mut_ptr().add(this.base);
+            let ptr = this.chunks[this.current].as_mut_ptr().add(this.base);
 
             this.base += capacity;
+            this.used += capacity;
             let slice = std::slice::from_raw_parts_mut(ptr, capacity);
             StackSlice { slice, stack: cell }
         }
     }
 
+    /// Move on to the next chunk, allocating it if there isn't one with room for `capacity`
+    fn grow(&mut self, capacity: usize) {
+        let next = self.current + 1;
+        if !matches!(self.chunks.get(next), Some(chunk) if chunk.len() >= capacity) {
+            self.chunks.truncate(next);
+            self.chunks.push(new_chunk(capacity.max(self.chunk_size)));
+        }
+        self.bases.push(self.base);
+        self.current = next;
+        self.base = 0;
+    }
+
+    fn pop(&mut self, capacity: usize) {
+        self.base -= capacity;
+        self.used -= capacity;
+        if self.base == 0 && self.current > 0 {
+            self.current -= 1;
+            self.base = self.bases.pop().unwrap();
+        }
+    }
+
+    /// The number of slots in use by frames
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// The number of slots allocated for frames, in use or not
+    pub fn capacity(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.len()).sum()
+    }
+
     pub fn release(this: &UnsafeCell<Self>, capacity: usize) {
-        let mut this = unsafe { &mut *this.get() };
-        this.base -= capacity;
+        let this = unsafe { &mut *this.get() };
+        this.pop(capacity);
     }
 }
 
@@ -70,3 +118,7 @@ impl<T: Default> Default for StackPool<T> {
         Self::with_capacity(10000)
     }
 }
+
+fn new_chunk<T: Default>(capacity: usize) -> Box<[T]> {
+    (0..capacity).map(|_| T::default()).collect()
+}
@@ -1,23 +1,48 @@
 use crate::{
+    coroutine::AsyncNativeFunction,
     error::FreightError,
     execution_engine::{ExecutionEngine, Stack},
     function::FunctionRef,
+    operators::{BinaryOperator, Initializer, TernaryOperator, UnaryOperator},
+    symbols::SymbolId,
+    value::Value,
     TypeSystem,
 };
 
-use std::{fmt::Debug, ops::Deref};
+use std::{cell::Cell, fmt::Debug, ops::Deref, rc::Rc};
 
 type NativeFuncInnerAlias<TS> = fn(
     &mut ExecutionEngine<TS>,
     Stack<<TS as TypeSystem>::Value>,
 ) -> Result<<TS as TypeSystem>::Value, FreightError>;
 
+/// A function implemented in Rust.
+///
+/// Natives are always invoked through the same calling convention, whether they are called
+/// through a [FunctionRef] or [Expression::NativeFunctionCall]: the argument count is validated,
+/// arguments are evaluated into a fresh stack frame which is passed as the slice, cancellation is
+/// checked and the call is wrapped in a region like any other function. Natives may call back
+/// into the engine, and errors they return propagate like errors raised by scripts.
+///
+/// A native can be given a name with [NativeFunction::named], which identifies it in
+/// [crate::function::Function::structural_hash] and [crate::program::fingerprint]. Unnamed natives
+/// are identified by their address, so hashes of functions calling them differ between processes,
+/// and programs calling them can't be fingerprinted.
 #[derive(Clone)]
-pub struct NativeFunction<TS: TypeSystem>(NativeFuncInnerAlias<TS>);
+pub struct NativeFunction<TS: TypeSystem>(NativeFuncInnerAlias<TS>, Option<&'static str>);
 
 impl<TS: TypeSystem> NativeFunction<TS> {
     pub fn new(value: NativeFuncInnerAlias<TS>) -> Self {
-        Self(value)
+        Self(value, None)
+    }
+
+    /// Create a native with a name which identifies it across processes
+    pub fn named(name: &'static str, value: NativeFuncInnerAlias<TS>) -> Self {
+        Self(value, Some(name))
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.1
     }
 }
 
@@ -31,19 +56,35 @@ impl<TS: TypeSystem> Deref for NativeFunction<TS> {
 
 impl<TS: TypeSystem> Debug for NativeFunction<TS> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("NativeFunction").finish()
+        let mut tuple = f.debug_tuple("NativeFunction");
+        if let Some(name) = self.1 {
+            tuple.field(&name);
+        }
+        tuple.finish()
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum VariableType {
     Captured(usize),
     Stack(usize),
     Global(usize),
 }
 
+/// An id a front-end gives a location in its source, such as an index into its own table of
+/// spans, see [Expression::Spanned]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SpanId(pub usize);
+
 /// Represents an expression tree that can be evaluated via an [ExecutionEngine]
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "TS: crate::serialization::SerializableTypeSystem")
+)]
 pub enum Expression<TS: TypeSystem> {
     /// Evaluate to a raw value, no computation required
     RawValue(TS::Value),
@@ -71,11 +112,218 @@ pub enum Expression<TS: TypeSystem> {
     AssignDynamic(Box<[Expression<TS>; 2]>),
     /// An expression which can be returned to
     ReturnTarget(usize, Box<Expression<TS>>),
-    /// Return to the specified return target
+    /// Return to the specified return target.
+    ///
+    /// The return is caught by the innermost active [Expression::ReturnTarget] or function call
+    /// with a matching target, so it may cross any number of function calls, including closures
+    /// and natives which propagate the error, and it is never intercepted by
+    /// [Expression::Catch]. Anything between the return and its target is abandoned, including
+    /// the rest of an argument list. Targets are matched by id rather than by activation: a
+    /// closure returning to the target of the function which created it is caught by the
+    /// innermost active call of that function, even if it is not the call that created the
+    /// closure. A return with no active target reaches the host as [FreightError::Return].
     Return(usize, Box<Expression<TS>>),
+    /// Evaluate an expression, converting any error it raises into a value
+    Catch(Box<Expression<TS>>),
+    /// Invoke a method found in the [crate::type_registry::TypeRegistry] by the receiver's type,
+    /// passing the receiver as the first argument
+    MethodCall(Box<Expression<TS>>, Rc<str>, Vec<Expression<TS>>),
+    /// Get a field of a value by its layout index
+    FieldGet(Box<Expression<TS>>, usize),
+    /// Assign to a field of a value by its layout index
+    FieldSet(Box<[Expression<TS>; 2]>, usize),
+    /// Look up the function or global a symbol is bound to when the expression is evaluated
+    ResolveSymbol(SymbolId),
+    /// Evaluate one of the branches depending on whether a symbol is bound to something scripts
+    /// can use: a global, or a function which isn't [FunctionRef::host_only] and whose module is
+    /// loaded. Checking doesn't resolve the symbol, so frontends can compile an `is_defined`
+    /// check to this with constant branches.
+    IsDefined {
+        symbol: SymbolId,
+        defined: Box<Expression<TS>>,
+        undefined: Box<Expression<TS>>,
+    },
+    /// Evaluate the arm selected by the scrutinee's [Value::as_discriminant] in constant time, or
+    /// the default arm if it doesn't select one
+    Switch {
+        scrutinee: Box<Expression<TS>>,
+        arms: Vec<Expression<TS>>,
+        default: Box<Expression<TS>>,
+    },
+    /// Move each of the given stack slots into a fresh reference holding its current value, see
+    /// [Value::fresh_ref]. Closures captured afterwards don't share the slots with closures
+    /// captured before, so emitting this at the start of each loop iteration gives every
+    /// iteration its own environment. Evaluates to the default value.
+    FreshCells(Vec<usize>),
+    /// Evaluate the condition, then evaluate one of the branches depending on whether the
+    /// condition's value [Value::is_truthy]
+    Conditional {
+        condition: Box<Expression<TS>>,
+        then: Box<Expression<TS>>,
+        otherwise: Box<Expression<TS>>,
+    },
+    /// Evaluate the body for as long as the condition's value [Value::is_truthy]. Evaluates to
+    /// the default value; use a [Expression::ReturnTarget] around the loop to break out of it.
+    While {
+        condition: Box<Expression<TS>>,
+        body: Box<Expression<TS>>,
+    },
+    /// Evaluate the body a fixed number of times. Evaluates to the default value.
+    Repeat(usize, Box<Expression<TS>>),
+    /// An escape point with the given label, created with
+    /// [crate::execution_engine::ExecutionEngine::create_escape_label]. Evaluates to the body's
+    /// value, or to the value of an [Expression::EscapeTo] with the same label which was
+    /// evaluated inside the body.
+    Escape(usize, Box<Expression<TS>>),
+    /// Exit to the innermost active [Expression::Escape] with the specified label.
+    ///
+    /// Like [Expression::Return], the escape crosses any number of function calls in one hop and
+    /// is never intercepted by [Expression::Catch], but it is only caught by escape points, never
+    /// by a function call, so it can leave the function passed to a closure-based iterator without
+    /// being mistaken for a return from it. Each call frame it crosses is exited normally. An
+    /// escape with no active escape point reaches the host as [FreightError::Escape].
+    EscapeTo(usize, Box<Expression<TS>>),
+    /// Evaluate a ternary operation on three sub-expressions, in order
+    TernaryOpEval(TS::TernaryOp, Box<[Expression<TS>; 3]>),
+    /// Capture values from an environment once, and create a closure of each function sharing
+    /// that one set of captured values, assigning each closure to its stack slot. The functions
+    /// must be capturing, and read the environment by its indices rather than their own capture
+    /// lists. Evaluates to the default value.
+    SharedCapture {
+        captures: Rc<[VariableType]>,
+        closures: Vec<(FunctionRef<TS>, usize)>,
+    },
+    /// Suspend the [crate::coroutine::Coroutine] running this expression, handing the value to
+    /// the host. Evaluates to the value the coroutine is resumed with.
+    Yield(Box<Expression<TS>>),
+    /// Invoke a function whose identity is not known until runtime, binding the positional
+    /// arguments to the first parameters and each named argument to the parameter with that
+    /// name, see [FunctionRef::with_param_names]. Arguments are evaluated in the order they are
+    /// written, positional arguments first. Optional parameters which aren't bound are given the
+    /// default value; a name which doesn't match a parameter, a parameter bound twice or a
+    /// required parameter left unbound raises [FreightError::ArgumentName].
+    NamedFunctionCall {
+        func: Box<Expression<TS>>,
+        args: Vec<Expression<TS>>,
+        named: Vec<(Rc<str>, Expression<TS>)>,
+    },
+    /// Record an evaluation of the probe with the given id in
+    /// [crate::execution_engine::ExecutionEngine::instrumentation], then evaluate the expression.
+    /// Inserted when functions are registered, see [crate::instrument::Instrumentation].
+    Probe(usize, Box<Expression<TS>>),
+    /// Evaluate the expression, attributing an error it raises to a location in the source, see
+    /// [crate::execution_engine::ExecutionEngine::error_span]. Front-ends wrap the nodes they can
+    /// map back to the source; other nodes carry nothing.
+    Spanned(SpanId, Box<Expression<TS>>),
+    /// An [Expression::DynamicFunctionCall] whose target isn't constant, which remembers the
+    /// function it last called so calling the same function again skips checking the target.
+    /// Created when functions are built, see [Expression::resolve_dynamic_calls].
+    CachedDynamicCall(
+        Box<Expression<TS>>,
+        Vec<Expression<TS>>,
+        #[cfg_attr(feature = "serde", serde(skip))] CallSiteCache,
+    ),
+    /// Call a native which returns a future, suspending the [crate::coroutine::Coroutine]
+    /// running this expression until the host has awaited it, see
+    /// [crate::coroutine::Coroutine::run_async]. Evaluates to the future's output. Can appear
+    /// wherever an [Expression::Yield] can, and its arguments are evaluated before suspending.
+    /// Functions containing it can't be serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    AwaitNative(AsyncNativeFunction<TS>, Vec<Expression<TS>>),
+}
+
+/// The function an [Expression::CachedDynamicCall] last called, as its address and the
+/// generation of its body, which changes when it is replaced
+#[derive(Debug, Default)]
+pub struct CallSiteCache(pub(crate) Cell<Option<(usize, u32)>>);
+
+/// The variant of an [Expression], without its contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ExpressionKind {
+    RawValue,
+    Variable,
+    BinaryOpEval,
+    UnaryOpEval,
+    Initialize,
+    StaticFunctionCall,
+    DynamicFunctionCall,
+    NativeFunctionCall,
+    FunctionCapture,
+    AssignStack,
+    AssignGlobal,
+    AssignDynamic,
+    ReturnTarget,
+    Return,
+    Catch,
+    MethodCall,
+    FieldGet,
+    FieldSet,
+    ResolveSymbol,
+    IsDefined,
+    Switch,
+    FreshCells,
+    Conditional,
+    While,
+    Repeat,
+    Escape,
+    EscapeTo,
+    TernaryOpEval,
+    SharedCapture,
+    Yield,
+    NamedFunctionCall,
+    Probe,
+    Spanned,
+    AwaitNative,
+}
+
+impl ExpressionKind {
+    /// The number of expression kinds
+    pub const COUNT: usize = ExpressionKind::AwaitNative as usize + 1;
 }
 
 impl<TS: TypeSystem> Expression<TS> {
+    /// The variant of this expression
+    pub fn kind(&self) -> ExpressionKind {
+        match self {
+            Expression::RawValue(_) => ExpressionKind::RawValue,
+            Expression::Variable(_) => ExpressionKind::Variable,
+            Expression::BinaryOpEval(..) => ExpressionKind::BinaryOpEval,
+            Expression::UnaryOpEval(..) => ExpressionKind::UnaryOpEval,
+            Expression::Initialize(..) => ExpressionKind::Initialize,
+            Expression::StaticFunctionCall(..) => ExpressionKind::StaticFunctionCall,
+            Expression::DynamicFunctionCall(..) | Expression::CachedDynamicCall(..) => {
+                ExpressionKind::DynamicFunctionCall
+            }
+            Expression::NativeFunctionCall(..) => ExpressionKind::NativeFunctionCall,
+            Expression::FunctionCapture(_) => ExpressionKind::FunctionCapture,
+            Expression::AssignStack(..) => ExpressionKind::AssignStack,
+            Expression::AssignGlobal(..) => ExpressionKind::AssignGlobal,
+            Expression::AssignDynamic(_) => ExpressionKind::AssignDynamic,
+            Expression::ReturnTarget(..) => ExpressionKind::ReturnTarget,
+            Expression::Return(..) => ExpressionKind::Return,
+            Expression::Catch(_) => ExpressionKind::Catch,
+            Expression::MethodCall(..) => ExpressionKind::MethodCall,
+            Expression::FieldGet(..) => ExpressionKind::FieldGet,
+            Expression::FieldSet(..) => ExpressionKind::FieldSet,
+            Expression::ResolveSymbol(_) => ExpressionKind::ResolveSymbol,
+            Expression::IsDefined { .. } => ExpressionKind::IsDefined,
+            Expression::Switch { .. } => ExpressionKind::Switch,
+            Expression::FreshCells(_) => ExpressionKind::FreshCells,
+            Expression::Conditional { .. } => ExpressionKind::Conditional,
+            Expression::While { .. } => ExpressionKind::While,
+            Expression::Repeat(..) => ExpressionKind::Repeat,
+            Expression::Escape(..) => ExpressionKind::Escape,
+            Expression::EscapeTo(..) => ExpressionKind::EscapeTo,
+            Expression::TernaryOpEval(..) => ExpressionKind::TernaryOpEval,
+            Expression::SharedCapture { .. } => ExpressionKind::SharedCapture,
+            Expression::Yield(_) => ExpressionKind::Yield,
+            Expression::NamedFunctionCall { .. } => ExpressionKind::NamedFunctionCall,
+            Expression::Probe(..) => ExpressionKind::Probe,
+            Expression::Spanned(..) => ExpressionKind::Spanned,
+            Expression::AwaitNative(..) => ExpressionKind::AwaitNative,
+        }
+    }
+
     /// Shorthand for a stack variable
     pub fn stack(addr: usize) -> Expression<TS> {
         Expression::Variable(VariableType::Stack(addr))
@@ -90,4 +338,169 @@ impl<TS: TypeSystem> Expression<TS> {
     pub fn global(addr: usize) -> Expression<TS> {
         Expression::Variable(VariableType::Global(addr))
     }
+
+    /// The direct sub-expressions of this expression, in evaluation order
+    pub fn children(&self) -> Vec<&Expression<TS>> {
+        match self {
+            Expression::RawValue(_)
+            | Expression::Variable(_)
+            | Expression::FunctionCapture(_)
+            | Expression::ResolveSymbol(_)
+            | Expression::FreshCells(_)
+            | Expression::SharedCapture { .. } => vec![],
+            Expression::BinaryOpEval(_, operands) => operands.iter().collect(),
+            Expression::TernaryOpEval(_, operands) => operands.iter().collect(),
+            Expression::UnaryOpEval(_, expr)
+            | Expression::AssignStack(_, expr)
+            | Expression::AssignGlobal(_, expr)
+            | Expression::ReturnTarget(_, expr)
+            | Expression::Return(_, expr)
+            | Expression::Catch(expr)
+            | Expression::FieldGet(expr, _)
+            | Expression::Repeat(_, expr)
+            | Expression::Escape(_, expr)
+            | Expression::EscapeTo(_, expr)
+            | Expression::Yield(expr)
+            | Expression::Probe(_, expr)
+            | Expression::Spanned(_, expr) => vec![&**expr],
+            Expression::Initialize(_, args)
+            | Expression::StaticFunctionCall(_, args)
+            | Expression::NativeFunctionCall(_, args)
+            | Expression::AwaitNative(_, args) => args.iter().collect(),
+            Expression::DynamicFunctionCall(func, args)
+            | Expression::CachedDynamicCall(func, args, _)
+            | Expression::MethodCall(func, _, args) => {
+                std::iter::once(&**func).chain(args.iter()).collect()
+            }
+            Expression::AssignDynamic(args) | Expression::FieldSet(args, _) => {
+                args.iter().collect()
+            }
+            Expression::Switch {
+                scrutinee,
+                arms,
+                default,
+            } => std::iter::once(&**scrutinee)
+                .chain(arms.iter())
+                .chain(std::iter::once(&**default))
+                .collect(),
+            Expression::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => vec![&**condition, &**then, &**otherwise],
+            Expression::IsDefined {
+                defined, undefined, ..
+            } => vec![&**defined, &**undefined],
+            Expression::NamedFunctionCall { func, args, named } => std::iter::once(&**func)
+                .chain(args.iter())
+                .chain(named.iter().map(|(_, arg)| arg))
+                .collect(),
+            Expression::While { condition, body } => vec![&**condition, &**body],
+        }
+    }
+
+    /// Mutable access to the direct sub-expressions of this expression, in evaluation order
+    pub fn children_mut(&mut self) -> Vec<&mut Expression<TS>> {
+        match self {
+            Expression::RawValue(_)
+            | Expression::Variable(_)
+            | Expression::FunctionCapture(_)
+            | Expression::ResolveSymbol(_)
+            | Expression::FreshCells(_)
+            | Expression::SharedCapture { .. } => vec![],
+            Expression::BinaryOpEval(_, operands) => operands.iter_mut().collect(),
+            Expression::TernaryOpEval(_, operands) => operands.iter_mut().collect(),
+            Expression::UnaryOpEval(_, expr)
+            | Expression::AssignStack(_, expr)
+            | Expression::AssignGlobal(_, expr)
+            | Expression::ReturnTarget(_, expr)
+            | Expression::Return(_, expr)
+            | Expression::Catch(expr)
+            | Expression::FieldGet(expr, _)
+            | Expression::Repeat(_, expr)
+            | Expression::Escape(_, expr)
+            | Expression::EscapeTo(_, expr)
+            | Expression::Yield(expr)
+            | Expression::Probe(_, expr)
+            | Expression::Spanned(_, expr) => vec![&mut **expr],
+            Expression::Initialize(_, args)
+            | Expression::StaticFunctionCall(_, args)
+            | Expression::NativeFunctionCall(_, args)
+            | Expression::AwaitNative(_, args) => args.iter_mut().collect(),
+            Expression::DynamicFunctionCall(func, args)
+            | Expression::CachedDynamicCall(func, args, _)
+            | Expression::MethodCall(func, _, args) => std::iter::once(&mut **func)
+                .chain(args.iter_mut())
+                .collect(),
+            Expression::AssignDynamic(args) | Expression::FieldSet(args, _) => {
+                args.iter_mut().collect()
+            }
+            Expression::Switch {
+                scrutinee,
+                arms,
+                default,
+            } => std::iter::once(&mut **scrutinee)
+                .chain(arms.iter_mut())
+                .chain(std::iter::once(&mut **default))
+                .collect(),
+            Expression::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => vec![&mut **condition, &mut **then, &mut **otherwise],
+            Expression::IsDefined {
+                defined, undefined, ..
+            } => vec![&mut **defined, &mut **undefined],
+            Expression::While { condition, body } => vec![&mut **condition, &mut **body],
+            Expression::NamedFunctionCall { func, args, named } => std::iter::once(&mut **func)
+                .chain(args.iter_mut())
+                .chain(named.iter_mut().map(|(_, arg)| arg))
+                .collect(),
+        }
+    }
+
+    /// Whether evaluating this expression can't fail and has no effect besides producing its
+    /// value, so it can be dropped if the value is discarded. Operators and initializers are pure
+    /// if they say so with [UnaryOperator::is_pure] and [Initializer::is_pure].
+    pub fn is_pure(&self) -> bool {
+        let pure = match self {
+            Expression::RawValue(_) | Expression::Variable(_) => true,
+            Expression::UnaryOpEval(op, _) => op.is_pure(),
+            Expression::BinaryOpEval(op, _) => op.is_pure(),
+            Expression::TernaryOpEval(op, _) => op.is_pure(),
+            Expression::Initialize(init, _) => init.is_pure(),
+            _ => false,
+        };
+        pure && self.children().into_iter().all(Expression::is_pure)
+    }
+
+    /// Replace dynamic calls whose target is a constant function value with static calls, so the
+    /// target doesn't need to be resolved with [Value::cast_to_function] on every invocation.
+    /// Calls which the dynamic path would reject, because the target is
+    /// [FunctionRef::host_only] or doesn't take that many arguments, are left as they are.
+    ///
+    /// Other dynamic calls, such as a closure held in a variable and called in a loop, become
+    /// [Expression::CachedDynamicCall]s.
+    pub fn resolve_dynamic_calls(&mut self) {
+        for child in self.children_mut() {
+            child.resolve_dynamic_calls();
+        }
+        let Expression::DynamicFunctionCall(func, args) = self else {
+            return;
+        };
+        let Expression::RawValue(value) = &**func else {
+            *self = Expression::CachedDynamicCall(
+                std::mem::replace(func, Expression::RawValue(Default::default()).into()),
+                std::mem::take(args),
+                CallSiteCache::default(),
+            );
+            return;
+        };
+        let Some(func) = value.cast_to_function() else {
+            return;
+        };
+        if !func.host_only && func.arg_count.valid_arg_count(args.len()) {
+            *self = Expression::StaticFunctionCall(func.clone(), std::mem::take(args));
+        }
+    }
 }
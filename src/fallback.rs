@@ -0,0 +1,47 @@
+use std::fmt::Debug;
+
+use crate::{error::FreightError, execution_engine::ExecutionEngine, TypeSystem};
+
+/// How many times a dynamic call may be redirected by [CallFallback::fallback] before it fails
+pub const MAX_CALL_REDIRECTS: usize = 16;
+
+/// What a dynamic call resolves to after a [CallFallback]
+#[derive(Debug)]
+pub enum CallRedirect<TS: TypeSystem> {
+    /// Use a value as the result of the call
+    Value(TS::Value),
+    /// Call another target with these arguments instead. If that call can't be made either, the
+    /// fallback is consulted again.
+    Call(TS::Value, Vec<TS::Value>),
+}
+
+/// Handles dynamic calls which can't be made as written, because the target isn't a function or
+/// doesn't take that many arguments. This lets languages support callable objects, automatic
+/// currying, or raising their own errors for bad calls.
+pub trait CallFallback<TS: TypeSystem>: Debug {
+    /// Whether the fallback handles calls to a target with a number of arguments. If not, the
+    /// call fails without evaluating its arguments.
+    fn handles(target: &TS::Value, arg_count: usize) -> bool;
+
+    /// Resolve a call the fallback handles, given its evaluated arguments
+    fn fallback(
+        engine: &mut ExecutionEngine<TS>,
+        target: TS::Value,
+        args: Vec<TS::Value>,
+    ) -> Result<CallRedirect<TS>, FreightError>;
+}
+
+/// Handles no calls
+impl<TS: TypeSystem> CallFallback<TS> for () {
+    fn handles(_: &TS::Value, _: usize) -> bool {
+        false
+    }
+
+    fn fallback(
+        _: &mut ExecutionEngine<TS>,
+        _: TS::Value,
+        _: Vec<TS::Value>,
+    ) -> Result<CallRedirect<TS>, FreightError> {
+        Err(FreightError::InvalidInvocationTarget)
+    }
+}
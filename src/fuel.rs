@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use crate::{
+    expression::{ExpressionKind, NativeFunction},
+    TypeSystem,
+};
+
+/// How much fuel evaluating each kind of expression and calling each native function costs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuelSchedule {
+    expressions: [u64; ExpressionKind::COUNT],
+    natives: HashMap<usize, u64>,
+    /// The cost of calling a native function without a cost of its own
+    pub default_native: u64,
+}
+
+impl Default for FuelSchedule {
+    /// Charges 1 for most expressions, 2 for expressions which allocate and 5 for calls. Natives
+    /// cost 5 on top of the expression which called them.
+    fn default() -> Self {
+        let mut schedule = FuelSchedule::uniform(1);
+        schedule.default_native = 5;
+        for kind in [ExpressionKind::Initialize, ExpressionKind::FunctionCapture] {
+            schedule.set_cost(kind, 2);
+        }
+        for kind in [
+            ExpressionKind::StaticFunctionCall,
+            ExpressionKind::DynamicFunctionCall,
+            ExpressionKind::NativeFunctionCall,
+            ExpressionKind::AwaitNative,
+            ExpressionKind::MethodCall,
+            ExpressionKind::NamedFunctionCall,
+        ] {
+            schedule.set_cost(kind, 5);
+        }
+        schedule
+    }
+}
+
+impl FuelSchedule {
+    /// A schedule where every expression costs the same and natives are free
+    pub fn uniform(cost: u64) -> FuelSchedule {
+        FuelSchedule {
+            expressions: [cost; ExpressionKind::COUNT],
+            natives: HashMap::new(),
+            default_native: 0,
+        }
+    }
+
+    pub fn set_cost(&mut self, kind: ExpressionKind, cost: u64) -> &mut Self {
+        self.expressions[kind as usize] = cost;
+        self
+    }
+
+    #[inline]
+    pub fn cost(&self, kind: ExpressionKind) -> u64 {
+        self.expressions[kind as usize]
+    }
+
+    /// Set the cost of calling a specific native function
+    pub fn set_native_cost<TS: TypeSystem>(
+        &mut self,
+        func: &NativeFunction<TS>,
+        cost: u64,
+    ) -> &mut Self {
+        self.natives.insert(**func as usize, cost);
+        self
+    }
+
+    pub fn native_cost<TS: TypeSystem>(&self, func: &NativeFunction<TS>) -> u64 {
+        if self.natives.is_empty() {
+            return self.default_native;
+        }
+        self.natives
+            .get(&(**func as usize))
+            .copied()
+            .unwrap_or(self.default_native)
+    }
+}
@@ -1,6 +1,7 @@
 use std::ops::{Bound, RangeBounds};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ArgCount {
     Fixed(usize),
     Range {
@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+
+use crate::{
+    expression::Expression,
+    visit::{Visit, Visitor},
+    TypeSystem,
+};
+
+/// What a function's expressions use, classified when it is built so calls can skip handling
+/// what it doesn't
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionFeatures {
+    /// Can be left by an [Expression::Return] to its own return target, either directly or
+    /// from a function it calls. Calls to functions which can't skip catching returns.
+    pub has_return_targets: bool,
+}
+
+impl FunctionFeatures {
+    pub(crate) fn classify<TS: TypeSystem>(
+        expressions: &[Expression<TS>],
+        return_target: usize,
+    ) -> FunctionFeatures {
+        let mut features = FunctionFeatures::default();
+        let mut classify = |expr: &Expression<TS>| match expr {
+            Expression::Return(target, _) if *target == return_target => {
+                features.has_return_targets = true;
+                Visit::Stop
+            }
+            Expression::StaticFunctionCall(..)
+            | Expression::DynamicFunctionCall(..)
+            | Expression::CachedDynamicCall(..)
+            | Expression::NativeFunctionCall(..)
+            | Expression::AwaitNative(..)
+            | Expression::MethodCall(..)
+            | Expression::NamedFunctionCall { .. } => {
+                features.has_return_targets = true;
+                Visit::Stop
+            }
+            _ => Visit::Descend,
+        };
+        for expr in expressions {
+            if !expr.visit(&mut classify) {
+                break;
+            }
+        }
+        features
+    }
+}
+
+/// Collects the expressions containing an [Expression::Yield] or [Expression::AwaitNative], see
+/// [yielding_expressions]
+struct Yielding<TS: TypeSystem> {
+    found: HashSet<*const Expression<TS>>,
+    /// Whether each expression being visited contains a yield so far, outermost first
+    open: Vec<bool>,
+}
+
+impl<TS: TypeSystem> Visitor<TS> for Yielding<TS> {
+    fn enter(&mut self, expr: &Expression<TS>) -> Visit {
+        self.open.push(matches!(
+            expr,
+            Expression::Yield(_) | Expression::AwaitNative(..)
+        ));
+        Visit::Descend
+    }
+
+    fn exit(&mut self, expr: &Expression<TS>) {
+        if self.open.pop() == Some(true) {
+            self.found.insert(expr);
+            if let Some(parent) = self.open.last_mut() {
+                *parent = true;
+            }
+        }
+    }
+}
+
+/// The expressions which are or contain a suspension point, found in one pass when a
+/// function is built so coroutines can tell which expressions to step into
+pub(crate) fn yielding_expressions<TS: TypeSystem>(
+    expressions: &[Expression<TS>],
+) -> HashSet<*const Expression<TS>> {
+    let mut yielding = Yielding {
+        found: HashSet::new(),
+        open: vec![],
+    };
+    for expr in expressions {
+        expr.visit(&mut yielding);
+    }
+    yielding.found
+}
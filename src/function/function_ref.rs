@@ -1,7 +1,10 @@
-use super::{arg_count::ArgCount, FunctionType};
-use crate::{expression::NativeFunction, TypeSystem};
+use std::rc::Rc;
 
-#[derive(Debug, Clone)]
+use super::{arg_count::ArgCount, FunctionMetadata, FunctionType};
+use crate::{expression::NativeFunction, module::ModuleTag, TypeSystem};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StackLayout(u128);
 
 impl StackLayout {
@@ -28,12 +31,21 @@ impl StackLayout {
 
 /// Represents a reference to a function that has been included in a VM
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "TS: crate::serialization::SerializableTypeSystem")
+)]
 pub struct FunctionRef<TS: TypeSystem> {
     pub(crate) arg_count: ArgCount,
     pub(crate) stack_size: usize,
     pub(crate) location: usize,
     pub function_type: FunctionType<TS>,
     pub layout: StackLayout,
+    pub(crate) module: Option<ModuleTag>,
+    pub(crate) host_only: bool,
+    pub(crate) param_names: Option<Rc<[Rc<str>]>>,
+    pub(crate) metadata: Option<Rc<FunctionMetadata>>,
 }
 
 impl<TS: TypeSystem> PartialEq for FunctionRef<TS> {
@@ -55,9 +67,76 @@ impl<TS: TypeSystem> FunctionRef<TS> {
             stack_size: arg_count.stack_size(),
             function_type: FunctionType::Native(func),
             layout: StackLayout::no_alloc(),
+            module: None,
+            host_only: false,
+            param_names: None,
+            metadata: None,
         }
     }
 
+    /// Mark this reference as belonging to a module, so it can only be invoked while the module is
+    /// loaded, see [crate::module::ModuleTable]
+    pub fn with_module(mut self, tag: ModuleTag) -> Self {
+        self.module = Some(tag);
+        self
+    }
+
+    /// Mark this reference as callable only from the host. Scripts which obtain it can't invoke it
+    /// dynamically or resolve it through a symbol, and [ExecutionEngine::help] doesn't describe it.
+    ///
+    /// [ExecutionEngine::help]: crate::execution_engine::ExecutionEngine::help
+    pub fn host_only(mut self) -> Self {
+        self.host_only = true;
+        self
+    }
+
+    /// Whether this reference can only be called from the host, see [FunctionRef::host_only]
+    pub fn is_host_only(&self) -> bool {
+        self.host_only
+    }
+
+    /// Name the parameters of the function, in order, so it can be called with named arguments
+    pub fn with_param_names(mut self, names: impl IntoIterator<Item = Rc<str>>) -> Self {
+        self.param_names = Some(names.into_iter().collect());
+        self
+    }
+
+    /// The names of the function's parameters, if they were given
+    pub fn param_names(&self) -> Option<&[Rc<str>]> {
+        self.param_names.as_deref()
+    }
+
+    /// The index of the parameter with the given name
+    pub fn param_index(&self, name: &str) -> Option<usize> {
+        self.param_names()?
+            .iter()
+            .position(|param| &**param == name)
+    }
+
+    /// Give the function a human-readable identity
+    pub fn with_metadata(mut self, metadata: FunctionMetadata) -> Self {
+        self.metadata = Some(metadata.into());
+        self
+    }
+
+    /// The metadata the function was written with, if any
+    pub fn metadata(&self) -> Option<&FunctionMetadata> {
+        self.metadata.as_deref()
+    }
+
+    /// The values captured by a closure, if this is one
+    pub fn captured_values(&self) -> Option<&[TS::Value]> {
+        match &self.function_type {
+            FunctionType::CapturingRef(captured) => Some(captured),
+            _ => None,
+        }
+    }
+
+    /// The module this reference belongs to, if any
+    pub fn module(&self) -> Option<ModuleTag> {
+        self.module
+    }
+
     /// The number of arguments the function takes
     pub fn arg_count(&self) -> ArgCount {
         self.arg_count
@@ -5,12 +5,18 @@ use std::fmt::Debug;
 use std::rc::Rc;
 
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "TS: crate::serialization::SerializableTypeSystem")
+)]
 pub enum FunctionType<TS: TypeSystem> {
     /// Static reference to a function, which can't capture any values.
     Static,
     /// Reference to a function which captures values, but hasn't been initialized with those values.
     CapturingDef(Rc<[VariableType]>),
     /// Reference to a function which captures values bundled with those captured values
+    #[cfg_attr(feature = "serde", serde(skip))]
     CapturingRef(PooledRcSlice<TS::Value>),
     /// Reference to a native function
     Native(NativeFunction<TS>),
@@ -1,15 +1,42 @@
 use super::arg_count::ArgCount;
-use super::{Function, FunctionRef, FunctionType, StackLayout};
+use super::{Function, FunctionMetadata, FunctionRef, FunctionType, StackLayout};
 use crate::expression::VariableType;
+use crate::visit::Visit;
 use crate::{expression::Expression, TypeSystem};
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::rc::Rc;
+
+/// The size of a function's stack frame compared to the slots its expressions use, see
+/// [FunctionWriter::infer_layout]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameSize {
+    /// The slots taken by the arguments
+    pub args: usize,
+    /// The size of the frame before it was inferred, including the arguments
+    pub declared: usize,
+    /// One past the highest slot used by the expressions
+    pub used: usize,
+}
+
+impl FrameSize {
+    /// Whether expressions used slots past the end of the declared frame
+    pub fn is_undersized(&self) -> bool {
+        self.used > self.declared
+    }
+}
 
 #[derive(Debug)]
 pub struct FunctionWriter<TS: TypeSystem> {
     pub(crate) variable_count: usize,
     pub(crate) args: ArgCount,
     pub(crate) expressions: Vec<Expression<TS>>,
+    /// The index of the statement each expression was written as, which differs from its
+    /// position once global reads are hoisted or discarded expressions are dropped
+    pub(crate) statements: Vec<usize>,
     pub(crate) function_type: FunctionType<TS>,
+    pub(crate) param_names: Option<Rc<[Rc<str>]>>,
+    pub(crate) metadata: Option<Rc<FunctionMetadata>>,
     pub layout: StackLayout,
 }
 
@@ -19,7 +46,10 @@ impl<TS: TypeSystem> FunctionWriter<TS> {
             args,
             variable_count: 0,
             expressions: vec![],
+            statements: vec![],
             function_type: FunctionType::Static,
+            param_names: None,
+            metadata: None,
             layout: StackLayout::all_alloc(),
         }
     }
@@ -32,11 +62,29 @@ impl<TS: TypeSystem> FunctionWriter<TS> {
             args,
             variable_count: 0,
             expressions: vec![],
+            statements: vec![],
             function_type: FunctionType::CapturingDef(capture.into()),
+            param_names: None,
+            metadata: None,
             layout: StackLayout::all_alloc(),
         }
     }
 
+    /// A writer holding the body of a built function, so it can be prepared and built again
+    #[cfg(feature = "serde")]
+    pub(crate) fn from_function(func: Function<TS>) -> FunctionWriter<TS> {
+        Self {
+            variable_count: func.stack_size.saturating_sub(func.arg_count.stack_size()),
+            args: func.arg_count,
+            expressions: func.expressions,
+            statements: func.statements,
+            function_type: FunctionType::Static,
+            param_names: None,
+            metadata: func.metadata,
+            layout: func.layout,
+        }
+    }
+
     pub fn to_ref(&self, location: usize) -> FunctionRef<TS> {
         FunctionRef {
             arg_count: self.args,
@@ -44,6 +92,10 @@ impl<TS: TypeSystem> FunctionWriter<TS> {
             location,
             function_type: self.function_type.clone(),
             layout: self.layout.clone(),
+            module: None,
+            host_only: false,
+            param_names: self.param_names.clone(),
+            metadata: self.metadata.clone(),
         }
     }
 
@@ -52,6 +104,18 @@ impl<TS: TypeSystem> FunctionWriter<TS> {
         self.function_type = FunctionType::CapturingDef(capture.into());
     }
 
+    /// Name the parameters of this function, in order, so it can be called with named arguments.
+    /// References created with [FunctionWriter::to_ref] afterwards carry the names.
+    pub fn set_param_names(&mut self, names: impl IntoIterator<Item = Rc<str>>) {
+        self.param_names = Some(names.into_iter().collect());
+    }
+
+    /// Give this function a human-readable identity, carried by references created with
+    /// [FunctionWriter::to_ref] afterwards and by the function once it is built
+    pub fn set_metadata(&mut self, metadata: FunctionMetadata) {
+        self.metadata = Some(metadata.into());
+    }
+
     /// Create a new variable in the scope of this function and return its address
     pub fn create_variable(&mut self) -> usize {
         let var = self.args.stack_size() + self.variable_count;
@@ -59,16 +123,168 @@ impl<TS: TypeSystem> FunctionWriter<TS> {
         var
     }
 
+    /// Find the stack slots used by the expressions written so far, and grow the frame with new
+    /// variables if it doesn't hold all of them. Slots are only ever added, so a frame with
+    /// variables the expressions don't use yet is left as it is.
+    pub fn infer_layout(&mut self) -> FrameSize {
+        let args = self.args.stack_size();
+        let size = FrameSize {
+            args,
+            declared: args + self.variable_count,
+            used: self.expressions.iter().map(frame_size).max().unwrap_or(0),
+        };
+        if size.is_undersized() {
+            self.variable_count = size.used - args;
+        }
+        size
+    }
+
     /// Add an expression to be evaluated when this function is called
     pub fn evaluate_expression(&mut self, expr: Expression<TS>) {
+        let statement = self.statements.last().map_or(0, |last| last + 1);
+        self.statements.push(statement);
         self.expressions.push(expr);
     }
 
+    /// Read each global which an expression reads more than once into a new variable before the
+    /// expression is evaluated, and have the expression read the variable instead. Only
+    /// expressions which can't write to a global are changed: those without any assignment,
+    /// call, initialization or yield. The reads belong to the same statement as the expression,
+    /// so breakpoints and errors refer to the statements as they were written.
+    pub fn hoist_global_reads(&mut self) {
+        let mut expressions = Vec::with_capacity(self.expressions.len());
+        let mut statements = Vec::with_capacity(self.statements.len());
+        let written = std::mem::take(&mut self.statements);
+        for (mut expr, statement) in std::mem::take(&mut self.expressions)
+            .into_iter()
+            .zip(written)
+        {
+            if !may_write_globals(&expr) {
+                let mut reads = BTreeMap::new();
+                count_global_reads(&expr, &mut reads);
+                for (global, count) in reads {
+                    if count < 2 {
+                        continue;
+                    }
+                    let var = self.create_variable();
+                    replace_global_reads(&mut expr, global, var);
+                    expressions.push(Expression::AssignStack(
+                        var,
+                        Expression::global(global).into(),
+                    ));
+                    statements.push(statement);
+                }
+            }
+            expressions.push(expr);
+            statements.push(statement);
+        }
+        self.expressions = expressions;
+        self.statements = statements;
+    }
+
+    /// Drop top-level expressions other than the last which are [Expression::is_pure]
+    pub(crate) fn drop_discarded(&mut self) {
+        let last = self.expressions.len().saturating_sub(1);
+        (self.expressions, self.statements) = std::mem::take(&mut self.expressions)
+            .into_iter()
+            .zip(std::mem::take(&mut self.statements))
+            .enumerate()
+            .filter(|(i, (expr, _))| *i == last || !expr.is_pure())
+            .map(|(_, kept)| kept)
+            .unzip();
+    }
+
     /// Create a function from this writer
-    pub fn build(self, return_target: usize) -> Function<TS> {
-        Function {
-            expressions: self.expressions,
-            return_target,
+    pub fn build(mut self, return_target: usize) -> Function<TS> {
+        for expr in &mut self.expressions {
+            expr.resolve_dynamic_calls();
         }
+        let mut func = Function::new(self.expressions, return_target);
+        func.metadata = self.metadata;
+        func.arg_count = self.args;
+        func.stack_size = self.args.stack_size() + self.variable_count;
+        func.layout = self.layout;
+        func.statements = self.statements;
+        func
     }
 }
+
+fn may_write_globals<TS: TypeSystem>(expr: &Expression<TS>) -> bool {
+    !expr.visit(&mut |expr: &Expression<TS>| match expr {
+        Expression::AssignStack(..)
+        | Expression::AssignGlobal(..)
+        | Expression::AssignDynamic(..)
+        | Expression::FieldSet(..)
+        | Expression::Initialize(..)
+        | Expression::StaticFunctionCall(..)
+        | Expression::DynamicFunctionCall(..)
+        | Expression::CachedDynamicCall(..)
+        | Expression::NativeFunctionCall(..)
+        | Expression::MethodCall(..)
+        | Expression::NamedFunctionCall { .. }
+        | Expression::SharedCapture { .. }
+        | Expression::Yield(_)
+        | Expression::AwaitNative(..) => Visit::Stop,
+        _ => Visit::Descend,
+    })
+}
+
+fn count_global_reads<TS: TypeSystem>(expr: &Expression<TS>, reads: &mut BTreeMap<usize, usize>) {
+    expr.visit(&mut |expr: &Expression<TS>| {
+        if let Expression::Variable(VariableType::Global(addr)) = expr {
+            *reads.entry(*addr).or_default() += 1;
+        }
+        Visit::Descend
+    });
+}
+
+fn replace_global_reads<TS: TypeSystem>(expr: &mut Expression<TS>, global: usize, var: usize) {
+    expr.visit_mut(&mut |expr: &mut Expression<TS>| {
+        if matches!(expr, Expression::Variable(VariableType::Global(addr)) if *addr == global) {
+            *expr = Expression::stack(var);
+        }
+        Visit::Descend
+    });
+}
+
+/// One past the highest stack slot an expression uses
+fn frame_size<TS: TypeSystem>(expr: &Expression<TS>) -> usize {
+    let captures = |func: &FunctionRef<TS>| match &func.function_type {
+        FunctionType::CapturingDef(captures) => captures_size(captures),
+        _ => 0,
+    };
+    let mut size = 0;
+    expr.visit(&mut |expr: &Expression<TS>| {
+        let own = match expr {
+            Expression::Variable(VariableType::Stack(addr)) | Expression::AssignStack(addr, _) => {
+                addr + 1
+            }
+            Expression::FreshCells(addrs) => addrs.iter().map(|addr| addr + 1).max().unwrap_or(0),
+            Expression::FunctionCapture(func) => captures(func),
+            Expression::SharedCapture {
+                captures: shared,
+                closures,
+            } => closures
+                .iter()
+                .map(|(_, addr)| addr + 1)
+                .chain(std::iter::once(captures_size(shared)))
+                .max()
+                .unwrap_or(0),
+            _ => 0,
+        };
+        size = size.max(own);
+        Visit::Descend
+    });
+    size
+}
+
+fn captures_size(captures: &[VariableType]) -> usize {
+    captures
+        .iter()
+        .map(|var| match var {
+            VariableType::Stack(addr) => addr + 1,
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0)
+}
@@ -0,0 +1,46 @@
+use std::{fmt::Display, ops::Range, rc::Rc};
+
+/// A human-readable identity for a function, set with [super::FunctionWriter::set_metadata] and
+/// carried by its [super::FunctionRef] and registered [super::Function]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FunctionMetadata {
+    pub name: Option<Rc<str>>,
+    /// The module or file the function was defined in
+    pub module: Option<Rc<str>>,
+    /// The range of the function's definition in the module's source, in bytes
+    pub span: Option<Range<usize>>,
+}
+
+impl FunctionMetadata {
+    pub fn named(name: &str) -> FunctionMetadata {
+        FunctionMetadata {
+            name: Some(name.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn in_module(mut self, module: &str) -> Self {
+        self.module = Some(module.into());
+        self
+    }
+
+    pub fn with_span(mut self, span: Range<usize>) -> Self {
+        self.span = Some(span);
+        self
+    }
+}
+
+impl Display for FunctionMetadata {
+    /// Writes the name, or `<anonymous>`, followed by the module and span if they are known, such
+    /// as `main (lib.fe:10..42)`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name.as_deref().unwrap_or("<anonymous>"))?;
+        match (&self.module, &self.span) {
+            (Some(module), Some(span)) => write!(f, " ({module}:{}..{})", span.start, span.end),
+            (Some(module), None) => write!(f, " ({module})"),
+            (None, Some(span)) => write!(f, " ({}..{})", span.start, span.end),
+            (None, None) => Ok(()),
+        }
+    }
+}
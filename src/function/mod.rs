@@ -4,25 +4,80 @@ use crate::{
     expression::Expression,
     TypeSystem,
 };
-use std::fmt::Debug;
+use std::{collections::HashSet, fmt::Debug, rc::Rc};
+
+#[cfg(feature = "compiled")]
+use crate::compiled::CompiledExpression;
 
 mod arg_count;
+mod features;
 mod function_ref;
 mod function_type;
 mod function_writer;
+mod metadata;
 
 pub use arg_count::*;
+pub use features::*;
 pub use function_ref::*;
 pub use function_type::*;
 pub use function_writer::*;
+pub use metadata::*;
 
 #[derive(Debug)]
 pub struct Function<TS: TypeSystem> {
     pub(crate) expressions: Vec<Expression<TS>>,
     pub(crate) return_target: usize,
+    pub(crate) metadata: Option<Rc<FunctionMetadata>>,
+    pub(crate) features: FunctionFeatures,
+    pub(crate) arg_count: ArgCount,
+    pub(crate) stack_size: usize,
+    pub(crate) layout: StackLayout,
+    /// The index of the statement each top-level expression was written as, see
+    /// [FunctionWriter::hoist_global_reads]
+    pub(crate) statements: Vec<usize>,
+    /// The expressions which are or contain an [Expression::Yield], which stay in place since
+    /// they are never mutated, see [crate::coroutine::Coroutine]
+    pub(crate) yielding: HashSet<*const Expression<TS>>,
+    /// How many times the function at this address has been replaced, see
+    /// [crate::execution_engine::ExecutionEngine::replace_function]
+    pub(crate) generation: u32,
+    #[cfg(feature = "compiled")]
+    pub(crate) compiled: Vec<CompiledExpression<TS>>,
 }
 
 impl<TS: TypeSystem> Function<TS> {
+    pub(crate) fn new(expressions: Vec<Expression<TS>>, return_target: usize) -> Self {
+        Function {
+            // The compiled closures point into the expressions' heap allocations, which stay in
+            // place for as long as the function exists since they are never mutated
+            #[cfg(feature = "compiled")]
+            compiled: expressions
+                .iter()
+                .map(|expr| unsafe { CompiledExpression::compile(expr) })
+                .collect(),
+            features: FunctionFeatures::classify(&expressions, return_target),
+            statements: (0..expressions.len()).collect(),
+            yielding: yielding_expressions(&expressions),
+            expressions,
+            return_target,
+            metadata: None,
+            arg_count: ArgCount::Fixed(0),
+            stack_size: 0,
+            layout: StackLayout::all_alloc(),
+            generation: 0,
+        }
+    }
+
+    /// The metadata the function was written with, if any
+    pub fn metadata(&self) -> Option<&FunctionMetadata> {
+        self.metadata.as_deref()
+    }
+
+    /// What the function's expressions use
+    pub fn features(&self) -> FunctionFeatures {
+        self.features
+    }
+
     pub fn call(
         &self,
         engine: &mut ExecutionEngine<TS>,
@@ -32,9 +87,12 @@ impl<TS: TypeSystem> Function<TS> {
         if self.expressions.is_empty() {
             return Ok(Default::default());
         }
+        if !self.features.has_return_targets {
+            return self.call_without_returns(engine, args, captured);
+        }
 
         for i in 0..self.expressions.len() - 1 {
-            match engine.evaluate_internal(&self.expressions[i], args, captured) {
+            match self.evaluate_nth(i, engine, args, captured) {
                 Err(FreightError::Return { target }) => {
                     if target == self.return_target {
                         return Ok(std::mem::take(&mut engine.return_value));
@@ -42,12 +100,63 @@ impl<TS: TypeSystem> Function<TS> {
                         return Err(FreightError::Return { target });
                     }
                 }
-                Err(e) => return Err(e),
+                Err(e) => {
+                    engine.failed_expression = self.statements[i];
+                    return Err(e);
+                }
                 _ => (),
             }
         }
-        engine
-            .evaluate_internal(self.expressions.last().unwrap(), args, captured)
-            .or_return(self.return_target, engine)
+        let last = self.expressions.len() - 1;
+        let result = self
+            .evaluate_nth(last, engine, args, captured)
+            .or_return(self.return_target, engine);
+        if result.is_err() {
+            engine.failed_expression = self.statements[last];
+        }
+        result
+    }
+
+    /// Call a function which can't be left by a return to its own target, so no error needs to
+    /// be checked for one
+    fn call_without_returns(
+        &self,
+        engine: &mut ExecutionEngine<TS>,
+        args: &mut [TS::Value],
+        captured: &[TS::Value],
+    ) -> Result<TS::Value, FreightError> {
+        let last = self.expressions.len() - 1;
+        for i in 0..last {
+            if let Err(e) = self.evaluate_nth(i, engine, args, captured) {
+                if !matches!(e, FreightError::Return { .. }) {
+                    engine.failed_expression = self.statements[i];
+                }
+                return Err(e);
+            }
+        }
+        let result = self.evaluate_nth(last, engine, args, captured);
+        if result.is_err() {
+            engine.failed_expression = self.statements[last];
+        }
+        result
+    }
+
+    #[inline]
+    fn evaluate_nth(
+        &self,
+        i: usize,
+        engine: &mut ExecutionEngine<TS>,
+        args: &mut [TS::Value],
+        captured: &[TS::Value],
+    ) -> Result<TS::Value, FreightError> {
+        #[cfg(feature = "compiled")]
+        if !engine.is_instrumented() {
+            return self.compiled[i].evaluate(engine, args, captured);
+        }
+        // Hoisted reads start the statement they were hoisted from
+        if engine.debugger.is_some() && (i == 0 || self.statements[i] != self.statements[i - 1]) {
+            engine.debug_statement(self.statements[i]);
+        }
+        engine.evaluate_internal(&self.expressions[i], args, captured)
     }
 }
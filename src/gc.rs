@@ -0,0 +1,99 @@
+//! Hooks for tracing garbage collectors, for embedders whose values can form reference cycles.
+//! A collector marks everything reachable from the roots between calls, through
+//! [Value::trace], and frees the rest.
+
+use std::collections::HashMap;
+
+use crate::{execution_engine::ExecutionEngine, value::Value, TypeSystem};
+
+/// The reference cycles among the values reachable from an engine's roots, see
+/// [ExecutionEngine::find_cycles]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CycleReport {
+    /// The number of references which close a cycle
+    pub cycles: usize,
+    /// Some of the cycles, each as the [Value::identity] of the values along it, starting and
+    /// ending with the same value
+    pub examples: Vec<Vec<usize>>,
+}
+
+impl<TS: TypeSystem> ExecutionEngine<TS> {
+    /// Call `visit` with each value the engine holds on to between calls: globals, the pending
+    /// return value and the entries of [ExecutionEngine::cache]. Values held by the host, such
+    /// as suspended coroutines (see [crate::coroutine::Coroutine::trace_roots]), are roots too.
+    ///
+    /// Must not be called while a call is running on this engine: the stack frames of running
+    /// calls aren't enumerated.
+    pub fn trace_roots(&self, mut visit: impl FnMut(&TS::Value)) {
+        self.globals.iter().for_each(&mut visit);
+        visit(&self.return_value);
+        for (key, value) in self.cache.entries() {
+            visit(key);
+            visit(value);
+        }
+    }
+
+    /// Call `mark` with each value reachable from [ExecutionEngine::trace_roots], descending
+    /// into the values it refers to with [Value::trace] whenever `mark` returns true. A collector
+    /// returns false for values it has already marked, so each value is only traced once and
+    /// cycles terminate.
+    pub fn trace_reachable(&self, mut mark: impl FnMut(&TS::Value) -> bool) {
+        self.trace_roots(|root| trace_from(root, &mut mark));
+    }
+
+    /// Find reference cycles among the values reachable from [ExecutionEngine::trace_roots],
+    /// keeping at most `max_examples` of them. Only values with a [Value::identity] can be part
+    /// of a cycle. Cycles reachable only through host-held roots aren't found.
+    pub fn find_cycles(&self, max_examples: usize) -> CycleReport {
+        let mut search = CycleSearch {
+            visited: HashMap::new(),
+            path: vec![],
+            max_examples,
+            report: CycleReport::default(),
+        };
+        self.trace_roots(|root| search.visit(root));
+        search.report
+    }
+}
+
+fn trace_from<V: Value>(value: &V, mark: &mut dyn FnMut(&V) -> bool) {
+    if mark(value) {
+        value.trace(&mut |child| trace_from(child, mark));
+    }
+}
+
+struct CycleSearch {
+    /// Whether each value seen is on the current path, rather than finished
+    visited: HashMap<usize, bool>,
+    path: Vec<usize>,
+    max_examples: usize,
+    report: CycleReport,
+}
+
+impl CycleSearch {
+    fn visit<V: Value>(&mut self, value: &V) {
+        let Some(id) = value.identity() else {
+            value.trace(&mut |child| self.visit(child));
+            return;
+        };
+        match self.visited.get(&id) {
+            Some(true) => {
+                self.report.cycles += 1;
+                if self.report.examples.len() < self.max_examples {
+                    let start = self.path.iter().position(|seen| *seen == id).unwrap();
+                    let mut cycle = self.path[start..].to_vec();
+                    cycle.push(id);
+                    self.report.examples.push(cycle);
+                }
+            }
+            Some(false) => (),
+            None => {
+                self.visited.insert(id, true);
+                self.path.push(id);
+                value.trace(&mut |child| self.visit(child));
+                self.path.pop();
+                self.visited.insert(id, false);
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use crate::{
+    error::FreightError,
+    execution_engine::ExecutionEngine,
+    expression::{Expression, ExpressionKind},
+    TypeSystem,
+};
+
+/// Called before an expression of a particular kind is evaluated.
+/// Returning an error aborts evaluation of the expression with that error.
+pub type ExpressionHook<TS> =
+    fn(&mut ExecutionEngine<TS>, &Expression<TS>) -> Result<(), FreightError>;
+
+/// A stack frame being popped because of an error, see [UnwindHook]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnwindFrame {
+    /// The address of the function, or the id of the native
+    pub function: usize,
+    pub native: bool,
+    /// The index of the function's top-level expression which raised the error, or `None` for
+    /// natives
+    pub expression: Option<usize>,
+}
+
+/// Called for each stack frame popped while an error propagates, innermost first, so hosts can
+/// record script frames in the global context as the error unwinds.
+/// [crate::expression::Expression::Return] and [crate::expression::Expression::EscapeTo] do not
+/// count as errors.
+pub type UnwindHook<TS> = fn(&mut ExecutionEngine<TS>, &UnwindFrame, &FreightError);
+
+/// Hooks to run before evaluating expressions, registered per [ExpressionKind].
+/// Evaluating a kind of expression with no hooks costs a single branch.
+#[derive(Debug, Clone)]
+pub struct ExpressionHooks<TS: TypeSystem> {
+    active: u64,
+    hooks: [Vec<ExpressionHook<TS>>; ExpressionKind::COUNT],
+}
+
+impl<TS: TypeSystem> Default for ExpressionHooks<TS> {
+    fn default() -> Self {
+        ExpressionHooks {
+            active: 0,
+            hooks: std::array::from_fn(|_| vec![]),
+        }
+    }
+}
+
+impl<TS: TypeSystem> ExpressionHooks<TS> {
+    /// Run a hook before every expression of the given kind is evaluated
+    pub fn register(&mut self, kind: ExpressionKind, hook: ExpressionHook<TS>) {
+        self.hooks[kind as usize].push(hook);
+        self.active |= 1 << kind as usize;
+    }
+
+    /// Remove all hooks for an expression kind
+    pub fn clear(&mut self, kind: ExpressionKind) {
+        self.hooks[kind as usize].clear();
+        self.active &= !(1 << kind as usize);
+    }
+
+    /// Whether any hooks are registered
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.active == 0
+    }
+
+    #[inline]
+    pub(crate) fn is_active(&self, kind: ExpressionKind) -> bool {
+        self.active & (1 << kind as usize) != 0
+    }
+
+    pub(crate) fn get(&self, kind: ExpressionKind) -> &[ExpressionHook<TS>] {
+        &self.hooks[kind as usize]
+    }
+}
@@ -0,0 +1,145 @@
+use crate::{
+    expression::{Expression, ExpressionKind},
+    visit::VisitorMut,
+    TypeSystem,
+};
+
+/// What a probe records each time the expression it wraps is evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeMode {
+    /// Count the evaluations of each probe
+    Count,
+    /// Count the evaluations of each probe, and append its id to [Instrumentation::trace]
+    Trace,
+}
+
+/// Where a probe was inserted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeSite {
+    /// The address of the function containing the probe
+    pub function: usize,
+    /// The kind of the expression the probe wraps
+    pub kind: ExpressionKind,
+}
+
+/// Wraps the selected kinds of expressions in [Expression::Probe] when a function is registered
+/// after [crate::execution_engine::ExecutionEngine::start_instrumentation], giving coverage and
+/// hot path data without expression hooks. Functions registered before it carry no probes and
+/// pay nothing.
+#[derive(Debug, Clone)]
+pub struct Instrumentation {
+    kinds: [bool; ExpressionKind::COUNT],
+    /// Whether each top-level expression of a function gets a probe, whatever its kind
+    pub statements: bool,
+    pub mode: ProbeMode,
+    sites: Vec<ProbeSite>,
+    counts: Vec<u64>,
+    trace: Vec<usize>,
+}
+
+impl Instrumentation {
+    /// Instrumentation which inserts no probes until kinds are selected with
+    /// [Instrumentation::probe] or [Instrumentation::statements] is set
+    pub(crate) fn new(mode: ProbeMode) -> Instrumentation {
+        Instrumentation {
+            kinds: [false; ExpressionKind::COUNT],
+            statements: false,
+            mode,
+            sites: vec![],
+            counts: vec![],
+            trace: vec![],
+        }
+    }
+
+    /// Insert probes around every expression of a kind
+    pub fn probe(&mut self, kind: ExpressionKind) -> &mut Self {
+        self.kinds[kind as usize] = true;
+        self
+    }
+
+    /// Where each probe was inserted, by id
+    pub fn sites(&self) -> &[ProbeSite] {
+        &self.sites
+    }
+
+    /// How many times each probe was evaluated, by id
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// The ids of the probes evaluated in [ProbeMode::Trace], in the order they were evaluated
+    pub fn trace(&self) -> &[usize] {
+        &self.trace
+    }
+
+    /// The ids of the probes which were never evaluated
+    pub fn uncovered(&self) -> impl Iterator<Item = usize> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count == 0)
+            .map(|(id, _)| id)
+    }
+
+    /// Reset the counts and trace, keeping the probes
+    pub fn reset(&mut self) {
+        self.counts.fill(0);
+        self.trace.clear();
+    }
+
+    /// Count an evaluation of a probe. Probes this instrumentation didn't insert are ignored.
+    #[inline]
+    pub(crate) fn hit(&mut self, probe: usize) {
+        let Some(count) = self.counts.get_mut(probe) else {
+            return;
+        };
+        *count += 1;
+        if self.mode == ProbeMode::Trace {
+            self.trace.push(probe);
+        }
+    }
+
+    /// Wrap the selected expressions of a function about to be registered at an address
+    pub(crate) fn instrument<TS: TypeSystem>(
+        &mut self,
+        expressions: &mut [Expression<TS>],
+        function: usize,
+    ) {
+        let mut inserter = ProbeInserter {
+            instrumentation: self,
+            function,
+        };
+        for expr in expressions {
+            expr.visit_mut(&mut inserter);
+            if inserter.instrumentation.statements && !matches!(expr, Expression::Probe(..)) {
+                inserter.wrap(expr);
+            }
+        }
+    }
+}
+
+struct ProbeInserter<'a> {
+    instrumentation: &'a mut Instrumentation,
+    function: usize,
+}
+
+impl ProbeInserter<'_> {
+    fn wrap<TS: TypeSystem>(&mut self, expr: &mut Expression<TS>) {
+        let id = self.instrumentation.sites.len();
+        self.instrumentation.sites.push(ProbeSite {
+            function: self.function,
+            kind: expr.kind(),
+        });
+        self.instrumentation.counts.push(0);
+        let inner = std::mem::replace(expr, Expression::FreshCells(vec![]));
+        *expr = Expression::Probe(id, inner.into());
+    }
+}
+
+impl<TS: TypeSystem> VisitorMut<TS> for ProbeInserter<'_> {
+    fn exit(&mut self, expr: &mut Expression<TS>) {
+        if self.instrumentation.kinds[expr.kind() as usize] {
+            self.wrap(expr);
+        }
+    }
+}
@@ -1,15 +1,57 @@
-use operators::{BinaryOperator, Initializer, UnaryOperator};
+use error::ErrorConverter;
+use fallback::CallFallback;
+use operators::{BinaryOperator, Initializer, TernaryOperator, UnaryOperator};
+use region::RegionAlloc;
 use std::fmt::Debug;
+use type_registry::RuntimeTypeId;
 use value::Value;
 
+pub mod args;
+pub mod cache;
+pub mod callbacks;
+#[cfg(feature = "compiled")]
+pub mod compiled;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod control_flow;
+pub mod coroutine;
+pub mod crash;
+pub mod debugger;
+pub mod docs;
 pub mod error;
 pub mod execution_engine;
 pub mod expression;
+pub mod fallback;
+pub mod fuel;
 pub mod function;
+pub mod gc;
+pub mod hooks;
+pub mod instrument;
+pub mod lint;
+#[cfg(feature = "serde")]
+pub mod manifest;
+pub mod module;
+pub mod namespace;
 pub mod operators;
+#[cfg(feature = "profiling")]
+pub mod profiler;
+pub mod program;
+pub mod rate_limit;
 pub mod ref_pool;
+pub mod region;
+pub mod rewrite;
+#[cfg(feature = "serde")]
+pub mod serialization;
+pub mod services;
 pub mod slice_pool;
+pub mod symbols;
+#[cfg(feature = "metrics")]
+pub mod telemetry;
+pub mod transfer;
+pub mod type_registry;
 pub mod value;
+pub mod visit;
+pub mod watchdog;
 
 /// Defines the type system for a programming language
 pub trait TypeSystem: Debug + Clone + 'static {
@@ -19,12 +61,24 @@ pub trait TypeSystem: Debug + Clone + 'static {
     type UnaryOp: UnaryOperator<Self::Value>;
     /// The binary operator type for a language
     type BinaryOp: BinaryOperator<Self::Value>;
+    /// The ternary operator type for a language, use [std::convert::Infallible] to opt out
+    type TernaryOp: TernaryOperator<Self::Value>;
     /// The initializers type for creating new values that take multiple expressions
     type Init: Initializer<Self>;
-    /// The type id type for a language
-    type TypeId: PartialEq + Debug;
+    /// The type id type for a language. Implement `From<RuntimeTypeToken>` and override
+    /// [RuntimeTypeId::token] to let scripts define their own types.
+    type TypeId: PartialEq + Debug + RuntimeTypeId;
     /// A global context object to be stored in the ExecutionEngine
     type GlobalContext: Debug;
+    /// Errors raised by the language's natives and operators through [error::FreightError::runtime],
+    /// use [std::convert::Infallible] to opt out
+    type RuntimeError: Debug + std::fmt::Display + PartialEq + 'static;
+    /// Converts errors to and from values when they are caught by scripts
+    type ErrorConverter: ErrorConverter<Self>;
+    /// Allocates temporary values per function call, use `()` to opt out
+    type RegionAlloc: RegionAlloc<Self>;
+    /// Handles dynamic calls which can't be made as written, use `()` to opt out
+    type CallFallback: CallFallback<Self>;
 }
 
 #[cfg(test)]
@@ -0,0 +1,166 @@
+use std::collections::HashSet;
+
+use crate::{
+    expression::{Expression, SpanId, VariableType},
+    function::{FunctionType, FunctionWriter},
+    value::Value,
+    visit::{Visit, Visitor},
+    TypeSystem,
+};
+
+/// A likely mistake in a function, found by [lint_function]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lint {
+    /// A top-level expression follows an [Expression::Return] or [Expression::EscapeTo] which is
+    /// always evaluated before it, so it is never evaluated
+    Unreachable,
+    /// An argument's stack slot is assigned, but the slot is never read
+    UnreadArgumentWrite { slot: usize },
+    /// A dynamic call's target is a constant which isn't a function
+    NotCallable,
+    /// A capturing function captures nothing, so it could be static
+    EmptyCaptures,
+}
+
+/// A lint found in a function
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The address of the function
+    pub function: usize,
+    /// The index of the function's top-level expression containing the problem, or `None` if it
+    /// is with the function itself
+    pub expression: Option<usize>,
+    /// The span of the innermost [Expression::Spanned] around the problem
+    pub span: Option<SpanId>,
+    pub lint: Lint,
+}
+
+/// Look for likely front-end bugs in a function about to be registered at an address. Run on
+/// every function registered while [crate::execution_engine::ExecutionEngine::lint] is set.
+pub fn lint_function<TS: TypeSystem>(
+    func: &FunctionWriter<TS>,
+    function: usize,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    if matches!(&func.function_type, FunctionType::CapturingDef(captures) if captures.is_empty()) {
+        diagnostics.push(Diagnostic {
+            function,
+            expression: None,
+            span: None,
+            lint: Lint::EmptyCaptures,
+        });
+    }
+    let mut reads = HashSet::new();
+    for expr in &func.expressions {
+        expr.visit(&mut |expr: &Expression<TS>| {
+            collect_reads(expr, &mut reads);
+            Visit::Descend
+        });
+    }
+    let mut linter = Linter {
+        args: func.args.stack_size(),
+        reads,
+        spans: vec![],
+        found: vec![],
+    };
+    for (i, expr) in func.expressions.iter().enumerate() {
+        expr.visit(&mut linter);
+        diagnostics.extend(linter.found.drain(..).map(|(span, lint)| Diagnostic {
+            function,
+            expression: Some(func.statements[i]),
+            span,
+            lint,
+        }));
+    }
+    let exit = func.expressions.iter().position(always_exits);
+    if let Some(next) = exit.map(|i| i + 1).filter(|i| *i < func.expressions.len()) {
+        diagnostics.push(Diagnostic {
+            function,
+            expression: Some(func.statements[next]),
+            span: match &func.expressions[next] {
+                Expression::Spanned(span, _) => Some(*span),
+                _ => None,
+            },
+            lint: Lint::Unreachable,
+        });
+    }
+    diagnostics
+}
+
+/// Add the stack slots an expression reads itself, not counting its children
+fn collect_reads<TS: TypeSystem>(expr: &Expression<TS>, reads: &mut HashSet<usize>) {
+    let mut captures = |captures: &[VariableType]| {
+        reads.extend(captures.iter().filter_map(|var| match var {
+            VariableType::Stack(addr) => Some(*addr),
+            _ => None,
+        }));
+    };
+    match expr {
+        Expression::Variable(VariableType::Stack(addr)) => {
+            reads.insert(*addr);
+        }
+        Expression::FreshCells(addrs) => reads.extend(addrs),
+        Expression::FunctionCapture(func) => {
+            if let FunctionType::CapturingDef(vars) = &func.function_type {
+                captures(vars);
+            }
+        }
+        Expression::SharedCapture { captures: vars, .. } => captures(vars),
+        _ => (),
+    }
+}
+
+/// Whether evaluating an expression always leaves the function, or the escape point around it
+fn always_exits<TS: TypeSystem>(expr: &Expression<TS>) -> bool {
+    match expr {
+        Expression::Return(..) | Expression::EscapeTo(..) => true,
+        Expression::Spanned(_, expr) | Expression::Probe(_, expr) => always_exits(expr),
+        _ => false,
+    }
+}
+
+struct Linter {
+    args: usize,
+    reads: HashSet<usize>,
+    /// The spans around the expression being visited, innermost last
+    spans: Vec<SpanId>,
+    found: Vec<(Option<SpanId>, Lint)>,
+}
+
+impl<TS: TypeSystem> Visitor<TS> for Linter {
+    fn enter(&mut self, expr: &Expression<TS>) -> Visit {
+        let lint = match expr {
+            Expression::Spanned(span, _) => {
+                self.spans.push(*span);
+                None
+            }
+            Expression::AssignStack(slot, _) if *slot < self.args && !self.reads.contains(slot) => {
+                Some(Lint::UnreadArgumentWrite { slot: *slot })
+            }
+            Expression::DynamicFunctionCall(func, _)
+            | Expression::NamedFunctionCall { func, .. } => match &**func {
+                Expression::RawValue(value) if value.cast_to_function().is_none() => {
+                    Some(Lint::NotCallable)
+                }
+                _ => None,
+            },
+            Expression::FunctionCapture(func) => match &func.function_type {
+                FunctionType::CapturingDef(captures) if captures.is_empty() => {
+                    Some(Lint::EmptyCaptures)
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(lint) = lint {
+            self.found.push((self.spans.last().copied(), lint));
+        }
+        Visit::Descend
+    }
+
+    fn exit(&mut self, expr: &Expression<TS>) {
+        if let Expression::Spanned(..) = expr {
+            self.spans.pop();
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! A machine-readable description of a program saved with [ExecutionEngine::save_program], so
+//! deployment tooling can check that a host provides every native a cached program expects before
+//! loading it, behind the `serde` feature.
+
+use std::collections::{BTreeSet, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    execution_engine::ExecutionEngine,
+    expression::{Expression, NativeFunction},
+    function::{ArgCount, FunctionRef, FunctionType},
+    program::visit_references,
+    serialization::NativeTable,
+    symbols::{Symbol, SymbolId},
+    visit::Visit,
+    TypeSystem,
+};
+
+/// The version of the format [ExecutionEngine::save_program] writes, increased whenever programs
+/// saved by an older version can't be loaded
+pub const PROGRAM_FORMAT_VERSION: u32 = 2;
+
+/// What a program consists of and what it needs from its host, see
+/// [ExecutionEngine::program_manifest]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProgramManifest {
+    pub format_version: u32,
+    pub functions: Vec<ManifestFunction>,
+    pub globals: Vec<ManifestGlobal>,
+    /// The names of the natives the program calls or refers to, sorted
+    pub natives: Vec<String>,
+    /// How many natives the program refers to which aren't registered in the [NativeTable] the
+    /// manifest was made with. A program with any of these can't be saved.
+    pub unnamed_natives: usize,
+}
+
+/// A function registered with the engine
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestFunction {
+    pub address: usize,
+    /// The name from the function's [crate::function::FunctionMetadata]
+    pub name: Option<String>,
+    /// The symbols bound to the function
+    pub symbols: Vec<String>,
+    /// The arguments the function takes, if a reference to it is bound to a symbol or embedded
+    /// in the program
+    pub arg_count: Option<ArgCount>,
+    pub param_names: Option<Vec<String>>,
+    /// The function's [crate::function::Function::structural_hash]
+    pub hash: u64,
+}
+
+/// A global created in the engine
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestGlobal {
+    pub address: usize,
+    /// The symbols bound to the global
+    pub symbols: Vec<String>,
+}
+
+impl ProgramManifest {
+    /// The natives the program needs which aren't registered in a host's native table
+    pub fn missing_natives<'a, TS: TypeSystem>(
+        &'a self,
+        natives: &NativeTable<TS>,
+    ) -> Vec<&'a str> {
+        self.natives
+            .iter()
+            .map(|name| &**name)
+            .filter(|name| natives.get(name).is_none())
+            .collect()
+    }
+}
+
+impl<TS: TypeSystem> ExecutionEngine<TS> {
+    /// Describe the program registered with this engine, naming natives through the same table
+    /// the program is saved with
+    pub fn program_manifest(&self, natives: &NativeTable<TS>) -> ProgramManifest {
+        let mut collector = Collector {
+            natives,
+            functions: self
+                .functions()
+                .iter()
+                .enumerate()
+                .map(|(address, func)| ManifestFunction {
+                    address,
+                    name: func
+                        .metadata()
+                        .and_then(|metadata| metadata.name.as_deref())
+                        .map(str::to_string),
+                    symbols: vec![],
+                    arg_count: None,
+                    param_names: None,
+                    hash: func.structural_hash(),
+                })
+                .collect(),
+            native_names: BTreeSet::new(),
+            unnamed: HashSet::new(),
+        };
+        let mut globals: Vec<ManifestGlobal> = (0..self.num_globals)
+            .map(|address| ManifestGlobal {
+                address,
+                symbols: vec![],
+            })
+            .collect();
+
+        for (id, name) in self.symbols.names().iter().enumerate() {
+            match self.symbols.resolve(SymbolId(id)) {
+                Some(Symbol::Function(func_ref)) => {
+                    if let Some(func) = collector.reference(func_ref) {
+                        func.symbols.push(name.to_string());
+                    }
+                }
+                Some(Symbol::Global(addr)) => {
+                    if let Some(global) = globals.get_mut(*addr) {
+                        global.symbols.push(name.to_string());
+                    }
+                }
+                None => (),
+            }
+        }
+        for func in self.functions() {
+            for expr in &func.expressions {
+                visit_references(expr, &mut |func_ref, _| {
+                    collector.reference(func_ref);
+                });
+                expr.visit(&mut |expr: &Expression<TS>| {
+                    if let Expression::NativeFunctionCall(native, _) = expr {
+                        collector.native(native);
+                    }
+                    Visit::Descend
+                });
+            }
+        }
+
+        ProgramManifest {
+            format_version: PROGRAM_FORMAT_VERSION,
+            functions: collector.functions,
+            globals,
+            natives: collector.native_names.into_iter().collect(),
+            unnamed_natives: collector.unnamed.len(),
+        }
+    }
+}
+
+struct Collector<'a, TS: TypeSystem> {
+    natives: &'a NativeTable<TS>,
+    functions: Vec<ManifestFunction>,
+    native_names: BTreeSet<String>,
+    /// The addresses of natives without a name
+    unnamed: HashSet<usize>,
+}
+
+impl<TS: TypeSystem> Collector<'_, TS> {
+    /// Record what a reference tells about the function it refers to, returning the function
+    /// unless it is a native
+    fn reference(&mut self, func_ref: &FunctionRef<TS>) -> Option<&mut ManifestFunction> {
+        if let FunctionType::Native(native) = &func_ref.function_type {
+            self.native(native);
+            return None;
+        }
+        let func = self.functions.get_mut(func_ref.location)?;
+        if func.arg_count.is_none() {
+            func.arg_count = Some(func_ref.arg_count);
+            func.param_names = func_ref
+                .param_names()
+                .map(|names| names.iter().map(|name| name.to_string()).collect());
+        }
+        Some(func)
+    }
+
+    fn native(&mut self, native: &NativeFunction<TS>) {
+        match self.natives.name(native) {
+            Some(name) => {
+                self.native_names.insert(name.to_string());
+            }
+            None => {
+                self.unnamed.insert(**native as usize);
+            }
+        }
+    }
+}
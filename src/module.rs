@@ -0,0 +1,62 @@
+/// Identifies a module created by [ModuleTable::create]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ModuleId(pub usize);
+
+/// The module a [crate::function::FunctionRef] belongs to, and the generation of the module it was
+/// created in. The reference can only be invoked while that generation is loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ModuleTag {
+    pub module: ModuleId,
+    pub generation: u32,
+}
+
+/// Tracks which modules, such as plugins, are loaded, so references to the functions of
+/// unloaded modules fail cleanly with [crate::error::FreightError::ModuleUnloaded] instead of
+/// invoking stale code
+#[derive(Debug, Clone, Default)]
+pub struct ModuleTable {
+    generations: Vec<u32>,
+    loaded: Vec<bool>,
+}
+
+impl ModuleTable {
+    /// Create a loaded module
+    pub fn create(&mut self) -> ModuleId {
+        self.generations.push(0);
+        self.loaded.push(true);
+        ModuleId(self.generations.len() - 1)
+    }
+
+    /// Unload a module, invalidating every reference to its functions
+    pub fn unload(&mut self, module: ModuleId) {
+        if self.loaded[module.0] {
+            self.loaded[module.0] = false;
+            self.generations[module.0] += 1;
+        }
+    }
+
+    /// Load a module again. References created before it was unloaded stay invalid.
+    pub fn reload(&mut self, module: ModuleId) {
+        self.loaded[module.0] = true;
+    }
+
+    pub fn is_loaded(&self, module: ModuleId) -> bool {
+        self.loaded[module.0]
+    }
+
+    /// The tag for references to functions of the module's current generation
+    pub fn tag(&self, module: ModuleId) -> ModuleTag {
+        ModuleTag {
+            module,
+            generation: self.generations[module.0],
+        }
+    }
+
+    /// Whether references with this tag may be invoked
+    #[inline]
+    pub fn is_live(&self, tag: ModuleTag) -> bool {
+        self.loaded[tag.module.0] && self.generations[tag.module.0] == tag.generation
+    }
+}
@@ -0,0 +1,60 @@
+use std::{cell::UnsafeCell, rc::Rc};
+
+use crate::{callbacks::CallbackRegistry, slice_pool::RcSlicePool, value::Value, TypeSystem};
+
+/// Identifies a namespace created by
+/// [crate::execution_engine::ExecutionEngine::create_namespace]. The engine starts in the root
+/// namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NamespaceId(pub usize);
+
+impl NamespaceId {
+    pub const ROOT: NamespaceId = NamespaceId(0);
+}
+
+/// The globals and callbacks of a namespace which is not currently active
+#[derive(Debug, Clone)]
+pub(crate) struct Namespace<TS: TypeSystem> {
+    pub(crate) num_globals: usize,
+    pub(crate) globals: Vec<TS::Value>,
+    pub(crate) callbacks: CallbackRegistry<TS>,
+}
+
+impl<TS: TypeSystem> Default for Namespace<TS> {
+    fn default() -> Self {
+        Namespace {
+            num_globals: 0,
+            globals: vec![],
+            callbacks: Default::default(),
+        }
+    }
+}
+
+impl<TS: TypeSystem> Namespace<TS> {
+    pub(crate) fn reset_globals(&mut self) {
+        self.globals = vec![Value::uninitialized_reference(); self.num_globals];
+    }
+
+    /// A copy for a fork, whose callbacks are made independent of this namespace's by `copy`,
+    /// along with its globals unless they are copied as the fork uses them
+    pub(crate) fn fork<E>(
+        &self,
+        lazy: bool,
+        copy: &mut impl FnMut(&TS::Value) -> Result<TS::Value, E>,
+        pool: &Rc<UnsafeCell<RcSlicePool<TS::Value>>>,
+    ) -> Result<Namespace<TS>, E> {
+        let globals = if lazy {
+            self.globals.clone()
+        } else {
+            self.globals
+                .iter()
+                .map(&mut *copy)
+                .collect::<Result<_, _>>()?
+        };
+        Ok(Namespace {
+            num_globals: self.num_globals,
+            globals,
+            callbacks: self.callbacks.fork(copy, pool)?,
+        })
+    }
+}
@@ -1,26 +1,135 @@
-use crate::{execution_engine::ExecutionEngine, value::Value};
-use std::fmt::Debug;
+use crate::{error::FreightError, execution_engine::ExecutionEngine, value::Value};
+use std::{convert::Infallible, fmt::Debug};
 
 #[derive(Clone, Debug)]
 pub enum Operator<TS: crate::TypeSystem> {
     Binary(TS::BinaryOp),
     Unary(TS::UnaryOp),
+    Ternary(TS::TernaryOp),
 }
 
 pub trait UnaryOperator<V: Value>: Debug + Clone {
     fn apply_1(&self, val: &V) -> V;
+
+    /// Whether applying this operator to the same operands always gives the same result without
+    /// side effects, so it can be applied to constant operands ahead of time, see
+    /// [crate::rewrite::ConstantFolder]. Defaults to `false`.
+    fn is_pure(&self) -> bool {
+        false
+    }
 }
 
 pub trait BinaryOperator<V: Value>: Debug + Clone {
     fn apply_2(&self, a: &V, b: &V) -> V;
+
+    /// Called with the left operand before the right operand is evaluated. Returning a value
+    /// skips evaluating the right operand and makes that value the result, which is how operators
+    /// like `&&` and `||` short-circuit. Never short-circuits by default.
+    #[inline(always)]
+    fn short_circuit(&self, left: &V) -> Option<V> {
+        let _ = left;
+        None
+    }
+
+    /// See [UnaryOperator::is_pure]
+    fn is_pure(&self) -> bool {
+        false
+    }
+
+    /// Whether this operator is pure and evaluates to a truthy value exactly when its operands
+    /// have the same [Value::as_discriminant], if either has one, so comparisons with it can be
+    /// turned into [crate::expression::Expression::Switch]es, see
+    /// [crate::rewrite::SwitchRewriter]. Defaults to `false`.
+    fn is_equality(&self) -> bool {
+        false
+    }
+}
+
+/// An operator applied to three operands, such as slicing (`a[b..c]`) or a conditional operator
+/// which evaluates every operand. Languages with none can use [Infallible].
+pub trait TernaryOperator<V: Value>: Debug + Clone {
+    fn apply_3(&self, a: &V, b: &V, c: &V) -> V;
+
+    /// See [UnaryOperator::is_pure]
+    fn is_pure(&self) -> bool {
+        false
+    }
+}
+
+impl<V: Value> TernaryOperator<V> for Infallible {
+    fn apply_3(&self, _: &V, _: &V, _: &V) -> V {
+        match *self {}
+    }
+}
+
+/// What an [Initializer] builds, which lets the engine and tools treat its arguments accordingly
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InitKind {
+    /// A sequence of any number of elements
+    List,
+    /// Keys and their values, alternating, so there must be an even number of arguments
+    Map,
+    /// A collection of any number of distinct elements
+    Set,
+    /// A fixed number of elements
+    Tuple(usize),
+    /// Anything else, whose arguments the engine makes no assumptions about
+    Other,
+}
+
+impl InitKind {
+    /// Check that an initializer of this kind can take `len` arguments
+    pub fn check_len(&self, len: usize) -> Result<(), FreightError> {
+        let reason = match *self {
+            InitKind::Map if !len.is_multiple_of(2) => {
+                format!("A map needs pairs of values, found {len}")
+            }
+            InitKind::Tuple(n) if len != n => format!("Expected {n} values, found {len}"),
+            _ => return Ok(()),
+        };
+        Err(FreightError::InvalidInitialization { reason })
+    }
 }
 
 pub trait Initializer<TS: crate::TypeSystem>: Debug + Clone {
-    fn initialize(&self, values: Vec<TS::Value>, ctx: &mut ExecutionEngine<TS>) -> TS::Value;
+    /// What this initializer builds. Arguments are checked against the kind before they are
+    /// evaluated. Defaults to [InitKind::Other].
+    fn kind(&self) -> InitKind {
+        InitKind::Other
+    }
+
+    /// Whether initializing always succeeds without side effects, so an initialization whose
+    /// value is discarded can be dropped. Defaults to `false`.
+    fn is_pure(&self) -> bool {
+        false
+    }
+
+    fn initialize(
+        &self,
+        values: Vec<TS::Value>,
+        ctx: &mut ExecutionEngine<TS>,
+    ) -> Result<TS::Value, FreightError>;
+
+    /// Build a value directly into the slot it is being assigned to, used when an initializer is
+    /// the immediate child of `AssignStack` or `AssignGlobal`.
+    /// Override this to avoid constructing a fresh value which then has to be assigned and dropped.
+    fn initialize_into(
+        &self,
+        values: Vec<TS::Value>,
+        target: &mut TS::Value,
+        ctx: &mut ExecutionEngine<TS>,
+    ) -> Result<(), FreightError> {
+        target.assign(self.initialize(values, ctx)?);
+        Ok(())
+    }
 }
 
 impl<TS: crate::TypeSystem> Initializer<TS> for () {
-    fn initialize(&self, _: Vec<TS::Value>, _: &mut ExecutionEngine<TS>) -> TS::Value {
-        TS::Value::default()
+    fn initialize(
+        &self,
+        _: Vec<TS::Value>,
+        _: &mut ExecutionEngine<TS>,
+    ) -> Result<TS::Value, FreightError> {
+        Ok(TS::Value::default())
     }
 }
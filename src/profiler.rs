@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use crate::{
+    execution_engine::ExecutionEngine,
+    expression::{Expression, SpanId},
+    TypeSystem,
+};
+
+/// Counts how many times each expression node is evaluated while profiling is enabled
+#[derive(Debug, Default)]
+pub struct Profiler {
+    evaluations: HashMap<usize, u64>,
+}
+
+impl Profiler {
+    #[inline]
+    pub(crate) fn record<TS: TypeSystem>(&mut self, expr: &Expression<TS>) {
+        *self
+            .evaluations
+            .entry(expr as *const Expression<TS> as usize)
+            .or_default() += 1;
+    }
+
+    /// Discard all recorded counts
+    pub fn clear(&mut self) {
+        self.evaluations.clear();
+    }
+
+    fn count<TS: TypeSystem>(&self, expr: &Expression<TS>) -> u64 {
+        self.evaluations
+            .get(&(expr as *const Expression<TS> as usize))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// An expression subtree inside a registered function and how many times it was evaluated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotSpot {
+    /// The address of the function containing the expression
+    pub function: usize,
+    /// The index of the top-level expression in the function body, followed by the index of each
+    /// child expression leading to this one
+    pub path: Vec<usize>,
+    /// The span of the innermost [Expression::Spanned] containing the expression, or of the
+    /// expression itself if it is one
+    pub span: Option<SpanId>,
+    /// How many times the expression was evaluated
+    pub evaluations: u64,
+}
+
+/// The hottest expression subtrees of a profiled run, hottest first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HotPathReport {
+    pub hot_spots: Vec<HotSpot>,
+    /// The totals of every expression evaluated, including those past the limit
+    function_totals: Vec<(usize, u64)>,
+}
+
+impl HotPathReport {
+    /// Build a report from the counts recorded by a profiler, keeping at most `limit` entries
+    pub fn new<TS: TypeSystem>(
+        profiler: &Profiler,
+        engine: &ExecutionEngine<TS>,
+        limit: usize,
+    ) -> HotPathReport {
+        let mut hot_spots = vec![];
+        for (function, func) in engine.functions().iter().enumerate() {
+            for (i, expr) in func.expressions.iter().enumerate() {
+                collect_hot_spots(profiler, function, &mut vec![i], None, expr, &mut hot_spots);
+            }
+        }
+        let mut totals: HashMap<usize, u64> = HashMap::new();
+        for spot in &hot_spots {
+            *totals.entry(spot.function).or_default() += spot.evaluations;
+        }
+        let mut function_totals: Vec<_> = totals.into_iter().collect();
+        function_totals.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot_spots.sort_by(|a, b| {
+            b.evaluations
+                .cmp(&a.evaluations)
+                .then(a.function.cmp(&b.function))
+                .then(a.path.cmp(&b.path))
+        });
+        hot_spots.truncate(limit);
+        HotPathReport {
+            hot_spots,
+            function_totals,
+        }
+    }
+
+    /// Total evaluations recorded in each function, hottest first, counting every expression
+    /// rather than only the hot spots kept in the report. Functions near the top are the best
+    /// candidates for being rewritten as native functions.
+    pub fn by_function(&self) -> Vec<(usize, u64)> {
+        self.function_totals.clone()
+    }
+}
+
+fn collect_hot_spots<TS: TypeSystem>(
+    profiler: &Profiler,
+    function: usize,
+    path: &mut Vec<usize>,
+    span: Option<SpanId>,
+    expr: &Expression<TS>,
+    hot_spots: &mut Vec<HotSpot>,
+) {
+    let evaluations = profiler.count(expr);
+    if evaluations == 0 {
+        return;
+    }
+    let span = match expr {
+        Expression::Spanned(span, _) => Some(*span),
+        _ => span,
+    };
+    hot_spots.push(HotSpot {
+        function,
+        path: path.clone(),
+        span,
+        evaluations,
+    });
+    for (i, child) in expr.children().into_iter().enumerate() {
+        path.push(i);
+        collect_hot_spots(profiler, function, path, span, child, hot_spots);
+        path.pop();
+    }
+}
@@ -0,0 +1,521 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{Debug, Write},
+    hash::Hasher,
+    io,
+    rc::Rc,
+};
+
+use crate::{
+    error::FreightError,
+    execution_engine::ExecutionEngine,
+    expression::{Expression, NativeFunction, SpanId, VariableType},
+    function::{ArgCount, Function, FunctionRef, FunctionType},
+    symbols::{Symbol, SymbolId},
+    value::Value,
+    visit::Visit,
+    TypeSystem,
+};
+
+/// A 64 bit FNV-1a hasher, whose output is stable across processes and platforms
+#[derive(Debug, Clone, Copy)]
+pub struct StableHasher {
+    hash: u64,
+    /// Whether a native without a name was written, whose hash differs between processes
+    unnamed_native: bool,
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher {
+            hash: 0xcbf29ce484222325,
+            unnamed_native: false,
+        }
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= *byte as u64;
+            self.hash = self.hash.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+impl Write for StableHasher {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl StableHasher {
+    pub fn write_number(&mut self, n: usize) {
+        self.write(&(n as u64).to_le_bytes());
+    }
+
+    /// Write a native's name, or its address if it has none
+    pub fn write_native<TS: TypeSystem>(&mut self, native: &NativeFunction<TS>) {
+        match native.name() {
+            Some(name) => {
+                self.write(&[1]);
+                self.write_debug(&name);
+            }
+            None => {
+                self.write(&[2]);
+                self.write_number(**native as usize);
+                self.unnamed_native = true;
+            }
+        }
+    }
+
+    pub fn write_debug(&mut self, value: &impl Debug) {
+        let _ = write!(self, "{value:?}");
+        // Separate consecutive values so their outputs can't run together
+        self.write(&[0]);
+    }
+}
+
+/// Hash the structure of an expression tree
+pub fn hash_expression<TS: TypeSystem>(expr: &Expression<TS>, hasher: &mut StableHasher) {
+    hasher.write_number(expr.kind() as usize);
+    match expr {
+        Expression::RawValue(value) => hasher.write_debug(value),
+        Expression::Variable(var) => hasher.write_debug(var),
+        Expression::BinaryOpEval(op, _) => hasher.write_debug(op),
+        Expression::UnaryOpEval(op, _) => hasher.write_debug(op),
+        Expression::TernaryOpEval(op, _) => hasher.write_debug(op),
+        Expression::Initialize(init, _) => hasher.write_debug(init),
+        Expression::StaticFunctionCall(func, _) | Expression::FunctionCapture(func) => {
+            hasher.write_debug(func);
+            if let FunctionType::Native(native) = &func.function_type {
+                hasher.write_native(native);
+            }
+        }
+        Expression::NativeFunctionCall(func, _) => hasher.write_native(func),
+        Expression::AwaitNative(native, _) => {
+            hasher.write_number(native.0 as usize);
+            hasher.unnamed_native = true;
+        }
+        Expression::AssignStack(addr, _)
+        | Expression::AssignGlobal(addr, _)
+        | Expression::ReturnTarget(addr, _)
+        | Expression::Return(addr, _)
+        | Expression::FieldGet(_, addr)
+        | Expression::FieldSet(_, addr)
+        | Expression::Repeat(addr, _)
+        | Expression::Escape(addr, _)
+        | Expression::EscapeTo(addr, _)
+        | Expression::Probe(addr, _)
+        | Expression::Spanned(SpanId(addr), _) => hasher.write_number(*addr),
+        Expression::MethodCall(_, name, _) => hasher.write_debug(name),
+        Expression::NamedFunctionCall { named, .. } => {
+            for (name, _) in named {
+                hasher.write_debug(name);
+            }
+        }
+        Expression::ResolveSymbol(id) | Expression::IsDefined { symbol: id, .. } => {
+            hasher.write_number(id.0)
+        }
+        Expression::FreshCells(slots) => hasher.write_debug(slots),
+        Expression::SharedCapture { captures, closures } => {
+            hasher.write_debug(captures);
+            hasher.write_debug(closures);
+        }
+        Expression::DynamicFunctionCall(..)
+        | Expression::CachedDynamicCall(..)
+        | Expression::AssignDynamic(_)
+        | Expression::Catch(_)
+        | Expression::Switch { .. }
+        | Expression::Conditional { .. }
+        | Expression::While { .. }
+        | Expression::Yield(_) => (),
+    }
+    let children = expr.children();
+    hasher.write_number(children.len());
+    for child in children {
+        hash_expression(child, hasher);
+    }
+}
+
+impl<TS: TypeSystem> Function<TS> {
+    /// A hash of this function's signature, frame and body which is equal for structurally
+    /// identical functions
+    pub fn structural_hash(&self) -> u64 {
+        self.structure_hasher().finish()
+    }
+
+    fn structure_hasher(&self) -> StableHasher {
+        let mut hasher = StableHasher::default();
+        hasher.write_debug(&self.arg_count);
+        hasher.write_number(self.stack_size);
+        hasher.write_debug(&self.layout);
+        hasher.write_number(self.return_target);
+        hasher.write_number(self.expressions.len());
+        for expr in &self.expressions {
+            hash_expression(expr, &mut hasher);
+        }
+        hasher
+    }
+}
+
+/// A hash of the functions, globals, runtime types and symbols registered with an engine, which
+/// is equal for engines built from identical inputs, even in different processes
+pub fn fingerprint<TS: TypeSystem>(engine: &ExecutionEngine<TS>) -> Result<u64, FreightError> {
+    let mut hasher = StableHasher::default();
+    let functions = engine.functions();
+    hasher.write_number(functions.len());
+    for (function, func) in functions.iter().enumerate() {
+        let func = func.structure_hasher();
+        if func.unnamed_native {
+            return Err(FreightError::UnnamedNative { function });
+        }
+        hasher.write_u64(func.finish());
+    }
+    hasher.write_number(engine.num_globals);
+    let types = engine.types.types();
+    hasher.write_number(types.len());
+    for info in types {
+        hasher.write_debug(&info.name);
+        hasher.write_debug(&info.fields);
+        let mut methods: Vec<_> = info.methods.iter().collect();
+        methods.sort_by(|a, b| a.0.cmp(b.0));
+        hasher.write_debug(&methods);
+    }
+    hasher.write_debug(&engine.symbols.names());
+    Ok(hasher.finish())
+}
+
+/// Why a reference found by [validate_references] can't be called as written
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReferenceProblem {
+    /// No function is registered at the address the reference points to
+    Unregistered,
+    /// A static call passes a number of arguments the function doesn't accept
+    ArgumentCount { expected: ArgCount, actual: usize },
+}
+
+/// A function reference embedded in a registered function which is invalid
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidReference {
+    /// The address of the function containing the reference
+    pub function: usize,
+    /// The index of the function's top-level expression containing the reference
+    pub expression: usize,
+    /// The address the reference points to
+    pub target: usize,
+    pub problem: ReferenceProblem,
+}
+
+/// Check every function reference embedded in the functions registered with an engine
+pub fn validate_references<TS: TypeSystem>(engine: &ExecutionEngine<TS>) -> Vec<InvalidReference> {
+    let functions = engine.functions();
+    let mut invalid = vec![];
+    for (function, func) in functions.iter().enumerate() {
+        for (expression, expr) in func.expressions.iter().enumerate() {
+            let mut check = |func_ref: &FunctionRef<TS>, args: Option<usize>| {
+                let problem = if !matches!(func_ref.function_type, FunctionType::Native(_))
+                    && func_ref.location >= functions.len()
+                {
+                    ReferenceProblem::Unregistered
+                } else {
+                    match args {
+                        Some(actual) if !func_ref.arg_count.valid_arg_count(actual) => {
+                            ReferenceProblem::ArgumentCount {
+                                expected: func_ref.arg_count,
+                                actual,
+                            }
+                        }
+                        _ => return,
+                    }
+                };
+                invalid.push(InvalidReference {
+                    function,
+                    expression,
+                    target: func_ref.location,
+                    problem,
+                });
+            };
+            visit_references(expr, &mut check);
+        }
+    }
+    invalid
+}
+
+pub(crate) fn visit_references<TS: TypeSystem>(
+    expr: &Expression<TS>,
+    check: &mut impl FnMut(&FunctionRef<TS>, Option<usize>),
+) {
+    expr.visit(&mut |expr: &Expression<TS>| {
+        match expr {
+            Expression::StaticFunctionCall(func, args) => check(func, Some(args.len())),
+            Expression::FunctionCapture(func) => check(func, None),
+            Expression::SharedCapture { closures, .. } => {
+                for (func, _) in closures {
+                    check(func, None);
+                }
+            }
+            Expression::RawValue(value) => {
+                if let Some(func) = value.cast_to_function() {
+                    check(func, None);
+                }
+            }
+            _ => (),
+        }
+        Visit::Descend
+    });
+}
+
+/// Hash every function in a function table, to be compared against a later version with
+/// [ProgramDiff::from_hashes]
+pub fn function_hashes<TS: TypeSystem>(functions: &[Rc<Function<TS>>]) -> Vec<u64> {
+    functions
+        .iter()
+        .map(|func| func.structural_hash())
+        .collect()
+}
+
+/// The functions which differ between two versions of a program, identified by their address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProgramDiff {
+    /// Functions present in both versions whose bodies differ
+    pub changed: Vec<usize>,
+    /// Functions only present in the new version
+    pub added: Vec<usize>,
+    /// Functions only present in the old version
+    pub removed: Vec<usize>,
+}
+
+impl ProgramDiff {
+    /// Compare two function tables
+    pub fn new<TS: TypeSystem>(old: &[Rc<Function<TS>>], new: &[Rc<Function<TS>>]) -> ProgramDiff {
+        Self::from_hashes(&function_hashes(old), &function_hashes(new))
+    }
+
+    /// Compare the hashes of two function tables, see [function_hashes]
+    pub fn from_hashes(old: &[u64], new: &[u64]) -> ProgramDiff {
+        let changed = old
+            .iter()
+            .zip(new)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(i, _)| i)
+            .collect();
+        ProgramDiff {
+            changed,
+            added: (old.len()..new.len()).collect(),
+            removed: (new.len()..old.len()).collect(),
+        }
+    }
+
+    /// Whether both versions are identical
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// The names of the functions and globals which symbols are bound to, see
+/// [ExecutionEngine::dump_program]
+struct DumpNames<'a> {
+    functions: HashMap<usize, &'a str>,
+    globals: HashMap<usize, &'a str>,
+}
+
+impl<TS: TypeSystem> ExecutionEngine<TS> {
+    /// Write a listing of every registered function, in address order, with one expression per
+    /// line indented below its parent
+    pub fn dump_program(&self, out: &mut impl io::Write) -> io::Result<()> {
+        let mut names = DumpNames {
+            functions: HashMap::new(),
+            globals: HashMap::new(),
+        };
+        for (id, name) in self.symbols.names().iter().enumerate() {
+            match self.symbols.resolve(SymbolId(id)) {
+                Some(Symbol::Function(func))
+                    if !matches!(func.function_type, FunctionType::Native(_)) =>
+                {
+                    names.functions.entry(func.location).or_insert(name);
+                }
+                Some(Symbol::Global(addr)) => {
+                    names.globals.entry(*addr).or_insert(name);
+                }
+                _ => (),
+            }
+        }
+        for (addr, func) in self.functions().iter().enumerate() {
+            write!(out, "fn {addr}")?;
+            if let Some(name) = names.functions.get(&addr) {
+                write!(out, " {name}")?;
+            }
+            writeln!(out, " return {}", func.return_target)?;
+            for expr in &func.expressions {
+                self.dump_expression(out, expr, 1, &names)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn dump_expression(
+        &self,
+        out: &mut impl io::Write,
+        expr: &Expression<TS>,
+        depth: usize,
+        names: &DumpNames,
+    ) -> io::Result<()> {
+        let func_ref = |func: &FunctionRef<TS>| match (
+            &func.function_type,
+            names.functions.get(&func.location),
+        ) {
+            (FunctionType::Native(_), _) => format!("native {}", func.location),
+            (_, Some(name)) => format!("fn {} {name}", func.location),
+            (_, None) => format!("fn {}", func.location),
+        };
+        let global = |addr: &usize| match names.globals.get(addr) {
+            Some(name) => format!("global {addr} {name}"),
+            None => format!("global {addr}"),
+        };
+        let variable = |var: &VariableType| match var {
+            VariableType::Stack(addr) => format!("stack {addr}"),
+            VariableType::Captured(addr) => format!("captured {addr}"),
+            VariableType::Global(addr) => global(addr),
+        };
+        let operands = match expr {
+            Expression::RawValue(value) => match value.cast_to_function() {
+                Some(func) => func_ref(func),
+                None => format!("{value:?}"),
+            },
+            Expression::Variable(var) => variable(var),
+            Expression::BinaryOpEval(op, _) => format!("{op:?}"),
+            Expression::UnaryOpEval(op, _) => format!("{op:?}"),
+            Expression::TernaryOpEval(op, _) => format!("{op:?}"),
+            Expression::Initialize(init, _) => format!("{init:?}"),
+            Expression::StaticFunctionCall(func, _) | Expression::FunctionCapture(func) => {
+                func_ref(func)
+            }
+            Expression::AssignStack(addr, _) => format!("stack {addr}"),
+            Expression::AssignGlobal(addr, _) => global(addr),
+            Expression::ReturnTarget(target, _) | Expression::Return(target, _) => {
+                format!("target {target}")
+            }
+            Expression::Escape(label, _) | Expression::EscapeTo(label, _) => {
+                format!("label {label}")
+            }
+            Expression::MethodCall(_, name, _) => name.to_string(),
+            Expression::FieldGet(_, field) | Expression::FieldSet(_, field) => {
+                format!("field {field}")
+            }
+            Expression::ResolveSymbol(id) | Expression::IsDefined { symbol: id, .. } => {
+                self.symbols.name(*id).to_string()
+            }
+            Expression::FreshCells(slots) => format!("stack {slots:?}"),
+            Expression::Repeat(times, _) => times.to_string(),
+            Expression::Probe(probe, _) => format!("probe {probe}"),
+            Expression::Spanned(SpanId(span), _) => format!("span {span}"),
+            Expression::SharedCapture { captures, closures } => {
+                let captures: Vec<_> = captures.iter().map(variable).collect();
+                let closures: Vec<_> = closures
+                    .iter()
+                    .map(|(func, addr)| format!("{} -> stack {addr}", func_ref(func)))
+                    .collect();
+                format!("[{}] [{}]", captures.join(", "), closures.join(", "))
+            }
+            Expression::NamedFunctionCall { args, named, .. } => {
+                let named: Vec<_> = named.iter().map(|(name, _)| &**name).collect();
+                format!("{} [{}]", args.len(), named.join(", "))
+            }
+            Expression::DynamicFunctionCall(..)
+            | Expression::CachedDynamicCall(..)
+            | Expression::NativeFunctionCall(..)
+            | Expression::AssignDynamic(_)
+            | Expression::Catch(_)
+            | Expression::Switch { .. }
+            | Expression::Conditional { .. }
+            | Expression::While { .. }
+            | Expression::Yield(_)
+            | Expression::AwaitNative(..) => String::new(),
+        };
+        write!(out, "{:indent$}{:?}", "", expr.kind(), indent = depth * 2)?;
+        if !operands.is_empty() {
+            write!(out, " {operands}")?;
+        }
+        writeln!(out)?;
+        for child in expr.children() {
+            self.dump_expression(out, child, depth + 1, names)?;
+        }
+        Ok(())
+    }
+}
+
+/// Split a listing written by [ExecutionEngine::dump_program] into the lines of each function,
+/// by address
+fn dump_sections(dump: &str) -> BTreeMap<usize, Vec<&str>> {
+    let mut sections = BTreeMap::new();
+    let mut current = None;
+    for line in dump.lines() {
+        let addr = line
+            .strip_prefix("fn ")
+            .and_then(|rest| rest.split(' ').next())
+            .and_then(|addr| addr.parse().ok());
+        if let Some(addr) = addr {
+            current = Some(addr);
+        }
+        if let Some(addr) = current {
+            sections.entry(addr).or_insert_with(Vec::new).push(line);
+        }
+    }
+    sections
+}
+
+/// Compare two listings written by [ExecutionEngine::dump_program], writing the functions which
+/// differ with each line prefixed by `-`, `+` or a space
+pub fn diff_dumps(old: &str, new: &str) -> String {
+    let old = dump_sections(old);
+    let new = dump_sections(new);
+    let mut addrs: Vec<_> = old.keys().chain(new.keys()).copied().collect();
+    addrs.sort();
+    addrs.dedup();
+    let mut diff = String::new();
+    for addr in addrs {
+        let old = old.get(&addr).map_or(&[][..], Vec::as_slice);
+        let new = new.get(&addr).map_or(&[][..], Vec::as_slice);
+        if old != new {
+            diff_lines(old, new, &mut diff);
+        }
+    }
+    diff
+}
+
+/// Write the lines of a diff between two sequences of lines, from their longest common
+/// subsequence
+fn diff_lines(old: &[&str], new: &[&str], diff: &mut String) {
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            let _ = writeln!(diff, " {}", old[i]);
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            let _ = writeln!(diff, "-{}", old[i]);
+            i += 1;
+        } else {
+            let _ = writeln!(diff, "+{}", new[j]);
+            j += 1;
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Limits how often a native function may be called
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The number of calls allowed per window
+    pub max_calls: u64,
+    /// How long each window lasts, or `None` for a quota over the lifetime of the engine
+    pub window: Option<Duration>,
+}
+
+impl RateLimit {
+    /// Allow a fixed number of calls in total
+    pub fn quota(max_calls: u64) -> RateLimit {
+        RateLimit {
+            max_calls,
+            window: None,
+        }
+    }
+
+    /// Allow a number of calls in each window of time
+    pub fn per(max_calls: u64, window: Duration) -> RateLimit {
+        RateLimit {
+            max_calls,
+            window: Some(window),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LimitState {
+    limit: RateLimit,
+    calls: u64,
+    window_start: Instant,
+}
+
+/// The rate limits of native functions, keyed by the id they were created with in
+/// [crate::function::FunctionRef::new_native]
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    limits: HashMap<usize, LimitState>,
+}
+
+impl RateLimits {
+    /// Limit calls to a native, resetting its call count
+    pub fn insert(&mut self, native: usize, limit: RateLimit) {
+        self.limits.insert(
+            native,
+            LimitState {
+                limit,
+                calls: 0,
+                window_start: Instant::now(),
+            },
+        );
+    }
+
+    pub fn remove(&mut self, native: usize) -> Option<RateLimit> {
+        self.limits.remove(&native).map(|state| state.limit)
+    }
+
+    pub fn get(&self, native: usize) -> Option<RateLimit> {
+        self.limits.get(&native).map(|state| state.limit)
+    }
+
+    /// Record a call to a native, returning `false` if it exceeds its limit
+    pub(crate) fn acquire(&mut self, native: usize) -> bool {
+        let Some(state) = self.limits.get_mut(&native) else {
+            return true;
+        };
+        if let Some(window) = state.limit.window {
+            let now = Instant::now();
+            if now.duration_since(state.window_start) >= window {
+                state.window_start = now;
+                state.calls = 0;
+            }
+        }
+        if state.calls >= state.limit.max_calls {
+            return false;
+        }
+        state.calls += 1;
+        true
+    }
+
+    /// Forget all recorded calls, keeping the limits
+    pub fn reset(&mut self) {
+        let now = Instant::now();
+        for state in self.limits.values_mut() {
+            state.calls = 0;
+            state.window_start = now;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+}
@@ -0,0 +1,39 @@
+use std::fmt::Debug;
+
+use crate::{execution_engine::ExecutionEngine, TypeSystem};
+
+/// Lets a type system allocate the temporary values created during a function call from a region
+/// which is freed all at once when the call ends.
+///
+/// The engine calls [RegionAlloc::enter_call] when a function's stack frame has been set up and
+/// [RegionAlloc::exit_call] when the call finishes, whether or not it succeeded. Calls nest, so
+/// regions must be managed as a stack. Values which outlive the call, such as the return value or
+/// values assigned to globals, must be moved out of the region by the type system.
+pub trait RegionAlloc<TS: TypeSystem>: Debug {
+    /// Identifies the region of a call, for example the offset of a bump allocator
+    type Mark;
+
+    /// Start a region for a function call
+    fn enter_call(engine: &mut ExecutionEngine<TS>) -> Self::Mark;
+
+    /// Free the region of a function call. The call's return value is given so it can be moved
+    /// out of the region first. If the call is being unwound by an
+    /// [crate::expression::Expression::Return] to a target outside of it, the value being
+    /// returned is given instead. It is `None` if the call failed.
+    fn exit_call(
+        engine: &mut ExecutionEngine<TS>,
+        mark: Self::Mark,
+        result: Option<&mut TS::Value>,
+    );
+}
+
+/// Allocates no regions
+impl<TS: TypeSystem> RegionAlloc<TS> for () {
+    type Mark = ();
+
+    #[inline(always)]
+    fn enter_call(_: &mut ExecutionEngine<TS>) {}
+
+    #[inline(always)]
+    fn exit_call(_: &mut ExecutionEngine<TS>, _: (), _: Option<&mut TS::Value>) {}
+}
@@ -0,0 +1,188 @@
+use crate::{
+    expression::{Expression, VariableType},
+    operators::{BinaryOperator, TernaryOperator, UnaryOperator},
+    value::Value,
+    TypeSystem,
+};
+
+/// A simplification of expression trees, run over every function when it is registered, which
+/// must not keep replacing an expression with one it will rewrite again
+pub trait Rewriter<TS: TypeSystem> {
+    /// Return a replacement for an expression whose children have been rewritten, if any
+    fn rewrite(&mut self, expr: &Expression<TS>) -> Option<Expression<TS>>;
+
+    /// Replace an expression in place, returning whether it was replaced
+    fn rewrite_in_place(&mut self, expr: &mut Expression<TS>) -> bool {
+        match self.rewrite(expr) {
+            Some(replacement) => {
+                *expr = replacement;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<TS: TypeSystem, F> Rewriter<TS> for F
+where
+    F: FnMut(&Expression<TS>) -> Option<Expression<TS>>,
+{
+    fn rewrite(&mut self, expr: &Expression<TS>) -> Option<Expression<TS>> {
+        self(expr)
+    }
+}
+
+/// Apply rewriters to an expression tree bottom-up until nothing changes, returning whether
+/// anything was replaced
+pub fn rewrite<TS: TypeSystem, R: Rewriter<TS> + ?Sized>(
+    expr: &mut Expression<TS>,
+    rewriters: &mut [Box<R>],
+) -> bool {
+    let mut changed = false;
+    for child in expr.children_mut() {
+        changed |= rewrite(child, rewriters);
+    }
+    for i in 0..rewriters.len() {
+        if rewriters[i].rewrite_in_place(expr) {
+            rewrite(expr, rewriters);
+            return true;
+        }
+    }
+    changed
+}
+
+/// Replaces pure operators applied to constant operands with their result
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConstantFolder;
+
+impl<TS: TypeSystem> Rewriter<TS> for ConstantFolder {
+    fn rewrite(&mut self, expr: &Expression<TS>) -> Option<Expression<TS>> {
+        let value = match expr {
+            Expression::UnaryOpEval(op, operand) if op.is_pure() => {
+                let Expression::RawValue(operand) = &**operand else {
+                    return None;
+                };
+                op.apply_1(operand)
+            }
+            Expression::BinaryOpEval(op, operands) if op.is_pure() => {
+                let [Expression::RawValue(l), r] = &**operands else {
+                    return None;
+                };
+                match (op.short_circuit(l), r) {
+                    // The right operand would never be evaluated, whatever it is
+                    (Some(value), _) => value,
+                    (None, Expression::RawValue(r)) => op.apply_2(l, r),
+                    _ => return None,
+                }
+            }
+            Expression::TernaryOpEval(op, operands) if op.is_pure() => {
+                let [Expression::RawValue(a), Expression::RawValue(b), Expression::RawValue(c)] =
+                    &**operands
+                else {
+                    return None;
+                };
+                op.apply_3(a, b, c)
+            }
+            _ => return None,
+        };
+        Some(Expression::RawValue(value))
+    }
+}
+
+/// Replaces chains of conditionals comparing a variable for equality with constants, whose
+/// discriminants select every arm from the first, with an [Expression::Switch]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SwitchRewriter;
+
+impl SwitchRewriter {
+    /// The variable a conditional compares, and the arm the constant it is compared with selects
+    fn case<TS: TypeSystem>(expr: &Expression<TS>) -> Option<(&VariableType, usize)> {
+        let Expression::Conditional { condition, .. } = expr else {
+            return None;
+        };
+        let Expression::BinaryOpEval(op, operands) = &**condition else {
+            return None;
+        };
+        if !op.is_equality() {
+            return None;
+        }
+        match &**operands {
+            [Expression::Variable(var), Expression::RawValue(value)]
+            | [Expression::RawValue(value), Expression::Variable(var)] => {
+                Some((var, value.as_discriminant()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<TS: TypeSystem> Rewriter<TS> for SwitchRewriter {
+    fn rewrite(&mut self, _: &Expression<TS>) -> Option<Expression<TS>> {
+        None
+    }
+
+    fn rewrite_in_place(&mut self, expr: &mut Expression<TS>) -> bool {
+        let Some((var, _)) = Self::case(expr) else {
+            return false;
+        };
+        let var = var.clone();
+        let mut cases = vec![];
+        let mut tail = &*expr;
+        while let Some((_, arm)) = Self::case(tail).filter(|(v, _)| **v == var) {
+            cases.push(arm);
+            let Expression::Conditional { otherwise, .. } = tail else {
+                unreachable!()
+            };
+            tail = otherwise;
+        }
+        let merged = match tail {
+            Expression::Switch {
+                scrutinee, arms, ..
+            } if matches!(&**scrutinee, Expression::Variable(v) if *v == var) => arms.len(),
+            _ => 0,
+        };
+        let len = cases
+            .iter()
+            .map(|arm| arm + 1)
+            .max()
+            .unwrap_or(0)
+            .max(merged);
+        let covered = (merged..len).all(|arm| cases.contains(&arm));
+        if cases.len() + merged < 2 || !covered {
+            return false;
+        }
+
+        let mut arms: Vec<Option<Expression<TS>>> = (0..len).map(|_| None).collect();
+        let mut tail = std::mem::replace(expr, Expression::RawValue(Default::default()));
+        for arm in cases {
+            let Expression::Conditional {
+                then, otherwise, ..
+            } = tail
+            else {
+                unreachable!()
+            };
+            // Later comparisons with the same constant are never reached
+            arms[arm].get_or_insert(*then);
+            tail = *otherwise;
+        }
+        let default = match tail {
+            Expression::Switch {
+                arms: tail_arms,
+                default,
+                ..
+            } if merged > 0 => {
+                for (arm, tail_arm) in arms.iter_mut().zip(tail_arms) {
+                    arm.get_or_insert(tail_arm);
+                }
+                default
+            }
+            tail => Box::new(tail),
+        };
+        *expr = Expression::Switch {
+            scrutinee: Expression::Variable(var).into(),
+            arms: arms.into_iter().map(Option::unwrap).collect(),
+            default,
+        };
+        true
+    }
+}
@@ -0,0 +1,267 @@
+//! Saving compiled programs and loading them back without recompiling their source, behind the
+//! `serde` feature.
+//!
+//! Native functions are plain function pointers, so they are saved by name: each native a
+//! program uses must be registered in a [NativeTable] under the same name when the program is
+//! saved and when it is loaded. Closures which have already captured their environment can't be
+//! saved, only the functions they were created from.
+
+use std::{any::Any, cell::RefCell, collections::HashMap, rc::Rc};
+
+use serde::{
+    de::{DeserializeOwned, Error as _},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{
+    execution_engine::ExecutionEngine,
+    expression::{Expression, NativeFunction},
+    function::{ArgCount, Function, FunctionMetadata, FunctionWriter, StackLayout},
+    program::validate_references,
+    visit::Visit,
+    TypeSystem,
+};
+
+/// A [TypeSystem] whose values and operators can be serialized, which is required to save
+/// expressions and functions
+pub trait SerializableTypeSystem:
+    TypeSystem<
+    Value: Serialize + DeserializeOwned,
+    UnaryOp: Serialize + DeserializeOwned,
+    BinaryOp: Serialize + DeserializeOwned,
+    TernaryOp: Serialize + DeserializeOwned,
+    Init: Serialize + DeserializeOwned,
+>
+{
+}
+
+impl<TS> SerializableTypeSystem for TS where
+    TS: TypeSystem<
+        Value: Serialize + DeserializeOwned,
+        UnaryOp: Serialize + DeserializeOwned,
+        BinaryOp: Serialize + DeserializeOwned,
+        TernaryOp: Serialize + DeserializeOwned,
+        Init: Serialize + DeserializeOwned,
+    >
+{
+}
+
+thread_local! {
+    /// The [NativeTable] of the innermost active [NativeTable::scope]
+    static ACTIVE: RefCell<Option<Rc<dyn Any>>> = const { RefCell::new(None) };
+}
+
+/// The names native functions are saved under
+#[derive(Debug, Clone)]
+pub struct NativeTable<TS: TypeSystem> {
+    names: HashMap<usize, Rc<str>>,
+    natives: HashMap<Rc<str>, NativeFunction<TS>>,
+}
+
+impl<TS: TypeSystem> Default for NativeTable<TS> {
+    fn default() -> Self {
+        NativeTable {
+            names: HashMap::new(),
+            natives: HashMap::new(),
+        }
+    }
+}
+
+impl<TS: TypeSystem> NativeTable<TS> {
+    /// Register a native under a name, replacing any native previously registered under it
+    pub fn register(&mut self, name: &str, native: NativeFunction<TS>) -> &mut Self {
+        let name: Rc<str> = name.into();
+        if let Some(old) = self.natives.insert(name.clone(), native.clone()) {
+            self.names.remove(&(*old as usize));
+        }
+        self.names.insert(*native as usize, name);
+        self
+    }
+
+    /// The name a native was registered under
+    pub fn name(&self, native: &NativeFunction<TS>) -> Option<&str> {
+        self.names.get(&(**native as usize)).map(|name| &**name)
+    }
+
+    /// The native registered under a name
+    pub fn get(&self, name: &str) -> Option<&NativeFunction<TS>> {
+        self.natives.get(name)
+    }
+
+    /// Run a function which serializes or deserializes expressions, function references or
+    /// functions, resolving natives through this table
+    pub fn scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        struct Restore(Option<Rc<dyn Any>>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                ACTIVE.with(|active| *active.borrow_mut() = self.0.take());
+            }
+        }
+
+        let previous = ACTIVE.with(|active| active.replace(Some(Rc::new(self.clone()))));
+        let _restore = Restore(previous);
+        f()
+    }
+
+    fn with_active<R>(f: impl FnOnce(Option<&Self>) -> R) -> R {
+        ACTIVE.with(|active| {
+            let active = active.borrow();
+            f(active.as_ref().and_then(|table| table.downcast_ref()))
+        })
+    }
+}
+
+impl<TS: TypeSystem> Serialize for NativeFunction<TS> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        NativeTable::with_active(|table| match table {
+            Some(table) => match table.name(self) {
+                Some(name) => serializer.serialize_str(name),
+                None => Err(serde::ser::Error::custom(
+                    "native function is not registered in the native table",
+                )),
+            },
+            None => Err(serde::ser::Error::custom(
+                "native functions can only be serialized in a NativeTable::scope",
+            )),
+        })
+    }
+}
+
+impl<'de, TS: TypeSystem> Deserialize<'de> for NativeFunction<TS> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        NativeTable::with_active(|table| match table {
+            Some(table) => table.get(&name).cloned().ok_or_else(|| {
+                serde::de::Error::custom(format!("no native function is named {name}"))
+            }),
+            None => Err(serde::de::Error::custom(
+                "native functions can only be deserialized in a NativeTable::scope",
+            )),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct FunctionData<E> {
+    expressions: E,
+    return_target: usize,
+    arg_count: ArgCount,
+    stack_size: usize,
+    layout: StackLayout,
+    statements: Vec<usize>,
+    #[serde(default)]
+    metadata: Option<Rc<FunctionMetadata>>,
+}
+
+impl<TS: SerializableTypeSystem> Serialize for Function<TS> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        FunctionData {
+            expressions: &self.expressions,
+            return_target: self.return_target,
+            arg_count: self.arg_count,
+            stack_size: self.stack_size,
+            layout: self.layout.clone(),
+            statements: self.statements.clone(),
+            metadata: self.metadata.clone(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, TS: SerializableTypeSystem> Deserialize<'de> for Function<TS> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = FunctionData::<Vec<Expression<TS>>>::deserialize(deserializer)?;
+        let mut func = Function::new(data.expressions, data.return_target);
+        func.metadata = data.metadata;
+        func.arg_count = data.arg_count;
+        func.stack_size = data.stack_size;
+        func.layout = data.layout;
+        if data.statements.len() != func.expressions.len() {
+            return Err(D::Error::custom(
+                "a function must have a statement index for each expression",
+            ));
+        }
+        func.statements = data.statements;
+        Ok(func)
+    }
+}
+
+/// The parts of an engine which make up a program
+#[derive(Serialize, Deserialize)]
+struct Program<F> {
+    functions: F,
+    globals: usize,
+    return_targets: usize,
+    escape_labels: usize,
+}
+
+impl<TS: SerializableTypeSystem> ExecutionEngine<TS> {
+    /// Save the registered functions, along with the number of globals, return targets and
+    /// escape labels the program uses. References to the functions can be saved separately
+    /// within [NativeTable::scope].
+    pub fn save_program<S: Serializer>(
+        &self,
+        natives: &NativeTable<TS>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let program = Program {
+            functions: self.functions(),
+            globals: self.num_globals,
+            return_targets: self.next_return_target,
+            escape_labels: self.next_escape_label,
+        };
+        natives.scope(|| program.serialize(serializer))
+    }
+
+    /// Load a program saved with [ExecutionEngine::save_program], replacing the registered
+    /// functions. Globals are created until there are as many as the program uses.
+    ///
+    /// Each function goes through the engine's rewriters, lints and instrumentation like a
+    /// registered function, except that global reads aren't hoisted since references saved with
+    /// the program record the size of its frame. The program is rejected, leaving the registered
+    /// functions in place, if it contains [Expression::Probe]s or any of its function references
+    /// is invalid, see [validate_references].
+    pub fn load_program<'de, D: Deserializer<'de>>(
+        &mut self,
+        natives: &NativeTable<TS>,
+        deserializer: D,
+    ) -> Result<(), D::Error> {
+        let program: Program<Vec<Box<Function<TS>>>> =
+            natives.scope(|| Program::deserialize(deserializer))?;
+        if let Some(function) = program.functions.iter().position(|func| has_probes(func)) {
+            return Err(D::Error::custom(format!(
+                "function {function} contains probes, which can't be loaded"
+            )));
+        }
+        let previous = std::mem::replace(&mut self.functions, vec![].into());
+        for (location, func) in program.functions.into_iter().enumerate() {
+            let return_target = func.return_target;
+            let mut func = FunctionWriter::from_function(*func);
+            self.prepare_function(&mut func, location, false);
+            unsafe { &mut *self.functions.get() }.push(Rc::new(func.build(return_target)));
+        }
+        if let Some(invalid) = validate_references(self).first() {
+            self.functions = previous;
+            return Err(D::Error::custom(format!(
+                "function {} refers to function {} which can't be called as written: {:?}",
+                invalid.function, invalid.target, invalid.problem
+            )));
+        }
+        while self.num_globals < program.globals {
+            self.create_global();
+        }
+        self.next_return_target = self.next_return_target.max(program.return_targets);
+        self.next_escape_label = self.next_escape_label.max(program.escape_labels);
+        Ok(())
+    }
+}
+
+fn has_probes<TS: TypeSystem>(func: &Function<TS>) -> bool {
+    func.expressions.iter().any(|expr| {
+        !expr.visit(&mut |expr: &Expression<TS>| match expr {
+            Expression::Probe(..) => Visit::Stop,
+            _ => Visit::Descend,
+        })
+    })
+}
@@ -0,0 +1,115 @@
+//! Randomness and time for scripts, provided by the engine so the host controls them. Scripts
+//! which only read randomness from [ExecutionEngine::rng] and time from [ExecutionEngine::clock]
+//! behave the same on every run with the same seed and clock, and both are saved in coroutine
+//! checkpoints.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use crate::{execution_engine::ExecutionEngine, expression::NativeFunction, value::ValueFactory};
+
+/// A pseudorandom number generator (SplitMix64) whose whole state is one number, so it can be
+/// saved and restored exactly. It is not suitable for cryptography.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rng {
+    state: u64,
+}
+
+impl Default for Rng {
+    /// A generator with a seed of 0, so scripts are deterministic unless the host seeds it
+    fn default() -> Self {
+        Rng::new(0)
+    }
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    /// The current state, which [Rng::new] continues the sequence from
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A native taking no arguments, which returns a random non-negative int from the engine's
+    /// generator
+    pub fn random_native<TS: ValueFactory>() -> NativeFunction<TS> {
+        NativeFunction::named("services.random", |engine: &mut ExecutionEngine<TS>, _| {
+            Ok(TS::int((engine.rng.next_u64() >> 1) as i64))
+        })
+    }
+}
+
+/// Where scripts read the time from, as a duration since the Unix epoch
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Clock {
+    /// Read the system clock
+    #[default]
+    System,
+    /// Read the system clock, recording every reading so the run can be replayed with
+    /// [Clock::Replay]
+    Recording(Vec<Duration>),
+    /// Return recorded readings in order. Once they run out, the last reading is returned again.
+    Replay(VecDeque<Duration>),
+    /// Start at `now`, and advance by `tick` after every reading. The host can also set `now`
+    /// between calls.
+    Manual { now: Duration, tick: Duration },
+}
+
+impl Clock {
+    pub fn now(&mut self) -> Duration {
+        match self {
+            Clock::System => system_time(),
+            Clock::Recording(readings) => {
+                let now = system_time();
+                readings.push(now);
+                now
+            }
+            Clock::Replay(readings) => match readings.len() {
+                0 => Duration::ZERO,
+                1 => readings[0],
+                _ => readings.pop_front().unwrap(),
+            },
+            Clock::Manual { now, tick } => {
+                let reading = *now;
+                *now += *tick;
+                reading
+            }
+        }
+    }
+
+    /// The readings of a [Clock::Recording], to be replayed with [Clock::Replay]
+    pub fn recorded(&self) -> Option<&[Duration]> {
+        match self {
+            Clock::Recording(readings) => Some(readings),
+            _ => None,
+        }
+    }
+
+    /// A native taking no arguments, which returns the time read from the engine's clock as an
+    /// int of milliseconds since the Unix epoch
+    pub fn now_native<TS: ValueFactory>() -> NativeFunction<TS> {
+        NativeFunction::named("services.now", |engine: &mut ExecutionEngine<TS>, _| {
+            Ok(TS::int(engine.clock.now().as_millis() as i64))
+        })
+    }
+}
+
+fn system_time() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
@@ -8,6 +8,7 @@ use std::{
 };
 
 pub type PooledVec<T> = Pooled<T, Vec<T>>;
+pub type VecPool<T> = SlicePool<T, Vec<T>>;
 pub type PooledRcSlice<T> = Pooled<T, Rc<[T]>>;
 pub type RcSlicePool<T> = SlicePool<T, Rc<[T]>>;
 pub type PooledBoxSlice<T> = Pooled<T, Box<[T]>>;
@@ -17,11 +18,15 @@ pub struct SlicePool<T, C: Poolable<T>> {
     pool: Vec<VecDeque<C>>,
     elem_type: PhantomData<T>,
     max_cache_per: usize,
+    /// The number of elements in containers requested from the pool and not yet released
+    live: usize,
 }
 
 pub struct Pooled<T, C: Poolable<T>> {
     pool: Rc<UnsafeCell<SlicePool<T, C>>>,
     collection: C,
+    /// The capacity counted as live when the collection was requested
+    size: usize,
 }
 
 impl<T, C: Poolable<T> + Clone> Clone for Pooled<T, C> {
@@ -29,6 +34,7 @@ impl<T, C: Poolable<T> + Clone> Clone for Pooled<T, C> {
         Pooled {
             pool: self.pool.clone(),
             collection: self.collection.clone(),
+            size: self.size,
         }
     }
 }
@@ -72,8 +78,11 @@ impl<T, C: Poolable<T>> DerefMut for Pooled<T, C> {
 
 impl<T, C: Poolable<T>> Drop for Pooled<T, C> {
     fn drop(&mut self) {
-        self.collection
-            .insert_to_pool(unsafe { &mut *self.pool.get() });
+        let pool = unsafe { &mut *self.pool.get() };
+        if self.collection.is_last() {
+            pool.live -= self.size;
+        }
+        self.collection.insert_to_pool(pool);
     }
 }
 
@@ -82,6 +91,11 @@ pub trait Poolable<T>: Sized {
     fn with_capacity(capacity: usize) -> Self;
     fn populate(&mut self, next: impl FnMut() -> T, len: usize);
     fn capacity(&self) -> usize;
+
+    /// Whether dropping this releases the container, rather than another handle to it
+    fn is_last(&self) -> bool {
+        true
+    }
 }
 
 impl<T: Default> Poolable<T> for Rc<[T]> {
@@ -111,6 +125,10 @@ impl<T: Default> Poolable<T> for Rc<[T]> {
     fn capacity(&self) -> usize {
         self.len()
     }
+
+    fn is_last(&self) -> bool {
+        Rc::strong_count(self) + Rc::weak_count(self) == 1
+    }
 }
 
 impl<T: Default> Poolable<T> for Box<[T]> {
@@ -135,6 +153,25 @@ impl<T: Default> Poolable<T> for Box<[T]> {
     }
 }
 
+impl<T> Poolable<T> for Vec<T> {
+    fn insert_to_pool(&mut self, pool: &mut SlicePool<T, Self>) {
+        self.clear();
+        pool.insert(std::mem::take(self));
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        Vec::with_capacity(capacity)
+    }
+
+    fn populate(&mut self, mut next: impl FnMut() -> T, len: usize) {
+        self.extend(std::iter::repeat_with(&mut next).take(len));
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity()
+    }
+}
+
 pub trait IntoExactSizeIterator: IntoIterator {
     type ExactSizeIter: ExactSizeIterator<Item = Self::Item>;
 
@@ -166,14 +203,27 @@ impl<T, C: Poolable<T>> Default for SlicePool<T, C> {
 
 impl<T, C: Poolable<T>> SlicePool<T, C> {
     pub fn with_max_cache_per(max_cache_per: usize) -> Self {
+        Self::new(100, max_cache_per)
+    }
+
+    /// Create a pool which keeps up to `max_cache_per` containers of each capacity below
+    /// `buckets` for reuse
+    pub fn new(buckets: usize, max_cache_per: usize) -> Self {
         SlicePool {
-            pool: std::array::from_fn::<_, 100, _>(|_| VecDeque::with_capacity(max_cache_per))
-                .into(),
+            pool: (0..buckets)
+                .map(|_| VecDeque::with_capacity(max_cache_per))
+                .collect(),
             elem_type: PhantomData,
             max_cache_per,
+            live: 0,
         }
     }
 
+    /// The number of elements in containers requested from the pool which are still in use
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
     pub fn insert(&mut self, container: C) {
         if let Some(v) = self.pool.get_mut(container.capacity()) {
             if v.len() < self.max_cache_per {
@@ -184,14 +234,19 @@ impl<T, C: Poolable<T>> SlicePool<T, C> {
 
     pub fn request(cell: Rc<UnsafeCell<Self>>, capacity: usize) -> Pooled<T, C> {
         let this = unsafe { &mut *cell.get() };
-        let collection = this
+        let pooled = this
             .pool
             .get_mut(capacity)
-            .and_then(|cache| cache.pop_back())
-            .unwrap_or_else(|| C::with_capacity(capacity));
+            .and_then(|cache| cache.pop_back());
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_pool_request(pooled.is_some());
+        let collection = pooled.unwrap_or_else(|| C::with_capacity(capacity));
+        let size = collection.capacity();
+        this.live += size;
         Pooled {
             pool: cell,
             collection,
+            size,
         }
     }
 
@@ -0,0 +1,96 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{function::FunctionRef, TypeSystem};
+
+/// An interned name, see [SymbolTable::intern]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SymbolId(pub usize);
+
+/// What a symbol is currently bound to
+#[derive(Debug, Clone)]
+pub enum Symbol<TS: TypeSystem> {
+    Function(FunctionRef<TS>),
+    Global(usize),
+}
+
+/// Maps interned names to functions and globals, so references can be bound late and rebound
+/// without patching every place they are used
+#[derive(Debug, Clone)]
+pub struct SymbolTable<TS: TypeSystem> {
+    ids: HashMap<Rc<str>, SymbolId>,
+    names: Vec<Rc<str>>,
+    bindings: Vec<Option<Symbol<TS>>>,
+}
+
+impl<TS: TypeSystem> Default for SymbolTable<TS> {
+    fn default() -> Self {
+        SymbolTable {
+            ids: HashMap::new(),
+            names: vec![],
+            bindings: vec![],
+        }
+    }
+}
+
+impl<TS: TypeSystem> SymbolTable<TS> {
+    /// Get the id of a name, creating an unbound symbol if the name hasn't been seen before
+    pub fn intern(&mut self, name: &str) -> SymbolId {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let name: Rc<str> = name.into();
+        let id = SymbolId(self.names.len());
+        self.ids.insert(name.clone(), id);
+        self.names.push(name);
+        self.bindings.push(None);
+        id
+    }
+
+    /// Get the id of a name without interning it
+    pub fn lookup(&self, name: &str) -> Option<SymbolId> {
+        self.ids.get(name).copied()
+    }
+
+    /// The name a symbol was interned with
+    pub fn name(&self, id: SymbolId) -> &str {
+        &self.names[id.0]
+    }
+
+    /// Bind a name to a function or global, replacing any previous binding
+    pub fn bind(&mut self, name: &str, symbol: Symbol<TS>) -> SymbolId {
+        let id = self.intern(name);
+        self.bindings[id.0] = Some(symbol);
+        id
+    }
+
+    /// Remove the binding of a symbol, returning what it was bound to
+    pub fn unbind(&mut self, id: SymbolId) -> Option<Symbol<TS>> {
+        self.bindings.get_mut(id.0)?.take()
+    }
+
+    /// Get what a symbol is currently bound to
+    #[inline]
+    pub fn resolve(&self, id: SymbolId) -> Option<&Symbol<TS>> {
+        self.bindings.get(id.0)?.as_ref()
+    }
+
+    /// The names of all interned symbols, in id order
+    pub fn names(&self) -> &[Rc<str>] {
+        &self.names
+    }
+
+    /// The names of bound symbols which scripts can resolve, leaving out those bound to functions
+    /// marked with [FunctionRef::host_only]
+    pub fn visible_names(&self) -> impl Iterator<Item = &str> {
+        self.names
+            .iter()
+            .zip(&self.bindings)
+            .filter(|(_, binding)| match binding {
+                Some(Symbol::Function(func)) => !func.host_only,
+                Some(Symbol::Global(_)) => true,
+                None => false,
+            })
+            .map(|(name, _)| &**name)
+    }
+}
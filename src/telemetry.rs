@@ -0,0 +1,77 @@
+//! Engine counters reported through the [metrics] facade. Install any `metrics` recorder, such as
+//! a Prometheus exporter, in the host to collect them.
+
+use metrics::counter;
+
+use crate::error::FreightError;
+
+/// Function calls, labeled with `kind` as `script` or `native`
+pub const CALLS: &str = "freight_calls_total";
+/// Errors reaching the host, labeled with the `error` variant of [FreightError]
+pub const ERRORS: &str = "freight_errors_total";
+/// Fuel consumed by engines with a fuel limit, see [crate::fuel]
+pub const FUEL_CONSUMED: &str = "freight_fuel_consumed_total";
+/// Requests for a slice from a [crate::slice_pool::SlicePool], labeled with `result` as `hit`
+/// if a pooled slice was reused or `miss` if a new one was allocated
+pub const POOL_REQUESTS: &str = "freight_pool_requests_total";
+/// Lookups in a [crate::cache::ValueCache], labeled with `result` as `hit` or `miss`
+pub const CACHE_REQUESTS: &str = "freight_cache_requests_total";
+
+fn result(hit: bool) -> &'static str {
+    if hit {
+        "hit"
+    } else {
+        "miss"
+    }
+}
+
+pub(crate) fn record_call(native: bool) {
+    let kind = if native { "native" } else { "script" };
+    counter!(CALLS, "kind" => kind).increment(1);
+}
+
+pub(crate) fn record_error(error: &FreightError) {
+    let variant = match error {
+        FreightError::InvalidInvocationTarget => "InvalidInvocationTarget",
+        FreightError::IncorrectArgumentCount { .. } => "IncorrectArgumentCount",
+        FreightError::Return { .. } => "Return",
+        FreightError::Escape { .. } => "Escape",
+        FreightError::InvalidInitialization { .. } => "InvalidInitialization",
+        FreightError::MethodNotFound { .. } => "MethodNotFound",
+        FreightError::FieldNotFound { .. } => "FieldNotFound",
+        FreightError::UnresolvedSymbol { .. } => "UnresolvedSymbol",
+        FreightError::Cancelled => "Cancelled",
+        FreightError::OutOfFuel => "OutOfFuel",
+        FreightError::OutOfMemory => "OutOfMemory",
+        FreightError::NestingTooDeep { .. } => "NestingTooDeep",
+        FreightError::CyclicValue => "CyclicValue",
+        FreightError::IncompatibleFunction { .. } => "IncompatibleFunction",
+        FreightError::IncompatibleReplacement { .. } => "IncompatibleReplacement",
+        FreightError::FunctionReplaced { .. } => "FunctionReplaced",
+        FreightError::ForkConflict { .. } => "ForkConflict",
+        FreightError::RateLimited { .. } => "RateLimited",
+        FreightError::UnnamedNative { .. } => "UnnamedNative",
+        FreightError::UnsupportedLiteral { .. } => "UnsupportedLiteral",
+        FreightError::Unhashable { .. } => "Unhashable",
+        FreightError::ModuleUnloaded { .. } => "ModuleUnloaded",
+        FreightError::HostOnlyFunction { .. } => "HostOnlyFunction",
+        FreightError::InvalidArgument { .. } => "InvalidArgument",
+        FreightError::ArgumentName { .. } => "ArgumentName",
+        FreightError::InvalidYield => "InvalidYield",
+        FreightError::CoroutineFinished => "CoroutineFinished",
+        FreightError::Runtime { .. } => "Runtime",
+    };
+    counter!(ERRORS, "error" => variant).increment(1);
+}
+
+pub(crate) fn record_fuel(cost: u64) {
+    counter!(FUEL_CONSUMED).increment(cost);
+}
+
+pub(crate) fn record_pool_request(hit: bool) {
+    counter!(POOL_REQUESTS, "result" => result(hit)).increment(1);
+}
+
+pub(crate) fn record_cache_request(hit: bool) {
+    counter!(CACHE_REQUESTS, "result" => result(hit)).increment(1);
+}
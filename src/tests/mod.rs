@@ -1,13 +1,54 @@
 use crate::{
-    execution_engine::ExecutionEngine,
-    expression::Expression,
-    function::{ArgCount, FunctionWriter},
+    args::{Args, FromValue},
+    cache::ValueCache,
+    callbacks::HandlerId,
+    control_flow::ControlFlow,
+    coroutine::{AsyncNativeFunction, CoroutineState},
+    crash::{self, CrashFrame},
+    debugger::{DebugSession, StepAction},
+    docs::{DocTarget, Documentation},
+    error::{rethrow, FreightError},
+    execution_engine::{stack::StackPool, ExecutionEngine, ReferenceHolder},
+    expression::NativeFunction,
+    expression::{Expression, ExpressionKind, SpanId, VariableType},
+    fuel::FuelSchedule,
+    function::{
+        ArgCount, FrameSize, FunctionFeatures, FunctionMetadata, FunctionRef, FunctionWriter,
+    },
+    gc::CycleReport,
+    hooks::UnwindFrame,
+    instrument::ProbeMode,
+    lint::{Diagnostic, Lint},
+    namespace::NamespaceId,
+    operators::InitKind,
+    program::{diff_dumps, function_hashes, InvalidReference, ProgramDiff, ReferenceProblem},
+    rate_limit::RateLimit,
+    services::{Clock, Rng},
+    symbols::Symbol,
+    value::Literal,
+    visit::{Visit, Visitor},
+    watchdog::{CancellationSnapshot, Watchdog, WatchdogAction, IN_LOOP},
+};
+use std::{
+    cell::{RefCell, UnsafeCell},
+    panic::AssertUnwindSafe,
+    rc::Rc,
+    time::Duration,
 };
 
-use self::type_system::{TestBinaryOperator, TestTypeSystem, TestValue, TestValueWrapper};
+use self::type_system::{
+    TestBinaryOperator, TestInitializer, TestRuntimeError, TestTernaryOperator, TestTypeId,
+    TestTypeSystem, TestUnaryOperator, TestValue, TestValueWrapper, REGION_DEPTH, REGION_EXITS,
+};
 
 mod type_system;
 
+fn number(n: i64) -> Expression<TestTypeSystem> {
+    Expression::RawValue(TestValueWrapper(TestValue::Number(n)))
+}
+
+crate::conformance_tests!(TestTypeSystem);
+
 #[test]
 fn test_functions() {
     let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
@@ -22,21 +63,3974 @@ fn test_functions() {
     let mut main = FunctionWriter::new(ArgCount::Fixed(0));
     let x = main.create_variable();
     let y = main.create_variable();
+    main.evaluate_expression(Expression::AssignStack(x, number(3).into()));
+    main.evaluate_expression(Expression::AssignStack(y, number(2).into()));
+    main.evaluate_expression(Expression::StaticFunctionCall(
+        add,
+        vec![Expression::stack(x), Expression::stack(y)],
+    ));
+    let main = engine.register_function(main, 0);
+    assert_eq!(
+        engine.call(&main, []).unwrap(),
+        TestValueWrapper(TestValue::Number(5))
+    );
+}
+
+#[test]
+fn test_catch() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.evaluate_expression(Expression::Catch(
+        Expression::DynamicFunctionCall(number(1).into(), vec![]).into(),
+    ));
+    let main = engine.register_function(main, 0);
+    assert_eq!(
+        engine.call(&main, []).unwrap(),
+        TestValueWrapper(TestValue::Error(FreightError::InvalidInvocationTarget))
+    );
+
+    let one_arg = engine.register_function(FunctionWriter::new(ArgCount::Fixed(1)), 0);
+    assert_eq!(
+        engine.call_checked(&one_arg, []),
+        TestValueWrapper(TestValue::Error(FreightError::IncorrectArgumentCount {
+            expected_min: 1,
+            expected_max: Some(1),
+            actual: 0,
+        }))
+    );
+
+    // A caught error is raised again as it was
+    let rethrow = rethrow::<TestTypeSystem>();
+    let caught =
+        Expression::Catch(Expression::ResolveSymbol(engine.symbols.intern("missing")).into());
+    let error = engine.evaluate(&caught).unwrap();
+    assert_eq!(
+        engine.evaluate(&Expression::NativeFunctionCall(
+            rethrow.clone(),
+            vec![Expression::RawValue(error)]
+        )),
+        Err(FreightError::UnresolvedSymbol {
+            name: "missing".into()
+        })
+    );
+    assert!(matches!(
+        engine.evaluate(&Expression::NativeFunctionCall(rethrow, vec![number(1)])),
+        Err(FreightError::InvalidArgument { index: 0, .. })
+    ));
+}
+
+#[test]
+fn test_initializer_failure() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let list = engine.evaluate(&Expression::Initialize(
+        TestInitializer::NumberList,
+        vec![number(1), number(2)],
+    ));
+    assert_eq!(
+        list.unwrap(),
+        TestValueWrapper(TestValue::List(vec![
+            TestValueWrapper(TestValue::Number(1)),
+            TestValueWrapper(TestValue::Number(2)),
+        ]))
+    );
+    let invalid = engine.evaluate(&Expression::Initialize(
+        TestInitializer::NumberList,
+        vec![
+            number(1),
+            Expression::RawValue(TestValueWrapper(TestValue::Null)),
+        ],
+    ));
+    assert!(matches!(
+        invalid,
+        Err(FreightError::InvalidInitialization { .. })
+    ));
+}
+
+#[test]
+fn test_initialize_into() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    let x = main.create_variable();
     main.evaluate_expression(Expression::AssignStack(
         x,
-        Expression::RawValue(TestValueWrapper(TestValue::Number(3))).into(),
+        Expression::Initialize(TestInitializer::NumberList, vec![number(1)]).into(),
     ));
     main.evaluate_expression(Expression::AssignStack(
-        y,
-        Expression::RawValue(TestValueWrapper(TestValue::Number(2))).into(),
+        x,
+        Expression::Initialize(TestInitializer::NumberList, vec![number(2), number(3)]).into(),
+    ));
+    main.evaluate_expression(Expression::stack(x));
+    let main = engine.register_function(main, 0);
+    assert_eq!(
+        engine.call(&main, []).unwrap(),
+        TestValueWrapper(TestValue::List(vec![
+            TestValueWrapper(TestValue::Number(2)),
+            TestValueWrapper(TestValue::Number(3)),
+        ]))
+    );
+
+    // Initializing in place still runs the initializer's hooks
+    engine.hooks.register(ExpressionKind::Initialize, |_, _| {
+        Err(FreightError::Cancelled)
+    });
+    assert_eq!(engine.call(&main, []), Err(FreightError::Cancelled));
+    let global = engine.create_global();
+    let assign_global = Expression::AssignGlobal(
+        global,
+        Expression::Initialize(TestInitializer::NumberList, vec![number(1)]).into(),
+    );
+    assert_eq!(
+        engine.evaluate(&assign_global),
+        Err(FreightError::Cancelled)
+    );
+
+    // An initializer reading the global it is assigned to sees its old value
+    engine.hooks = Default::default();
+    let assign_self = Expression::AssignGlobal(
+        global,
+        Expression::Initialize(TestInitializer::WithGlobal(global), vec![number(2)]).into(),
+    );
+    engine
+        .evaluate(&Expression::AssignGlobal(global, number(1).into()))
+        .unwrap();
+    engine.evaluate(&assign_self).unwrap();
+    assert_eq!(
+        engine.evaluate(&Expression::global(global)).unwrap(),
+        TestValueWrapper(TestValue::List(vec![
+            TestValueWrapper(TestValue::Number(1)),
+            TestValueWrapper(TestValue::Number(2)),
+        ]))
+    );
+}
+
+#[cfg(feature = "profiling")]
+#[test]
+fn test_hot_path_report() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut add = FunctionWriter::new(ArgCount::Fixed(2));
+    add.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Add,
+        [
+            Expression::Spanned(SpanId(4), Expression::stack(0).into()),
+            Expression::stack(1),
+        ]
+        .into(),
+    ));
+    let add = engine.register_function(add, 0);
+    engine.start_profiling();
+    for i in 0..3 {
+        let args = [TestValue::Number(i), TestValue::Number(1)].map(TestValueWrapper);
+        engine.call(&add, args).unwrap();
+    }
+    let report = engine.hot_path_report(1);
+    assert_eq!(report.hot_spots.len(), 1);
+    assert_eq!(report.hot_spots[0].function, add.address());
+    assert_eq!(report.hot_spots[0].path, vec![0]);
+    assert_eq!(report.hot_spots[0].evaluations, 3);
+    assert_eq!(report.hot_spots[0].span, None);
+    // The totals count the expressions past the limit
+    assert_eq!(report.by_function(), vec![(add.address(), 12)]);
+    let report = engine.hot_path_report(10);
+    assert_eq!(report.hot_spots.len(), 4);
+    let spans: Vec<_> = report.hot_spots.iter().map(|spot| spot.span).collect();
+    assert_eq!(spans, [None, Some(SpanId(4)), Some(SpanId(4)), None]);
+}
+
+#[test]
+fn test_resolve_dynamic_calls() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut inc = FunctionWriter::new(ArgCount::Fixed(1));
+    inc.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Add,
+        [Expression::stack(0), number(1)].into(),
+    ));
+    let inc = engine.register_function(inc, 0);
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.evaluate_expression(Expression::DynamicFunctionCall(
+        Expression::RawValue(inc.clone().into()).into(),
+        vec![number(1)],
+    ));
+    let main = engine.register_function(main, 0);
+    assert!(matches!(
+        engine.functions()[main.address()].expressions[0],
+        Expression::StaticFunctionCall(..)
+    ));
+    assert_eq!(
+        engine.call(&main, []).unwrap(),
+        TestValueWrapper(TestValue::Number(2))
+    );
+
+    let mut looped = FunctionWriter::new(ArgCount::Fixed(2));
+    looped.evaluate_expression(Expression::Repeat(
+        3,
+        Expression::AssignStack(
+            1,
+            Expression::DynamicFunctionCall(
+                Expression::stack(0).into(),
+                vec![Expression::stack(1)],
+            )
+            .into(),
+        )
+        .into(),
+    ));
+    looped.evaluate_expression(Expression::stack(1));
+    let looped = engine.register_function(looped, 0);
+    let cached = |engine: &ExecutionEngine<TestTypeSystem>| {
+        let Expression::Repeat(_, assign) = &engine.functions()[looped.address()].expressions[0]
+        else {
+            panic!("Expected a loop");
+        };
+        let Expression::AssignStack(_, call) = &**assign else {
+            panic!("Expected an assignment");
+        };
+        let Expression::CachedDynamicCall(_, _, cache) = &**call else {
+            panic!("Expected a cached call");
+        };
+        cache.0.get()
+    };
+    assert_eq!(cached(&engine), None);
+    let result = engine.call(
+        &looped,
+        [inc.clone().into(), TestValueWrapper(TestValue::Number(0))],
+    );
+    assert_eq!(result.unwrap(), TestValueWrapper(TestValue::Number(3)));
+    assert_eq!(cached(&engine), Some((inc.address(), 0)));
+
+    let mut add_two = FunctionWriter::new(ArgCount::Fixed(1));
+    add_two.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Add,
+        [Expression::stack(0), number(2)].into(),
+    ));
+    engine.replace_function(&inc, add_two, 0).unwrap();
+    let result = engine.call(
+        &looped,
+        [inc.clone().into(), TestValueWrapper(TestValue::Number(0))],
+    );
+    assert_eq!(result.unwrap(), TestValueWrapper(TestValue::Number(6)));
+    assert_eq!(cached(&engine), Some((inc.address(), 1)));
+    let result = engine.call(
+        &looped,
+        [
+            TestValueWrapper(TestValue::Number(1)),
+            TestValueWrapper(TestValue::Number(0)),
+        ],
+    );
+    assert!(matches!(result, Err(FreightError::InvalidInvocationTarget)));
+}
+
+#[test]
+fn test_summarize() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let list = TestValueWrapper(TestValue::List(vec![
+        TestValueWrapper(TestValue::Number(1));
+        100
+    ]));
+    engine.verbosity.max_len = 10;
+    assert_eq!(engine.summarize(&list), "TestValueW...");
+    engine.verbosity = Default::default();
+    assert_eq!(
+        engine.summarize(&TestValueWrapper(TestValue::Null)),
+        "TestValueWrapper(Null)"
+    );
+}
+
+#[test]
+fn test_method_call() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let point = engine
+        .types
+        .define_type("Point", vec!["x".into(), "y".into()]);
+    assert_eq!(engine.types.field_index(&point, "y"), Some(1));
+    let mut second = FunctionWriter::new(ArgCount::Fixed(2));
+    second.evaluate_expression(Expression::stack(1));
+    let second = engine.register_function(second, 0);
+    assert!(engine.types.define_method(&point, "second", second));
+
+    let receiver =
+        || Expression::RawValue(TestValueWrapper(TestValue::Struct(point.clone(), vec![])));
+    let arg = || vec![number(4)];
+    let value = engine.evaluate(&Expression::MethodCall(
+        receiver().into(),
+        "second".into(),
+        arg(),
+    ));
+    assert_eq!(value.unwrap(), TestValueWrapper(TestValue::Number(4)));
+    let missing = engine.evaluate(&Expression::MethodCall(
+        receiver().into(),
+        "missing".into(),
+        arg(),
+    ));
+    assert_eq!(
+        missing,
+        Err(FreightError::MethodNotFound {
+            method: "missing".into()
+        })
+    );
+}
+
+#[test]
+fn test_field_access() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let point = engine
+        .types
+        .define_type("Point", vec!["x".into(), "y".into()]);
+    let fields = [3, 4].map(|n| TestValueWrapper(TestValue::Number(n)));
+    let value = TestValueWrapper(TestValue::Struct(point, fields.to_vec()));
+    let y = engine.evaluate(&Expression::FieldGet(
+        Expression::RawValue(value.clone()).into(),
+        1,
+    ));
+    assert_eq!(y.unwrap(), fields[1]);
+    let missing = engine.evaluate(&Expression::FieldSet(
+        [
+            Expression::RawValue(value),
+            Expression::RawValue(fields[0].clone()),
+        ]
+        .into(),
+        2,
     ));
+    assert_eq!(missing, Err(FreightError::FieldNotFound { index: 2 }));
+}
+
+#[test]
+fn test_resolve_symbol() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let config = engine.symbols.intern("config");
+    let expr = Expression::ResolveSymbol(config);
+    assert_eq!(
+        engine.evaluate(&expr),
+        Err(FreightError::UnresolvedSymbol {
+            name: "config".into()
+        })
+    );
+    let global = engine.create_global();
+    engine
+        .evaluate(&Expression::AssignGlobal(global, number(7).into()))
+        .unwrap();
+    engine.symbols.bind("config", Symbol::Global(global));
+    assert_eq!(
+        engine.evaluate(&expr).unwrap(),
+        TestValueWrapper(TestValue::Number(7))
+    );
+}
+
+#[test]
+fn test_expression_hooks() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let counter = engine.create_global();
+    engine
+        .hooks
+        .register(ExpressionKind::BinaryOpEval, |engine, _| {
+            if let TestValue::Number(n) = &mut engine.globals[0].0 {
+                *n += 1;
+            }
+            Ok(())
+        });
+    engine
+        .evaluate(&Expression::AssignGlobal(counter, number(1).into()))
+        .unwrap();
+    let add = |l, r| Expression::BinaryOpEval(TestBinaryOperator::Add, [l, r].into());
+    engine
+        .evaluate(&add(add(number(1), number(1)), number(1)))
+        .unwrap();
+    assert_eq!(
+        engine.evaluate(&Expression::global(counter)).unwrap(),
+        TestValueWrapper(TestValue::Number(3))
+    );
+}
+
+#[test]
+fn test_scratch() {
+    let engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut scratch = engine.scratch(4);
+    assert!(scratch.is_empty());
+    assert!(scratch.capacity() >= 4);
+    scratch.push(TestValueWrapper(TestValue::Number(1)));
+    let ptr = scratch.as_ptr();
+    drop(scratch);
+    let reused = engine.scratch(4);
+    assert!(reused.is_empty());
+    assert_eq!(reused.as_ptr(), ptr);
+}
+
+#[test]
+fn test_watchdog() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let sleep = FunctionRef::new_native(
+        0,
+        NativeFunction::new(|_, _| {
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(Default::default())
+        }),
+        ArgCount::Fixed(0),
+    );
+    let after = engine.register_function(FunctionWriter::new(ArgCount::Fixed(1)), 0);
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.evaluate_expression(Expression::StaticFunctionCall(sleep, vec![]));
     main.evaluate_expression(Expression::StaticFunctionCall(
-        add,
-        vec![Expression::stack(x), Expression::stack(y)],
+        after.clone(),
+        vec![number(1)],
+    ));
+    let main = engine.register_function(main, 0);
+    let watchdog = Watchdog::new(
+        engine.cancellation.clone(),
+        Duration::from_millis(10),
+        WatchdogAction::Cancel,
+    );
+    let result = {
+        let _guard = watchdog.guard();
+        engine.call(&main, [])
+    };
+    assert!(watchdog.timed_out());
+    assert_eq!(result, Err(FreightError::Cancelled));
+    assert_eq!(
+        engine.cancelled_at,
+        Some(CancellationSnapshot {
+            function: after.address(),
+            frame: vec!["TestValueWrapper(Number(1))".into()],
+        })
+    );
+}
+
+#[test]
+fn test_switch() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let switch = |n| Expression::Switch {
+        scrutinee: number(n).into(),
+        arms: vec![number(10), number(11)],
+        default: number(-1).into(),
+    };
+    for (n, expected) in [(0, 10), (1, 11), (2, -1), (-5, -1)] {
+        assert_eq!(
+            engine.evaluate(&switch(n)).unwrap(),
+            TestValueWrapper(TestValue::Number(expected))
+        );
+    }
+
+    // Chains of equality comparisons with constants become switches when they're registered
+    let case = |n, then, otherwise| Expression::Conditional {
+        condition: Expression::BinaryOpEval(
+            TestBinaryOperator::Eq,
+            [Expression::stack(0), number(n)].into(),
+        )
+        .into(),
+        then: number(then).into(),
+        otherwise: Box::new(otherwise),
+    };
+    let mut chain = FunctionWriter::new(ArgCount::Fixed(1));
+    chain.evaluate_expression(case(
+        1,
+        11,
+        case(0, 10, case(1, -2, case(2, 12, number(-1)))),
+    ));
+    let chain = engine.register_function(chain, 0);
+    let Expression::Switch { arms, .. } = &engine.functions()[chain.address()].expressions[0]
+    else {
+        panic!("Expected a switch");
+    };
+    assert_eq!(arms.len(), 3);
+    for (n, expected) in [(0, 10), (1, 11), (2, 12), (3, -1), (-5, -1)] {
+        assert_eq!(
+            engine
+                .call(&chain, [TestValueWrapper(TestValue::Number(n))])
+                .unwrap(),
+            TestValueWrapper(TestValue::Number(expected))
+        );
+    }
+    // A switch can't skip an arm, so chains which don't select every arm are left alone
+    let mut sparse = FunctionWriter::new(ArgCount::Fixed(1));
+    sparse.evaluate_expression(case(0, 10, case(2, 12, number(-1))));
+    let sparse = engine.register_function(sparse, 0);
+    assert!(matches!(
+        engine.functions()[sparse.address()].expressions[0],
+        Expression::Conditional { .. }
     ));
+}
+
+#[test]
+fn test_program_diff() {
+    let program = |constants: &[i64]| {
+        let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+        for n in constants {
+            let mut func = FunctionWriter::new(ArgCount::Fixed(0));
+            func.evaluate_expression(number(*n));
+            engine.register_function(func, 0);
+        }
+        engine
+    };
+    let old = program(&[1, 2, 3]);
+    let new = program(&[1, 5, 3, 4]);
+    let old_hashes = function_hashes(old.functions());
+    assert_eq!(
+        new.diff_functions(&old_hashes),
+        ProgramDiff {
+            changed: vec![1],
+            added: vec![3],
+            removed: vec![],
+        }
+    );
+    assert!(ProgramDiff::new(old.functions(), program(&[1, 2, 3]).functions()).is_empty());
+}
+
+#[test]
+fn test_regions() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let observe_depth = FunctionRef::new_native(
+        0,
+        NativeFunction::new(|_, _| {
+            let depth = REGION_DEPTH.with(|depth| depth.get());
+            Ok(TestValueWrapper(TestValue::Number(depth as i64)))
+        }),
+        ArgCount::Fixed(0),
+    );
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.evaluate_expression(Expression::StaticFunctionCall(observe_depth, vec![]));
     let main = engine.register_function(main, 0);
     assert_eq!(
         engine.call(&main, []).unwrap(),
-        TestValueWrapper(TestValue::Number(5))
+        TestValueWrapper(TestValue::Number(2))
+    );
+    assert!(engine
+        .call(&main, [TestValueWrapper(TestValue::Null)])
+        .is_err());
+    assert_eq!(REGION_DEPTH.with(|depth| depth.get()), 0);
+}
+
+#[test]
+fn test_clear_runtime_state() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let global = engine.create_global();
+    let mut set = FunctionWriter::new(ArgCount::Fixed(1));
+    set.evaluate_expression(Expression::AssignGlobal(
+        global,
+        Expression::stack(0).into(),
+    ));
+    let set = engine.register_function(set, 0);
+    engine
+        .call(&set, [TestValueWrapper(TestValue::Number(3))])
+        .unwrap();
+    let handler = engine.callbacks.register("set", 0, set.clone());
+    let namespace = engine.create_namespace();
+    let root = engine.enter_namespace(namespace);
+    engine.callbacks.register("set", 0, set.clone());
+    engine.enter_namespace(root);
+    engine.clear_runtime_state();
+    assert_eq!(
+        engine.evaluate(&Expression::global(global)).unwrap(),
+        TestValueWrapper(TestValue::Null)
+    );
+    assert_eq!(engine.callbacks.handlers("set").count(), 0);
+    assert!(!engine.callbacks.unregister(handler));
+    engine.enter_namespace(namespace);
+    assert_eq!(engine.callbacks.handlers("set").count(), 0);
+    engine.enter_namespace(root);
+    engine
+        .call(&set, [TestValueWrapper(TestValue::Number(4))])
+        .unwrap();
+    assert_eq!(
+        engine.evaluate(&Expression::global(global)).unwrap(),
+        TestValueWrapper(TestValue::Number(4))
+    );
+}
+
+#[test]
+fn test_fuel_schedule() {
+    let add = Expression::BinaryOpEval(TestBinaryOperator::Add, [number(1), number(1)].into());
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    engine.fuel_schedule = FuelSchedule::uniform(1);
+    engine
+        .fuel_schedule
+        .set_cost(ExpressionKind::BinaryOpEval, 10);
+    engine.set_fuel(Some(15));
+    assert!(engine.evaluate(&add).is_ok());
+    assert_eq!(engine.remaining_fuel(), Some(3));
+    assert_eq!(engine.evaluate(&add), Err(FreightError::OutOfFuel));
+    assert_eq!(engine.remaining_fuel(), Some(0));
+
+    let native = NativeFunction::new(|_, _| Ok(Default::default()));
+    engine.fuel_schedule.set_native_cost(&native, 100);
+    engine.set_fuel(Some(100));
+    assert_eq!(
+        engine.evaluate(&Expression::NativeFunctionCall(native, vec![])),
+        Err(FreightError::OutOfFuel)
+    );
+}
+
+#[test]
+fn test_fingerprint() {
+    let build = |n: i64| {
+        let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+        let mut writer = FunctionWriter::new(ArgCount::Fixed(0));
+        writer.evaluate_expression(number(n));
+        engine.register_function(writer, 0);
+        engine.create_global();
+        engine
+            .types
+            .define_type("Point", vec!["x".into(), "y".into()]);
+        engine
+    };
+    let engine = build(1);
+    let fingerprint = engine.fingerprint().unwrap();
+    // The hash doesn't depend on the process, so it can be checked against a fixed value
+    assert_eq!(fingerprint, 10961244860138026702);
+    assert_eq!(fingerprint, build(1).fingerprint().unwrap());
+    assert_ne!(fingerprint, build(2).fingerprint().unwrap());
+    let mut with_global = build(1);
+    with_global.create_global();
+    assert_ne!(fingerprint, with_global.fingerprint().unwrap());
+
+    let hash = |args, native: NativeFunction<TestTypeSystem>| {
+        let mut writer = FunctionWriter::new(args);
+        writer.evaluate_expression(Expression::NativeFunctionCall(native, vec![]));
+        writer.build(0).structural_hash()
+    };
+    let first = NativeFunction::named("first", |_, _| Ok(Default::default()));
+    let second = NativeFunction::named("second", |_, _| Ok(Default::default()));
+    let unnamed = NativeFunction::new(|_, _| Ok(Default::default()));
+    let renamed = NativeFunction::named("second", *unnamed);
+    let fixed = ArgCount::Fixed(0);
+    assert_eq!(hash(fixed, first.clone()), hash(fixed, first.clone()));
+    assert_ne!(hash(fixed, first.clone()), hash(fixed, second.clone()));
+    assert_ne!(
+        hash(fixed, first.clone()),
+        hash(ArgCount::Fixed(1), first.clone())
+    );
+    // Natives are identified by their name rather than their address
+    assert_eq!(hash(fixed, second), hash(fixed, renamed));
+    assert_ne!(
+        hash(fixed, unnamed.clone()),
+        hash(fixed, NativeFunction::named("unnamed", *unnamed))
+    );
+
+    let mut with_native = build(1);
+    let mut writer = FunctionWriter::new(ArgCount::Fixed(0));
+    writer.evaluate_expression(Expression::NativeFunctionCall(first, vec![]));
+    with_native.register_function(writer, 0);
+    assert!(with_native.fingerprint().is_ok());
+    let mut writer = FunctionWriter::new(ArgCount::Fixed(0));
+    writer.evaluate_expression(Expression::NativeFunctionCall(unnamed, vec![]));
+    with_native.register_function(writer, 0);
+    assert!(matches!(
+        with_native.fingerprint(),
+        Err(FreightError::UnnamedNative { function: 2 })
+    ));
+}
+
+#[test]
+fn test_docs() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let native = FunctionRef::new_native(
+        0,
+        NativeFunction::new(|_, _| Ok(Default::default())),
+        ArgCount::Fixed(0),
+    );
+    let script = engine.register_function(FunctionWriter::new(ArgCount::Fixed(0)), 0);
+    let global = engine.create_global();
+    engine.docs.insert(
+        DocTarget::function(&native),
+        Documentation::new("Does nothing").with_signature("noop()"),
+    );
+    engine
+        .docs
+        .insert(DocTarget::Global(global), Documentation::new("A global"));
+    engine
+        .symbols
+        .bind("noop", Symbol::Function(native.clone()));
+    engine
+        .symbols
+        .bind("script", Symbol::Function(script.clone()));
+    engine.symbols.bind("g", Symbol::Global(global));
+
+    let help = engine.help("noop").unwrap();
+    assert_eq!(help.signature.as_deref(), Some("noop()"));
+    assert_eq!(&*engine.help("g").unwrap().doc, "A global");
+    // The native has id 0, just like the script function at address 0
+    assert!(engine.help("script").is_none());
+    assert!(engine.help("missing").is_none());
+}
+
+#[test]
+fn test_rate_limits() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let native = engine.register_limited_native(
+        7,
+        NativeFunction::new(|_, _| Ok(Default::default())),
+        ArgCount::Fixed(0),
+        RateLimit::quota(2),
+    );
+    assert!(engine.call(&native, []).is_ok());
+    assert!(engine.call(&native, []).is_ok());
+    assert_eq!(
+        engine.call(&native, []),
+        Err(FreightError::RateLimited { native: 7 })
+    );
+    engine.clear_runtime_state();
+    assert!(engine.call(&native, []).is_ok());
+
+    engine
+        .rate_limits
+        .insert(7, RateLimit::per(1, Duration::from_millis(20)));
+    assert!(engine.call(&native, []).is_ok());
+    assert!(engine.call(&native, []).is_err());
+    std::thread::sleep(Duration::from_millis(25));
+    assert!(engine.call(&native, []).is_ok());
+}
+
+#[test]
+fn test_rewriters() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    // Fold additions of constants, which only reaches the outer addition once the inner one has
+    // been folded
+    engine
+        .rewriters
+        .borrow_mut()
+        .push(Box::new(|expr: &Expression<TestTypeSystem>| {
+        let Expression::BinaryOpEval(TestBinaryOperator::Add, operands) = expr else {
+            return None;
+        };
+        match &**operands {
+            [Expression::RawValue(TestValueWrapper(TestValue::Number(a))), Expression::RawValue(TestValueWrapper(TestValue::Number(b)))] => {
+                Some(number(a + b))
+            }
+            _ => None,
+        }
+    }));
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Add,
+        [
+            number(1),
+            Expression::BinaryOpEval(TestBinaryOperator::Add, [number(2), number(3)].into()),
+        ]
+        .into(),
+    ));
+    let main = engine.register_function(main, 0);
+    assert!(matches!(
+        engine.functions()[main.address()].expressions[..],
+        [Expression::RawValue(TestValueWrapper(TestValue::Number(6)))]
+    ));
+    assert_eq!(
+        engine.call(&main, []).unwrap(),
+        TestValueWrapper(TestValue::Number(6))
+    );
+
+    // Functions registered by a fork go through the same rewriters
+    engine.rewriters.borrow_mut().push(Box::new(
+        move |expr: &Expression<TestTypeSystem>| match expr {
+            Expression::RawValue(TestValueWrapper(TestValue::Number(7))) => Some(number(70)),
+            _ => None,
+        },
+    ));
+    let mut fork = engine.fork();
+    let mut seven = FunctionWriter::new(ArgCount::Fixed(0));
+    seven.evaluate_expression(number(7));
+    let seven = fork.register_function(seven, 0);
+    assert_eq!(
+        fork.call(&seven, []).unwrap(),
+        TestValueWrapper(TestValue::Number(70))
+    );
+}
+
+#[test]
+fn test_prepare_call() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut add = FunctionWriter::new(ArgCount::Fixed(2));
+    add.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Add,
+        [Expression::stack(0), Expression::stack(1)].into(),
+    ));
+    let add = engine.register_function(add, 0);
+    assert!(matches!(
+        engine.prepare_call(&add, 1),
+        Err(FreightError::IncorrectArgumentCount { actual: 1, .. })
+    ));
+    let mut prepared = engine.prepare_call(&add, 2).unwrap();
+    let mut total = TestValueWrapper(TestValue::Number(0));
+    for _ in 0..10 {
+        total = prepared
+            .invoke([total, TestValueWrapper(TestValue::Number(2))])
+            .unwrap();
+    }
+    assert_eq!(total, TestValueWrapper(TestValue::Number(20)));
+    assert!(prepared.invoke([total]).is_err());
+}
+
+#[test]
+fn test_call_with_literals() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut identity = FunctionWriter::new(ArgCount::Fixed(1));
+    identity.evaluate_expression(Expression::stack(0));
+    let identity = engine.register_function(identity, 0);
+    let list = Literal::List(vec![Literal::Int(1), Literal::Null]);
+    assert_eq!(
+        engine.call_with_literals(&identity, &[list]),
+        Ok(TestValueWrapper(TestValue::List(vec![
+            TestValueWrapper(TestValue::Number(1)),
+            TestValueWrapper(TestValue::Null)
+        ])))
+    );
+    assert_eq!(
+        engine.call_with_literals(&identity, &[Literal::List(vec![Literal::Float(1.5)])]),
+        Err(FreightError::UnsupportedLiteral {
+            kind: "float".to_string()
+        })
+    );
+}
+
+#[test]
+fn test_fresh_cells() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let set = FunctionRef::new_native(
+        0,
+        NativeFunction::new(|_, args: &mut [TestValueWrapper]| {
+            let TestValue::Cell(cell) = &args[0].0 else {
+                return Err(FreightError::InvalidInvocationTarget);
+            };
+            *cell.borrow_mut() = args[1].clone();
+            Ok(Default::default())
+        }),
+        ArgCount::Fixed(2),
+    );
+    let read = FunctionRef::new_native(
+        1,
+        NativeFunction::new(|_, args: &mut [TestValueWrapper]| match &args[0].0 {
+            TestValue::Cell(cell) => Ok(cell.borrow().clone()),
+            _ => Err(FreightError::InvalidInvocationTarget),
+        }),
+        ArgCount::Fixed(1),
+    );
+    let mut get = FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![VariableType::Stack(0)]);
+    get.evaluate_expression(Expression::StaticFunctionCall(
+        read,
+        vec![Expression::captured(0)],
+    ));
+    let get = engine.register_function(get, 0);
+
+    // Unrolled `for i in 1..=2 { closures.push(|| i) }`, where `i` is a cell written in place and
+    // each iteration's closure is stored in its own slot
+    let program = |engine: &mut ExecutionEngine<TestTypeSystem>, fresh: bool| {
+        let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+        let i = main.create_variable();
+        let first = main.create_variable();
+        let second = main.create_variable();
+        let cell = TestValue::Cell(Rc::new(RefCell::new(TestValueWrapper(TestValue::Null))));
+        main.evaluate_expression(Expression::AssignStack(
+            i,
+            Expression::RawValue(TestValueWrapper(cell)).into(),
+        ));
+        for (n, slot) in [(1, first), (2, second)] {
+            if fresh {
+                main.evaluate_expression(Expression::FreshCells(vec![i]));
+            }
+            main.evaluate_expression(Expression::StaticFunctionCall(
+                set.clone(),
+                vec![Expression::stack(i), number(n)],
+            ));
+            main.evaluate_expression(Expression::AssignStack(
+                slot,
+                Expression::FunctionCapture(get.clone()).into(),
+            ));
+        }
+        main.evaluate_expression(Expression::BinaryOpEval(
+            TestBinaryOperator::Add,
+            [
+                Expression::DynamicFunctionCall(Expression::stack(first).into(), vec![]),
+                Expression::DynamicFunctionCall(Expression::stack(second).into(), vec![]),
+            ]
+            .into(),
+        ));
+        let main = engine.register_function(main, 0);
+        engine.call(&main, [])
+    };
+    // Each iteration's closure sees its own cell
+    assert_eq!(
+        program(&mut engine, true),
+        Ok(TestValueWrapper(TestValue::Number(3)))
+    );
+    // Without fresh cells both closures share the cell and see the last value written to it
+    assert_eq!(
+        program(&mut engine, false),
+        Ok(TestValueWrapper(TestValue::Number(4)))
+    );
+}
+
+#[test]
+fn test_region_exit_on_return() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let outer_target = engine.create_return_target();
+    let mut inner = FunctionWriter::new(ArgCount::Fixed(0));
+    inner.evaluate_expression(Expression::Return(outer_target, number(5).into()));
+    let inner = engine.register_function(inner, 0);
+    let mut outer = FunctionWriter::new(ArgCount::Fixed(0));
+    outer.evaluate_expression(Expression::StaticFunctionCall(inner, vec![]));
+    let outer = engine.register_function(outer, outer_target);
+    REGION_EXITS.with(|exits| exits.borrow_mut().clear());
+    assert_eq!(
+        engine.call(&outer, []),
+        Ok(TestValueWrapper(TestValue::Number(5)))
+    );
+    // The inner call is unwound by the return, but still sees the value being returned
+    let five = Some(TestValueWrapper(TestValue::Number(5)));
+    assert_eq!(
+        REGION_EXITS.with(|exits| exits.take()),
+        vec![five.clone(), five]
+    );
+}
+
+#[test]
+fn test_iterate() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let counter = engine.create_global();
+    engine
+        .evaluate(&Expression::AssignGlobal(counter, number(0).into()))
+        .unwrap();
+    // Yields 10, 20 and 30, then null
+    let mut next = FunctionWriter::new(ArgCount::Fixed(0));
+    next.evaluate_expression(Expression::AssignGlobal(
+        counter,
+        Expression::BinaryOpEval(
+            TestBinaryOperator::Add,
+            [Expression::global(counter), number(1)].into(),
+        )
+        .into(),
+    ));
+    next.evaluate_expression(Expression::Switch {
+        scrutinee: Expression::global(counter).into(),
+        arms: vec![number(0), number(10), number(20), number(30)],
+        default: Expression::RawValue(TestValueWrapper(TestValue::Null)).into(),
+    });
+    let next = engine.register_function(next, 0);
+
+    let mut iter = engine.iterate(&next.into());
+    let values: Vec<_> = iter.by_ref().collect::<Result<_, _>>().unwrap();
+    assert_eq!(
+        values,
+        [10, 20, 30].map(|n| TestValueWrapper(TestValue::Number(n)))
+    );
+    assert!(iter.next().is_none());
+
+    let mut iter = engine.iterate(&TestValueWrapper(TestValue::Number(1)));
+    assert_eq!(
+        iter.next(),
+        Some(Err(FreightError::InvalidInvocationTarget))
+    );
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn test_fork() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let global = engine.create_global();
+    let mut set = FunctionWriter::new(ArgCount::Fixed(1));
+    set.evaluate_expression(Expression::AssignGlobal(
+        global,
+        Expression::stack(0).into(),
+    ));
+    let set = engine.register_function(set, 0);
+    engine
+        .call(&set, [TestValueWrapper(TestValue::Number(1))])
+        .unwrap();
+
+    let mut fork = engine.fork();
+    fork.call(&set, [TestValueWrapper(TestValue::Number(2))])
+        .unwrap();
+    assert_eq!(
+        engine.evaluate(&Expression::global(global)),
+        Ok(TestValueWrapper(TestValue::Number(1)))
+    );
+    drop(fork);
+    assert_eq!(
+        engine.evaluate(&Expression::global(global)),
+        Ok(TestValueWrapper(TestValue::Number(1)))
+    );
+
+    let mut fork = engine.fork();
+    let added = fork.create_global();
+    fork.evaluate(&Expression::AssignGlobal(added, number(3).into()))
+        .unwrap();
+    fork.call(&set, [TestValueWrapper(TestValue::Number(2))])
+        .unwrap();
+    engine.merge(fork).unwrap();
+    assert_eq!(
+        engine.evaluate(&Expression::global(global)),
+        Ok(TestValueWrapper(TestValue::Number(2)))
+    );
+    assert_eq!(
+        engine.evaluate(&Expression::global(added)),
+        Ok(TestValueWrapper(TestValue::Number(3)))
+    );
+
+    // Functions registered by a discarded fork never reach the parent
+    let noop = || {
+        let mut noop = FunctionWriter::new(ArgCount::Fixed(0));
+        noop.evaluate_expression(number(0));
+        noop
+    };
+    let mut fork = engine.fork();
+    fork.register_function(noop(), 0);
+    drop(fork);
+    assert_eq!(engine.functions().len(), 1);
+
+    // Merging only copies back the globals the fork used, keeping the parent's later changes
+    let mut fork = engine.fork();
+    engine
+        .evaluate(&Expression::AssignGlobal(added, number(4).into()))
+        .unwrap();
+    fork.call(&set, [TestValueWrapper(TestValue::Number(5))])
+        .unwrap();
+    let registered = fork.register_function(noop(), 0);
+    engine.merge(fork).unwrap();
+    assert_eq!(
+        engine.evaluate(&Expression::global(global)),
+        Ok(TestValueWrapper(TestValue::Number(5)))
+    );
+    assert_eq!(
+        engine.evaluate(&Expression::global(added)),
+        Ok(TestValueWrapper(TestValue::Number(4)))
+    );
+    assert!(engine.call(&registered, []).is_ok());
+
+    let mut fork = engine.fork();
+    fork.register_function(noop(), 0);
+    engine.register_function(noop(), 0);
+    assert_eq!(
+        engine.merge(fork),
+        Err(FreightError::ForkConflict { table: "functions" })
+    );
+
+    // Globals are copied when the fork first uses them, and cached values when it is created
+    let cell = Rc::new(RefCell::new(TestValueWrapper(TestValue::Number(1))));
+    let shared = TestValueWrapper(TestValue::Cell(cell.clone()));
+    engine
+        .evaluate(&Expression::AssignGlobal(
+            global,
+            Expression::RawValue(shared.clone()).into(),
+        ))
+        .unwrap();
+    let key = TestValueWrapper(TestValue::Number(0));
+    engine.cache.put(key.clone(), shared).unwrap();
+    let mut fork = engine.fork();
+    *cell.borrow_mut() = TestValueWrapper(TestValue::Number(2));
+    let Ok(TestValueWrapper(TestValue::Cell(copy))) = fork.evaluate(&Expression::global(global))
+    else {
+        panic!("global should be a cell");
+    };
+    *copy.borrow_mut() = TestValueWrapper(TestValue::Number(3));
+    let Ok(Some(TestValueWrapper(TestValue::Cell(cached)))) = fork.cache.get(&key) else {
+        panic!("cached value should be a cell");
+    };
+    assert_eq!(*cached.borrow(), TestValueWrapper(TestValue::Number(1)));
+    *cached.borrow_mut() = TestValueWrapper(TestValue::Number(3));
+    assert_eq!(*cell.borrow(), TestValueWrapper(TestValue::Number(2)));
+}
+
+#[test]
+fn test_dispatch() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let log = engine.create_global();
+    engine
+        .evaluate(&Expression::AssignGlobal(log, number(0).into()))
+        .unwrap();
+    // Each handler appends its digit to the log and returns its argument
+    let mut handler = |digit: i64| {
+        let mut writer = FunctionWriter::new(ArgCount::Fixed(1));
+        writer.evaluate_expression(Expression::AssignGlobal(
+            log,
+            Expression::BinaryOpEval(
+                TestBinaryOperator::Add,
+                [
+                    Expression::BinaryOpEval(
+                        TestBinaryOperator::Mul,
+                        [Expression::global(log), number(10)].into(),
+                    ),
+                    number(digit),
+                ]
+                .into(),
+            )
+            .into(),
+        ));
+        writer.evaluate_expression(Expression::stack(0));
+        engine.register_function(writer, 0)
+    };
+    let (one, two, three) = (handler(1), handler(2), handler(3));
+    let failing = FunctionRef::new_native(
+        0,
+        NativeFunction::new(|_, _| Err(FreightError::InvalidInvocationTarget)),
+        ArgCount::Fixed(1),
+    );
+    engine.callbacks.register("tick", 0, one);
+    engine.callbacks.register("tick", 5, two);
+    let failing = engine.callbacks.register("tick", 5, failing);
+    let third = engine.callbacks.register("tick", -1, three);
+    engine.callbacks.consumed = |value| value.0 == TestValue::Number(1);
+
+    let report = engine.dispatch("tick", &[TestValueWrapper(TestValue::Number(0))]);
+    assert_eq!(report.handled, 4);
+    assert_eq!(report.consumed_by, None);
+    assert_eq!(
+        report.errors,
+        vec![(failing, FreightError::InvalidInvocationTarget)]
+    );
+    let expected = TestValueWrapper(TestValue::Number(213));
+    assert_eq!(engine.evaluate(&Expression::global(log)), Ok(expected));
+
+    let report = engine.dispatch("tick", &[TestValueWrapper(TestValue::Number(1))]);
+    assert_eq!(report.consumed_by, Some(HandlerId(1)));
+    assert!(engine.callbacks.unregister(third));
+    assert!(!engine.callbacks.unregister(third));
+    assert_eq!(engine.dispatch("other", &[]), Default::default());
+}
+
+#[test]
+fn test_cache() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    engine.cache.max_entries = 2;
+    let get = ValueCache::get_native();
+    let put = ValueCache::put_native();
+    for n in 1..=3 {
+        engine
+            .evaluate(&Expression::NativeFunctionCall(
+                put.clone(),
+                vec![number(n), number(n * 10)],
+            ))
+            .unwrap();
+    }
+    // The first entry was evicted to make room for the third
+    let lookup = |engine: &mut ExecutionEngine<TestTypeSystem>, n| {
+        engine.evaluate(&Expression::NativeFunctionCall(
+            get.clone(),
+            vec![number(n)],
+        ))
+    };
+    assert_eq!(
+        lookup(&mut engine, 1),
+        Ok(TestValueWrapper(TestValue::Null))
+    );
+    assert_eq!(
+        lookup(&mut engine, 3),
+        Ok(TestValueWrapper(TestValue::Number(30)))
+    );
+    assert_eq!(engine.cache.len(), 2);
+    assert!(matches!(
+        engine.evaluate(&Expression::NativeFunctionCall(
+            get.clone(),
+            vec![Expression::RawValue(TestValueWrapper(TestValue::Null))]
+        )),
+        Err(FreightError::Unhashable { .. })
+    ));
+
+    engine.cache.ttl = Some(Duration::from_millis(10));
+    std::thread::sleep(Duration::from_millis(15));
+    assert_eq!(
+        lookup(&mut engine, 3),
+        Ok(TestValueWrapper(TestValue::Null))
+    );
+    assert_eq!(engine.cache.len(), 1);
+}
+
+#[test]
+fn test_namespaces() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let tenants = [engine.create_namespace(), engine.create_namespace()];
+    // Every tenant has a counter at the same address
+    let mut counter = 0;
+    for tenant in tenants {
+        counter = engine.in_namespace(tenant, |engine| {
+            let counter = engine.create_global();
+            engine
+                .evaluate(&Expression::AssignGlobal(counter, number(0).into()))
+                .unwrap();
+            counter
+        });
+    }
+    let mut add = FunctionWriter::new(ArgCount::Fixed(1));
+    add.evaluate_expression(Expression::AssignGlobal(
+        counter,
+        Expression::BinaryOpEval(
+            TestBinaryOperator::Add,
+            [Expression::global(counter), Expression::stack(0)].into(),
+        )
+        .into(),
+    ));
+    add.evaluate_expression(Expression::global(counter));
+    let add = engine.register_function(add, 0);
+
+    let one = TestValueWrapper(TestValue::Number(1));
+    let five = TestValueWrapper(TestValue::Number(5));
+    engine.call_in(tenants[0], &add, [one.clone()]).unwrap();
+    assert_eq!(
+        engine.call_in(tenants[0], &add, [one]),
+        Ok(TestValueWrapper(TestValue::Number(2)))
+    );
+    assert_eq!(
+        engine.call_in(tenants[1], &add, [five.clone()]),
+        Ok(five.clone())
+    );
+    assert_eq!(engine.current_namespace(), NamespaceId::ROOT);
+    // The root namespace has no globals of its own
+    assert_eq!(engine.num_globals, 0);
+
+    engine.in_namespace(tenants[1], |engine| {
+        engine.callbacks.register("event", 0, add.clone())
+    });
+    assert_eq!(engine.dispatch("event", &[]).handled, 0);
+    let report = engine.in_namespace(tenants[1], |engine| engine.dispatch("event", &[five]));
+    assert_eq!(report.handled, 1);
+}
+
+#[test]
+fn test_module_unloading() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let plugin = engine.modules.create();
+    let mut hello = FunctionWriter::new(ArgCount::Fixed(0));
+    hello.evaluate_expression(number(1));
+    let hello = engine.register_module_function(plugin, hello, 0);
+    let global = engine.create_global();
+    engine
+        .evaluate(&Expression::AssignGlobal(
+            global,
+            Expression::RawValue(hello.clone().into()).into(),
+        ))
+        .unwrap();
+    let call_global = Expression::DynamicFunctionCall(Expression::global(global).into(), vec![]);
+    assert!(engine.evaluate(&call_global).is_ok());
+    assert!(engine.dangling_globals().is_empty());
+
+    engine.modules.unload(plugin);
+    assert_eq!(
+        engine.evaluate(&call_global),
+        Err(FreightError::ModuleUnloaded { module: plugin.0 })
+    );
+    assert_eq!(engine.dangling_globals(), vec![(NamespaceId::ROOT, global)]);
+    // Reloading doesn't revive references to the old generation
+    engine.modules.reload(plugin);
+    assert!(engine.call(&hello, []).is_err());
+}
+
+#[test]
+fn test_is_defined() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let is_defined = |symbol| Expression::IsDefined {
+        symbol,
+        defined: number(1).into(),
+        undefined: number(0).into(),
+    };
+    let plugin = engine.modules.create();
+    let mut hello = FunctionWriter::new(ArgCount::Fixed(0));
+    hello.evaluate_expression(number(1));
+    let hello = engine.register_module_function(plugin, hello, 0);
+    let hello = engine.symbols.bind("hello", Symbol::Function(hello));
+    let hidden = engine.register_function(FunctionWriter::new(ArgCount::Fixed(0)), 0);
+    let hidden = engine
+        .symbols
+        .bind("hidden", Symbol::Function(hidden.host_only()));
+    let global = engine.create_global();
+    let config = engine.symbols.bind("config", Symbol::Global(global));
+    let missing = engine.symbols.intern("missing");
+
+    let defined = |engine: &mut ExecutionEngine<TestTypeSystem>, symbol| {
+        engine.evaluate(&is_defined(symbol)).unwrap() == TestValueWrapper(TestValue::Number(1))
+    };
+    assert!(defined(&mut engine, hello));
+    assert!(defined(&mut engine, config));
+    assert!(!defined(&mut engine, hidden));
+    assert!(!defined(&mut engine, missing));
+    assert!(engine.is_defined("hello"));
+    assert!(!engine.is_defined("never interned"));
+
+    engine.modules.unload(plugin);
+    assert!(!defined(&mut engine, hello));
+    assert!(!engine.is_defined("hello"));
+    engine.symbols.unbind(config);
+    assert!(!defined(&mut engine, config));
+}
+
+#[test]
+fn test_conditional() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    // Only the selected branch is evaluated, so the invalid call is never made
+    let invalid = || Expression::DynamicFunctionCall(number(0).into(), vec![]);
+    for (condition, expected) in [(1, 10), (0, 20)] {
+        let expr = Expression::Conditional {
+            condition: number(condition).into(),
+            then: Expression::Conditional {
+                condition: number(1).into(),
+                then: number(10).into(),
+                otherwise: invalid().into(),
+            }
+            .into(),
+            otherwise: number(20).into(),
+        };
+        assert_eq!(
+            engine.evaluate(&expr),
+            Ok(TestValueWrapper(TestValue::Number(expected)))
+        );
+    }
+}
+
+#[test]
+fn test_unwind_hook() {
+    thread_local! {
+        static FRAMES: std::cell::RefCell<Vec<UnwindFrame>> = const { std::cell::RefCell::new(vec![]) };
+    }
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    engine.on_unwind_frame = Some(|_, frame, error| {
+        assert_eq!(error, &FreightError::InvalidInvocationTarget);
+        FRAMES.with(|frames| frames.borrow_mut().push(*frame));
+    });
+    let fail = FunctionRef::new_native(
+        3,
+        NativeFunction::new(|_, _| Err(FreightError::InvalidInvocationTarget)),
+        ArgCount::Fixed(0),
+    );
+    let mut inner = FunctionWriter::new(ArgCount::Fixed(0));
+    // Not pure, so it isn't dropped from the function
+    inner.evaluate_expression(Expression::FreshCells(vec![]));
+    inner.evaluate_expression(Expression::StaticFunctionCall(fail, vec![]));
+    let inner = engine.register_function(inner, 0);
+    let mut outer = FunctionWriter::new(ArgCount::Fixed(0));
+    outer.evaluate_expression(Expression::StaticFunctionCall(inner.clone(), vec![]));
+    outer.evaluate_expression(Expression::RawValue(TestValueWrapper(TestValue::Null)));
+    let outer = engine.register_function(outer, 0);
+
+    assert!(engine.call(&outer, []).is_err());
+    let frame = |function, native, expression| UnwindFrame {
+        function,
+        native,
+        expression,
+    };
+    assert_eq!(
+        FRAMES.with(|frames| frames.take()),
+        vec![
+            frame(3, true, None),
+            frame(inner.address(), false, Some(1)),
+            frame(outer.address(), false, Some(0)),
+        ]
+    );
+}
+
+#[test]
+fn test_loops() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut count = FunctionWriter::new(ArgCount::Fixed(1));
+    let total = count.create_variable();
+    count.evaluate_expression(Expression::AssignStack(total, number(0).into()));
+    // Count down the argument to 0, far deeper than recursion could go
+    count.evaluate_expression(Expression::While {
+        condition: Expression::stack(0).into(),
+        body: Expression::AssignStack(
+            0,
+            Expression::BinaryOpEval(
+                TestBinaryOperator::Add,
+                [Expression::stack(0), number(-1)].into(),
+            )
+            .into(),
+        )
+        .into(),
+    });
+    count.evaluate_expression(Expression::Repeat(
+        3,
+        Expression::AssignStack(
+            total,
+            Expression::UnaryOpEval(TestUnaryOperator::Inc, Expression::stack(total).into()).into(),
+        )
+        .into(),
+    ));
+    count.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Add,
+        [Expression::stack(0), Expression::stack(total)].into(),
+    ));
+    let count = engine.register_function(count, 0);
+    assert_eq!(
+        engine.call(&count, [TestValueWrapper(TestValue::Number(100_000))]),
+        Ok(TestValueWrapper(TestValue::Number(3)))
+    );
+
+    engine.cancellation.cancel();
+    let forever = Expression::While {
+        condition: number(1).into(),
+        body: number(0).into(),
+    };
+    assert_eq!(engine.evaluate(&forever), Err(FreightError::Cancelled));
+    assert_eq!(engine.cancelled_at.unwrap().function, IN_LOOP);
+}
+
+#[test]
+fn test_call_into() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let return_target = engine.create_return_target();
+    let mut build = FunctionWriter::new(ArgCount::Fixed(1));
+    build.evaluate_expression(Expression::Return(
+        return_target,
+        Expression::Initialize(TestInitializer::NumberList, vec![Expression::stack(0)]).into(),
+    ));
+    let build = engine.register_function(build, return_target);
+    let mut dest = TestValueWrapper(TestValue::Number(0));
+    engine
+        .call_into(&build, [TestValueWrapper(TestValue::Number(1))], &mut dest)
+        .unwrap();
+    assert_eq!(
+        dest,
+        TestValueWrapper(TestValue::List(vec![TestValueWrapper(TestValue::Number(
+            1
+        ))]))
+    );
+    assert!(engine
+        .call_into(&build, [TestValueWrapper(TestValue::Null)], &mut dest)
+        .is_err());
+    assert_eq!(
+        dest,
+        TestValueWrapper(TestValue::List(vec![TestValueWrapper(TestValue::Number(
+            1
+        ))]))
+    );
+}
+
+#[test]
+fn test_short_circuit() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let invalid = Expression::DynamicFunctionCall(number(0).into(), vec![]);
+    let and = |l, r| Expression::BinaryOpEval(TestBinaryOperator::And, [l, r].into());
+    assert_eq!(
+        engine.evaluate(&and(number(0), invalid)),
+        Ok(TestValueWrapper(TestValue::Number(0)))
+    );
+    assert_eq!(
+        engine.evaluate(&and(number(1), number(2))),
+        Ok(TestValueWrapper(TestValue::Number(2)))
+    );
+}
+
+#[test]
+fn test_escape() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let done = engine.create_escape_label();
+    let target = engine.create_return_target();
+
+    // Calls its argument with each of 1, 2 and 3, returning 0 if it finishes
+    let mut for_each = FunctionWriter::new(ArgCount::Fixed(1));
+    for i in 1..=3 {
+        for_each.evaluate_expression(Expression::DynamicFunctionCall(
+            Expression::stack(0).into(),
+            vec![number(i)],
+        ));
+    }
+    for_each.evaluate_expression(number(0));
+    let for_each = engine.register_function(for_each, target);
+
+    // Escapes with its argument times 10 once it reaches 2, even though it shares the return
+    // target of `for_each`
+    let mut body = FunctionWriter::new(ArgCount::Fixed(1));
+    body.evaluate_expression(Expression::Conditional {
+        condition: Expression::BinaryOpEval(
+            TestBinaryOperator::Add,
+            [Expression::stack(0), number(-2)].into(),
+        )
+        .into(),
+        then: Expression::Return(target, number(-1).into()).into(),
+        otherwise: Expression::EscapeTo(
+            done,
+            Expression::BinaryOpEval(
+                TestBinaryOperator::Mul,
+                [Expression::stack(0), number(10)].into(),
+            )
+            .into(),
+        )
+        .into(),
+    });
+    let body = engine.register_function(body, target);
+
+    let depth = REGION_DEPTH.with(|depth| depth.get());
+    let expr = Expression::Escape(
+        done,
+        Expression::StaticFunctionCall(for_each, vec![Expression::RawValue(body.clone().into())])
+            .into(),
+    );
+    assert_eq!(
+        engine.evaluate(&expr),
+        Ok(TestValueWrapper(TestValue::Number(20)))
+    );
+    assert_eq!(REGION_DEPTH.with(|depth| depth.get()), depth);
+
+    let uncaught = Expression::Catch(Expression::EscapeTo(done, number(1).into()).into());
+    assert_eq!(
+        engine.evaluate(&uncaught),
+        Err(FreightError::Escape { label: done })
+    );
+}
+
+#[test]
+fn test_validate_references() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut inc = FunctionWriter::new(ArgCount::Fixed(1));
+    inc.evaluate_expression(Expression::UnaryOpEval(
+        TestUnaryOperator::Inc,
+        Expression::stack(0).into(),
+    ));
+    let missing = inc.to_ref(10);
+    let inc = engine.register_function(inc, 0);
+    assert_eq!(engine.validate(), Ok(()));
+
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.evaluate_expression(Expression::StaticFunctionCall(inc.clone(), vec![number(1)]));
+    main.evaluate_expression(Expression::AssignStack(
+        0,
+        Expression::StaticFunctionCall(inc.clone(), vec![]).into(),
+    ));
+    main.evaluate_expression(Expression::FunctionCapture(missing));
+    let main = engine.register_function(main, 0);
+    assert_eq!(
+        engine.validate(),
+        Err(vec![
+            InvalidReference {
+                function: main.location,
+                expression: 1,
+                target: inc.location,
+                problem: ReferenceProblem::ArgumentCount {
+                    expected: ArgCount::Fixed(1),
+                    actual: 0
+                },
+            },
+            InvalidReference {
+                function: main.location,
+                expression: 2,
+                target: 10,
+                problem: ReferenceProblem::Unregistered,
+            },
+        ])
+    );
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_metrics() {
+    use metrics::{
+        Counter, CounterFn, Gauge, Histogram, Key, KeyName, Label, Metadata, Recorder,
+        SharedString, Unit,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    #[derive(Default)]
+    struct Count(AtomicU64);
+
+    impl CounterFn for Count {
+        fn increment(&self, value: u64) {
+            self.0.fetch_add(value, Ordering::Relaxed);
+        }
+
+        fn absolute(&self, value: u64) {
+            self.0.store(value, Ordering::Relaxed);
+        }
+    }
+
+    #[derive(Default)]
+    struct TestRecorder(Mutex<HashMap<Key, Arc<Count>>>);
+
+    impl TestRecorder {
+        fn get(&self, name: &'static str, label: (&'static str, &'static str)) -> u64 {
+            let key = Key::from_parts(name, vec![Label::new(label.0, label.1)]);
+            let counters = self.0.lock().unwrap();
+            counters
+                .get(&key)
+                .map_or(0, |count| count.0.load(Ordering::Relaxed))
+        }
+    }
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            let mut counters = self.0.lock().unwrap();
+            Counter::from_arc(counters.entry(key.clone()).or_default().clone())
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    let recorder = TestRecorder::default();
+    metrics::with_local_recorder(&recorder, || {
+        let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+        let mut inc = FunctionWriter::new(ArgCount::Fixed(1));
+        inc.evaluate_expression(Expression::UnaryOpEval(
+            TestUnaryOperator::Inc,
+            Expression::stack(0).into(),
+        ));
+        let inc = engine.register_function(inc, 0);
+        engine
+            .call(&inc, [TestValueWrapper(TestValue::Number(1))])
+            .unwrap();
+        assert!(engine.call(&inc, []).is_err());
+    });
+    assert_eq!(recorder.get(crate::telemetry::CALLS, ("kind", "script")), 1);
+    assert_eq!(
+        recorder.get(
+            crate::telemetry::ERRORS,
+            ("error", "IncorrectArgumentCount")
+        ),
+        1
+    );
+}
+
+#[test]
+fn test_ternary_operator() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut clamp = FunctionWriter::new(ArgCount::Fixed(1));
+    clamp.evaluate_expression(Expression::TernaryOpEval(
+        TestTernaryOperator::Clamp,
+        [Expression::stack(0), number(0), number(10)].into(),
+    ));
+    let clamp = engine.register_function(clamp, 0);
+    for (n, clamped) in [(-5, 0), (5, 5), (15, 10)] {
+        assert_eq!(
+            engine.call(&clamp, [TestValueWrapper(TestValue::Number(n))]),
+            Ok(TestValueWrapper(TestValue::Number(clamped)))
+        );
+    }
+}
+
+#[test]
+fn test_references_to() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut writer = FunctionWriter::new(ArgCount::Fixed(0));
+    writer.evaluate_expression(number(1));
+    let func = engine.register_function(writer, 0);
+    let other = engine.register_function(FunctionWriter::new(ArgCount::Fixed(0)), 0);
+    assert_eq!(engine.references_to(&func), vec![]);
+
+    let global = engine.create_global();
+    engine
+        .evaluate(&Expression::AssignGlobal(
+            global,
+            Expression::RawValue(func.clone().into()).into(),
+        ))
+        .unwrap();
+    engine
+        .cache
+        .put(TestValueWrapper(TestValue::Number(1)), func.clone().into())
+        .unwrap();
+    let handler = engine.callbacks.register("tick", 0, func.clone());
+    engine.callbacks.register("tick", 0, other.clone());
+    assert_eq!(
+        engine.references_to(&func),
+        vec![
+            ReferenceHolder::Global {
+                namespace: NamespaceId::ROOT,
+                addr: global
+            },
+            ReferenceHolder::CacheEntry {
+                key: "TestValueWrapper(Number(1))".to_string()
+            },
+            ReferenceHolder::Callback {
+                event: "tick".into(),
+                handler
+            },
+        ]
+    );
+    assert_eq!(engine.references_to(&other).len(), 1);
+}
+
+#[test]
+fn test_runtime_error() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let divide = FunctionRef::new_native(
+        0,
+        NativeFunction::new(
+            |_, args: &mut [TestValueWrapper]| match (&args[0].0, &args[1].0) {
+                (_, TestValue::Number(0)) => Err(FreightError::runtime::<TestTypeSystem>(
+                    TestRuntimeError::DivideByZero,
+                )),
+                (TestValue::Number(a), TestValue::Number(b)) => {
+                    Ok(TestValueWrapper(TestValue::Number(a / b)))
+                }
+                _ => Err(FreightError::InvalidInvocationTarget),
+            },
+        ),
+        ArgCount::Fixed(2),
+    );
+    let args = |b| {
+        [
+            TestValueWrapper(TestValue::Number(6)),
+            TestValueWrapper(TestValue::Number(b)),
+        ]
+    };
+    assert_eq!(
+        engine.call(&divide, args(2)),
+        Ok(TestValueWrapper(TestValue::Number(3)))
+    );
+    let error = engine.call(&divide, args(0)).unwrap_err();
+    assert_eq!(
+        error.as_runtime::<TestTypeSystem>(),
+        Some(&TestRuntimeError::DivideByZero)
+    );
+    assert_eq!(
+        error,
+        FreightError::runtime::<TestTypeSystem>(TestRuntimeError::DivideByZero)
+    );
+    assert_eq!(error.to_string(), "Division by zero");
+    assert_eq!(
+        engine.call_checked(&divide, args(0)),
+        TestValueWrapper(TestValue::Error(error))
+    );
+}
+
+#[test]
+fn test_error_trace() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let fail = FunctionRef::new_native(
+        3,
+        NativeFunction::new(|_, _| Err(FreightError::InvalidInvocationTarget)),
+        ArgCount::Fixed(0),
+    );
+    let mut inner = FunctionWriter::new(ArgCount::Fixed(0));
+    inner.evaluate_expression(Expression::StaticFunctionCall(fail, vec![]));
+    let inner = engine.register_function(inner, 0);
+    let mut outer = FunctionWriter::new(ArgCount::Fixed(0));
+    outer.evaluate_expression(Expression::FreshCells(vec![]));
+    outer.evaluate_expression(Expression::StaticFunctionCall(inner.clone(), vec![]));
+    let outer = engine.register_function(outer, 0);
+
+    assert!(engine.call(&outer, []).is_err());
+    assert_eq!(
+        engine.error_trace(),
+        [
+            UnwindFrame {
+                function: 3,
+                native: true,
+                expression: None
+            },
+            UnwindFrame {
+                function: inner.address(),
+                native: false,
+                expression: Some(0)
+            },
+            UnwindFrame {
+                function: outer.address(),
+                native: false,
+                expression: Some(1)
+            },
+        ]
+    );
+
+    let caught = Expression::Catch(Expression::StaticFunctionCall(outer, vec![]).into());
+    assert!(engine.evaluate(&caught).is_ok());
+    assert_eq!(engine.error_trace(), []);
+}
+
+#[test]
+fn test_shared_capture() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    // Both closures read the environment [x, y], but each only captures one value of its own
+    let mut get_x = FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![VariableType::Stack(0)]);
+    get_x.evaluate_expression(Expression::captured(0));
+    let get_x = engine.register_function(get_x, 0);
+    let mut get_y = FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![VariableType::Stack(0)]);
+    get_y.evaluate_expression(Expression::captured(1));
+    let get_y = engine.register_function(get_y, 0);
+
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    let x = main.create_variable();
+    let y = main.create_variable();
+    let f = main.create_variable();
+    let g = main.create_variable();
+    for (slot, n) in [(x, 1), (y, 2)] {
+        main.evaluate_expression(Expression::AssignStack(slot, number(n).into()));
+    }
+    main.evaluate_expression(Expression::SharedCapture {
+        captures: [VariableType::Stack(x), VariableType::Stack(y)].into(),
+        closures: vec![(get_x, f), (get_y, g)],
+    });
+    main.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Mul,
+        [
+            Expression::DynamicFunctionCall(Expression::stack(f).into(), vec![]),
+            Expression::DynamicFunctionCall(Expression::stack(g).into(), vec![]),
+        ]
+        .into(),
+    ));
+    let main = engine.register_function(main, 0);
+    assert_eq!(
+        engine.call(&main, []),
+        Ok(TestValueWrapper(TestValue::Number(2)))
+    );
+}
+
+#[test]
+fn test_debug_session() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut inc = FunctionWriter::new(ArgCount::Fixed(1));
+    inc.evaluate_expression(Expression::UnaryOpEval(
+        TestUnaryOperator::Inc,
+        Expression::stack(0).into(),
+    ));
+    let inc = engine.register_function(inc, 0);
+    let mut main = FunctionWriter::new(ArgCount::Fixed(1));
+    main.evaluate_expression(Expression::StaticFunctionCall(
+        inc.clone(),
+        vec![Expression::stack(0)],
+    ));
+    let main = engine.register_function(main, 0);
+
+    let steps = Rc::new(RefCell::new(vec![]));
+    let recorded = steps.clone();
+    let mut session = DebugSession::new(
+        &mut engine,
+        Box::new(move |step| {
+            recorded.borrow_mut().push((
+                step.expression.kind(),
+                step.function,
+                step.depth,
+                step.frame.to_vec(),
+            ));
+            StepAction::Step
+        }),
+    );
+    assert_eq!(
+        session.call(&main, [TestValueWrapper(TestValue::Number(1))]),
+        Ok(TestValueWrapper(TestValue::Number(2)))
+    );
+    drop(session);
+    let one = vec![TestValueWrapper(TestValue::Number(1))];
+    assert_eq!(
+        steps.take(),
+        vec![
+            (
+                ExpressionKind::StaticFunctionCall,
+                Some(main.address()),
+                1,
+                one.clone()
+            ),
+            (
+                ExpressionKind::Variable,
+                Some(main.address()),
+                1,
+                one.clone()
+            ),
+            (
+                ExpressionKind::UnaryOpEval,
+                Some(inc.address()),
+                2,
+                one.clone()
+            ),
+            (ExpressionKind::Variable, Some(inc.address()), 2, one),
+        ]
+    );
+
+    let mut session = DebugSession::new(&mut engine, Box::new(|_| StepAction::Abort));
+    assert_eq!(
+        session.call(&main, [TestValueWrapper(TestValue::Number(1))]),
+        Err(FreightError::Cancelled)
+    );
+    drop(session);
+    assert_eq!(
+        engine.call(&main, [TestValueWrapper(TestValue::Number(1))]),
+        Ok(TestValueWrapper(TestValue::Number(2)))
+    );
+}
+
+#[test]
+fn test_breakpoints() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut main = FunctionWriter::new(ArgCount::Fixed(1));
+    for _ in 0..3 {
+        main.evaluate_expression(Expression::AssignStack(
+            0,
+            Expression::UnaryOpEval(TestUnaryOperator::Inc, Expression::stack(0).into()).into(),
+        ));
+    }
+    main.evaluate_expression(Expression::stack(0));
+    let main = engine.register_function(main, 0);
+
+    let stops = Rc::new(RefCell::new(vec![]));
+    let recorded = stops.clone();
+    let mut session = DebugSession::new(
+        &mut engine,
+        Box::new(move |step| {
+            recorded
+                .borrow_mut()
+                .push((step.statement, step.breakpoint, step.frame[0].clone()));
+            StepAction::Run
+        }),
+    );
+    session.set_stepping(false);
+    session.set_breakpoint(&main, 2);
+    session.set_breakpoint(&main, 3);
+    assert!(session.clear_breakpoint(&main, 3));
+    assert_eq!(
+        session.call(&main, [TestValueWrapper(TestValue::Number(0))]),
+        Ok(TestValueWrapper(TestValue::Number(3)))
+    );
+    drop(session);
+    assert_eq!(
+        stops.take(),
+        vec![(Some(2), true, TestValueWrapper(TestValue::Number(2)))]
+    );
+}
+
+#[test]
+fn test_with_context_and_caller() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut inc = FunctionWriter::new(ArgCount::Fixed(1));
+    inc.evaluate_expression(Expression::UnaryOpEval(
+        TestUnaryOperator::Inc,
+        Expression::stack(0).into(),
+    ));
+    let inc = engine.register_function(inc, 0);
+    let result = engine.with_context_and_caller(|context, caller| {
+        let one = caller.call(context, &inc, [TestValueWrapper(TestValue::Number(0))])?;
+        caller.evaluate(
+            context,
+            &Expression::StaticFunctionCall(inc.clone(), vec![Expression::RawValue(one)]),
+        )
+    });
+    assert_eq!(result, Ok(TestValueWrapper(TestValue::Number(2))));
+}
+
+#[test]
+fn test_fuel_stops_infinite_loop() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let forever = Expression::While {
+        condition: number(1).into(),
+        body: Expression::RawValue(TestValueWrapper(TestValue::Null)).into(),
+    };
+    engine.set_fuel(Some(1000));
+    assert_eq!(engine.evaluate(&forever), Err(FreightError::OutOfFuel));
+    assert_eq!(engine.remaining_fuel(), Some(0));
+}
+
+#[test]
+fn test_coroutine() {
+    let value = |n| TestValueWrapper(TestValue::Number(n));
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let target = engine.create_return_target();
+
+    // Yields one more than the value it was last resumed with, three times
+    let mut count = FunctionWriter::new(ArgCount::Fixed(0));
+    let i = count.create_variable();
+    count.evaluate_expression(Expression::AssignStack(i, number(0).into()));
+    count.evaluate_expression(Expression::Repeat(
+        3,
+        Expression::AssignStack(
+            i,
+            Expression::Yield(
+                Expression::BinaryOpEval(
+                    TestBinaryOperator::Add,
+                    [Expression::stack(i), number(1)].into(),
+                )
+                .into(),
+            )
+            .into(),
+        )
+        .into(),
+    ));
+    count.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Mul,
+        [Expression::stack(i), number(10)].into(),
+    ));
+    let count = engine.register_function(count, target);
+
+    let mut coroutine = engine.start_coroutine(&count, []).unwrap();
+    let mut last = TestValueWrapper(TestValue::Null);
+    for n in 1..=3 {
+        assert_eq!(
+            coroutine.resume(&mut engine, last),
+            Ok(CoroutineState::Yielded(value(n)))
+        );
+        last = value(n);
+    }
+    assert_eq!(
+        coroutine.resume(&mut engine, last),
+        Ok(CoroutineState::Returned(value(30)))
+    );
+    assert!(coroutine.is_finished());
+    assert_eq!(
+        coroutine.resume(&mut engine, value(0)),
+        Err(FreightError::CoroutineFinished)
+    );
+
+    // Yields once if its argument is truthy, then returns early
+    let mut early = FunctionWriter::new(ArgCount::Fixed(1));
+    early.evaluate_expression(Expression::Conditional {
+        condition: Expression::stack(0).into(),
+        then: Expression::Yield(number(5).into()).into(),
+        otherwise: number(0).into(),
+    });
+    early.evaluate_expression(Expression::Return(target, number(7).into()));
+    early.evaluate_expression(number(8));
+    let early = engine.register_function(early, target);
+    let mut coroutine = engine.start_coroutine(&early, [value(1)]).unwrap();
+    assert_eq!(
+        coroutine.resume(&mut engine, value(0)),
+        Ok(CoroutineState::Yielded(value(5)))
+    );
+    assert_eq!(
+        coroutine.resume(&mut engine, value(0)),
+        Ok(CoroutineState::Returned(value(7)))
+    );
+
+    let nested = Expression::BinaryOpEval(
+        TestBinaryOperator::Add,
+        [Expression::Yield(number(1).into()), number(1)].into(),
+    );
+    assert_eq!(engine.evaluate(&nested), Err(FreightError::InvalidYield));
+
+    // Expressions a coroutine steps into are entered once, not again each time it is resumed
+    thread_local! {
+        static REPEATS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+    engine.hooks.register(ExpressionKind::Repeat, |_, _| {
+        REPEATS.with(|repeats| repeats.set(repeats.get() + 1));
+        Ok(())
+    });
+    let mut coroutine = engine.start_coroutine(&count, []).unwrap();
+    while !matches!(
+        coroutine.resume(&mut engine, value(0)),
+        Ok(CoroutineState::Returned(_))
+    ) {}
+    assert_eq!(REPEATS.with(|repeats| repeats.get()), 1);
+
+    // Resuming enters the function like a call
+    let mut coroutine = engine.start_coroutine(&count, []).unwrap();
+    engine.cancellation.cancel();
+    assert_eq!(
+        coroutine.resume(&mut engine, value(0)),
+        Err(FreightError::Cancelled)
+    );
+    assert!(coroutine.is_finished());
+}
+
+#[test]
+fn test_call_async() {
+    use std::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    /// Resolves to its value after being polled twice
+    struct Delayed(Option<TestValueWrapper>, bool);
+
+    impl Future for Delayed {
+        type Output = Result<TestValueWrapper, FreightError>;
+
+        fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            if std::mem::replace(&mut self.1, true) {
+                Poll::Ready(Ok(self.0.take().unwrap()))
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Poll a future until it is ready, returning its output and how many times it was polled
+    fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            if let Poll::Ready(result) = future.as_mut().poll(&mut cx) {
+                return (result, polls);
+            }
+        }
+    }
+
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    // Asks the host to double its argument, then adds one to the answer
+    let mut main = FunctionWriter::new(ArgCount::Fixed(1));
+    main.evaluate_expression(Expression::AssignStack(
+        0,
+        Expression::Yield(Expression::stack(0).into()).into(),
+    ));
+    main.evaluate_expression(Expression::UnaryOpEval(
+        TestUnaryOperator::Inc,
+        Expression::stack(0).into(),
+    ));
+    let main = engine.register_function(main, 0);
+
+    let args = [TestValueWrapper(TestValue::Number(20))];
+    let (result, polls) = block_on(engine.call_async(&main, args, |request| {
+        let TestValue::Number(n) = request.0 else {
+            panic!("Expected a number");
+        };
+        Delayed(Some(TestValueWrapper(TestValue::Number(n * 2))), false)
+    }));
+    assert_eq!(result, Ok(TestValueWrapper(TestValue::Number(41))));
+    assert_eq!(polls, 2);
+
+    // Futures returned by natives are awaited without going through the host's handler
+    let fetch = AsyncNativeFunction::<TestTypeSystem>::new(|_, args| {
+        let TestValue::Number(n) = args[0].0 else {
+            panic!("Expected a number");
+        };
+        Box::pin(Delayed(
+            Some(TestValueWrapper(TestValue::Number(n * 3))),
+            false,
+        ))
+    });
+    let mut main = FunctionWriter::new(ArgCount::Fixed(1));
+    main.evaluate_expression(Expression::AssignStack(
+        0,
+        Expression::AwaitNative(fetch, vec![Expression::stack(0)]).into(),
+    ));
+    main.evaluate_expression(Expression::UnaryOpEval(
+        TestUnaryOperator::Inc,
+        Expression::stack(0).into(),
+    ));
+    let main = engine.register_function(main, 0);
+    let args = [TestValueWrapper(TestValue::Number(20))];
+    let (result, _) = block_on(engine.call_async(&main, args, |_| -> Delayed {
+        panic!("Natives' futures shouldn't be handled by the host")
+    }));
+    assert_eq!(result, Ok(TestValueWrapper(TestValue::Number(61))));
+    assert_eq!(
+        engine.call(&main, [TestValueWrapper(TestValue::Number(1))]),
+        Err(FreightError::InvalidYield)
+    );
+}
+
+#[test]
+fn test_host_only() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let secret = FunctionRef::new_native(
+        3,
+        NativeFunction::new(|_, _| Ok(TestValueWrapper(TestValue::Number(42)))),
+        ArgCount::Fixed(0),
+    )
+    .host_only();
+    engine
+        .docs
+        .insert(DocTarget::function(&secret), Documentation::new("Internal"));
+    let id = engine
+        .symbols
+        .bind("secret", Symbol::Function(secret.clone()));
+    engine.symbols.intern("unbound");
+
+    assert_eq!(
+        engine.call(&secret, []),
+        Ok(TestValueWrapper(TestValue::Number(42)))
+    );
+    let denied = Err(FreightError::HostOnlyFunction { function: 3 });
+    let call =
+        Expression::DynamicFunctionCall(Expression::RawValue(secret.clone().into()).into(), vec![]);
+    assert_eq!(engine.evaluate(&call), denied);
+    assert_eq!(engine.evaluate(&Expression::ResolveSymbol(id)), denied);
+    // Registering a function doesn't turn the call into a static call which skips the check
+    let mut script = FunctionWriter::new(ArgCount::Fixed(0));
+    script.evaluate_expression(call);
+    let script = engine.register_function(script, 0);
+    assert_eq!(engine.call(&script, []), denied);
+    assert!(engine.help("secret").is_none());
+    assert_eq!(engine.symbols.visible_names().count(), 0);
+
+    let public = FunctionRef::new_native(
+        4,
+        NativeFunction::new(|_, _| Ok(Default::default())),
+        ArgCount::Fixed(0),
+    );
+    engine.symbols.bind("public", Symbol::Function(public));
+    assert_eq!(
+        engine.symbols.visible_names().collect::<Vec<_>>(),
+        ["public"]
+    );
+}
+
+#[test]
+fn test_named_arguments() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let digits = FunctionRef::new_native(
+        5,
+        NativeFunction::new(|_, args: &mut [TestValueWrapper]| {
+            let digit = |i: usize| match args.get(i) {
+                Some(TestValueWrapper(TestValue::Number(n))) => *n,
+                _ => 0,
+            };
+            Ok(TestValueWrapper(TestValue::Number(
+                digit(0) * 100 + digit(1) * 10 + digit(2),
+            )))
+        }),
+        ArgCount::Range { min: 2, max: 3 },
+    )
+    .with_param_names(["a".into(), "b".into(), "c".into()]);
+    assert_eq!(digits.param_index("c"), Some(2));
+    let call = |args: Vec<Expression<TestTypeSystem>>, named: Vec<(&str, i64)>| {
+        Expression::NamedFunctionCall {
+            func: Expression::RawValue(digits.clone().into()).into(),
+            args,
+            named: named
+                .into_iter()
+                .map(|(name, n)| (name.into(), number(n)))
+                .collect(),
+        }
+    };
+    let result = |n| Ok(TestValueWrapper(TestValue::Number(n)));
+
+    assert_eq!(
+        engine.evaluate(&call(vec![number(1)], vec![("c", 3), ("b", 2)])),
+        result(123)
+    );
+    assert_eq!(
+        engine.evaluate(&call(vec![], vec![("b", 2), ("a", 1)])),
+        result(120)
+    );
+    let unbound = |name: &str| {
+        Err(FreightError::ArgumentName {
+            function: 5,
+            name: name.into(),
+        })
+    };
+    assert_eq!(
+        engine.evaluate(&call(vec![number(1)], vec![("d", 4)])),
+        unbound("d")
+    );
+    assert_eq!(
+        engine.evaluate(&call(vec![number(1)], vec![("a", 1)])),
+        unbound("a")
+    );
+    assert_eq!(
+        engine.evaluate(&call(vec![], vec![("a", 1), ("c", 3)])),
+        unbound("b")
+    );
+
+    let mut writer = FunctionWriter::<TestTypeSystem>::new(ArgCount::Fixed(2));
+    writer.set_param_names(["x".into(), "y".into()]);
+    writer.evaluate_expression(Expression::stack(1));
+    let sub = engine.register_function(writer, 0);
+    let call = Expression::NamedFunctionCall {
+        func: Expression::RawValue(sub.into()).into(),
+        args: vec![],
+        named: vec![("y".into(), number(3)), ("x".into(), number(10))],
+    };
+    assert_eq!(engine.evaluate(&call), result(3));
+}
+
+#[test]
+fn test_dump_program() {
+    let build = |constant| {
+        let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+        let global = engine.create_global();
+        engine.symbols.bind("counter", Symbol::Global(global));
+        let mut writer = FunctionWriter::new(ArgCount::Fixed(1));
+        writer.evaluate_expression(Expression::AssignGlobal(
+            global,
+            Expression::BinaryOpEval(
+                TestBinaryOperator::Add,
+                [Expression::stack(0), number(constant)].into(),
+            )
+            .into(),
+        ));
+        let func = engine.register_function(writer, 0);
+        engine.symbols.bind("bump", Symbol::Function(func));
+        let mut dump = vec![];
+        engine.dump_program(&mut dump).unwrap();
+        String::from_utf8(dump).unwrap()
+    };
+
+    let old = build(1);
+    assert_eq!(
+        old,
+        "fn 0 bump return 0\n\
+        \x20 AssignGlobal global 0 counter\n\
+        \x20   BinaryOpEval Add\n\
+        \x20     Variable stack 0\n\
+        \x20     RawValue TestValueWrapper(Number(1))\n"
+    );
+    assert_eq!(diff_dumps(&old, &build(1)), "");
+    assert_eq!(
+        diff_dumps(&old, &build(2)),
+        " fn 0 bump return 0\n\
+        \x20  AssignGlobal global 0 counter\n\
+        \x20    BinaryOpEval Add\n\
+        \x20      Variable stack 0\n\
+        -      RawValue TestValueWrapper(Number(1))\n\
+        +      RawValue TestValueWrapper(Number(2))\n"
+    );
+}
+
+#[test]
+fn test_visit() {
+    struct Trace(Vec<String>);
+
+    impl Visitor<TestTypeSystem> for Trace {
+        fn enter(&mut self, expr: &Expression<TestTypeSystem>) -> Visit {
+            self.0.push(format!("enter {:?}", expr.kind()));
+            match expr {
+                Expression::Catch(_) => Visit::Skip,
+                Expression::Yield(_) => Visit::Stop,
+                _ => Visit::Descend,
+            }
+        }
+
+        fn exit(&mut self, expr: &Expression<TestTypeSystem>) {
+            self.0.push(format!("exit {:?}", expr.kind()));
+        }
+    }
+
+    let mut expr = Expression::BinaryOpEval(
+        TestBinaryOperator::Add,
+        [
+            Expression::Catch(Expression::stack(0).into()),
+            Expression::global(1),
+        ]
+        .into(),
+    );
+    let mut trace = Trace(vec![]);
+    assert!(expr.visit(&mut trace));
+    assert_eq!(
+        trace.0,
+        [
+            "enter BinaryOpEval",
+            "enter Catch",
+            "exit Catch",
+            "enter Variable",
+            "exit Variable",
+            "exit BinaryOpEval",
+        ]
+    );
+
+    let mut trace = Trace(vec![]);
+    let stopped = Expression::Repeat(2, Expression::Yield(Expression::stack(0).into()).into());
+    assert!(!stopped.visit(&mut trace));
+    assert_eq!(trace.0, ["enter Repeat", "enter Yield"]);
+
+    expr.visit_mut(&mut |expr: &mut Expression<TestTypeSystem>| {
+        if let Expression::Variable(var) = expr {
+            *var = VariableType::Stack(2);
+        }
+        Visit::Descend
+    });
+    let mut slots = vec![];
+    expr.visit(&mut |expr: &Expression<TestTypeSystem>| {
+        if let Expression::Variable(var) = expr {
+            slots.push(var.clone());
+        }
+        Visit::Descend
+    });
+    assert_eq!(slots, [VariableType::Stack(2), VariableType::Stack(2)]);
+}
+
+#[test]
+fn test_instrumentation() {
+    let number_value = |n| TestValueWrapper(TestValue::Number(n));
+    let register = |engine: &mut ExecutionEngine<TestTypeSystem>| {
+        let mut writer = FunctionWriter::new(ArgCount::Fixed(1));
+        writer.evaluate_expression(Expression::Conditional {
+            condition: Expression::stack(0).into(),
+            then: number(1).into(),
+            otherwise: number(2).into(),
+        });
+        engine.register_function(writer, 0)
+    };
+
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let uninstrumented = register(&mut engine);
+    engine
+        .start_instrumentation(ProbeMode::Count)
+        .probe(ExpressionKind::RawValue)
+        .statements = true;
+    let func = register(&mut engine);
+    engine.call(&uninstrumented, [number_value(1)]).unwrap();
+    assert_eq!(
+        engine.call(&func, [number_value(1)]),
+        Ok(TestValueWrapper(TestValue::Number(1)))
+    );
+
+    let instrumentation = engine.instrumentation_mut().unwrap();
+    let kinds: Vec<_> = instrumentation
+        .sites()
+        .iter()
+        .map(|site| site.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            ExpressionKind::RawValue,
+            ExpressionKind::RawValue,
+            ExpressionKind::Conditional
+        ]
+    );
+    assert!(instrumentation
+        .sites()
+        .iter()
+        .all(|site| site.function == 1));
+    assert_eq!(instrumentation.counts(), [1, 0, 1]);
+    assert_eq!(instrumentation.uncovered().collect::<Vec<_>>(), [1]);
+    assert!(instrumentation.trace().is_empty());
+
+    instrumentation.reset();
+    instrumentation.mode = ProbeMode::Trace;
+    engine.call(&func, [number_value(0)]).unwrap();
+    engine.call(&func, [number_value(3)]).unwrap();
+    let instrumentation = engine.instrumentation().unwrap();
+    assert_eq!(instrumentation.counts(), [1, 1, 2]);
+    assert_eq!(instrumentation.trace(), [2, 1, 2, 0]);
+
+    // Starting again keeps the probes of functions already registered
+    engine.start_instrumentation(ProbeMode::Count).reset();
+    engine.call(&func, [number_value(1)]).unwrap();
+    assert_eq!(engine.instrumentation().unwrap().counts(), [1, 0, 1]);
+
+    // Forks don't insert probes, and don't count the parent's
+    let mut fork = engine.fork();
+    let forked = register(&mut fork);
+    fork.call(&func, [number_value(1)]).unwrap();
+    fork.call(&forked, [number_value(1)]).unwrap();
+    assert_eq!(engine.instrumentation().unwrap().counts(), [1, 0, 1]);
+}
+
+#[test]
+fn test_function_metadata() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut writer = FunctionWriter::new(ArgCount::Fixed(0));
+    writer.set_metadata(
+        FunctionMetadata::named("main")
+            .in_module("lib.fe")
+            .with_span(10..42),
+    );
+    let main = engine.register_function(writer, 0);
+    let anonymous = engine.register_function(FunctionWriter::new(ArgCount::Fixed(0)), 0);
+
+    let metadata = main.metadata().unwrap();
+    assert_eq!(metadata.name.as_deref(), Some("main"));
+    assert_eq!(metadata.to_string(), "main (lib.fe:10..42)");
+    assert_eq!(engine.function_metadata(main.address()), Some(metadata));
+    assert_eq!(anonymous.metadata(), None);
+    assert_eq!(engine.function_metadata(anonymous.address()), None);
+    assert_eq!(
+        FunctionMetadata::default().with_span(1..2).to_string(),
+        "<anonymous> (1..2)"
+    );
+
+    let native = FunctionRef::<TestTypeSystem>::new_native(
+        0,
+        NativeFunction::new(|_, _| Ok(Default::default())),
+        ArgCount::Fixed(0),
+    )
+    .with_metadata(FunctionMetadata::named("print"));
+    assert_eq!(native.metadata().unwrap().to_string(), "print");
+}
+
+#[test]
+fn test_error_span() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let spanned =
+        |span, expr: Expression<TestTypeSystem>| Expression::Spanned(SpanId(span), expr.into());
+    let failing = || Expression::DynamicFunctionCall(number(1).into(), vec![]);
+
+    let mut writer = FunctionWriter::new(ArgCount::Fixed(0));
+    writer.evaluate_expression(spanned(5, failing()));
+    let func = engine.register_function(writer, 0);
+    let call = spanned(
+        1,
+        spanned(2, Expression::StaticFunctionCall(func.clone(), vec![])),
+    );
+    assert_eq!(
+        engine.evaluate(&call),
+        Err(FreightError::InvalidInvocationTarget)
+    );
+    assert_eq!(engine.error_span(), Some(SpanId(5)));
+    assert_eq!(engine.error_trace().len(), 1);
+
+    let caught = spanned(1, Expression::Catch(spanned(2, failing()).into()));
+    assert!(engine.evaluate(&caught).is_ok());
+    assert_eq!(engine.error_span(), None);
+
+    let target = engine.create_return_target();
+    let returned = Expression::ReturnTarget(
+        target,
+        spanned(
+            3,
+            Expression::Return(target, Expression::RawValue(Default::default()).into()),
+        )
+        .into(),
+    );
+    assert!(engine.evaluate(&returned).is_ok());
+    assert_eq!(engine.error_span(), None);
+}
+
+#[test]
+fn test_services() {
+    let run = |engine: &mut ExecutionEngine<TestTypeSystem>| {
+        let random = FunctionRef::new_native(0, Rng::random_native(), ArgCount::Fixed(0));
+        let now = FunctionRef::new_native(1, Clock::now_native(), ArgCount::Fixed(0));
+        [
+            engine.call(&random, []).unwrap(),
+            engine.call(&random, []).unwrap(),
+            engine.call(&now, []).unwrap(),
+            engine.call(&now, []).unwrap(),
+        ]
+    };
+
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    engine.rng = Rng::new(7);
+    engine.clock = Clock::Manual {
+        now: Duration::from_secs(1),
+        tick: Duration::from_millis(10),
+    };
+    let first = run(&mut engine);
+    assert_ne!(first[0], first[1]);
+    assert!(matches!(first[0], TestValueWrapper(TestValue::Number(n)) if n >= 0));
+    assert_eq!(
+        first[2..],
+        [1000, 1010].map(|n| TestValueWrapper(TestValue::Number(n)))
+    );
+
+    engine.rng = Rng::new(7);
+    engine.clock = Clock::Recording(vec![]);
+    let recorded = run(&mut engine);
+    assert_eq!(recorded[..2], first[..2]);
+    let readings = engine.clock.recorded().unwrap().to_vec();
+    assert_eq!(readings.len(), 2);
+
+    let mut replay = ExecutionEngine::<TestTypeSystem>::new_default();
+    replay.rng = Rng::new(7);
+    replay.clock = Clock::Replay(readings.into());
+    assert_eq!(run(&mut replay), recorded);
+    assert_eq!(replay.clock.now(), replay.clock.now());
+}
+
+#[test]
+fn test_trace_reachable() {
+    use crate::value::Value;
+
+    let number = |n| TestValueWrapper(TestValue::Number(n));
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let list = engine.create_global();
+    let secret = engine.create_global();
+    engine
+        .evaluate(&Expression::AssignGlobal(
+            secret,
+            Expression::RawValue(number(42)).into(),
+        ))
+        .unwrap();
+    let closure = engine.register_function(
+        FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![VariableType::Global(secret)]),
+        0,
+    );
+    let closure = engine
+        .evaluate(&Expression::FunctionCapture(closure))
+        .unwrap();
+    assert_eq!(
+        closure.cast_to_function().unwrap().captured_values(),
+        Some(&[number(42)][..])
+    );
+    let value = TestValueWrapper(TestValue::List(vec![number(1), closure]));
+    engine
+        .evaluate(&Expression::AssignGlobal(
+            list,
+            Expression::RawValue(value).into(),
+        ))
+        .unwrap();
+
+    let mut roots = 0;
+    engine.trace_roots(|_| roots += 1);
+    assert_eq!(roots, 3);
+
+    let mut reached = vec![];
+    engine.trace_reachable(|value| {
+        reached.push(value.get_type().clone());
+        true
+    });
+    assert_eq!(
+        reached,
+        [
+            TestTypeId::List,
+            TestTypeId::Number,
+            TestTypeId::Function,
+            TestTypeId::Number,
+            TestTypeId::Number,
+            TestTypeId::Null,
+        ]
+    );
+
+    let mut lists = 0;
+    engine.trace_reachable(|value| {
+        lists += 1;
+        !matches!(value.0, TestValue::List(_))
+    });
+    assert_eq!(lists, 3);
+}
+
+#[test]
+fn test_find_cycles() {
+    let cell = |value| Rc::new(RefCell::new(TestValueWrapper(value)));
+    let a = cell(TestValue::Null);
+    let b = cell(TestValue::List(vec![TestValueWrapper(TestValue::Cell(
+        a.clone(),
+    ))]));
+    *a.borrow_mut() = TestValueWrapper(TestValue::List(vec![TestValueWrapper(TestValue::Cell(
+        b.clone(),
+    ))]));
+    let acyclic = cell(TestValue::Number(1));
+
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    for value in [
+        TestValue::Cell(a.clone()),
+        TestValue::List(vec![
+            TestValueWrapper(TestValue::Cell(acyclic)),
+            TestValueWrapper(TestValue::Cell(b.clone())),
+        ]),
+    ] {
+        let global = engine.create_global();
+        engine
+            .evaluate(&Expression::AssignGlobal(
+                global,
+                Expression::RawValue(TestValueWrapper(value)).into(),
+            ))
+            .unwrap();
+    }
+
+    let id = |cell: &Rc<RefCell<TestValueWrapper>>| Rc::as_ptr(cell) as usize;
+    assert_eq!(
+        engine.find_cycles(4),
+        CycleReport {
+            cycles: 1,
+            examples: vec![vec![id(&a), id(&b), id(&a)]],
+        }
+    );
+    assert!(engine.find_cycles(0).examples.is_empty());
+
+    *a.borrow_mut() = TestValueWrapper(TestValue::Null);
+    assert_eq!(engine.find_cycles(4), CycleReport::default());
+}
+
+#[test]
+fn test_lint() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let target = engine.create_return_target();
+    let write = || {
+        let mut writer = FunctionWriter::new(ArgCount::Fixed(1));
+        writer.evaluate_expression(Expression::Spanned(
+            SpanId(7),
+            Expression::AssignStack(0, number(1).into()).into(),
+        ));
+        writer.evaluate_expression(Expression::DynamicFunctionCall(number(3).into(), vec![]));
+        writer.evaluate_expression(Expression::Return(target, number(2).into()));
+        writer.evaluate_expression(Expression::Spanned(SpanId(9), number(4).into()));
+        writer
+    };
+
+    engine.register_function(write(), target);
+    assert!(engine.diagnostics().is_empty());
+
+    engine.lint = true;
+    engine.register_function(write(), target);
+    engine.register_function(
+        FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![]),
+        target,
+    );
+    let diagnostic = |expression, span, lint| Diagnostic {
+        function: 1,
+        expression,
+        span,
+        lint,
+    };
+    assert_eq!(
+        engine.take_diagnostics(),
+        [
+            diagnostic(
+                Some(0),
+                Some(SpanId(7)),
+                Lint::UnreadArgumentWrite { slot: 0 }
+            ),
+            diagnostic(Some(1), None, Lint::NotCallable),
+            diagnostic(Some(3), Some(SpanId(9)), Lint::Unreachable),
+            Diagnostic {
+                function: 2,
+                ..diagnostic(None, None, Lint::EmptyCaptures)
+            },
+        ]
+    );
+    assert!(engine.diagnostics().is_empty());
+}
+
+#[test]
+fn test_memory_limit() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let global = engine.create_global();
+    let mut recurse = FunctionWriter::new(ArgCount::Fixed(0));
+    recurse.create_variable();
+    recurse.evaluate_expression(Expression::DynamicFunctionCall(
+        Expression::global(global).into(),
+        vec![],
+    ));
+    let recurse = engine.register_function(recurse, 0);
+    engine
+        .evaluate(&Expression::AssignGlobal(
+            global,
+            Expression::RawValue(recurse.clone().into()).into(),
+        ))
+        .unwrap();
+    let baseline = engine.memory_used();
+    assert_eq!(baseline, 1);
+
+    engine.set_memory_limit(Some(20));
+    assert_eq!(engine.call(&recurse, []), Err(FreightError::OutOfMemory));
+    assert_eq!(engine.memory_used(), baseline);
+
+    let mut capture =
+        FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![VariableType::Stack(0)]);
+    capture.evaluate_expression(Expression::captured(0));
+    let capture = engine.register_function(capture, 0);
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.create_variable();
+    main.evaluate_expression(Expression::FunctionCapture(capture));
+    let main = engine.register_function(main, 0);
+    engine.set_memory_limit(Some(baseline + 1));
+    assert_eq!(engine.call(&main, []), Err(FreightError::OutOfMemory));
+    engine.set_memory_limit(Some(baseline + 2));
+    let closure = engine.call(&main, []).unwrap();
+    assert_eq!(engine.memory_used(), baseline + 1);
+    drop(closure);
+    // The test regions keep the results of calls
+    REGION_EXITS.with(|exits| exits.borrow_mut().clear());
+    assert_eq!(engine.memory_used(), baseline);
+}
+
+#[test]
+fn test_function_features() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut square = FunctionWriter::new(ArgCount::Fixed(1));
+    square.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Mul,
+        [Expression::stack(0), Expression::stack(0)].into(),
+    ));
+    let square = engine.register_function(square, 0);
+    assert_eq!(
+        engine.function_features(square.location),
+        Some(FunctionFeatures::default())
+    );
+    assert_eq!(
+        engine.call(&square, [TestValueWrapper(TestValue::Number(3))]),
+        Ok(TestValueWrapper(TestValue::Number(9)))
+    );
+
+    let target = engine.create_return_target();
+    let mut early = FunctionWriter::new(ArgCount::Fixed(0));
+    early.evaluate_expression(Expression::Return(target, number(1).into()));
+    early.evaluate_expression(number(2));
+    let early = engine.register_function(early, target);
+    let features = engine.function_features(early.location).unwrap();
+    assert!(features.has_return_targets);
+    assert_eq!(
+        engine.call(&early, []),
+        Ok(TestValueWrapper(TestValue::Number(1)))
+    );
+
+    // A function it calls could return to its target
+    let global = engine.create_global();
+    let mut caller = FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![]);
+    caller.evaluate_expression(Expression::AssignGlobal(
+        global,
+        Expression::captured(0).into(),
+    ));
+    caller.evaluate_expression(Expression::StaticFunctionCall(square, vec![number(2)]));
+    let caller = engine.register_function(caller, 0);
+    assert_eq!(
+        engine.function_features(caller.location),
+        Some(FunctionFeatures {
+            has_return_targets: true
+        })
+    );
+}
+
+#[test]
+fn test_stack_growth() {
+    let pool = Rc::new(UnsafeCell::new(StackPool::<usize>::with_capacity(4)));
+    let mut outer = StackPool::request(pool.clone(), 3);
+    outer[2] = 1;
+    // The frame doesn't fit in the first chunk, so it's taken from a new one
+    let mut inner = StackPool::request(pool.clone(), 2);
+    inner[1] = 2;
+    assert_eq!(outer[2], 1);
+    assert_eq!(unsafe { &*pool.get() }.used(), 5);
+    assert_eq!(unsafe { &*pool.get() }.capacity(), 8);
+    drop(inner);
+    // Frames bigger than a chunk get a chunk of their own
+    let big = StackPool::request(pool.clone(), 20000);
+    assert_eq!(big.len(), 20000);
+    assert_eq!(unsafe { &*pool.get() }.capacity(), 20004);
+    drop(big);
+    drop(outer);
+    assert_eq!(unsafe { &*pool.get() }.used(), 0);
+}
+
+#[test]
+fn test_engine_builder() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::builder()
+        .stack_capacity(16)
+        .pool_buckets(4)
+        .max_cached_per_size(2)
+        .build_default();
+    assert_eq!(unsafe { &*engine.stack.get() }.capacity(), 16);
+    let mut sum = FunctionWriter::new(ArgCount::Fixed(2));
+    sum.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Add,
+        [Expression::stack(0), Expression::stack(1)].into(),
+    ));
+    let sum = engine.register_function(sum, 0);
+    let number = |n| TestValueWrapper(TestValue::Number(n));
+    assert_eq!(engine.call(&sum, [number(1), number(2)]), Ok(number(3)));
+    // Forks are built with the same sizes
+    let fork = engine.fork();
+    assert_eq!(unsafe { &*fork.stack.get() }.capacity(), 16);
+}
+
+#[test]
+fn test_call_fallback() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let number = |n| TestValueWrapper(TestValue::Number(n));
+    let call = |target: TestValueWrapper, args: Vec<TestValueWrapper>| {
+        Expression::DynamicFunctionCall(
+            Expression::RawValue(target).into(),
+            args.into_iter().map(Expression::RawValue).collect(),
+        )
+    };
+    let list = TestValueWrapper(TestValue::List(vec![number(10), number(20)]));
+    assert_eq!(
+        engine.evaluate(&call(list.clone(), vec![number(1)])),
+        Ok(number(20))
+    );
+    assert_eq!(
+        engine.evaluate(&call(list, vec![number(1), number(2)])),
+        Err(FreightError::InvalidInvocationTarget)
+    );
+
+    let mut last = FunctionWriter::new(ArgCount::Fixed(3));
+    last.evaluate_expression(Expression::stack(2));
+    let last = engine.register_function(last, 0);
+    assert_eq!(
+        engine.evaluate(&call(last.clone().into(), vec![number(1)])),
+        Err(FreightError::IncorrectArgumentCount {
+            expected_min: 3,
+            expected_max: Some(3),
+            actual: 1
+        })
+    );
+    // Calling the outer object redirects to the inner one, which redirects to the function
+    let object = |handler| TestValueWrapper(TestValue::Struct(TestTypeId::Null, vec![handler]));
+    let outer = object(object(last.into()));
+    assert_eq!(
+        engine.evaluate(&call(outer, vec![number(7)])),
+        Ok(number(7))
+    );
+}
+
+#[test]
+fn test_control_flow() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let number = |n| TestValueWrapper(TestValue::Number(n));
+    let constant = |engine: &mut ExecutionEngine<TestTypeSystem>, n| {
+        let mut func = FunctionWriter::new(ArgCount::Fixed(0));
+        func.evaluate_expression(Expression::RawValue(number(n)));
+        engine.register_function(func, 0)
+    };
+    let if_then_else = FunctionRef::new_native(0, ControlFlow::if_then_else(), ArgCount::Fixed(3));
+    let (one, two) = (constant(&mut engine, 1), constant(&mut engine, 2));
+    for (condition, expected) in [(5, 1), (0, 2)] {
+        assert_eq!(
+            engine.call(
+                &if_then_else,
+                [number(condition), one.clone().into(), two.clone().into()]
+            ),
+            Ok(number(expected))
+        );
+    }
+
+    // Counts the global down to zero, more times than the stack could hold recursive calls
+    let global = engine.create_global();
+    let add_to_global = |engine: &mut ExecutionEngine<TestTypeSystem>, args, amount| {
+        let mut func = FunctionWriter::new(ArgCount::Fixed(args));
+        func.evaluate_expression(Expression::AssignGlobal(
+            global,
+            Expression::BinaryOpEval(
+                TestBinaryOperator::Add,
+                [Expression::global(global), amount].into(),
+            )
+            .into(),
+        ));
+        engine.register_function(func, 0)
+    };
+    let mut condition = FunctionWriter::new(ArgCount::Fixed(0));
+    condition.evaluate_expression(Expression::global(global));
+    let condition = engine.register_function(condition, 0);
+    let decrement = add_to_global(&mut engine, 0, Expression::RawValue(number(-1)));
+    let while_do = FunctionRef::new_native(1, ControlFlow::while_do(), ArgCount::Fixed(2));
+    engine
+        .evaluate(&Expression::AssignGlobal(
+            global,
+            Expression::RawValue(number(50000)).into(),
+        ))
+        .unwrap();
+    assert!(engine
+        .call(&while_do, [condition.into(), decrement.into()])
+        .is_ok());
+    assert_eq!(engine.evaluate(&Expression::global(global)), Ok(number(0)));
+
+    let add_arg = add_to_global(&mut engine, 1, Expression::stack(0));
+    let for_range = FunctionRef::new_native(2, ControlFlow::for_range(), ArgCount::Fixed(3));
+    assert!(engine
+        .call(&for_range, [number(1), number(5), add_arg.into()])
+        .is_ok());
+    assert_eq!(engine.evaluate(&Expression::global(global)), Ok(number(10)));
+}
+
+#[test]
+fn test_call_with_scope() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let number = |n| TestValueWrapper(TestValue::Number(n));
+    let scope = NativeFunction::new(|engine, _| Ok(engine.scope().cloned().unwrap_or_default()));
+    let read_scope = || Expression::NativeFunctionCall(scope.clone(), vec![]);
+    let mut read = FunctionWriter::new(ArgCount::Fixed(0));
+    read.evaluate_expression(read_scope());
+    let read = engine.register_function(read, 0);
+    assert_eq!(engine.call_with_scope(&read, [], number(7)), Ok(number(7)));
+    assert_eq!(engine.scope(), None);
+
+    // Reads the scope of a nested call, then its own scope again once the nested call is done
+    let nested = NativeFunction::new(|engine, _| {
+        let read = FunctionRef::new_native(
+            0,
+            NativeFunction::new(|engine, _| {
+                Ok(TestValueWrapper(TestValue::List(engine.scopes().to_vec())))
+            }),
+            ArgCount::Fixed(0),
+        );
+        engine.call_with_scope(&read, [], TestValueWrapper(TestValue::Number(2)))
+    });
+    let mut outer = FunctionWriter::new(ArgCount::Fixed(0));
+    outer.evaluate_expression(Expression::Initialize(
+        TestInitializer::Pair,
+        vec![Expression::NativeFunctionCall(nested, vec![]), read_scope()],
+    ));
+    let outer = engine.register_function(outer, 0);
+    assert_eq!(
+        engine.call_with_scope(&outer, [], number(1)),
+        Ok(TestValueWrapper(TestValue::List(vec![
+            TestValueWrapper(TestValue::List(vec![number(1), number(2)])),
+            number(1)
+        ])))
+    );
+
+    let mut fail = FunctionWriter::new(ArgCount::Fixed(0));
+    fail.evaluate_expression(Expression::DynamicFunctionCall(
+        Expression::RawValue(number(0)).into(),
+        vec![],
+    ));
+    let fail = engine.register_function(fail, 0);
+    assert!(engine.call_with_scope(&fail, [], number(1)).is_err());
+    assert_eq!(engine.scopes(), &[]);
+
+    // A coroutine keeps the scopes it was started in, as if started by a native in a scoped call
+    let mut yielding = FunctionWriter::new(ArgCount::Fixed(0));
+    yielding.evaluate_expression(Expression::Yield(read_scope().into()));
+    yielding.evaluate_expression(read_scope());
+    let yielding = engine.register_function(yielding, 0);
+    engine.scopes.push(number(3));
+    let mut coroutine = engine.start_coroutine(&yielding, []).unwrap();
+    engine.scopes.clear();
+    assert_eq!(
+        coroutine.resume(&mut engine, number(0)),
+        Ok(CoroutineState::Yielded(number(3)))
+    );
+    assert_eq!(engine.scope(), None);
+    assert_eq!(engine.call_with_scope(&read, [], number(4)), Ok(number(4)));
+    assert_eq!(
+        coroutine.resume(&mut engine, number(0)),
+        Ok(CoroutineState::Returned(number(3)))
+    );
+}
+
+#[test]
+fn test_crash_report() {
+    crash::install_panic_hook();
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    engine.crash_reports = true;
+    let explode = FunctionRef::new_native(
+        9,
+        NativeFunction::new(|_, _| panic!("exploded")),
+        ArgCount::Fixed(0),
+    );
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.set_metadata(FunctionMetadata::named("main"));
+    main.evaluate_expression(Expression::Spanned(
+        SpanId(4),
+        Expression::StaticFunctionCall(explode, vec![]).into(),
+    ));
+    let main = engine.register_function(main, 0);
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| engine.call(&main, [])));
+    assert!(result.is_err());
+    let report = crash::take_crash_report().unwrap();
+    assert!(report.message.unwrap().contains("exploded"));
+    assert_eq!(
+        report.frames,
+        vec![
+            CrashFrame {
+                function: 9,
+                metadata: None,
+                span: None,
+            },
+            CrashFrame {
+                function: main.location,
+                metadata: Some(FunctionMetadata::named("main").into()),
+                span: Some(SpanId(4)),
+            },
+        ]
+    );
+    assert_eq!(crash::take_crash_report(), None);
+}
+
+#[test]
+fn test_nesting_guard() {
+    let value = |v| TestValueWrapper(v);
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let global = engine.create_global();
+    let cell = Rc::new(RefCell::new(value(TestValue::Number(1))));
+    let nested = value(TestValue::List(vec![value(TestValue::Cell(cell.clone()))]));
+    engine
+        .evaluate(&Expression::AssignGlobal(
+            global,
+            Expression::RawValue(nested).into(),
+        ))
+        .unwrap();
+    let fork = engine.try_fork().unwrap();
+    *cell.borrow_mut() = value(TestValue::Number(2));
+    let TestValue::List(list) = fork.globals[global].0.clone() else {
+        panic!("global should be a list");
+    };
+    assert_eq!(
+        list[0],
+        value(TestValue::Cell(Rc::new(RefCell::new(value(
+            TestValue::Number(1)
+        )))))
+    );
+
+    *cell.borrow_mut() = value(TestValue::Cell(cell.clone()));
+    assert_eq!(engine.try_fork().err(), Some(FreightError::CyclicValue));
+    *cell.borrow_mut() = Default::default();
+
+    let deep = (0..10).fold(value(TestValue::Null), |inner, _| {
+        value(TestValue::List(vec![inner]))
+    });
+    engine
+        .evaluate(&Expression::AssignGlobal(
+            global,
+            Expression::RawValue(deep).into(),
+        ))
+        .unwrap();
+    engine.max_value_depth = 5;
+    assert_eq!(
+        engine.try_fork().err(),
+        Some(FreightError::NestingTooDeep { max_depth: 5 })
+    );
+    engine.max_value_depth = 11;
+    assert!(engine.try_fork().is_ok());
+}
+
+#[test]
+fn test_transfer_value() {
+    use crate::value::Value;
+
+    let number = |n| TestValueWrapper(TestValue::Number(n));
+    let build = |captured: Expression<TestTypeSystem>| {
+        let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+        let secret = engine.create_global();
+        engine
+            .evaluate(&Expression::AssignGlobal(secret, captured.into()))
+            .unwrap();
+        let mut writer =
+            FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![VariableType::Global(secret)]);
+        writer.evaluate_expression(Expression::Variable(VariableType::Captured(0)));
+        let closure = engine.register_function(writer, 0);
+        let closure = engine
+            .evaluate(&Expression::FunctionCapture(closure))
+            .unwrap();
+        (engine, closure)
+    };
+    let (source, closure) = build(Expression::RawValue(number(42)));
+    let (mut target, _) = build(Expression::RawValue(number(0)));
+    let value = TestValueWrapper(TestValue::List(vec![number(1), closure]));
+
+    let detached = source.detach_value(&value).unwrap();
+    assert_eq!(detached.functions().collect::<Vec<_>>(), vec![0]);
+    let moved = target.rehydrate_value(detached).unwrap();
+    assert_eq!(moved, value);
+    let TestValue::List(list) = &moved.0 else {
+        panic!("value should be a list");
+    };
+    let func = list[1].cast_to_function().unwrap();
+    assert_eq!(func.captured_values(), Some(&[number(42)][..]));
+    assert_eq!(target.call(func, []), Ok(number(42)));
+
+    let mut unrelated = ExecutionEngine::<TestTypeSystem>::new_default();
+    assert_eq!(
+        source.transfer_value(&value, &unrelated),
+        Err(FreightError::IncompatibleFunction { location: 0 })
+    );
+    let mut writer = FunctionWriter::new(ArgCount::Fixed(0));
+    writer.evaluate_expression(Expression::RawValue(number(7)));
+    unrelated.register_function(writer, 0);
+    assert_eq!(
+        source.transfer_value(&value, &unrelated),
+        Err(FreightError::IncompatibleFunction { location: 0 })
+    );
+    assert_eq!(source.transfer_value(&number(3), &unrelated), Ok(number(3)));
+}
+
+#[test]
+fn test_replace_function() {
+    use crate::value::Value;
+
+    let value = |n| TestValueWrapper(TestValue::Number(n));
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let global = engine.create_global();
+    // Replaces the function it is passed with one returning 2
+    let redefine = NativeFunction::<TestTypeSystem>::new(|engine, args| {
+        let func: FunctionRef<TestTypeSystem> = Args::<TestTypeSystem>::new(args).get(0)?;
+        let mut body = FunctionWriter::new(ArgCount::Fixed(0));
+        body.evaluate_expression(number(2));
+        engine.replace_function(&func, body, 0)?;
+        Ok(Default::default())
+    });
+    let mut writer = FunctionWriter::new(ArgCount::Fixed(0));
+    writer.evaluate_expression(Expression::NativeFunctionCall(
+        redefine,
+        vec![Expression::global(global)],
+    ));
+    writer.evaluate_expression(number(1));
+    let func = engine.register_function(writer, 0);
+    engine
+        .evaluate(&Expression::AssignGlobal(
+            global,
+            Expression::RawValue(func.clone().into()).into(),
+        ))
+        .unwrap();
+    let call_global = Expression::DynamicFunctionCall(Expression::global(global).into(), vec![]);
+    // The running call finishes the old body
+    assert_eq!(engine.evaluate(&call_global), Ok(value(1)));
+    assert_eq!(engine.evaluate(&call_global), Ok(value(2)));
+    assert_eq!(engine.call(&func, []), Ok(value(2)));
+
+    let mut writer =
+        FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![VariableType::Global(global)]);
+    writer.evaluate_expression(Expression::Variable(VariableType::Captured(0)));
+    let getter = engine.register_function(writer, 0);
+    engine
+        .evaluate(&Expression::AssignGlobal(global, number(42).into()))
+        .unwrap();
+    let closure = engine
+        .evaluate(&Expression::FunctionCapture(getter.clone()))
+        .unwrap();
+    let mut writer =
+        FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![VariableType::Global(global)]);
+    writer.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Add,
+        [Expression::Variable(VariableType::Captured(0)), number(100)].into(),
+    ));
+    engine.replace_function(&getter, writer, 0).unwrap();
+    assert_eq!(
+        engine.call(closure.cast_to_function().unwrap(), []),
+        Ok(value(142))
+    );
+
+    let incompatible = FunctionWriter::new(ArgCount::Fixed(1));
+    assert_eq!(
+        engine.replace_function(&func, incompatible, 0).err(),
+        Some(FreightError::IncompatibleReplacement {
+            location: func.location
+        })
+    );
+    assert_eq!(engine.call(&func, []), Ok(value(2)));
+
+    let yielding = |n| {
+        let mut writer = FunctionWriter::new(ArgCount::Fixed(0));
+        writer.evaluate_expression(Expression::Yield(number(n).into()));
+        writer.evaluate_expression(number(n));
+        writer
+    };
+    let func = engine.register_function(yielding(1), 0);
+    let mut suspended = engine.start_coroutine(&func, []).unwrap();
+    let mut fresh = engine.start_coroutine(&func, []).unwrap();
+    assert_eq!(
+        suspended.resume(&mut engine, value(0)),
+        Ok(CoroutineState::Yielded(value(1)))
+    );
+    engine.replace_function(&func, yielding(2), 0).unwrap();
+    assert_eq!(
+        suspended.resume(&mut engine, value(0)),
+        Err(FreightError::FunctionReplaced {
+            function: func.location
+        })
+    );
+    assert!(suspended.is_finished());
+    // A coroutine which hasn't started runs the new body
+    assert_eq!(
+        fresh.resume(&mut engine, value(0)),
+        Ok(CoroutineState::Yielded(value(2)))
+    );
+    assert_eq!(
+        fresh.resume(&mut engine, value(0)),
+        Ok(CoroutineState::Returned(value(2)))
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_program_manifest() {
+    use crate::{
+        function::FunctionMetadata,
+        manifest::{ManifestGlobal, PROGRAM_FORMAT_VERSION},
+        serialization::NativeTable,
+    };
+
+    let double = NativeFunction::<TestTypeSystem>::new(|_, args| match args[0].0 {
+        TestValue::Number(n) => Ok(TestValueWrapper(TestValue::Number(n * 2))),
+        _ => Err(FreightError::InvalidInvocationTarget),
+    });
+    let print = NativeFunction::<TestTypeSystem>::new(|_, _| Ok(Default::default()));
+    let unnamed = NativeFunction::<TestTypeSystem>::new(|_, args| Ok(args[0].clone()));
+    let mut natives = NativeTable::default();
+    natives.register("double", double.clone());
+    natives.register("print", print.clone());
+
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let global = engine.create_global();
+    engine.symbols.bind("count", Symbol::Global(global));
+    let mut helper = FunctionWriter::new(ArgCount::Fixed(1));
+    helper.evaluate_expression(Expression::NativeFunctionCall(
+        double,
+        vec![Expression::stack(0)],
+    ));
+    let helper = engine
+        .register_function(helper, 0)
+        .with_param_names(["n".into()]);
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.set_metadata(FunctionMetadata::named("main"));
+    main.evaluate_expression(Expression::StaticFunctionCall(
+        helper,
+        vec![Expression::global(global)],
+    ));
+    main.evaluate_expression(Expression::RawValue(
+        FunctionRef::new_native(0, unnamed, ArgCount::Fixed(1)).into(),
+    ));
+    let main = engine.register_function(main, 0);
+    engine.symbols.bind("main", Symbol::Function(main));
+    engine.symbols.bind(
+        "print",
+        Symbol::Function(FunctionRef::new_native(1, print, ArgCount::Fixed(1))),
+    );
+
+    let manifest = engine.program_manifest(&natives);
+    assert_eq!(manifest.format_version, PROGRAM_FORMAT_VERSION);
+    assert_eq!(manifest.natives, vec!["double", "print"]);
+    assert_eq!(manifest.unnamed_natives, 1);
+    assert_eq!(
+        manifest.globals,
+        vec![ManifestGlobal {
+            address: global,
+            symbols: vec!["count".to_string()],
+        }]
+    );
+    let [helper, main] = &manifest.functions[..] else {
+        panic!("manifest should have two functions");
+    };
+    assert_eq!(helper.arg_count, Some(ArgCount::Fixed(1)));
+    assert_eq!(helper.param_names, Some(vec!["n".to_string()]));
+    assert!(helper.symbols.is_empty());
+    assert_eq!(main.name.as_deref(), Some("main"));
+    assert_eq!(main.symbols, vec!["main"]);
+    assert_eq!(main.hash, engine.get_function(1).structural_hash());
+
+    let json = serde_json::to_string(&manifest).unwrap();
+    let manifest: crate::manifest::ProgramManifest = serde_json::from_str(&json).unwrap();
+    let mut host = NativeTable::<TestTypeSystem>::default();
+    host.register("print", NativeFunction::new(|_, _| Ok(Default::default())));
+    assert_eq!(manifest.missing_natives(&host), vec!["double"]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_save_program() {
+    use crate::{instrument::ProbeMode, serialization::NativeTable};
+
+    let double = NativeFunction::<TestTypeSystem>::new(|_, args| match args[0].0 {
+        TestValue::Number(n) => Ok(TestValueWrapper(TestValue::Number(n * 2))),
+        _ => Err(FreightError::InvalidInvocationTarget),
+    });
+    let mut natives = NativeTable::default();
+    natives.register("double", double.clone());
+
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let global = engine.create_global();
+    let mut helper = FunctionWriter::new(ArgCount::Fixed(1));
+    helper.evaluate_expression(Expression::NativeFunctionCall(
+        double,
+        vec![Expression::stack(0)],
+    ));
+    let helper = engine.register_function(helper, 0);
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.evaluate_expression(Expression::AssignGlobal(global, number(5).into()));
+    main.evaluate_expression(Expression::BinaryOpEval(
+        TestBinaryOperator::Add,
+        [
+            Expression::StaticFunctionCall(helper, vec![Expression::global(global)]),
+            number(1),
+        ]
+        .into(),
+    ));
+    let main = engine.register_function(main, 0);
+
+    let mut program = vec![];
+    engine
+        .save_program(&natives, &mut serde_json::Serializer::new(&mut program))
+        .unwrap();
+    let entry = natives.scope(|| serde_json::to_string(&main)).unwrap();
+
+    let mut loaded = ExecutionEngine::<TestTypeSystem>::new_default();
+    loaded
+        .load_program(
+            &natives,
+            &mut serde_json::Deserializer::from_slice(&program),
+        )
+        .unwrap();
+    let main: FunctionRef<TestTypeSystem> = natives.scope(|| serde_json::from_str(&entry)).unwrap();
+    assert_eq!(
+        loaded.call(&main, []),
+        Ok(TestValueWrapper(TestValue::Number(11)))
+    );
+    assert_eq!(
+        loaded.evaluate(&Expression::global(global)),
+        Ok(TestValueWrapper(TestValue::Number(5)))
+    );
+
+    assert!(engine
+        .save_program(
+            &NativeTable::default(),
+            &mut serde_json::Serializer::new(vec![])
+        )
+        .is_err());
+
+    // Loaded functions are instrumented like registered ones
+    let mut instrumented = ExecutionEngine::<TestTypeSystem>::new_default();
+    instrumented
+        .start_instrumentation(ProbeMode::Count)
+        .statements = true;
+    instrumented
+        .load_program(
+            &natives,
+            &mut serde_json::Deserializer::from_slice(&program),
+        )
+        .unwrap();
+    assert_eq!(
+        instrumented.call(&main, []),
+        Ok(TestValueWrapper(TestValue::Number(11)))
+    );
+    let counts = instrumented.instrumentation().unwrap().counts();
+    assert!(!counts.is_empty() && counts.iter().all(|&count| count == 1));
+    // but programs saved with probes are rejected
+    let mut probed = vec![];
+    instrumented
+        .save_program(&natives, &mut serde_json::Serializer::new(&mut probed))
+        .unwrap();
+    let mut rejected = ExecutionEngine::<TestTypeSystem>::new_default();
+    assert!(rejected
+        .load_program(&natives, &mut serde_json::Deserializer::from_slice(&probed))
+        .is_err());
+
+    // A program with a reference to a missing function is rejected, keeping the loaded program
+    let mut broken = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut caller = FunctionWriter::new(ArgCount::Fixed(0));
+    caller.evaluate_expression(Expression::StaticFunctionCall(
+        FunctionWriter::<TestTypeSystem>::new(ArgCount::Fixed(0)).to_ref(7),
+        vec![],
+    ));
+    broken.register_function(caller, 0);
+    let mut program = vec![];
+    broken
+        .save_program(&natives, &mut serde_json::Serializer::new(&mut program))
+        .unwrap();
+    assert!(loaded
+        .load_program(
+            &natives,
+            &mut serde_json::Deserializer::from_slice(&program)
+        )
+        .is_err());
+    assert_eq!(
+        loaded.call(&main, []),
+        Ok(TestValueWrapper(TestValue::Number(11)))
+    );
+}
+
+#[test]
+fn test_args() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    // Calls its second argument with its first argument plus the rest of its arguments
+    let apply = FunctionRef::new_native(
+        0,
+        NativeFunction::new(|engine, args| {
+            let mut args = Args::<TestTypeSystem>::new(args);
+            args.len_at_least(2)?;
+            let n: i64 = args.get(0)?;
+            let func: FunctionRef<TestTypeSystem> = args.get(1)?;
+            let rest: i64 = args.rest(2).iter().filter_map(i64::from_value).sum();
+            engine.call(&func, [TestValueWrapper(TestValue::Number(n + rest))])
+        }),
+        ArgCount::Range { min: 2, max: 4 },
+    );
+    let mut inc = FunctionWriter::new(ArgCount::Fixed(1));
+    inc.evaluate_expression(Expression::UnaryOpEval(
+        TestUnaryOperator::Inc,
+        Expression::stack(0).into(),
+    ));
+    let inc = engine.register_function(inc, 0);
+    let number = |n| TestValueWrapper(TestValue::Number(n));
+
+    assert_eq!(
+        engine.call(
+            &apply,
+            [number(1), inc.clone().into(), number(2), number(3)]
+        ),
+        Ok(number(7))
+    );
+    assert_eq!(
+        engine.call(&apply, [number(1), number(2)]),
+        Err(FreightError::InvalidArgument {
+            index: 1,
+            expected: "a function",
+            found: "TestValueWrapper(Number(2))".into(),
+        })
+    );
+    let mut empty = [];
+    let args = Args::<TestTypeSystem>::new(&mut empty);
+    assert_eq!(
+        args.get::<i64>(0),
+        Err(FreightError::IncorrectArgumentCount {
+            expected_min: 1,
+            expected_max: None,
+            actual: 0,
+        })
+    );
+}
+
+#[test]
+fn test_hoist_global_reads() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    engine.hoist_global_reads = true;
+    let rate = engine.create_global();
+    let offset = engine.create_global();
+    let mul = |l, r| Expression::BinaryOpEval(TestBinaryOperator::Mul, [l, r].into());
+    let add = |l, r| Expression::BinaryOpEval(TestBinaryOperator::Add, [l, r].into());
+
+    // rate * x + rate * offset + offset, reading both globals twice
+    let mut formula = FunctionWriter::new(ArgCount::Fixed(1));
+    formula.evaluate_expression(add(
+        add(
+            mul(Expression::global(rate), Expression::stack(0)),
+            mul(Expression::global(rate), Expression::global(offset)),
+        ),
+        Expression::global(offset),
+    ));
+    let formula = engine.register_function(formula, 0);
+    assert_eq!(formula.stack_size(), 3);
+    let statements = &engine.get_function(formula.address()).expressions;
+    assert_eq!(statements.len(), 3);
+    assert!(matches!(statements[0], Expression::AssignStack(1, _)));
+
+    // A statement which writes a global keeps reading it
+    let mut update = FunctionWriter::new(ArgCount::Fixed(0));
+    update.evaluate_expression(Expression::AssignGlobal(
+        rate,
+        add(Expression::global(rate), Expression::global(rate)).into(),
+    ));
+    let update = engine.register_function(update, 0);
+    assert_eq!(update.stack_size(), 0);
+
+    let set = |engine: &mut ExecutionEngine<TestTypeSystem>, global, n| {
+        engine
+            .evaluate(&Expression::AssignGlobal(global, number(n).into()))
+            .unwrap();
+    };
+    set(&mut engine, rate, 3);
+    set(&mut engine, offset, 2);
+    let x = [TestValueWrapper(TestValue::Number(10))];
+    assert_eq!(
+        engine.call(&formula, x.clone()),
+        Ok(TestValueWrapper(TestValue::Number(38)))
+    );
+    engine.call(&update, []).unwrap();
+    assert_eq!(
+        engine.call(&formula, x),
+        Ok(TestValueWrapper(TestValue::Number(74)))
+    );
+
+    // Hoisted reads belong to the statement they were hoisted from
+    let missing = engine.symbols.intern("missing");
+    let mut register = |last| {
+        let mut writer = FunctionWriter::new(ArgCount::Fixed(1));
+        writer.evaluate_expression(Expression::AssignStack(
+            0,
+            add(Expression::stack(0), number(1)).into(),
+        ));
+        writer.evaluate_expression(add(
+            mul(Expression::global(rate), Expression::global(rate)),
+            last,
+        ));
+        engine.register_function(writer, 0)
+    };
+    let scaled = register(Expression::stack(0));
+    let failing = register(Expression::ResolveSymbol(missing));
+    assert_eq!(engine.get_function(scaled.address()).expressions.len(), 3);
+
+    let stops = Rc::new(RefCell::new(vec![]));
+    let recorded = stops.clone();
+    let mut session = DebugSession::new(
+        &mut engine,
+        Box::new(move |step| {
+            recorded
+                .borrow_mut()
+                .push((step.statement, step.breakpoint));
+            StepAction::Step
+        }),
+    );
+    session.set_breakpoint(&scaled, 1);
+    assert_eq!(
+        session.call(&scaled, [TestValueWrapper(TestValue::Number(1))]),
+        Ok(TestValueWrapper(TestValue::Number(38)))
+    );
+    drop(session);
+    let mut stops = stops.take();
+    assert_eq!(
+        stops.iter().filter(|(_, breakpoint)| *breakpoint).count(),
+        1
+    );
+    stops.dedup_by_key(|(statement, _)| *statement);
+    assert_eq!(
+        stops
+            .iter()
+            .map(|(statement, _)| *statement)
+            .collect::<Vec<_>>(),
+        [Some(0), Some(1)]
+    );
+
+    assert!(engine
+        .call(&failing, [TestValueWrapper(TestValue::Number(1))])
+        .is_err());
+    assert_eq!(
+        engine.error_trace(),
+        [UnwindFrame {
+            function: failing.address(),
+            native: false,
+            expression: Some(1)
+        }]
+    );
+}
+
+#[test]
+fn test_init_kind() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let global = engine.create_global();
+    let pair = Expression::Initialize(TestInitializer::Pair, vec![number(1), number(2)]);
+    assert_eq!(
+        engine.evaluate(&pair),
+        Ok(TestValueWrapper(TestValue::List(vec![
+            TestValueWrapper(TestValue::Number(1)),
+            TestValueWrapper(TestValue::Number(2)),
+        ])))
+    );
+
+    // The arguments of an initializer of the wrong length are never evaluated
+    let triple = Expression::Initialize(
+        TestInitializer::Pair,
+        vec![
+            number(1),
+            number(2),
+            Expression::AssignGlobal(global, number(3).into()),
+        ],
+    );
+    assert_eq!(
+        engine.evaluate(&triple),
+        Err(FreightError::InvalidInitialization {
+            reason: "Expected 2 values, found 3".into()
+        })
+    );
+    assert_eq!(
+        engine.evaluate(&Expression::global(global)),
+        Ok(TestValueWrapper(TestValue::Null))
+    );
+    assert!(InitKind::Map.check_len(3).is_err());
+    assert!(InitKind::Map.check_len(4).is_ok());
+}
+
+#[test]
+fn test_constant_folding() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let binary = |op, l, r| Expression::BinaryOpEval(op, [l, r].into());
+    let mut register = |expr| {
+        let mut writer = FunctionWriter::new(ArgCount::Fixed(1));
+        writer.evaluate_expression(expr);
+        engine.register_function(writer, 0)
+    };
+    // (2 * 3) + 1 folds entirely
+    let constant = register(binary(
+        TestBinaryOperator::Add,
+        binary(TestBinaryOperator::Mul, number(2), number(3)),
+        number(1),
+    ));
+    // 0 && x short-circuits, so it folds even though x isn't constant
+    let short_circuit = register(binary(
+        TestBinaryOperator::And,
+        number(0),
+        Expression::stack(0),
+    ));
+    // (1 + 1) + x folds only the left operand
+    let partial = register(binary(
+        TestBinaryOperator::Add,
+        binary(TestBinaryOperator::Add, number(1), number(1)),
+        Expression::stack(0),
+    ));
+    let body =
+        |func: &FunctionRef<TestTypeSystem>| &engine.get_function(func.address()).expressions[0];
+    assert!(matches!(
+        body(&constant),
+        Expression::RawValue(TestValueWrapper(TestValue::Number(7)))
+    ));
+    assert!(matches!(
+        body(&short_circuit),
+        Expression::RawValue(TestValueWrapper(TestValue::Number(0)))
+    ));
+    let Expression::BinaryOpEval(_, operands) = body(&partial) else {
+        panic!("Expected the addition to remain");
+    };
+    assert!(matches!(
+        operands[0],
+        Expression::RawValue(TestValueWrapper(TestValue::Number(2)))
+    ));
+    assert_eq!(
+        engine.call(&partial, [TestValueWrapper(TestValue::Number(5))]),
+        Ok(TestValueWrapper(TestValue::Number(7)))
+    );
+
+    let mut engine = ExecutionEngine::<TestTypeSystem>::builder()
+        .optimize(false)
+        .build_default();
+    let mut writer = FunctionWriter::new(ArgCount::Fixed(0));
+    writer.evaluate_expression(binary(TestBinaryOperator::Add, number(1), number(1)));
+    let unfolded = engine.register_function(writer, 0);
+    assert!(matches!(
+        engine.get_function(unfolded.address()).expressions[0],
+        Expression::BinaryOpEval(..)
+    ));
+    assert_eq!(
+        engine.call(&unfolded, []),
+        Ok(TestValueWrapper(TestValue::Number(2)))
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_checkpoint() {
+    use crate::{coroutine::Checkpoint, serialization::NativeTable};
+
+    let value = |n| TestValueWrapper(TestValue::Number(n));
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let step = engine.create_global();
+    // Adds the step to the value it was last resumed with and yields it, forever
+    let mut job = FunctionWriter::new(ArgCount::Fixed(0));
+    let total = job.create_variable();
+    job.evaluate_expression(Expression::AssignGlobal(step, number(10).into()));
+    job.evaluate_expression(Expression::AssignStack(total, number(0).into()));
+    job.evaluate_expression(Expression::While {
+        condition: number(1).into(),
+        body: Expression::AssignStack(
+            total,
+            Expression::Yield(
+                Expression::BinaryOpEval(
+                    TestBinaryOperator::Add,
+                    [Expression::stack(total), Expression::global(step)].into(),
+                )
+                .into(),
+            )
+            .into(),
+        )
+        .into(),
+    });
+    let job = engine.register_function(job, 0);
+
+    let mut coroutine = engine.start_coroutine(&job, []).unwrap();
+    assert_eq!(
+        coroutine.resume(&mut engine, value(0)),
+        Ok(CoroutineState::Yielded(value(10)))
+    );
+    assert_eq!(
+        coroutine.resume(&mut engine, value(5)),
+        Ok(CoroutineState::Yielded(value(15)))
+    );
+    let natives = NativeTable::default();
+    let mut program = vec![];
+    engine
+        .save_program(&natives, &mut serde_json::Serializer::new(&mut program))
+        .unwrap();
+    engine.rng = Rng::new(42);
+    engine.rng.next_u64();
+    let checkpoint = serde_json::to_string(&coroutine.checkpoint(&engine)).unwrap();
+
+    let mut restored = ExecutionEngine::<TestTypeSystem>::new_default();
+    restored
+        .load_program(
+            &natives,
+            &mut serde_json::Deserializer::from_slice(&program),
+        )
+        .unwrap();
+    let checkpoint: Checkpoint<TestTypeSystem> = serde_json::from_str(&checkpoint).unwrap();
+    let mut coroutine = restored.restore_coroutine(checkpoint);
+    assert_eq!(
+        coroutine.resume(&mut restored, value(7)),
+        Ok(CoroutineState::Yielded(value(17)))
+    );
+    assert_eq!(restored.rng.next_u64(), engine.rng.next_u64());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_snapshot() {
+    use crate::{coroutine::Snapshot, serialization::NativeTable};
+
+    let value = |n| TestValueWrapper(TestValue::Number(n));
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let total = engine.create_global();
+    // Yields the total, then twice adds the value it is resumed with to it and yields it again
+    let mut job = FunctionWriter::new(ArgCount::Fixed(0));
+    let resumed = job.create_variable();
+    for _ in 0..2 {
+        job.evaluate_expression(Expression::AssignStack(
+            resumed,
+            Expression::Yield(Expression::global(total).into()).into(),
+        ));
+        job.evaluate_expression(Expression::AssignGlobal(
+            total,
+            Expression::BinaryOpEval(
+                TestBinaryOperator::Add,
+                [Expression::global(total), Expression::stack(resumed)].into(),
+            )
+            .into(),
+        ));
+    }
+    job.evaluate_expression(Expression::Yield(Expression::global(total).into()));
+    let job = engine.register_function(job, 0);
+    engine
+        .evaluate(&Expression::AssignGlobal(
+            total,
+            Expression::RawValue(value(0)).into(),
+        ))
+        .unwrap();
+
+    let mut first = engine.start_coroutine(&job, []).unwrap();
+    let mut second = engine.start_coroutine(&job, []).unwrap();
+    assert_eq!(
+        first.resume(&mut engine, value(0)),
+        Ok(CoroutineState::Yielded(value(0)))
+    );
+    assert_eq!(
+        second.resume(&mut engine, value(0)),
+        Ok(CoroutineState::Yielded(value(0)))
+    );
+    assert_eq!(
+        first.resume(&mut engine, value(3)),
+        Ok(CoroutineState::Yielded(value(3)))
+    );
+    engine.set_fuel(Some(1000));
+    let natives = NativeTable::default();
+    let mut program = vec![];
+    engine
+        .save_program(&natives, &mut serde_json::Serializer::new(&mut program))
+        .unwrap();
+    let snapshot = serde_json::to_string(&engine.snapshot([&first, &second])).unwrap();
+
+    let mut restored = ExecutionEngine::<TestTypeSystem>::new_default();
+    restored
+        .load_program(
+            &natives,
+            &mut serde_json::Deserializer::from_slice(&program),
+        )
+        .unwrap();
+    let snapshot: Snapshot<TestTypeSystem> = serde_json::from_str(&snapshot).unwrap();
+    let mut coroutines = restored.restore_snapshot(snapshot);
+    assert_eq!(restored.remaining_fuel(), engine.remaining_fuel());
+    assert_eq!(
+        coroutines[1].resume(&mut restored, value(4)),
+        Ok(CoroutineState::Yielded(value(7)))
+    );
+    assert_eq!(
+        coroutines[0].resume(&mut restored, value(5)),
+        Ok(CoroutineState::Yielded(value(12)))
+    );
+}
+
+#[test]
+fn test_dead_expression_elimination() {
+    let writer = |global| {
+        let mut writer = FunctionWriter::new(ArgCount::Fixed(1));
+        let add = |l, r| Expression::BinaryOpEval(TestBinaryOperator::Add, [l, r].into());
+        writer.evaluate_expression(add(Expression::stack(0), Expression::global(global)));
+        writer.evaluate_expression(Expression::AssignGlobal(
+            global,
+            Expression::stack(0).into(),
+        ));
+        writer.evaluate_expression(Expression::stack(0));
+        writer.evaluate_expression(Expression::UnaryOpEval(
+            TestUnaryOperator::Inc,
+            Expression::stack(0).into(),
+        ));
+        writer
+    };
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let global = engine.create_global();
+    let func = engine.register_function(writer(global), 0);
+    let expressions = &engine.get_function(func.address()).expressions;
+    // The unary operator isn't pure, so it stays even though its value is discarded
+    assert_eq!(expressions.len(), 2);
+    assert!(matches!(expressions[0], Expression::AssignGlobal(..)));
+    assert_eq!(engine.get_function(func.address()).statements, [1, 3]);
+    assert_eq!(
+        engine.call(&func, [TestValueWrapper(TestValue::Number(4))]),
+        Ok(TestValueWrapper(TestValue::Number(5)))
+    );
+
+    let mut engine = ExecutionEngine::<TestTypeSystem>::builder()
+        .optimize(false)
+        .build_default();
+    let global = engine.create_global();
+    // The addition is evaluated now, so the global needs a number
+    engine
+        .evaluate(&Expression::AssignGlobal(global, number(0).into()))
+        .unwrap();
+    let func = engine.register_function(writer(global), 0);
+    let function = engine.get_function(func.address());
+    assert_eq!(function.expressions.len(), 4);
+    assert_eq!(function.statements, [0, 1, 2, 3]);
+    assert_eq!(
+        engine.call(&func, [TestValueWrapper(TestValue::Number(4))]),
+        Ok(TestValueWrapper(TestValue::Number(5)))
+    );
+}
+
+#[test]
+fn test_infer_layout() {
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let mut writer = FunctionWriter::new(ArgCount::Fixed(1));
+    // Slots 1 and 2 are used without being created
+    writer.evaluate_expression(Expression::AssignStack(1, Expression::stack(0).into()));
+    writer.evaluate_expression(Expression::FreshCells(vec![2]));
+    writer.evaluate_expression(Expression::stack(1));
+    let size = writer.infer_layout();
+    assert_eq!(
+        size,
+        FrameSize {
+            args: 1,
+            declared: 1,
+            used: 3,
+        }
+    );
+    assert!(size.is_undersized());
+    assert_eq!(writer.create_variable(), 3);
+    assert!(!writer.infer_layout().is_undersized());
+    let func = engine.register_function(writer, 0);
+    assert_eq!(func.stack_size(), 4);
+    assert_eq!(
+        engine.call(&func, [TestValueWrapper(TestValue::Number(6))]),
+        Ok(TestValueWrapper(TestValue::Number(6)))
+    );
+
+    let mut closure =
+        FunctionWriter::new_capturing(ArgCount::Fixed(0), vec![VariableType::Stack(4)]);
+    closure.evaluate_expression(Expression::captured(0));
+    let closure = engine.register_function(closure, 0);
+    let mut writer = FunctionWriter::new(ArgCount::Fixed(0));
+    writer.evaluate_expression(Expression::FunctionCapture(closure));
+    assert_eq!(writer.infer_layout().used, 5);
+}
+
+#[test]
+fn test_reentrant_hooks() {
+    thread_local! {
+        /// The hook depth each time the assignment hook ran
+        static DEPTHS: RefCell<Vec<usize>> = const { RefCell::new(vec![]) };
+        static VALIDATOR: RefCell<Option<FunctionRef<TestTypeSystem>>> = const { RefCell::new(None) };
+    }
+    let mut engine = ExecutionEngine::<TestTypeSystem>::new_default();
+    let log = engine.create_global();
+    let value = engine.create_global();
+    // Records each value assigned to the value global in the log global
+    let mut validator = FunctionWriter::new(ArgCount::Fixed(1));
+    validator.evaluate_expression(Expression::AssignGlobal(log, Expression::stack(0).into()));
+    let validator = engine.register_function(validator, 0);
+    VALIDATOR.with(|slot| *slot.borrow_mut() = Some(validator));
+    engine
+        .hooks
+        .register(ExpressionKind::AssignGlobal, |engine, expr| {
+            DEPTHS.with(|depths| depths.borrow_mut().push(engine.hook_depth()));
+            if engine.hook_depth() > 1 {
+                return Ok(());
+            }
+            let Expression::AssignGlobal(_, value) = expr else {
+                unreachable!();
+            };
+            let value = engine.evaluate(value)?;
+            // Functions registered while others are running don't move them
+            for _ in 0..64 {
+                engine.register_function(FunctionWriter::new(ArgCount::Fixed(0)), 0);
+            }
+            let validator = VALIDATOR.with(|slot| slot.borrow().clone()).unwrap();
+            engine.call(&validator, [value])?;
+            Ok(())
+        });
+    let mut main = FunctionWriter::new(ArgCount::Fixed(0));
+    main.evaluate_expression(Expression::AssignGlobal(value, number(3).into()));
+    main.evaluate_expression(Expression::global(value));
+    let main = engine.register_function(main, 0);
+
+    assert_eq!(
+        engine.call(&main, []),
+        Ok(TestValueWrapper(TestValue::Number(3)))
+    );
+    assert_eq!(
+        engine.evaluate(&Expression::global(log)),
+        Ok(TestValueWrapper(TestValue::Number(3)))
+    );
+    assert_eq!(DEPTHS.with(|depths| depths.take()), [1, 2]);
+    assert_eq!(engine.hook_depth(), 0);
+
+    // Calls made by an unwind hook don't disturb the trace of the error being unwound
+    engine.hooks.clear(ExpressionKind::AssignGlobal);
+    engine.on_unwind_frame = Some(|engine, _, _| {
+        let invalid = Expression::DynamicFunctionCall(
+            Expression::RawValue(TestValueWrapper(TestValue::Null)).into(),
+            vec![],
+        );
+        assert!(engine.evaluate(&invalid).is_err());
+    });
+    let fail = FunctionRef::new_native(
+        3,
+        NativeFunction::new(|_, _| Err(FreightError::InvalidInvocationTarget)),
+        ArgCount::Fixed(0),
     );
+    let mut outer = FunctionWriter::new(ArgCount::Fixed(0));
+    outer.evaluate_expression(Expression::StaticFunctionCall(fail, vec![]));
+    let outer = engine.register_function(outer, 0);
+    assert!(engine.call(&outer, []).is_err());
+    assert_eq!(engine.error_trace().len(), 2);
 }
@@ -1,9 +1,24 @@
 #![allow(dead_code)]
 
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Display,
+    rc::Rc,
+};
+
 use crate::{
+    args::FromValue,
+    conformance::ConformanceTypeSystem,
+    error::{ErrorConverter, FreightError},
+    execution_engine::ExecutionEngine,
+    expression::Expression,
+    fallback::{CallFallback, CallRedirect},
     function::FunctionRef,
-    operators::{BinaryOperator, UnaryOperator},
-    value::Value,
+    operators::{BinaryOperator, InitKind, Initializer, TernaryOperator, UnaryOperator},
+    region::RegionAlloc,
+    transfer::{Detacher, Rehydrator},
+    type_registry::{RuntimeTypeId, RuntimeTypeToken},
+    value::{NestingGuard, Value, ValueFactory},
     TypeSystem,
 };
 
@@ -17,31 +32,166 @@ impl TypeSystem for TestTypeSystem {
 
     type BinaryOp = TestBinaryOperator;
 
+    type TernaryOp = TestTernaryOperator;
+
     type TypeId = TestTypeId;
 
-    type Init = ();
+    type Init = TestInitializer;
 
     type GlobalContext = ();
+
+    type RuntimeError = TestRuntimeError;
+
+    type ErrorConverter = TestErrorConverter;
+
+    type RegionAlloc = TestRegions;
+
+    type CallFallback = TestCallFallback;
+}
+
+thread_local! {
+    /// The region depth of each test's engine, which runs on its own thread
+    pub static REGION_DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// The value given to each region exit, in the order regions were exited
+    pub static REGION_EXITS: RefCell<Vec<Option<TestValueWrapper>>> = const { RefCell::new(vec![]) };
+}
+
+/// Tracks how deeply regions are nested
+#[derive(Debug)]
+pub struct TestRegions;
+
+impl RegionAlloc<TestTypeSystem> for TestRegions {
+    type Mark = usize;
+
+    fn enter_call(_: &mut ExecutionEngine<TestTypeSystem>) -> usize {
+        REGION_DEPTH.with(|depth| depth.replace(depth.get() + 1))
+    }
+
+    fn exit_call(
+        _: &mut ExecutionEngine<TestTypeSystem>,
+        mark: usize,
+        result: Option<&mut TestValueWrapper>,
+    ) {
+        REGION_DEPTH.with(|depth| depth.set(mark));
+        REGION_EXITS.with(|exits| exits.borrow_mut().push(result.cloned()));
+    }
+}
+
+#[derive(Debug)]
+pub struct TestErrorConverter;
+
+/// Lists can be called with an index to get an element, and structs are called through the
+/// handler in their first field, which is given the struct followed by the arguments
+#[derive(Debug)]
+pub struct TestCallFallback;
+
+impl CallFallback<TestTypeSystem> for TestCallFallback {
+    fn handles(target: &TestValueWrapper, arg_count: usize) -> bool {
+        match &target.0 {
+            TestValue::List(_) => arg_count == 1,
+            TestValue::Struct(_, fields) => !fields.is_empty(),
+            _ => false,
+        }
+    }
+
+    fn fallback(
+        _: &mut ExecutionEngine<TestTypeSystem>,
+        target: TestValueWrapper,
+        mut args: Vec<TestValueWrapper>,
+    ) -> Result<CallRedirect<TestTypeSystem>, FreightError> {
+        match (&target.0, &args[..]) {
+            (TestValue::List(list), [TestValueWrapper(TestValue::Number(index))]) => list
+                .get(*index as usize)
+                .cloned()
+                .map(CallRedirect::Value)
+                .ok_or(FreightError::FieldNotFound {
+                    index: *index as usize,
+                }),
+            (TestValue::Struct(_, fields), _) => {
+                let handler = fields[0].clone();
+                args.insert(0, target);
+                Ok(CallRedirect::Call(handler, args))
+            }
+            _ => Err(FreightError::InvalidInvocationTarget),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestRuntimeError {
+    DivideByZero,
+}
+
+impl Display for TestRuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DivideByZero => f.write_str("Division by zero"),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TestBinaryOperator {
     Add,
+    Mul,
+    /// Evaluates to the left operand if it is falsy, and the right operand otherwise
+    And,
+    /// Evaluates to 1 if the operands are equal, and 0 otherwise
+    Eq,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TestTernaryOperator {
+    /// Clamps the first operand between the second and third
+    Clamp,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TestInitializer {
+    /// A list which may only contain numbers
+    NumberList,
+    /// A list of exactly two values
+    Pair,
+    /// A list of the value of a global, read through the engine while initializing, and one
+    /// other value
+    WithGlobal(usize),
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TestUnaryOperator {
     Inc,
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum TestTypeId {
     Number,
     Function,
     List,
+    Error,
+    Struct(RuntimeTypeToken),
+    Cell,
     Null,
 }
 
+impl RuntimeTypeId for TestTypeId {
+    fn token(&self) -> Option<RuntimeTypeToken> {
+        match self {
+            TestTypeId::Struct(token) => Some(*token),
+            _ => None,
+        }
+    }
+}
+
+impl From<RuntimeTypeToken> for TestTypeId {
+    fn from(value: RuntimeTypeToken) -> Self {
+        TestTypeId::Struct(value)
+    }
+}
+
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct TestValueWrapper(pub TestValue);
 
@@ -50,6 +200,10 @@ pub enum TestValue {
     Number(i64),
     Function(FunctionRef<TestTypeSystem>),
     List(Vec<TestValueWrapper>),
+    Error(FreightError),
+    Struct(TestTypeId, Vec<TestValueWrapper>),
+    /// A shared mutable cell, which can form reference cycles
+    Cell(Rc<RefCell<TestValueWrapper>>),
     #[default]
     Null,
 }
@@ -66,6 +220,9 @@ impl Value for TestValueWrapper {
             TestValue::Number(_) => &TestTypeId::Number,
             TestValue::Function(_) => &TestTypeId::Function,
             TestValue::List(_) => &TestTypeId::List,
+            TestValue::Error(_) => &TestTypeId::Error,
+            TestValue::Struct(type_id, _) => type_id,
+            TestValue::Cell(_) => &TestTypeId::Cell,
             TestValue::Null => &TestTypeId::Null,
         }
     }
@@ -74,6 +231,92 @@ impl Value for TestValueWrapper {
         self.clone()
     }
 
+    fn try_deep_clone(&self, guard: &mut NestingGuard) -> Result<Self, FreightError> {
+        let copy_all = |values: &[TestValueWrapper], guard: &mut NestingGuard| {
+            values
+                .iter()
+                .map(|value| value.try_deep_clone(guard))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        guard.enter(self)?;
+        let copy = match &self.0 {
+            TestValue::List(values) => copy_all(values, guard).map(TestValue::List),
+            TestValue::Struct(id, fields) => {
+                copy_all(fields, guard).map(|fields| TestValue::Struct(id.clone(), fields))
+            }
+            TestValue::Cell(cell) => cell
+                .borrow()
+                .try_deep_clone(guard)
+                .map(|value| TestValue::Cell(Rc::new(RefCell::new(value)))),
+            value => Ok(value.clone()),
+        };
+        guard.exit(self);
+        copy.map(TestValueWrapper)
+    }
+
+    fn fresh_ref(&self) -> Self {
+        match &self.0 {
+            TestValue::Cell(cell) => TestValueWrapper(TestValue::Cell(Rc::new(RefCell::new(
+                cell.borrow().clone(),
+            )))),
+            _ => self.clone(),
+        }
+    }
+
+    fn try_fresh_ref(&self, guard: &mut NestingGuard) -> Result<Self, FreightError> {
+        self.try_deep_clone(guard)
+    }
+
+    fn detach(&self, detacher: &mut Detacher<TestTypeSystem>) -> Result<Self, FreightError> {
+        let detach_all = |values: &[TestValueWrapper], detacher: &mut Detacher<_>| {
+            values
+                .iter()
+                .map(|value| value.detach(detacher))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        detacher.guard.enter(self)?;
+        let detached = match &self.0 {
+            TestValue::Function(func) => detacher.function(func).map(TestValue::Function),
+            TestValue::List(values) => detach_all(values, detacher).map(TestValue::List),
+            TestValue::Struct(id, fields) => {
+                detach_all(fields, detacher).map(|fields| TestValue::Struct(id.clone(), fields))
+            }
+            TestValue::Cell(cell) => cell
+                .borrow()
+                .detach(detacher)
+                .map(|value| TestValue::Cell(Rc::new(RefCell::new(value)))),
+            value => Ok(value.clone()),
+        };
+        detacher.guard.exit(self);
+        detached.map(TestValueWrapper)
+    }
+
+    fn rehydrate(self, rehydrator: &mut Rehydrator<TestTypeSystem>) -> Result<Self, FreightError> {
+        let rehydrate_all = |values: Vec<TestValueWrapper>, rehydrator: &mut Rehydrator<_>| {
+            values
+                .into_iter()
+                .map(|value| value.rehydrate(rehydrator))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        rehydrator.guard.enter(&self)?;
+        let rehydrated = match &self.0 {
+            TestValue::Function(func) => rehydrator.function(func.clone()).map(TestValue::Function),
+            TestValue::List(values) => {
+                rehydrate_all(values.clone(), rehydrator).map(TestValue::List)
+            }
+            TestValue::Struct(id, fields) => rehydrate_all(fields.clone(), rehydrator)
+                .map(|fields| TestValue::Struct(id.clone(), fields)),
+            TestValue::Cell(cell) => {
+                let value = cell.borrow().clone().rehydrate(rehydrator)?;
+                *cell.borrow_mut() = value;
+                Ok(self.0.clone())
+            }
+            value => Ok(value.clone()),
+        };
+        rehydrator.guard.exit(&self);
+        rehydrated.map(TestValueWrapper)
+    }
+
     fn dupe_ref(&self) -> Self {
         self.clone()
     }
@@ -93,12 +336,104 @@ impl Value for TestValueWrapper {
         self
     }
 
+    fn is_truthy(&self) -> bool {
+        !matches!(self.0, TestValue::Null | TestValue::Number(0))
+    }
+
+    fn hash_key(&self) -> Option<u64> {
+        match self.0 {
+            TestValue::Number(n) => Some(n as u64),
+            _ => None,
+        }
+    }
+
+    fn is_iteration_end(&self) -> bool {
+        self.0 == TestValue::Null
+    }
+
+    fn as_discriminant(&self) -> Option<usize> {
+        match self.0 {
+            TestValue::Number(n) => n.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(&Self)) {
+        match &self.0 {
+            TestValue::List(values) | TestValue::Struct(_, values) => values.iter().for_each(visit),
+            TestValue::Function(func) => {
+                func.captured_values().into_iter().flatten().for_each(visit)
+            }
+            TestValue::Cell(cell) => visit(&cell.borrow()),
+            _ => (),
+        }
+    }
+
+    fn identity(&self) -> Option<usize> {
+        match &self.0 {
+            TestValue::Cell(cell) => Some(Rc::as_ptr(cell) as usize),
+            _ => None,
+        }
+    }
+
+    fn get_field_by_index(&self, index: usize) -> Option<Self> {
+        match &self.0 {
+            TestValue::Struct(_, fields) => fields.get(index).cloned(),
+            _ => None,
+        }
+    }
+
+    fn set_field_by_index(&mut self, index: usize, value: Self) -> bool {
+        match &mut self.0 {
+            TestValue::Struct(_, fields) if index < fields.len() => {
+                fields[index] = value;
+                true
+            }
+            _ => false,
+        }
+    }
+
     #[cfg(feature = "variadic_functions")]
     fn gen_list(values: Vec<Self>) -> Self {
         TestValueWrapper(TestValue::List(values.into_iter().collect()))
     }
 }
 
+/// Only numbers and null can be serialized
+#[cfg(feature = "serde")]
+impl serde::Serialize for TestValueWrapper {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            TestValue::Number(n) => serializer.serialize_some(&n),
+            TestValue::Null => serializer.serialize_none(),
+            _ => Err(serde::ser::Error::custom(
+                "Only numbers and null can be serialized",
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TestValueWrapper {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Option::<i64>::deserialize(deserializer)?;
+        Ok(TestValueWrapper(
+            value.map_or(TestValue::Null, TestValue::Number),
+        ))
+    }
+}
+
+impl FromValue<TestValueWrapper> for i64 {
+    const EXPECTED: &'static str = "a number";
+
+    fn from_value(value: &TestValueWrapper) -> Option<Self> {
+        match value.0 {
+            TestValue::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
 impl From<FunctionRef<TestTypeSystem>> for TestValueWrapper {
     fn from(value: FunctionRef<TestTypeSystem>) -> Self {
         TestValueWrapper(TestValue::Function(value))
@@ -120,7 +455,145 @@ impl BinaryOperator<TestValueWrapper> for TestBinaryOperator {
             (Self::Add, TestValue::Number(a), TestValue::Number(b)) => {
                 TestValueWrapper(TestValue::Number(a + b))
             }
-            _ => panic!("Attempt to add non-integer type"),
+            (Self::Mul, TestValue::Number(a), TestValue::Number(b)) => {
+                TestValueWrapper(TestValue::Number(a * b))
+            }
+            (Self::And, _, _) => b.clone(),
+            (Self::Eq, a, b) => TestValueWrapper(TestValue::Number((a == b) as i64)),
+            _ => panic!("Attempt to apply an operator to non-integer types"),
+        }
+    }
+
+    fn short_circuit(&self, left: &TestValueWrapper) -> Option<TestValueWrapper> {
+        match self {
+            Self::And if !left.is_truthy() => Some(left.clone()),
+            _ => None,
+        }
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
+
+    fn is_equality(&self) -> bool {
+        matches!(self, Self::Eq)
+    }
+}
+
+impl TernaryOperator<TestValueWrapper> for TestTernaryOperator {
+    fn apply_3(
+        &self,
+        a: &TestValueWrapper,
+        b: &TestValueWrapper,
+        c: &TestValueWrapper,
+    ) -> TestValueWrapper {
+        match (self, &a.0, &b.0, &c.0) {
+            (Self::Clamp, TestValue::Number(n), TestValue::Number(min), TestValue::Number(max)) => {
+                TestValueWrapper(TestValue::Number(*n.clamp(min, max)))
+            }
+            _ => panic!("Attempt to apply an operator to non-integer types"),
+        }
+    }
+}
+
+impl ErrorConverter<TestTypeSystem> for TestErrorConverter {
+    fn error_to_value(
+        error: FreightError,
+        _: &mut ExecutionEngine<TestTypeSystem>,
+    ) -> TestValueWrapper {
+        TestValueWrapper(TestValue::Error(error))
+    }
+
+    fn value_to_error(value: &TestValueWrapper) -> Option<FreightError> {
+        match value {
+            TestValueWrapper(TestValue::Error(error)) => Some(error.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl Initializer<TestTypeSystem> for TestInitializer {
+    fn kind(&self) -> InitKind {
+        match self {
+            Self::NumberList => InitKind::List,
+            Self::Pair => InitKind::Tuple(2),
+            Self::WithGlobal(_) => InitKind::Tuple(1),
+        }
+    }
+
+    fn initialize(
+        &self,
+        values: Vec<TestValueWrapper>,
+        engine: &mut ExecutionEngine<TestTypeSystem>,
+    ) -> Result<TestValueWrapper, FreightError> {
+        match self {
+            Self::NumberList => {
+                if let Some(value) = values.iter().find(|v| !matches!(v.0, TestValue::Number(_))) {
+                    return Err(FreightError::InvalidInitialization {
+                        reason: format!("{} is not a number", engine.summarize(value)),
+                    });
+                }
+                Ok(TestValueWrapper(TestValue::List(values)))
+            }
+            Self::Pair => Ok(TestValueWrapper(TestValue::List(values))),
+            Self::WithGlobal(addr) => {
+                let global = engine.evaluate(&Expression::global(*addr))?;
+                Ok(TestValueWrapper(TestValue::List(
+                    std::iter::once(global).chain(values).collect(),
+                )))
+            }
+        }
+    }
+
+    fn initialize_into(
+        &self,
+        values: Vec<TestValueWrapper>,
+        target: &mut TestValueWrapper,
+        engine: &mut ExecutionEngine<TestTypeSystem>,
+    ) -> Result<(), FreightError> {
+        match &mut target.0 {
+            TestValue::List(list) => {
+                let TestValue::List(values) = self.initialize(values, engine)?.0 else {
+                    unreachable!("Every initializer creates a list");
+                };
+                list.clear();
+                list.extend(values);
+            }
+            _ => *target = self.initialize(values, engine)?,
+        }
+        Ok(())
+    }
+}
+
+impl ValueFactory for TestTypeSystem {
+    fn int(value: i64) -> TestValueWrapper {
+        TestValueWrapper(TestValue::Number(value))
+    }
+
+    fn list(values: Vec<TestValueWrapper>) -> Option<TestValueWrapper> {
+        Some(TestValueWrapper(TestValue::List(values)))
+    }
+}
+
+impl ConformanceTypeSystem for TestTypeSystem {
+    fn global_context() {}
+
+    fn as_int(value: &TestValueWrapper) -> Option<i64> {
+        match value.0 {
+            TestValue::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn add() -> TestBinaryOperator {
+        TestBinaryOperator::Add
+    }
+
+    #[cfg(feature = "variadic_functions")]
+    fn list_len(value: &TestValueWrapper) -> Option<usize> {
+        match &value.0 {
+            TestValue::List(list) => Some(list.len()),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,131 @@
+//! Moving values between engines, for hosts which run several engines side by side. A value is
+//! detached from the engine which made it with [ExecutionEngine::detach_value], which copies it
+//! with [Value::detach] so it shares nothing with that engine, then rehydrated into another with
+//! [ExecutionEngine::rehydrate_value].
+//!
+//! Functions are referred to by their address, so a closure can only be rehydrated into an
+//! engine whose function at that address is structurally identical to the one it was detached
+//! from, such as an engine which loaded the same program. Values shared between several parts of
+//! a detached value are copied separately, as [Value::deep_clone] would.
+
+use std::{cell::UnsafeCell, collections::HashMap, rc::Rc};
+
+use crate::{
+    error::FreightError,
+    execution_engine::ExecutionEngine,
+    function::{FunctionRef, FunctionType},
+    slice_pool::RcSlicePool,
+    value::{NestingGuard, Value},
+    TypeSystem,
+};
+
+/// A value which shares nothing with the engine it was detached from
+#[derive(Debug, Clone)]
+pub struct Detached<TS: TypeSystem> {
+    value: TS::Value,
+    /// The structural hash of each function the value refers to, by address
+    functions: HashMap<usize, u64>,
+}
+
+impl<TS: TypeSystem> Detached<TS> {
+    /// The addresses of the functions the value refers to, which the receiving engine must have
+    pub fn functions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.functions.keys().copied()
+    }
+}
+
+/// Copies a value out of an engine, see [Value::detach]
+pub struct Detacher<'a, TS: TypeSystem> {
+    engine: &'a ExecutionEngine<TS>,
+    functions: HashMap<usize, u64>,
+    /// Owns the captured environments of detached closures, and caches none of them
+    pool: Rc<UnsafeCell<RcSlicePool<TS::Value>>>,
+    pub guard: NestingGuard,
+}
+
+impl<TS: TypeSystem> Detacher<'_, TS> {
+    /// Detach a function, recording its address and detaching the values it captured
+    pub fn function(&mut self, func: &FunctionRef<TS>) -> Result<FunctionRef<TS>, FreightError> {
+        let mut func = func.clone();
+        if let FunctionType::Native(_) = func.function_type {
+            return Ok(func);
+        }
+        let hash = self.engine.get_function(func.location).structural_hash();
+        self.functions.insert(func.location, hash);
+        if let FunctionType::CapturingRef(captures) = &func.function_type {
+            let captures = captures
+                .iter()
+                .map(|value| value.detach(self))
+                .collect::<Result<Vec<_>, _>>()?;
+            func.function_type =
+                FunctionType::CapturingRef(RcSlicePool::from_pool(self.pool.clone(), captures));
+        }
+        Ok(func)
+    }
+}
+
+/// Binds a detached value to an engine, see [Value::rehydrate]
+pub struct Rehydrator<'a, TS: TypeSystem> {
+    engine: &'a ExecutionEngine<TS>,
+    pub guard: NestingGuard,
+}
+
+impl<TS: TypeSystem> Rehydrator<'_, TS> {
+    /// Rehydrate a detached function, moving the values it captured into the engine's pool
+    pub fn function(&mut self, mut func: FunctionRef<TS>) -> Result<FunctionRef<TS>, FreightError> {
+        if let FunctionType::CapturingRef(captures) = &func.function_type {
+            let captures = captures
+                .iter()
+                .map(|value| value.clone().rehydrate(self))
+                .collect::<Result<Vec<_>, _>>()?;
+            func.function_type = FunctionType::CapturingRef(RcSlicePool::from_pool(
+                self.engine.rc_pool.clone(),
+                captures,
+            ));
+        }
+        Ok(func)
+    }
+}
+
+impl<TS: TypeSystem> ExecutionEngine<TS> {
+    /// Copy a value so it can be moved to another engine, nesting no deeper than
+    /// [ExecutionEngine::max_value_depth]
+    pub fn detach_value(&self, value: &TS::Value) -> Result<Detached<TS>, FreightError> {
+        let mut detacher = Detacher {
+            engine: self,
+            functions: HashMap::new(),
+            pool: Rc::new(UnsafeCell::new(RcSlicePool::new(0, 0))),
+            guard: self.nesting_guard(),
+        };
+        let value = value.detach(&mut detacher)?;
+        Ok(Detached {
+            value,
+            functions: detacher.functions,
+        })
+    }
+
+    /// Bind a value detached from another engine to this one. Fails without binding anything if
+    /// the value refers to a function this engine doesn't have.
+    pub fn rehydrate_value(&self, detached: Detached<TS>) -> Result<TS::Value, FreightError> {
+        for (&location, &hash) in &detached.functions {
+            match self.functions().get(location) {
+                Some(func) if func.structural_hash() == hash => (),
+                _ => return Err(FreightError::IncompatibleFunction { location }),
+            }
+        }
+        let mut rehydrator = Rehydrator {
+            engine: self,
+            guard: self.nesting_guard(),
+        };
+        detached.value.rehydrate(&mut rehydrator)
+    }
+
+    /// Move a copy of a value from this engine to another
+    pub fn transfer_value(
+        &self,
+        value: &TS::Value,
+        target: &ExecutionEngine<TS>,
+    ) -> Result<TS::Value, FreightError> {
+        target.rehydrate_value(self.detach_value(value)?)
+    }
+}
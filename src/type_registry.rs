@@ -0,0 +1,102 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{function::FunctionRef, TypeSystem};
+
+/// Identifies a type created at runtime by a [TypeRegistry]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RuntimeTypeToken(pub usize);
+
+/// Implemented by all type ids so the engine can recognize types created at runtime.
+/// Type ids which can represent runtime types should also implement `From<RuntimeTypeToken>`.
+pub trait RuntimeTypeId {
+    /// The token of this type id, if it represents a type created at runtime
+    fn token(&self) -> Option<RuntimeTypeToken> {
+        None
+    }
+}
+
+/// Metadata about a type created at runtime
+#[derive(Debug, Clone)]
+pub struct TypeInfo<TS: TypeSystem> {
+    pub name: Rc<str>,
+    /// The names of the type's fields, in layout order
+    pub fields: Vec<Rc<str>>,
+    /// The type's methods, which take the receiver as their first argument
+    pub methods: HashMap<Rc<str>, FunctionRef<TS>>,
+}
+
+/// Allocates type ids for types defined by scripts and stores their metadata
+#[derive(Debug, Clone)]
+pub struct TypeRegistry<TS: TypeSystem> {
+    types: Vec<TypeInfo<TS>>,
+}
+
+impl<TS: TypeSystem> Default for TypeRegistry<TS> {
+    fn default() -> Self {
+        TypeRegistry { types: vec![] }
+    }
+}
+
+impl<TS: TypeSystem> TypeRegistry<TS> {
+    /// Create a new type with the given fields and return its id
+    pub fn define_type(&mut self, name: impl Into<Rc<str>>, fields: Vec<Rc<str>>) -> TS::TypeId
+    where
+        TS::TypeId: From<RuntimeTypeToken>,
+    {
+        self.types.push(TypeInfo {
+            name: name.into(),
+            fields,
+            methods: HashMap::new(),
+        });
+        RuntimeTypeToken(self.types.len() - 1).into()
+    }
+
+    /// Get the metadata of a type, if it was created by this registry
+    pub fn get(&self, type_id: &TS::TypeId) -> Option<&TypeInfo<TS>> {
+        self.types.get(type_id.token()?.0)
+    }
+
+    /// Get mutable access to the metadata of a type, if it was created by this registry
+    pub fn get_mut(&mut self, type_id: &TS::TypeId) -> Option<&mut TypeInfo<TS>> {
+        self.types.get_mut(type_id.token()?.0)
+    }
+
+    /// Add or replace a method on a type, returning `false` if the type was not created by this
+    /// registry
+    pub fn define_method(
+        &mut self,
+        type_id: &TS::TypeId,
+        name: impl Into<Rc<str>>,
+        method: FunctionRef<TS>,
+    ) -> bool {
+        let Some(info) = self.get_mut(type_id) else {
+            return false;
+        };
+        info.methods.insert(name.into(), method);
+        true
+    }
+
+    /// Find a method on a type
+    pub fn method(&self, type_id: &TS::TypeId, name: &str) -> Option<&FunctionRef<TS>> {
+        self.get(type_id)?.methods.get(name)
+    }
+
+    /// Find the layout index of a field on a type
+    pub fn field_index(&self, type_id: &TS::TypeId, name: &str) -> Option<usize> {
+        self.get(type_id)?.fields.iter().position(|f| &**f == name)
+    }
+
+    /// Every type created by this registry, in the order they were defined
+    pub fn types(&self) -> &[TypeInfo<TS>] {
+        &self.types
+    }
+
+    /// The number of types created by this registry
+    pub fn len(&self) -> usize {
+        self.types.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+}
@@ -1,5 +1,10 @@
-use crate::{function::FunctionRef, TypeSystem};
-use std::fmt::Debug;
+use crate::{
+    error::FreightError,
+    function::FunctionRef,
+    transfer::{Detacher, Rehydrator},
+    TypeSystem,
+};
+use std::{collections::HashSet, fmt::Debug, rc::Rc};
 
 pub trait Value: Clone + Default + Debug + From<FunctionRef<Self::TS>> + PartialEq {
     type TS: TypeSystem<Value = Self>;
@@ -19,13 +24,248 @@ pub trait Value: Clone + Default + Debug + From<FunctionRef<Self::TS>> + Partial
     /// Convert this value into a reference, if it isn't already
     fn into_ref(self) -> Self;
 
+    /// Create a new reference holding the value this one holds, so assigning to either
+    /// afterwards doesn't affect the other. Defaults to a shallow [Clone] made into a reference
+    /// with [Value::into_ref]; types whose values are already reference cells should override it
+    /// to copy the cell.
+    fn fresh_ref(&self) -> Self {
+        self.clone().into_ref()
+    }
+
+    /// Create a deep copy like [Value::deep_clone], entering each nested value with the guard so
+    /// a cyclic or very deeply nested value fails instead of overflowing the stack. Defaults to
+    /// [Value::deep_clone], which suits types whose values can't nest.
+    fn try_deep_clone(&self, guard: &mut NestingGuard) -> Result<Self, FreightError> {
+        let _ = guard;
+        Ok(self.deep_clone())
+    }
+
+    /// Create a fresh reference like [Value::fresh_ref], guarding any deep copy it makes like
+    /// [Value::try_deep_clone]. Defaults to [Value::fresh_ref], which makes no deep copy.
+    fn try_fresh_ref(&self, guard: &mut NestingGuard) -> Result<Self, FreightError> {
+        let _ = guard;
+        Ok(self.fresh_ref())
+    }
+
+    /// Copy this value so it shares nothing with the engine it was made by, passing any
+    /// functions it contains to [Detacher::function]. Types whose values can nest should enter
+    /// each nested value with [Detacher::guard]. Defaults to detaching the value if it is a
+    /// function and [Value::deep_clone] otherwise.
+    fn detach(&self, detacher: &mut Detacher<Self::TS>) -> Result<Self, FreightError> {
+        match self.cast_to_function() {
+            Some(func) => detacher.function(func).map(Self::from),
+            None => Ok(self.deep_clone()),
+        }
+    }
+
+    /// Bind a value made by [Value::detach] to the engine it is being moved to, passing any
+    /// functions it contains to [Rehydrator::function]. Defaults to rehydrating the value if it
+    /// is a function and keeping it as it is otherwise.
+    fn rehydrate(self, rehydrator: &mut Rehydrator<Self::TS>) -> Result<Self, FreightError> {
+        match self.cast_to_function() {
+            Some(func) => rehydrator.function(func.clone()).map(Self::from),
+            None => Ok(self),
+        }
+    }
+
     /// Attempt to cast this value to a function so it can be dynamically invoked
     fn cast_to_function(&self) -> Option<&FunctionRef<Self::TS>>;
 
     /// Assign to this value
     fn assign(&mut self, value: <Self::TS as TypeSystem>::Value);
 
+    /// Whether this value counts as true when used as the condition of
+    /// [crate::expression::Expression::Conditional]. By default every value other than the
+    /// default value is truthy.
+    fn is_truthy(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Get a field of this value by its layout index, see [crate::type_registry::TypeInfo::fields]
+    fn get_field_by_index(&self, index: usize) -> Option<Self> {
+        let _ = index;
+        None
+    }
+
+    /// Assign to a field of this value by its layout index, returning `false` if there is no such
+    /// field
+    fn set_field_by_index(&mut self, index: usize, value: Self) -> bool {
+        let _ = (index, value);
+        false
+    }
+
+    /// The index of the [crate::expression::Expression::Switch] arm this value selects, or
+    /// `None` to select the default arm
+    fn as_discriminant(&self) -> Option<usize> {
+        None
+    }
+
+    /// A hash of this value for use as a key in tables managed by the engine, such as
+    /// [crate::cache::ValueCache]. Values which are equal must have equal hashes. Returns `None`
+    /// for values which can't be used as keys, which is the default.
+    fn hash_key(&self) -> Option<u64> {
+        None
+    }
+
+    /// Whether this value marks the end of a sequence produced by a script iterator, see
+    /// [crate::execution_engine::ExecutionEngine::iterate]
+    fn is_iteration_end(&self) -> bool {
+        false
+    }
+
+    /// Describe this value for diagnostic output, descending at most `depth` levels into nested
+    /// values and keeping the description to roughly `max_len` characters.
+    /// Defaults to the `Debug` output truncated to `max_len` characters.
+    fn summarize(&self, depth: usize, max_len: usize) -> String {
+        let _ = depth;
+        let mut summary = format!("{self:?}");
+        if let Some((end, _)) = summary.char_indices().nth(max_len) {
+            summary.truncate(end);
+            summary.push_str("...");
+        }
+        summary
+    }
+
+    /// Call `visit` with each value this one refers to directly, such as the elements of a list
+    /// and the values captured by a closure (see [FunctionRef::captured_values]), so tracing
+    /// garbage collectors can find every reachable value, see
+    /// [crate::execution_engine::ExecutionEngine::trace_reachable]. Defaults to none.
+    fn trace(&self, visit: &mut dyn FnMut(&Self)) {
+        let _ = visit;
+    }
+
+    /// An id shared by every value referring to the same allocation, such as the address of a
+    /// shared cell, so reference cycles can be found, see
+    /// [crate::execution_engine::ExecutionEngine::find_cycles]. Defaults to `None`, for values
+    /// which aren't shared.
+    fn identity(&self) -> Option<usize> {
+        None
+    }
+
     #[cfg(feature = "variadic_functions")]
     /// Create a `Value` type list out of `Vec` of `Value`
     fn gen_list(values: Vec<Self>) -> Self;
 }
+
+/// Limits how deep a recursive operation on values, such as [Value::try_deep_clone] or a
+/// serializer, descends, and detects values which contain themselves through shared references
+/// with [Value::identity]. Call [NestingGuard::enter] before descending into a value and
+/// [NestingGuard::exit] after.
+#[derive(Debug, Clone)]
+pub struct NestingGuard {
+    max_depth: usize,
+    depth: usize,
+    /// The identities of the values being descended into
+    open: HashSet<usize>,
+}
+
+impl NestingGuard {
+    pub fn new(max_depth: usize) -> NestingGuard {
+        NestingGuard {
+            max_depth,
+            depth: 0,
+            open: HashSet::new(),
+        }
+    }
+
+    /// Start descending into a value, failing if it would be nested too deeply or is already
+    /// being descended into
+    pub fn enter<V: Value>(&mut self, value: &V) -> Result<(), FreightError> {
+        if self.depth == self.max_depth {
+            return Err(FreightError::NestingTooDeep {
+                max_depth: self.max_depth,
+            });
+        }
+        if let Some(id) = value.identity() {
+            if !self.open.insert(id) {
+                return Err(FreightError::CyclicValue);
+            }
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Finish descending into a value entered with [NestingGuard::enter]
+    pub fn exit<V: Value>(&mut self, value: &V) {
+        if let Some(id) = value.identity() {
+            self.open.remove(&id);
+        }
+        self.depth -= 1;
+    }
+
+    /// How many values are being descended into
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+/// A literal value which can be created without knowing the concrete [Value] type, see
+/// [ValueFactory]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(Rc<str>),
+    List(Vec<Literal>),
+}
+
+impl Literal {
+    /// The name of this literal's kind, for error messages
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Literal::Null => "null",
+            Literal::Bool(_) => "bool",
+            Literal::Int(_) => "int",
+            Literal::Float(_) => "float",
+            Literal::String(_) => "string",
+            Literal::List(_) => "list",
+        }
+    }
+}
+
+/// Optionally implemented by type systems so generic tooling can construct values of common
+/// kinds. Kinds the language doesn't have return `None`.
+pub trait ValueFactory: TypeSystem {
+    fn int(value: i64) -> Self::Value;
+
+    fn float(_value: f64) -> Option<Self::Value> {
+        None
+    }
+
+    fn bool(_value: bool) -> Option<Self::Value> {
+        None
+    }
+
+    fn string(_value: &str) -> Option<Self::Value> {
+        None
+    }
+
+    fn list(_values: Vec<Self::Value>) -> Option<Self::Value> {
+        None
+    }
+
+    fn null() -> Self::Value {
+        Default::default()
+    }
+
+    /// Create a value from a literal, returning the literal which couldn't be created if the
+    /// language doesn't support its kind
+    fn literal(literal: &Literal) -> Result<Self::Value, &Literal> {
+        match literal {
+            Literal::Null => Ok(Self::null()),
+            Literal::Int(value) => Ok(Self::int(*value)),
+            Literal::Bool(value) => Self::bool(*value).ok_or(literal),
+            Literal::Float(value) => Self::float(*value).ok_or(literal),
+            Literal::String(value) => Self::string(value).ok_or(literal),
+            Literal::List(elements) => {
+                let values = elements
+                    .iter()
+                    .map(Self::literal)
+                    .collect::<Result<_, _>>()?;
+                Self::list(values).ok_or(literal)
+            }
+        }
+    }
+}
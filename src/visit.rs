@@ -0,0 +1,97 @@
+//! A shared traversal of expression trees, for analyses which look at every expression without
+//! caring how each variant stores its children. Use [crate::rewrite] to replace expressions
+//! bottom-up instead.
+
+use crate::{expression::Expression, TypeSystem};
+
+/// What a traversal does after entering an expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visit {
+    /// Visit the expression's children, then exit it
+    Descend,
+    /// Exit the expression without visiting its children
+    Skip,
+    /// End the traversal without exiting the expression or any of its ancestors
+    Stop,
+}
+
+/// Observes the expressions of a tree in evaluation order, see [Expression::visit]
+pub trait Visitor<TS: TypeSystem> {
+    /// Called before the expression's children are visited
+    fn enter(&mut self, _expr: &Expression<TS>) -> Visit {
+        Visit::Descend
+    }
+
+    /// Called after the expression's children are visited, or right after entering it if they
+    /// were skipped
+    fn exit(&mut self, _expr: &Expression<TS>) {}
+}
+
+impl<TS: TypeSystem, F> Visitor<TS> for F
+where
+    F: FnMut(&Expression<TS>) -> Visit,
+{
+    fn enter(&mut self, expr: &Expression<TS>) -> Visit {
+        self(expr)
+    }
+}
+
+/// Like [Visitor], but able to change each expression, see [Expression::visit_mut]
+pub trait VisitorMut<TS: TypeSystem> {
+    /// Called before the expression's children are visited. If the expression is replaced, the
+    /// replacement's children are visited instead.
+    fn enter(&mut self, _expr: &mut Expression<TS>) -> Visit {
+        Visit::Descend
+    }
+
+    /// Called after the expression's children are visited, or right after entering it if they
+    /// were skipped
+    fn exit(&mut self, _expr: &mut Expression<TS>) {}
+}
+
+impl<TS: TypeSystem, F> VisitorMut<TS> for F
+where
+    F: FnMut(&mut Expression<TS>) -> Visit,
+{
+    fn enter(&mut self, expr: &mut Expression<TS>) -> Visit {
+        self(expr)
+    }
+}
+
+impl<TS: TypeSystem> Expression<TS> {
+    /// Walk this expression tree, entering each expression before its children and exiting it
+    /// after them. Returns false if the visitor stopped the traversal.
+    pub fn visit<V: Visitor<TS> + ?Sized>(&self, visitor: &mut V) -> bool {
+        match visitor.enter(self) {
+            Visit::Stop => return false,
+            Visit::Skip => (),
+            Visit::Descend => {
+                for child in self.children() {
+                    if !child.visit(visitor) {
+                        return false;
+                    }
+                }
+            }
+        }
+        visitor.exit(self);
+        true
+    }
+
+    /// Walk this expression tree like [Expression::visit], letting the visitor change each
+    /// expression. Returns false if the visitor stopped the traversal.
+    pub fn visit_mut<V: VisitorMut<TS> + ?Sized>(&mut self, visitor: &mut V) -> bool {
+        match visitor.enter(self) {
+            Visit::Stop => return false,
+            Visit::Skip => (),
+            Visit::Descend => {
+                for child in self.children_mut() {
+                    if !child.visit_mut(visitor) {
+                        return false;
+                    }
+                }
+            }
+        }
+        visitor.exit(self);
+        true
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// A flag which can be set from any thread to stop an [crate::execution_engine::ExecutionEngine].
+/// The engine checks it whenever a function is entered, and raises
+/// [crate::error::FreightError::Cancelled] once it is set.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Request that execution stops
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Clear a previous cancellation, so execution can continue
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The [CancellationSnapshot::function] of cancellations between iterations of a loop
+pub const IN_LOOP: usize = usize::MAX;
+
+/// The state of the engine when it stopped because of a cancellation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CancellationSnapshot {
+    /// The address of the function that was being entered, or [IN_LOOP] if execution stopped
+    /// between iterations of a loop
+    pub function: usize,
+    /// A summary of each value in that function's stack frame, or the frame the loop ran in
+    pub frame: Vec<String>,
+}
+
+/// What a [Watchdog] does when a watched call runs for too long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    /// Cancel the call through its [CancellationToken]
+    Cancel,
+    /// Only record the timeout and notify the callback, letting the call continue
+    Notify,
+}
+
+type TimeoutCallback = Box<dyn Fn(Duration) + Send>;
+
+#[derive(Default)]
+struct WatchState {
+    deadline: Option<Instant>,
+    started: Option<Instant>,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<WatchState>,
+    wake: Condvar,
+    timed_out: AtomicBool,
+}
+
+/// A monitor thread which acts on calls that exceed a wall time limit.
+///
+/// ```ignore
+/// let watchdog = Watchdog::new(engine.cancellation.clone(), timeout, WatchdogAction::Cancel);
+/// let result = {
+///     let _guard = watchdog.guard();
+///     engine.call(&main, [])
+/// };
+/// ```
+pub struct Watchdog {
+    shared: Arc<Shared>,
+    token: CancellationToken,
+    thread: Option<JoinHandle<()>>,
+    timeout: Duration,
+}
+
+impl Watchdog {
+    pub fn new(token: CancellationToken, timeout: Duration, action: WatchdogAction) -> Watchdog {
+        Self::with_callback(token, timeout, action, Box::new(|_| {}))
+    }
+
+    /// Create a watchdog which also calls `on_timeout` from the monitor thread with the elapsed
+    /// time whenever a watched call times out
+    pub fn with_callback(
+        token: CancellationToken,
+        timeout: Duration,
+        action: WatchdogAction,
+        on_timeout: TimeoutCallback,
+    ) -> Watchdog {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(WatchState::default()),
+            wake: Condvar::new(),
+            timed_out: AtomicBool::new(false),
+        });
+        let thread = {
+            let shared = shared.clone();
+            let token = token.clone();
+            std::thread::spawn(move || monitor(&shared, &token, action, on_timeout))
+        };
+        Watchdog {
+            shared,
+            token,
+            thread: Some(thread),
+            timeout,
+        }
+    }
+
+    /// Start watching a call, which stops being watched when the guard is dropped.
+    /// Clears any previous cancellation and timeout.
+    pub fn guard(&self) -> WatchdogGuard<'_> {
+        self.token.reset();
+        self.shared.timed_out.store(false, Ordering::Relaxed);
+        let mut state = self.shared.state.lock().unwrap();
+        let now = Instant::now();
+        state.started = Some(now);
+        state.deadline = Some(now + self.timeout);
+        self.shared.wake.notify_one();
+        WatchdogGuard { watchdog: self }
+    }
+
+    /// Whether the most recently watched call timed out
+    pub fn timed_out(&self) -> bool {
+        self.shared.timed_out.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Keeps a call watched by a [Watchdog] until dropped
+pub struct WatchdogGuard<'a> {
+    watchdog: &'a Watchdog,
+}
+
+impl Drop for WatchdogGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.watchdog.shared.state.lock().unwrap();
+        state.deadline = None;
+        state.started = None;
+        self.watchdog.shared.wake.notify_one();
+    }
+}
+
+fn monitor(
+    shared: &Shared,
+    token: &CancellationToken,
+    action: WatchdogAction,
+    on_timeout: TimeoutCallback,
+) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if state.shutdown {
+            return;
+        }
+        let Some(deadline) = state.deadline else {
+            state = shared.wake.wait(state).unwrap();
+            continue;
+        };
+        let now = Instant::now();
+        if now < deadline {
+            state = shared.wake.wait_timeout(state, deadline - now).unwrap().0;
+            continue;
+        }
+        let elapsed = state.started.map(|s| now - s).unwrap_or_default();
+        state.deadline = None;
+        shared.timed_out.store(true, Ordering::Relaxed);
+        if action == WatchdogAction::Cancel {
+            token.cancel();
+        }
+        on_timeout(elapsed);
+    }
+}